@@ -0,0 +1,695 @@
+use std::cmp::min;
+use std::fs::{remove_file, rename, File};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process;
+use std::str::from_utf8;
+
+use database::acl::{hash_password, CommandRule, LogReason, User, DEFAULT_USER};
+use database::Database;
+use logger::Level;
+use parser::ParsedCommand;
+use response::Response;
+use util::{mstime, splitargs};
+
+use crate::command::{command_properties, Client, CommandFlags, CommandProperties, COMMAND_NAMES};
+
+/// Command categories, derived from the command flags.
+pub const CATEGORIES: &[&str] = &[
+    "read",
+    "write",
+    "admin",
+    "dangerous",
+    "pubsub",
+    "fast",
+    "slow",
+];
+
+const UNKNOWN_COMMAND_OR_CATEGORY: &str = "Unknown command or category name in ACL";
+const INVALID_HASH: &str = "The password hash must be exactly 64 characters and contain only \
+                            lowercase hexadecimal characters";
+const NO_SUCH_PASSWORD: &str = "The password you are trying to remove from the user does not exist";
+
+/// Gets the categories of a command with the given flags.
+pub(crate) fn command_categories(flags: CommandFlags) -> Vec<&'static str> {
+    let mut categories = vec![];
+    if flags.contains(CommandFlags::READONLY) {
+        categories.push("read");
+    }
+    if flags.contains(CommandFlags::WRITE) {
+        categories.push("write");
+    }
+    if flags.contains(CommandFlags::ADMIN) {
+        categories.push("admin");
+        categories.push("dangerous");
+    }
+    if flags.contains(CommandFlags::PUBSUB) {
+        categories.push("pubsub");
+    }
+    categories.push(if flags.contains(CommandFlags::FAST) {
+        "fast"
+    } else {
+        "slow"
+    });
+    categories
+}
+
+/// Parses the name in a +command or -@category rule.
+fn command_rule(name: &str) -> Result<CommandRule, &'static str> {
+    if let Some(category) = name.strip_prefix('@') {
+        if category == "all" {
+            Ok(CommandRule::All)
+        } else if CATEGORIES.contains(&category) {
+            Ok(CommandRule::Category(category.to_owned()))
+        } else {
+            Err(UNKNOWN_COMMAND_OR_CATEGORY)
+        }
+    } else if COMMAND_NAMES.contains(&name) {
+        Ok(CommandRule::Command(name.to_owned()))
+    } else {
+        Err(UNKNOWN_COMMAND_OR_CATEGORY)
+    }
+}
+
+fn parse_hash(hash: &[u8]) -> Result<String, &'static str> {
+    if hash.len() != 64 || !hash.iter().all(|c| b"0123456789abcdef".contains(c)) {
+        return Err(INVALID_HASH);
+    }
+    Ok(from_utf8(hash).unwrap().to_owned())
+}
+
+fn add_pattern(patterns: &mut Vec<Vec<u8>>, pattern: &[u8]) {
+    if !patterns.iter().any(|p| &**p == pattern) {
+        patterns.push(pattern.to_vec());
+    }
+}
+
+/// Applies a rule, as accepted by ACL SETUSER, to a user.
+///
+/// # Examples
+///
+/// ```
+/// # use command::acl::apply_rule;
+/// # use database::acl::User;
+/// #
+/// let mut user = User::new("foo");
+/// apply_rule(&mut user, b"on").unwrap();
+/// apply_rule(&mut user, b"+@read").unwrap();
+/// assert!(user.enabled);
+/// assert_eq!(user.describe_commands(), "-@all +@read");
+/// assert!(apply_rule(&mut user, b"+@unknown").is_err());
+/// ```
+pub fn apply_rule(user: &mut User, rule: &[u8]) -> Result<(), &'static str> {
+    match &*rule.to_ascii_lowercase() {
+        b"on" => user.enabled = true,
+        b"off" => user.enabled = false,
+        b"nopass" => {
+            user.nopass = true;
+            user.passwords.clear();
+        }
+        b"resetpass" => {
+            user.nopass = false;
+            user.passwords.clear();
+        }
+        b"allkeys" => user.keys = vec![b"*".to_vec()],
+        b"resetkeys" => user.keys.clear(),
+        b"allchannels" => user.channels = vec![b"*".to_vec()],
+        b"resetchannels" => user.channels.clear(),
+        b"allcommands" => user.set_command_rule(true, CommandRule::All),
+        b"nocommands" => user.set_command_rule(false, CommandRule::All),
+        b"reset" => *user = User::new(&user.name),
+        lowercase => match rule.first() {
+            Some(b'>') => user.add_password(hash_password(&rule[1..])),
+            Some(b'<') => {
+                if !user.remove_password(&hash_password(&rule[1..])) {
+                    return Err(NO_SUCH_PASSWORD);
+                }
+            }
+            Some(b'#') => user.add_password(parse_hash(&rule[1..])?),
+            Some(b'!') => {
+                if !user.remove_password(&parse_hash(&rule[1..])?) {
+                    return Err(NO_SUCH_PASSWORD);
+                }
+            }
+            Some(b'~') => add_pattern(&mut user.keys, &rule[1..]),
+            Some(b'&') => add_pattern(&mut user.channels, &rule[1..]),
+            Some(&sign) if sign == b'+' || sign == b'-' => {
+                let name = from_utf8(&lowercase[1..]).map_err(|_| UNKNOWN_COMMAND_OR_CATEGORY)?;
+                user.set_command_rule(sign == b'+', command_rule(name)?);
+            }
+            _ => return Err("Syntax error"),
+        },
+    }
+    Ok(())
+}
+
+/// Gets the position of the keys in a command with `argc` arguments.
+pub(crate) fn key_positions(argc: usize, properties: &CommandProperties) -> Vec<usize> {
+    if properties.first_key_index <= 0 {
+        return vec![];
+    }
+    let last = if properties.last_key_index < 0 {
+        argc as i64 + properties.last_key_index
+    } else {
+        min(properties.last_key_index, argc as i64 - 1)
+    };
+    (properties.first_key_index..=last)
+        .step_by(properties.key_step.max(1) as usize)
+        .map(|i| i as usize)
+        .collect()
+}
+
+/// Position of the `numkeys` argument of the commands that take one. It is
+/// followed by that many keys.
+pub(crate) fn numkeys_position(command_name: &str) -> Option<usize> {
+    match command_name {
+        "zunion" | "zinter" | "zdiff" | "zintercard" | "sintercard" | "lmpop" | "zmpop" => Some(1),
+        "zunionstore" | "zinterstore" | "zdiffstore" | "blmpop" | "bzmpop" => Some(2),
+        _ => None,
+    }
+}
+
+/// Whether the keys of a command depend on its arguments, instead of being
+/// described by its first key, last key and step.
+pub(crate) fn has_movable_keys(command_name: &str) -> bool {
+    numkeys_position(command_name).is_some() || command_name == "migrate"
+}
+
+/// Gets the position of the keys in a command, including the ones with
+/// movable keys. An invalid `numkeys` results in no keys.
+pub(crate) fn command_key_positions(command_name: &str, parser: &ParsedCommand) -> Vec<usize> {
+    let argc = parser.argv.len();
+    let properties = command_properties(command_name);
+    if let Some(pos) = numkeys_position(command_name) {
+        let numkeys = match parser.get_i64(pos) {
+            Ok(n) if n > 0 && (n as usize) < argc - pos => n as usize,
+            _ => return vec![],
+        };
+        // the destination of the store commands
+        let mut positions = key_positions(pos, &properties);
+        positions.extend(pos + 1..pos + 1 + numkeys);
+        return positions;
+    }
+    if command_name == "migrate" {
+        // MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+        // [AUTH password | AUTH2 username password] [KEYS key [key ...]]
+        if parser.get_slice(3).is_ok_and(|key| !key.is_empty()) {
+            return vec![3];
+        }
+        let mut i = 6;
+        while i < argc {
+            match &*parser.get_str(i).unwrap_or_default().to_ascii_lowercase() {
+                "auth" => i += 1,
+                "auth2" => i += 2,
+                "keys" => return (i + 1..argc).collect(),
+                _ => (),
+            }
+            i += 1;
+        }
+        return vec![];
+    }
+    key_positions(argc, &properties)
+}
+
+/// Checks whether the client user can run a command, including the keys and
+/// channels it accesses. Returns the error to reply with if it cannot.
+pub(crate) fn permission_error(
+    parser: &ParsedCommand,
+    db: &mut Database,
+    client: &Client,
+    command_name: &str,
+) -> Option<Response> {
+    let (reason, object, username) = {
+        let acl = db.acl();
+        let user = acl.get_user(&client.user)?;
+        let properties = command_properties(command_name);
+        let argc = parser.argv.len();
+        let key = command_key_positions(command_name, parser)
+            .into_iter()
+            .filter_map(|i| parser.get_slice(i).ok())
+            .find(|key| !user.can_access_key(key));
+        let (channels, is_pattern) = match command_name {
+            "publish" | "spublish" => (1..min(argc, 2), false),
+            "subscribe" | "ssubscribe" => (1..argc, false),
+            "psubscribe" => (1..argc, true),
+            _ => (0..0, false),
+        };
+        let channel = channels
+            .filter_map(|i| parser.get_slice(i).ok())
+            .find(|channel| !user.can_access_channel(channel, is_pattern));
+
+        let (reason, object) =
+            if !user.can_execute(command_name, &command_categories(properties.flags)) {
+                (LogReason::Command, command_name.as_bytes().to_vec())
+            } else if let Some(key) = key {
+                (LogReason::Key, key.to_vec())
+            } else if let Some(channel) = channel {
+                (LogReason::Channel, channel.to_vec())
+            } else {
+                return None;
+            };
+        (reason, object, user.name.clone())
+    };
+
+    let message = match reason {
+        LogReason::Command => format!(
+            "NOPERM User {} has no permissions to run the '{}' command",
+            username, command_name
+        ),
+        LogReason::Key => "NOPERM No permissions to access a key".to_owned(),
+        _ => "NOPERM No permissions to access a channel".to_owned(),
+    };
+    db.acl().log(
+        reason,
+        "toplevel",
+        String::from_utf8_lossy(&object).into_owned(),
+        username,
+        format!("id={}", client.id),
+    );
+    Some(Response::Error(message))
+}
+
+/// Authenticates a client, with `AUTH password` for the default user or
+/// `AUTH username password`.
+pub(crate) fn auth(parser: &ParsedCommand, db: &mut Database, client: &mut Client) -> Response {
+    let (username, password) = match parser.argv.len() {
+        2 => (DEFAULT_USER.to_owned(), parser.get_vec(1)),
+        3 => match parser.get_str(1) {
+            Ok(username) => (username.to_owned(), parser.get_vec(2)),
+            Err(_) => return Response::Error("ERR Invalid username".to_owned()),
+        },
+        _ => return Response::Error("ERR syntax error".to_owned()),
+    };
+    let password = match password {
+        Ok(p) => p,
+        Err(_) => return Response::Error("ERR Invalid password".to_owned()),
+    };
+    let legacy = parser.argv.len() == 2;
+    if legacy && db.acl().get_user(DEFAULT_USER).is_some_and(|u| u.nopass) {
+        return Response::Error("ERR Client sent AUTH, but no password is set".to_owned());
+    }
+
+    if db
+        .acl()
+        .get_user(&username)
+        .is_some_and(|u| u.check_password(&password))
+    {
+        client.user = username;
+        client.auth = true;
+        return Response::Status("OK".to_owned());
+    }
+    db.acl().log(
+        LogReason::Auth,
+        "toplevel",
+        "AUTH".to_owned(),
+        username,
+        format!("id={}", client.id),
+    );
+    Response::Error(
+        if legacy {
+            "ERR invalid password"
+        } else {
+            "WRONGPASS invalid username-password pair or user is disabled."
+        }
+        .to_owned(),
+    )
+}
+
+fn user_response(user: &User) -> Response {
+    let mut flags = vec![Response::Data(
+        if user.enabled { "on" } else { "off" }.as_bytes().to_vec(),
+    )];
+    if user.nopass {
+        flags.push(Response::Data(b"nopass".to_vec()));
+    }
+    Response::Array(vec![
+        Response::Data(b"flags".to_vec()),
+        Response::Array(flags),
+        Response::Data(b"passwords".to_vec()),
+        Response::Array(
+            user.passwords
+                .iter()
+                .map(|p| Response::Data(p.as_bytes().to_vec()))
+                .collect(),
+        ),
+        Response::Data(b"commands".to_vec()),
+        Response::Data(user.describe_commands().into_bytes()),
+        Response::Data(b"keys".to_vec()),
+        Response::Data(user.describe_keys().into_bytes()),
+        Response::Data(b"channels".to_vec()),
+        Response::Data(user.describe_channels().into_bytes()),
+    ])
+}
+
+fn log_response(db: &Database, count: usize) -> Response {
+    let now = mstime();
+    Response::Array(
+        db.acl()
+            .log_entries()
+            .take(count)
+            .map(|e| {
+                Response::Array(vec![
+                    Response::Data(b"count".to_vec()),
+                    Response::Integer(e.count as i64),
+                    Response::Data(b"reason".to_vec()),
+                    Response::Data(e.reason.as_str().as_bytes().to_vec()),
+                    Response::Data(b"context".to_vec()),
+                    Response::Data(e.context.as_bytes().to_vec()),
+                    Response::Data(b"object".to_vec()),
+                    Response::Data(e.object.as_bytes().to_vec()),
+                    Response::Data(b"username".to_vec()),
+                    Response::Data(e.username.as_bytes().to_vec()),
+                    Response::Data(b"age-seconds".to_vec()),
+                    Response::Data(format!("{:.3}", (now - e.mstime) as f64 / 1000.0).into_bytes()),
+                    Response::Data(b"client-info".to_vec()),
+                    Response::Data(e.client_info.as_bytes().to_vec()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn setuser(parser: &ParsedCommand, db: &mut Database) -> Response {
+    let name = match parser.get_str(2) {
+        Ok(name) => name,
+        Err(_) => return Response::Error("ERR Invalid username".to_owned()),
+    };
+    if name.contains(|c: char| c.is_whitespace() || c == '\0') {
+        return Response::Error("ERR Usernames can't contain spaces or null characters".to_owned());
+    }
+    let mut user = db
+        .acl()
+        .get_user(name)
+        .cloned()
+        .unwrap_or_else(|| User::new(name));
+    for i in 3..parser.argv.len() {
+        let rule = parser.get_slice(i).unwrap();
+        if let Err(err) = apply_rule(&mut user, rule) {
+            return Response::Error(format!(
+                "ERR Error in ACL SETUSER modifier '{}': {}",
+                String::from_utf8_lossy(rule),
+                err
+            ));
+        }
+    }
+    db.acl().set_user(user);
+    Response::Status("OK".to_owned())
+}
+
+fn deluser(parser: &ParsedCommand, db: &mut Database) -> Response {
+    let mut names = Vec::with_capacity(parser.argv.len() - 2);
+    for i in 2..parser.argv.len() {
+        match parser.get_str(i) {
+            Ok(DEFAULT_USER) => {
+                return Response::Error("ERR The 'default' user cannot be removed".to_owned())
+            }
+            Ok(name) => names.push(name),
+            Err(_) => return Response::Error("ERR Invalid username".to_owned()),
+        }
+    }
+    Response::Integer(
+        names
+            .into_iter()
+            .filter(|n| db.acl().remove_user(n))
+            .count() as i64,
+    )
+}
+
+fn cat(category: Option<&str>) -> Response {
+    let names = match category {
+        None => CATEGORIES.to_vec(),
+        Some(category) => {
+            let category = category.to_ascii_lowercase();
+            if !CATEGORIES.contains(&&*category) {
+                return Response::Error(format!("ERR Unknown category '{}'", category));
+            }
+            COMMAND_NAMES
+                .iter()
+                .filter(|name| {
+                    command_categories(command_properties(name).flags).contains(&&*category)
+                })
+                .cloned()
+                .collect()
+        }
+    };
+    Response::Array(
+        names
+            .into_iter()
+            .map(|n| Response::Data(n.as_bytes().to_vec()))
+            .collect(),
+    )
+}
+
+/// Manages users and their permissions.
+pub(crate) fn acl(parser: &ParsedCommand, db: &mut Database, client: &Client) -> Response {
+    let subcommand = match parser.get_str(1) {
+        Ok(s) => s.to_ascii_lowercase(),
+        Err(_) => return Response::Error("ERR Invalid subcommand".to_owned()),
+    };
+    let argc = parser.argv.len();
+    match (&*subcommand, argc) {
+        ("setuser", _) if argc >= 3 => setuser(parser, db),
+        ("getuser", 3) => match db.acl().get_user(parser.get_str(2).unwrap_or("")) {
+            Some(user) => user_response(user),
+            None => Response::Nil,
+        },
+        ("deluser", _) if argc >= 3 => deluser(parser, db),
+        ("list", 2) => Response::Array(
+            db.acl()
+                .users()
+                .map(|u| Response::Data(u.describe().into_bytes()))
+                .collect(),
+        ),
+        ("whoami", 2) => Response::Data(client.user.as_bytes().to_vec()),
+        ("cat", 2) => cat(None),
+        ("cat", 3) => match parser.get_str(2) {
+            Ok(category) => cat(Some(category)),
+            Err(_) => Response::Error("ERR Invalid category".to_owned()),
+        },
+        ("log", 2) => log_response(db, 10),
+        ("log", 3) => {
+            if parser
+                .get_str(2)
+                .is_ok_and(|s| s.eq_ignore_ascii_case("reset"))
+            {
+                db.acl().reset_log();
+                return Response::Status("OK".to_owned());
+            }
+            match parser.get_i64(2) {
+                Ok(count) if count >= 0 => log_response(db, count as usize),
+                _ => Response::Error("ERR value is out of range, must be positive".to_owned()),
+            }
+        }
+        ("save", 2) | ("load", 2) if db.config.aclfile.is_none() => Response::Error(
+            "ERR This Redis instance is not configured to use an ACL file. You may want to \
+             specify users via the ACL SETUSER command and then issue a CONFIG REWRITE \
+             (assuming you have a Redis configuration file set) in order to store users in the \
+             Redis configuration."
+                .to_owned(),
+        ),
+        ("save", 2) => match save(db) {
+            Ok(_) => Response::Status("OK".to_owned()),
+            Err(err) => {
+                logger::log!(db.config.logger, Warning, "Saving the ACL file: {}", err);
+                Response::Error(
+                    "ERR There was an error trying to save the ACLs. Please check the server \
+                     logs for more information"
+                        .to_owned(),
+                )
+            }
+        },
+        ("load", 2) => match load(db) {
+            Ok(_) => Response::Status("OK".to_owned()),
+            Err(err) => Response::Error(format!("ERR {}", err)),
+        },
+        _ => Response::Error(format!(
+            "ERR Unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        )),
+    }
+}
+
+/// Replaces the users with the ones in `config.aclfile`. If the file has any
+/// error no user is changed.
+pub fn load(db: &mut Database) -> Result<(), String> {
+    let path = db.config.aclfile.clone().unwrap();
+    let file = File::open(&path)
+        .map_err(|err| format!("Error loading ACLs, opening file '{}': {}", path, err))?;
+    let mut users: Vec<User> = vec![];
+    for (i, line) in BufReader::new(file).split(b'\n').enumerate() {
+        let line = line.map_err(|err| format!("Error loading ACLs from '{}': {}", path, err))?;
+        let error = |message: &str| format!("{}:{}: {}", path, i + 1, message);
+        let args = splitargs(&line).map_err(|_| error("unbalanced quotes in acl line"))?;
+        if args.is_empty() {
+            continue;
+        }
+        if args.len() < 2 || args[0] != b"user" {
+            return Err(error("line should start with user keyword"));
+        }
+        let name = from_utf8(&args[1]).map_err(|_| error("invalid user name"))?;
+        if users.iter().any(|u| u.name == name) {
+            return Err(error(&format!("Duplicate user '{}' found", name)));
+        }
+        let mut user = User::new(name);
+        for rule in args[2..].iter() {
+            apply_rule(&mut user, rule).map_err(error)?;
+        }
+        users.push(user);
+    }
+    db.acl().set_users(users);
+    Ok(())
+}
+
+/// Writes every user into `config.aclfile`.
+/// The file is written to a temporary file first, so the previous one is kept
+/// if anything fails.
+pub fn save(db: &Database) -> io::Result<()> {
+    let path = Path::new(db.config.aclfile.as_ref().unwrap());
+    let tmppath = path.with_file_name(format!("temp-{}.acl", process::id()));
+    let r = save_to(db, &tmppath);
+    if r.is_err() {
+        let _ = remove_file(&tmppath);
+        return r;
+    }
+    rename(&tmppath, path)
+}
+
+fn save_to(db: &Database, path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    for user in db.acl().users() {
+        writeln!(file, "{}", user.describe())?;
+    }
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod test_acl {
+    use std::env::temp_dir;
+    use std::fs::File;
+    use std::io::Write;
+
+    use database::acl::{hash_password, User};
+    use database::Database;
+    use parser::ParsedCommand;
+
+    use super::{apply_rule, command_categories, command_key_positions, key_positions, load, save};
+    use crate::command::{command_properties, CommandFlags};
+
+    #[test]
+    fn categories() {
+        assert_eq!(
+            command_categories(command_properties("get").flags),
+            vec!["read", "fast"]
+        );
+        assert_eq!(
+            command_categories(command_properties("shutdown").flags),
+            vec!["read", "admin", "dangerous", "slow"]
+        );
+        assert_eq!(
+            command_categories(CommandFlags::WRITE | CommandFlags::PUBSUB),
+            vec!["write", "pubsub", "slow"]
+        );
+    }
+
+    #[test]
+    fn rules() {
+        let mut user = User::new("user");
+        for rule in [
+            &b"on"[..],
+            b">secret",
+            b"~foo:*",
+            b"&news",
+            b"+@read",
+            b"-KEYS",
+        ]
+        .iter()
+        {
+            apply_rule(&mut user, rule).unwrap();
+        }
+        assert_eq!(
+            user.describe(),
+            format!(
+                "user user on #{} ~foo:* &news -@all +@read -keys",
+                hash_password(b"secret")
+            )
+        );
+        assert!(user.check_password(b"secret"));
+
+        apply_rule(&mut user, b"<secret").unwrap();
+        assert!(apply_rule(&mut user, b"<secret").is_err());
+        assert!(apply_rule(&mut user, b"#abc").is_err());
+        assert!(apply_rule(&mut user, b"+notacommand").is_err());
+        assert!(apply_rule(&mut user, b"whatever").is_err());
+
+        apply_rule(&mut user, b"reset").unwrap();
+        assert_eq!(user, User::new("user"));
+    }
+
+    #[test]
+    fn keys() {
+        let argc = 5;
+        assert_eq!(key_positions(argc, &command_properties("get")), vec![1]);
+        assert_eq!(
+            key_positions(argc, &command_properties("del")),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(key_positions(argc, &command_properties("mset")), vec![1, 3]);
+        assert_eq!(key_positions(argc, &command_properties("ping")), vec![]);
+    }
+
+    #[test]
+    fn movable_keys() {
+        let positions = |command: &[u8]| {
+            let parser = ParsedCommand::from_args(
+                command
+                    .split(|c| *c == b' ')
+                    .map(|arg| arg.to_vec())
+                    .collect(),
+            );
+            command_key_positions(parser.get_str(0).unwrap(), &parser)
+        };
+        assert_eq!(positions(b"zunionstore d 2 a b weights 1 2"), vec![1, 3, 4]);
+        assert_eq!(positions(b"zinterstore d 3 a b"), vec![]);
+        assert_eq!(positions(b"zunion 2 a b"), vec![2, 3]);
+        assert_eq!(positions(b"zunion 0 a"), vec![]);
+        assert_eq!(positions(b"bzmpop 1 2 a b min"), vec![3, 4]);
+        assert_eq!(positions(b"sintercard 1 a limit 1"), vec![2]);
+        assert_eq!(positions(b"migrate h 1 k 0 10"), vec![3]);
+        assert_eq!(
+            positions(b"migrate h 1  0 10 copy auth keys keys a b"),
+            vec![10, 11]
+        );
+        assert_eq!(positions(b"mset a 1 b 2"), vec![1, 3]);
+    }
+
+    #[test]
+    fn save_load() {
+        let mut path = temp_dir();
+        path.push("acltest.acl");
+
+        let mut db = Database::mock();
+        db.config.aclfile = Some(path.to_str().unwrap().to_owned());
+        let mut user = User::new("user");
+        apply_rule(&mut user, b"on").unwrap();
+        apply_rule(&mut user, b"nopass").unwrap();
+        apply_rule(&mut user, b"+@write").unwrap();
+        db.acl().set_user(user.clone());
+        save(&db).unwrap();
+
+        let mut other = Database::mock();
+        other.config.aclfile = db.config.aclfile.clone();
+        load(&mut other).unwrap();
+        assert_eq!(other.acl().get_user("user"), Some(&user));
+        assert_eq!(other.acl().users().count(), 2);
+
+        File::create(&path)
+            .unwrap()
+            .write_all(b"user default on nopass +@all\nuser foo +unknown\n")
+            .unwrap();
+        assert!(load(&mut other)
+            .unwrap_err()
+            .ends_with(":2: Unknown command or category name in ACL"));
+        assert_eq!(other.acl().get_user("user"), Some(&user));
+    }
+}
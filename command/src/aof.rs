@@ -13,12 +13,14 @@ const UNEXPECTED_END: &str =
      'aof-load-truncated' configuration option to yes and restart the server.";
 
 pub fn load(db: &mut Database) {
-    let mut aof = db.aof.take().unwrap();
-    db.loading = true;
+    let mut aof = db.aof().take().unwrap();
+    db.set_loading(true);
     let mut client = command::Client::new(channel().0, 0);
-    let mut parser = Parser::new();
+    let mut parser = Parser::multibulk();
+    parser.set_max_bulk_len(db.config.proto_max_bulk_len);
+    let mut read_more = false;
     loop {
-        if parser.is_incomplete() {
+        if read_more {
             parser.allocate();
             let len = {
                 let pos = parser.written;
@@ -30,13 +32,14 @@ pub fn load(db: &mut Database) {
                 }
             };
             parser.written += len;
+            read_more = false;
 
             if len == 0 {
-                if parser.written > parser.position {
+                if parser.written > parser.position() {
                     if !db.config.aof_load_truncated {
                         logger::log_and_exit!(db.config.logger, Warning, 1, "{}", UNEXPECTED_END);
                     }
-                    aof.truncate(parser.position);
+                    aof.truncate(parser.position());
                 }
                 break;
             }
@@ -47,6 +50,7 @@ pub fn load(db: &mut Database) {
             Err(err) => {
                 match err {
                     ParseError::Incomplete => {
+                        read_more = true;
                         continue;
                     }
                     // TODO: break, continue, or panic?
@@ -69,6 +73,6 @@ pub fn load(db: &mut Database) {
     if client.multi && !db.config.aof_load_truncated {
         logger::log_and_exit!(db.config.logger, Warning, 1, "{}", UNEXPECTED_END);
     }
-    db.aof = Some(aof);
-    db.loading = false;
+    *db.aof() = Some(aof);
+    db.set_loading(false);
 }
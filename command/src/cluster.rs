@@ -0,0 +1,514 @@
+use std::fs::{remove_file, rename, File};
+use std::io;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::process;
+
+use database::cluster::{key_hash_slot, Cluster, CLUSTER_PORT_INCR, CLUSTER_SLOTS};
+use database::Database;
+use logger::Level;
+use parser::ParsedCommand;
+use response::Response;
+use util::mstime;
+
+use crate::acl::command_key_positions;
+use crate::command::{command_properties, Client, CommandFlags};
+
+const CLUSTER_DISABLED: &str = "ERR This instance has cluster support disabled";
+
+/// Loads the cluster configuration from `config.cluster_config_file`, or
+/// creates a new node if the file does not exist yet.
+pub fn load(db: &mut Database) -> Result<(), String> {
+    let path = db.config.cluster_config_file.clone();
+    let port = db.config.port;
+    let node_timeout = db.config.cluster_node_timeout;
+    let mut contents = String::new();
+    match File::open(&path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut contents)
+                .map_err(|err| format!("Loading the cluster config from '{}': {}", path, err))?;
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => {
+            return Err(format!(
+                "Loading the cluster config from '{}': {}",
+                path, err
+            ))
+        }
+    }
+    let cluster = if contents.trim().is_empty() {
+        let cluster = Cluster::new(port, node_timeout);
+        logger::log!(
+            db.config.logger,
+            Notice,
+            "No cluster configuration found, I'm {}",
+            cluster.myself().id
+        );
+        cluster
+    } else {
+        let cluster = Cluster::from_config(&contents, port, node_timeout).map_err(|err| {
+            format!(
+                "Unrecoverable error: corrupted cluster config file '{}': {}",
+                path, err
+            )
+        })?;
+        logger::log!(
+            db.config.logger,
+            Notice,
+            "Node configuration loaded, I'm {}",
+            cluster.myself().id
+        );
+        cluster
+    };
+    *db.cluster() = Some(cluster);
+    save_config(db);
+    Ok(())
+}
+
+/// Writes the cluster configuration into `config.cluster_config_file`.
+/// The file is written to a temporary file first, so the previous one is kept
+/// if anything fails.
+pub fn save(db: &Database) -> io::Result<()> {
+    let path = Path::new(&db.config.cluster_config_file);
+    let tmppath = path.with_file_name(format!("temp-{}.conf", process::id()));
+    let r = save_to(db, &tmppath);
+    if r.is_err() {
+        let _ = remove_file(&tmppath);
+        return r;
+    }
+    rename(&tmppath, path)
+}
+
+fn save_to(db: &Database, path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    if let Some(cluster) = db.cluster().as_ref() {
+        file.write_all(cluster.config_string().as_bytes())?;
+    }
+    file.sync_all()
+}
+
+/// Saves the cluster configuration if it changed since it was last saved.
+pub fn save_config(db: &mut Database) {
+    if !db.cluster().as_ref().is_some_and(|c| c.dirty) {
+        return;
+    }
+    match save(db) {
+        Ok(_) => db.cluster().as_mut().unwrap().dirty = false,
+        Err(err) => logger::log!(
+            db.config.logger,
+            Warning,
+            "Saving the cluster config file: {}",
+            err
+        ),
+    }
+}
+
+/// Checks whether the keys a command accesses are served by this node.
+/// Returns the redirection or error to reply with if they are not.
+pub(crate) fn redirect(
+    parser: &ParsedCommand,
+    db: &Database,
+    client: &Client,
+    command_name: &str,
+) -> Option<Response> {
+    let cluster = db.cluster();
+    let cluster = cluster.as_ref()?;
+    let properties = command_properties(command_name);
+    let positions = match command_name {
+        "ssubscribe" | "sunsubscribe" => (1..parser.argv.len()).collect(),
+        "spublish" => vec![1],
+        _ => command_key_positions(command_name, parser),
+    };
+    let mut slot = None;
+    let (mut existing, mut missing) = (0, 0);
+    for i in positions {
+        let key = match parser.get_slice(i) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        let key_slot = key_hash_slot(key);
+        if slot.is_some_and(|s| s != key_slot) {
+            return Some(Response::Error(
+                "CROSSSLOT Keys in request don't hash to the same slot".to_owned(),
+            ));
+        }
+        slot = Some(key_slot);
+        if db.get(client.dbindex, key).is_some() {
+            existing += 1;
+        } else {
+            missing += 1;
+        }
+    }
+    let slot = slot?;
+    let owner = match cluster.slot_owner(slot) {
+        Some(owner) => owner,
+        None => {
+            return Some(Response::Error(
+                "CLUSTERDOWN Hash slot not served".to_owned(),
+            ))
+        }
+    };
+    if !cluster.state_ok() {
+        return Some(Response::Error(
+            "CLUSTERDOWN The cluster is down".to_owned(),
+        ));
+    }
+    let tryagain = || {
+        Some(Response::Error(
+            "TRYAGAIN Multiple keys request during rehashing of slot".to_owned(),
+        ))
+    };
+    if owner.id == cluster.myself().id {
+        return match cluster.migrating(slot) {
+            Some(_) if missing > 0 && existing > 0 => tryagain(),
+            Some(target) if missing > 0 => {
+                Some(Response::Error(format!("ASK {} {}", slot, target.addr())))
+            }
+            _ => None,
+        };
+    }
+    if cluster.importing(slot).is_some()
+        && (client.asking || properties.flags.contains(CommandFlags::ASKING))
+    {
+        return if existing + missing > 1 && missing > 0 {
+            tryagain()
+        } else {
+            None
+        };
+    }
+    Some(Response::Error(format!("MOVED {} {}", slot, owner.addr())))
+}
+
+/// Lets the next command access a slot this node is importing.
+pub(crate) fn asking(db: &Database, client: &mut Client) -> Response {
+    if !db.is_cluster() {
+        return Response::Error(CLUSTER_DISABLED.to_owned());
+    }
+    client.asking = true;
+    Response::Status("OK".to_owned())
+}
+
+/// READONLY and READWRITE. There are no replicas to read from, so they only
+/// check the server is running in cluster mode.
+pub(crate) fn readonly(db: &Database) -> Response {
+    if !db.is_cluster() {
+        return Response::Error(CLUSTER_DISABLED.to_owned());
+    }
+    Response::Status("OK".to_owned())
+}
+
+fn parse_slot(parser: &ParsedCommand, i: usize) -> Result<usize, Response> {
+    match parser.get_i64(i) {
+        Ok(slot) if slot >= 0 && (slot as usize) < CLUSTER_SLOTS => Ok(slot as usize),
+        _ => Err(Response::Error(
+            "ERR Invalid or out of range slot".to_owned(),
+        )),
+    }
+}
+
+fn parse_slots(parser: &ParsedCommand) -> Result<Vec<usize>, Response> {
+    (2..parser.argv.len())
+        .map(|i| parse_slot(parser, i))
+        .collect()
+}
+
+/// Keys in database 0 that belong to a slot.
+fn keys_in_slot(db: &Database, slot: usize) -> impl Iterator<Item = &Vec<u8>> {
+    db.iter_db(0)
+        .map(|(key, _)| key)
+        .filter(move |key| key_hash_slot(key) == slot)
+}
+
+fn status(r: Result<(), String>) -> Response {
+    match r {
+        Ok(_) => Response::Status("OK".to_owned()),
+        Err(err) => Response::Error(err),
+    }
+}
+
+fn meet(parser: &ParsedCommand, cluster: &mut Cluster) -> Response {
+    let ip = parser.get_str(2).unwrap_or("");
+    let port_arg = parser.get_str(3).unwrap_or("");
+    let port = match port_arg.parse::<u16>() {
+        Ok(port) => port,
+        Err(_) => {
+            return Response::Error(format!("ERR Invalid TCP base port specified: {}", port_arg))
+        }
+    };
+    let cport = if parser.argv.len() == 5 {
+        let cport_arg = parser.get_str(4).unwrap_or("");
+        match cport_arg.parse::<u16>() {
+            Ok(cport) => cport,
+            Err(_) => {
+                return Response::Error(format!(
+                    "ERR Invalid TCP bus port specified: {}",
+                    cport_arg
+                ))
+            }
+        }
+    } else {
+        port.wrapping_add(CLUSTER_PORT_INCR)
+    };
+    if ip.parse::<IpAddr>().is_err() {
+        return Response::Error(format!(
+            "ERR Invalid node address specified: {}:{}",
+            ip, port_arg
+        ));
+    }
+    cluster.meet(ip, port, cport, mstime());
+    Response::Status("OK".to_owned())
+}
+
+fn setslot(parser: &ParsedCommand, db: &mut Database) -> Response {
+    let slot = match parse_slot(parser, 2) {
+        Ok(slot) => slot,
+        Err(err) => return err,
+    };
+    let action = parser.get_str(3).unwrap_or("").to_ascii_lowercase();
+    let id = parser.get_str(4).unwrap_or("");
+    match (&*action, parser.argv.len()) {
+        ("migrating", 5) => status(db.cluster().as_mut().unwrap().set_slot_migrating(slot, id)),
+        ("importing", 5) => status(db.cluster().as_mut().unwrap().set_slot_importing(slot, id)),
+        ("stable", 4) => {
+            db.cluster().as_mut().unwrap().set_slot_stable(slot);
+            Response::Status("OK".to_owned())
+        }
+        ("node", 5) => {
+            let (owned, myself) = {
+                let cluster = db.cluster();
+                let cluster = cluster.as_ref().unwrap();
+                let myself = &cluster.myself().id;
+                let owned = cluster.slot_owner(slot).is_some_and(|n| &n.id == myself);
+                (owned, id == myself)
+            };
+            if owned && !myself && keys_in_slot(db, slot).next().is_some() {
+                return Response::Error(format!(
+                    "ERR Can't assign hashslot {} to a different node while I still hold keys \
+                     for this hash slot.",
+                    slot
+                ));
+            }
+            status(db.cluster().as_mut().unwrap().set_slot_node(slot, id))
+        }
+        _ => Response::Error(
+            "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"
+                .to_owned(),
+        ),
+    }
+}
+
+fn info(cluster: &Cluster) -> Response {
+    let (assigned, pfail, fail) = cluster.slots_stats();
+    Response::Data(
+        format!(
+            "cluster_state:{}\r\n\
+             cluster_slots_assigned:{}\r\n\
+             cluster_slots_ok:{}\r\n\
+             cluster_slots_pfail:{}\r\n\
+             cluster_slots_fail:{}\r\n\
+             cluster_known_nodes:{}\r\n\
+             cluster_size:{}\r\n\
+             cluster_current_epoch:{}\r\n\
+             cluster_my_epoch:{}\r\n\
+             cluster_stats_messages_sent:{}\r\n\
+             cluster_stats_messages_received:{}\r\n",
+            if cluster.state_ok() { "ok" } else { "fail" },
+            assigned,
+            assigned - pfail - fail,
+            pfail,
+            fail,
+            cluster.nodes().len(),
+            cluster.size(),
+            cluster.current_epoch,
+            cluster.myself().config_epoch,
+            cluster.messages_sent,
+            cluster.messages_received,
+        )
+        .into_bytes(),
+    )
+}
+
+fn slots(cluster: &Cluster) -> Response {
+    let mut ranges = vec![];
+    for node in cluster.nodes() {
+        for (start, end) in cluster.slot_ranges(&node.id) {
+            ranges.push((start, end, node));
+        }
+    }
+    ranges.sort_by_key(|r| r.0);
+    Response::Array(
+        ranges
+            .into_iter()
+            .map(|(start, end, node)| {
+                Response::Array(vec![
+                    Response::Integer(start as i64),
+                    Response::Integer(end as i64),
+                    Response::Array(vec![
+                        Response::Data(node.ip.as_bytes().to_vec()),
+                        Response::Integer(node.port as i64),
+                        Response::Data(node.id.as_bytes().to_vec()),
+                    ]),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn shards(cluster: &Cluster) -> Response {
+    let data = |s: &str| Response::Data(s.as_bytes().to_vec());
+    Response::Array(
+        cluster
+            .nodes()
+            .into_iter()
+            .filter(|node| !node.handshake)
+            .map(|node| {
+                let mut slots = vec![];
+                for (start, end) in cluster.slot_ranges(&node.id) {
+                    slots.push(Response::Integer(start as i64));
+                    slots.push(Response::Integer(end as i64));
+                }
+                Response::Array(vec![
+                    data("slots"),
+                    Response::Array(slots),
+                    data("nodes"),
+                    Response::Array(vec![Response::Array(vec![
+                        data("id"),
+                        data(&node.id),
+                        data("port"),
+                        Response::Integer(node.port as i64),
+                        data("ip"),
+                        data(&node.ip),
+                        data("endpoint"),
+                        data(&node.ip),
+                        data("role"),
+                        data("master"),
+                        data("replication-offset"),
+                        Response::Integer(0),
+                        data("health"),
+                        data(if node.fail || node.pfail {
+                            "failed"
+                        } else {
+                            "online"
+                        }),
+                    ])]),
+                ])
+            })
+            .collect(),
+    )
+}
+
+pub(crate) fn cluster(parser: &ParsedCommand, db: &mut Database) -> Response {
+    let subcommand = match parser.get_str(1) {
+        Ok(s) => s.to_ascii_lowercase(),
+        Err(_) => return Response::Error("ERR Invalid subcommand".to_owned()),
+    };
+    if !db.is_cluster() {
+        return Response::Error(CLUSTER_DISABLED.to_owned());
+    }
+    let argc = parser.argv.len();
+    let response = match (&*subcommand, argc) {
+        ("meet", 4) | ("meet", 5) => meet(parser, db.cluster().as_mut().unwrap()),
+        ("addslots", _) if argc >= 3 => match parse_slots(parser) {
+            Ok(slots) => status(db.cluster().as_mut().unwrap().add_slots(&slots)),
+            Err(err) => err,
+        },
+        ("delslots", _) if argc >= 3 => match parse_slots(parser) {
+            Ok(slots) => status(db.cluster().as_mut().unwrap().del_slots(&slots)),
+            Err(err) => err,
+        },
+        ("setslot", _) if argc >= 4 => setslot(parser, db),
+        ("nodes", 2) => Response::Data(
+            db.cluster()
+                .as_ref()
+                .unwrap()
+                .nodes_description()
+                .into_bytes(),
+        ),
+        ("slots", 2) => slots(db.cluster().as_ref().unwrap()),
+        ("shards", 2) => shards(db.cluster().as_ref().unwrap()),
+        ("info", 2) => info(db.cluster().as_ref().unwrap()),
+        ("myid", 2) => Response::Data(
+            db.cluster()
+                .as_ref()
+                .unwrap()
+                .myself()
+                .id
+                .as_bytes()
+                .to_vec(),
+        ),
+        ("keyslot", 3) => match parser.get_slice(2) {
+            Ok(key) => Response::Integer(key_hash_slot(key) as i64),
+            Err(_) => Response::Error("ERR Invalid key".to_owned()),
+        },
+        ("countkeysinslot", 3) => match parser.get_i64(2) {
+            Ok(slot) if slot >= 0 && (slot as usize) < CLUSTER_SLOTS => {
+                Response::Integer(keys_in_slot(db, slot as usize).count() as i64)
+            }
+            _ => Response::Error("ERR Invalid slot".to_owned()),
+        },
+        ("getkeysinslot", 4) => match (parser.get_i64(2), parser.get_i64(3)) {
+            (Ok(slot), Ok(count)) if slot >= 0 && (slot as usize) < CLUSTER_SLOTS && count >= 0 => {
+                Response::Array(
+                    keys_in_slot(db, slot as usize)
+                        .take(count as usize)
+                        .map(|key| Response::Data(key.clone()))
+                        .collect(),
+                )
+            }
+            _ => Response::Error("ERR Invalid slot or number of keys".to_owned()),
+        },
+        _ => Response::Error(format!(
+            "ERR Unknown subcommand or wrong number of arguments for '{}'. Try CLUSTER HELP.",
+            subcommand
+        )),
+    };
+    save_config(db);
+    response
+}
+
+#[cfg(test)]
+mod test_cluster {
+    use std::env::temp_dir;
+    use std::fs::{remove_file, File};
+    use std::io::{Read, Write};
+
+    use database::Database;
+
+    use super::load;
+
+    #[test]
+    fn load_save() {
+        let mut path = temp_dir();
+        path.push("clustertest-nodes.conf");
+        let _ = remove_file(&path);
+
+        let mut db = Database::mock();
+        db.config.cluster_config_file = path.to_str().unwrap().to_owned();
+        load(&mut db).unwrap();
+        let id = db.cluster().as_ref().unwrap().myself().id.clone();
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert!(contents.starts_with(&format!(
+            "{} :0@10000 myself,master - 0 0 0 connected\n",
+            id
+        )));
+
+        let mut other = Database::mock();
+        other.config.cluster_config_file = db.config.cluster_config_file.clone();
+        load(&mut other).unwrap();
+        assert_eq!(other.cluster().as_ref().unwrap().myself().id, id);
+
+        File::create(&path)
+            .unwrap()
+            .write_all(b"foo bar\n")
+            .unwrap();
+        assert!(load(&mut other)
+            .unwrap_err()
+            .starts_with("Unrecoverable error: corrupted cluster config file"));
+        let _ = remove_file(&path);
+    }
+}
@@ -12,11 +12,22 @@ use std::{
 use bitflags::bitflags;
 
 use compat::{getos, getpid};
+use config::{NOTIFY_GENERIC, NOTIFY_LIST, NOTIFY_SET, NOTIFY_STRING, NOTIFY_ZSET};
+use database::acl::DEFAULT_USER;
+use database::error::OperationError;
+use database::tracking::TrackingOptions;
 use database::{zset, Database, PubsubEvent, Value};
+use logger::Level;
 use parser::{Argument, OwnedParsedCommand, ParsedCommand};
 use response::{Response, ResponseError};
 use util::mstime;
 
+use crate::acl;
+use crate::cluster;
+use crate::introspection;
+use crate::migrate;
+use crate::sentinel;
+
 macro_rules! opt_validate {
     ($expr: expr, $err: expr) => {
         if !($expr) {
@@ -100,6 +111,16 @@ macro_rules! get_values {
     }};
 }
 
+/// What a SET does to the time to live of the key.
+enum SetTtl {
+    /// Removes the time to live
+    Clear,
+    /// Keeps the time to live the key had, if any
+    Keep,
+    /// Expires the key at a unix time in milliseconds
+    At(i64),
+}
+
 fn generic_set(
     db: &mut Database,
     dbindex: usize,
@@ -107,7 +128,7 @@ fn generic_set(
     val: Vec<u8>,
     nx: bool,
     xx: bool,
-    expiration: Option<i64>,
+    ttl: SetTtl,
 ) -> Result<bool, Response> {
     if nx && db.get(dbindex, &key).is_some() {
         return Ok(false);
@@ -117,12 +138,27 @@ fn generic_set(
         return Ok(false);
     }
 
+    if db.config.lazyfree_lazy_server_del {
+        if let Some(value) = db.get_mut(dbindex, &key) {
+            let old = replace(value, Value::Nil);
+            db.free(old, true);
+        }
+    }
+
     match db.get_or_create(dbindex, &key).set(val) {
         Ok(_) => {
+            db.notify_keyspace_event(NOTIFY_STRING, "set", dbindex, &key);
             db.key_updated(dbindex, &key);
 
-            if let Some(msexp) = expiration {
-                db.set_msexpiration(dbindex, key, msexp + mstime());
+            match ttl {
+                SetTtl::Clear => {
+                    db.remove_msexpiration(dbindex, &key);
+                }
+                SetTtl::Keep => (),
+                SetTtl::At(msexp) => {
+                    db.set_msexpiration(dbindex, key.clone(), msexp);
+                    db.notify_keyspace_event(NOTIFY_GENERIC, "expire", dbindex, &key);
+                }
             }
 
             Ok(true)
@@ -131,45 +167,82 @@ fn generic_set(
     }
 }
 
+/// Converts the value of an EX, PX, EXAT or PXAT option to a unix time in
+/// milliseconds. Returns None if it overflows.
+fn absolute_msexpiration(option: &str, value: i64) -> Option<i64> {
+    match option {
+        "ex" => value
+            .checked_mul(1000)
+            .and_then(|ms| ms.checked_add(mstime())),
+        "px" => value.checked_add(mstime()),
+        "exat" => value.checked_mul(1000),
+        _ => Some(value),
+    }
+}
+
+/// Parses the value of an EX, PX, EXAT or PXAT option at `pos` as a unix
+/// time in milliseconds.
+fn parse_expiration(
+    parser: &ParsedCommand,
+    option: &str,
+    pos: usize,
+    command_name: &str,
+) -> Result<i64, Response> {
+    let value = parser
+        .get_i64(pos)
+        .map_err(|_| Response::Error("ERR value is not an integer or out of range".to_owned()))?;
+    match absolute_msexpiration(option, value) {
+        Some(msexp) if value > 0 => Ok(msexp),
+        _ => Err(Response::Error(format!(
+            "ERR invalid expire time in '{}' command",
+            command_name
+        ))),
+    }
+}
+
 fn set(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
     validate_arguments_gte!(parser, 3);
     let key = try_validate!(parser.get_vec(1), "ERR syntax error");
     let val = try_validate!(parser.get_vec(2), "ERR syntax error");
     let mut nx = false;
     let mut xx = false;
-    let mut expiration = None;
-    let mut skip = false;
-    for i in 3..parser.argv.len() {
-        if skip {
-            skip = false;
-            continue;
-        }
-        let param = try_validate!(parser.get_str(i), "ERR syntax error");
-        match &*param.to_ascii_lowercase() {
-            "nx" => nx = true,
-            "xx" => xx = true,
-            "px" => {
-                let px = try_validate!(parser.get_i64(i + 1), "ERR syntax error");
-                expiration = Some(px);
-                skip = true;
-            }
-            "ex" => {
-                let ex = try_validate!(parser.get_i64(i + 1), "ERR syntax error");
-                expiration = Some(ex * 1000);
-                skip = true;
+    let mut get = false;
+    let mut ttl = None;
+    let mut i = 3;
+    while i < parser.argv.len() {
+        let param = try_validate!(parser.get_str(i), "ERR syntax error").to_ascii_lowercase();
+        match &*param {
+            "nx" if !xx => nx = true,
+            "xx" if !nx => xx = true,
+            "get" => get = true,
+            "keepttl" if ttl.is_none() => ttl = Some(SetTtl::Keep),
+            "ex" | "px" | "exat" | "pxat" if ttl.is_none() && i + 1 < parser.argv.len() => {
+                ttl = match parse_expiration(parser, &param, i + 1, "set") {
+                    Ok(msexp) => Some(SetTtl::At(msexp)),
+                    Err(err) => return err,
+                };
+                i += 1;
             }
             _ => return Response::Error("ERR syntax error".to_owned()),
         }
+        i += 1;
     }
 
-    match generic_set(db, dbindex, key, val, nx, xx, expiration) {
-        Ok(updated) => {
-            if updated {
-                Response::Status("OK".to_owned())
-            } else {
-                Response::Nil
-            }
+    let old = if get {
+        match generic_get(db, dbindex, key.clone(), true) {
+            Response::Error(err) => return Response::Error(err),
+            old => Some(old),
         }
+    } else {
+        None
+    };
+
+    match generic_set(db, dbindex, key, val, nx, xx, ttl.unwrap_or(SetTtl::Clear)) {
+        Ok(updated) => match old {
+            Some(old) => old,
+            None if updated => Response::Status("OK".to_owned()),
+            None => Response::Nil,
+        },
         Err(r) => r,
     }
 }
@@ -178,7 +251,7 @@ fn setnx(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respo
     validate_arguments_exact!(parser, 3);
     let key = try_validate!(parser.get_vec(1), "ERR syntax error");
     let val = try_validate!(parser.get_vec(2), "ERR syntax error");
-    match generic_set(db, dbindex, key, val, true, false, None) {
+    match generic_set(db, dbindex, key, val, true, false, SetTtl::Clear) {
         Ok(updated) => Response::Integer(if updated { 1 } else { 0 }),
         Err(r) => r,
     }
@@ -190,7 +263,8 @@ fn setex(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respo
     let exp = try_validate!(parser.get_i64(2), "ERR syntax error");
     validate!(exp >= 0, "ERR invalid expire time");
     let val = try_validate!(parser.get_vec(3), "ERR syntax error");
-    match generic_set(db, dbindex, key, val, false, false, Some(exp * 1000)) {
+    let ttl = SetTtl::At(mstime() + exp * 1000);
+    match generic_set(db, dbindex, key, val, false, false, ttl) {
         Ok(_) => Response::Status("OK".to_owned()),
         Err(r) => r,
     }
@@ -202,7 +276,8 @@ fn psetex(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Resp
     let exp = try_validate!(parser.get_i64(2), "ERR syntax error");
     validate!(exp >= 0, "ERR invalid expire time");
     let val = try_validate!(parser.get_vec(3), "ERR syntax error");
-    match generic_set(db, dbindex, key, val, false, false, Some(exp)) {
+    let ttl = SetTtl::At(mstime() + exp);
+    match generic_set(db, dbindex, key, val, false, false, ttl) {
         Ok(_) => Response::Status("OK".to_owned()),
         Err(r) => r,
     }
@@ -217,13 +292,19 @@ fn exists(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Resp
     })
 }
 
-fn del(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+fn generic_del(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    lazy: bool,
+) -> Response {
     validate!(parser.argv.len() >= 2, "Wrong number of parameters");
     let mut c = 0;
     for i in 1..parser.argv.len() {
         let key = try_validate!(parser.get_vec(i), "Invalid key");
-        if db.remove(dbindex, &key).is_some() {
+        if db.delete(dbindex, &key, lazy) {
             c += 1;
+            db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, &key);
             db.key_updated(dbindex, &key);
         }
     }
@@ -231,8 +312,17 @@ fn del(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respons
     Response::Integer(c)
 }
 
+fn del(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    let lazy = db.config.lazyfree_lazy_user_del;
+    generic_del(parser, db, dbindex, lazy)
+}
+
+fn unlink(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    generic_del(parser, db, dbindex, true)
+}
+
 fn debug_object(db: &mut Database, dbindex: usize, key: Vec<u8>) -> Option<String> {
-    db.get(dbindex, &key).map(|val| val.debug_object())
+    db.get_notouch(dbindex, &key).map(|val| val.debug_object())
 }
 
 fn debug(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
@@ -251,6 +341,34 @@ fn debug(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respo
     }
 }
 
+/// OBJECT ENCODING, IDLETIME, FREQ and REFCOUNT. There is no eviction
+/// policy, so both the idle time and the access frequency are tracked.
+/// Values are never shared, their reference count is always one.
+fn object(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    let subcommand = try_validate!(parser.get_str(1), "Invalid subcommand").to_ascii_lowercase();
+    match (&*subcommand, parser.argv.len()) {
+        ("encoding", 3) | ("idletime", 3) | ("freq", 3) | ("refcount", 3) => {
+            let key = try_validate!(parser.get_vec(2), "Invalid key");
+            let value = match db.get_notouch(dbindex, &key) {
+                Some(value) => value,
+                None => return Response::Nil,
+            };
+            match &*subcommand {
+                "encoding" => Response::Data(value.encoding().as_bytes().to_vec()),
+                "idletime" => Response::Integer(db.idle_ms(dbindex, &key).unwrap_or(0) / 1000),
+                "freq" => {
+                    Response::Integer(i64::from(db.access_frequency(dbindex, &key).unwrap_or(0)))
+                }
+                _ => Response::Integer(1),
+            }
+        }
+        _ => Response::Error(format!(
+            "ERR Unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        )),
+    }
+}
+
 fn dbsize(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
     validate_arguments_exact!(parser, 1);
     Response::Integer(db.dbsize(dbindex) as i64)
@@ -271,54 +389,230 @@ fn dump(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
     }
 }
 
+fn restore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate!(parser.argv.len() >= 4, "Wrong number of parameters");
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let ttl = try_validate!(
+        parser.get_i64(2),
+        "ERR value is not an integer or out of range"
+    );
+    let payload = try_validate!(parser.get_vec(3), "Invalid payload");
+    let mut replace = false;
+    let mut absttl = false;
+    let mut idletime = false;
+    let mut freq = false;
+    let mut i = 4;
+    while i < parser.argv.len() {
+        let param = try_validate!(parser.get_str(i), "ERR syntax error");
+        match &*param.to_ascii_lowercase() {
+            "replace" => replace = true,
+            "absttl" => absttl = true,
+            // there is no eviction policy, the access information is ignored
+            "idletime" if !freq && i + 1 < parser.argv.len() => {
+                let idle = try_validate!(
+                    parser.get_i64(i + 1),
+                    "ERR value is not an integer or out of range"
+                );
+                validate!(idle >= 0, "ERR Invalid IDLETIME value, must be >= 0");
+                idletime = true;
+                i += 1;
+            }
+            "freq" if !idletime && i + 1 < parser.argv.len() => {
+                let f = try_validate!(
+                    parser.get_i64(i + 1),
+                    "ERR value is not an integer or out of range"
+                );
+                validate!(
+                    (0..=255).contains(&f),
+                    "ERR Invalid FREQ value, must be >= 0 and <= 255"
+                );
+                freq = true;
+                i += 1;
+            }
+            _ => return Response::Error("ERR syntax error".to_owned()),
+        }
+        i += 1;
+    }
+    validate!(ttl >= 0, "ERR Invalid TTL value, must be >= 0");
+    if !replace && db.get(dbindex, &key).is_some() {
+        return Response::Error("BUSYKEY Target key name already exists.".to_owned());
+    }
+    let value = match Value::restore(&payload) {
+        Ok(value) => value,
+        Err(err) => return Response::Error(err.to_string()),
+    };
+
+    let lazy = db.config.lazyfree_lazy_server_del;
+    let existed = db.delete(dbindex, &key, lazy);
+    let expiration = match ttl {
+        0 => None,
+        ttl if absttl => Some(ttl),
+        ttl => Some(mstime() + ttl),
+    };
+    match expiration {
+        // already expired, there is nothing to create
+        Some(expiration) if expiration <= mstime() => {
+            if existed {
+                db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, &key);
+            }
+        }
+        _ => {
+            *db.get_or_create(dbindex, &key) = value;
+            if let Some(expiration) = expiration {
+                db.set_msexpiration(dbindex, key.clone(), expiration);
+            }
+            db.notify_keyspace_event(NOTIFY_GENERIC, "restore", dbindex, &key);
+        }
+    }
+    db.key_updated(dbindex, &key);
+    Response::Status("OK".to_owned())
+}
+
 fn echo(parser: &mut ParsedCommand) -> Response {
     validate_arguments_exact!(parser, 2);
     let msg = try_validate!(parser.get_str(1), "Syntax error");
     Response::Data(msg.as_bytes().to_vec())
 }
 
-fn generic_expire(db: &mut Database, dbindex: usize, key: Vec<u8>, msexpiration: i64) -> Response {
-    Response::Integer(match db.get(dbindex, &key) {
-        Some(_) => {
-            db.set_msexpiration(dbindex, key.clone(), msexpiration);
-            db.key_updated(dbindex, &key);
-            1
+/// Sets the time to live of a key for EXPIRE, PEXPIRE, EXPIREAT and
+/// PEXPIREAT. `unit` is the SET option whose value has the same meaning.
+/// A time in the past deletes the key. What was done is written to the aof
+/// as PEXPIREAT or DEL, a relative time would be wrong when it is loaded.
+fn generic_expire(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    write: &mut bool,
+    unit: &str,
+    command_name: &str,
+) -> Response {
+    validate_arguments_gte!(parser, 3);
+    *write = false;
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let value = try_validate!(
+        parser.get_i64(2),
+        "ERR value is not an integer or out of range"
+    );
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for i in 3..parser.argv.len() {
+        let option = try_validate!(parser.get_str(i), "ERR syntax error");
+        match &*option.to_ascii_lowercase() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            _ => return Response::Error(format!("ERR Unsupported option {}", option)),
         }
-        None => 0,
-    })
+    }
+    validate!(
+        !nx || !(xx || gt || lt),
+        "ERR NX and XX, GT or LT options at the same time are not compatible"
+    );
+    validate!(
+        !(gt && lt),
+        "ERR GT and LT options at the same time are not compatible"
+    );
+    let msexpiration = match absolute_msexpiration(unit, value) {
+        Some(msexpiration) => msexpiration,
+        None => {
+            return Response::Error(format!(
+                "ERR invalid expire time in '{}' command",
+                command_name
+            ))
+        }
+    };
+
+    if db.get(dbindex, &key).is_none() {
+        return Response::Integer(0);
+    }
+    // a key without time to live never expires, it is greater than any time
+    let allowed = match db.get_msexpiration(dbindex, &key) {
+        Some(&current) => !nx && (!gt || msexpiration > current) && (!lt || msexpiration < current),
+        None => !xx && !gt,
+    };
+    if !allowed {
+        return Response::Integer(0);
+    }
+
+    let args = if msexpiration <= mstime() {
+        let lazy = db.config.lazyfree_lazy_expire;
+        db.delete(dbindex, &key, lazy);
+        db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, &key);
+        vec![b"DEL".to_vec(), key.clone()]
+    } else {
+        db.set_msexpiration(dbindex, key.clone(), msexpiration);
+        db.notify_keyspace_event(NOTIFY_GENERIC, "expire", dbindex, &key);
+        vec![
+            b"PEXPIREAT".to_vec(),
+            key.clone(),
+            format!("{}", msexpiration).into_bytes(),
+        ]
+    };
+    db.propagate(dbindex, &ParsedCommand::from_args(args));
+    db.key_updated(dbindex, &key);
+    Response::Integer(1)
 }
 
-fn expire(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    validate_arguments_exact!(parser, 3);
-    let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let expiration = try_validate!(parser.get_i64(2), "Invalid expiration");
-    generic_expire(db, dbindex, key, mstime() + expiration * 1000)
+fn expire(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    write: &mut bool,
+) -> Response {
+    generic_expire(parser, db, dbindex, write, "ex", "expire")
 }
 
-fn expireat(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    validate_arguments_exact!(parser, 3);
-    let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let expiration = try_validate!(parser.get_i64(2), "Invalid expiration");
-    generic_expire(db, dbindex, key, expiration * 1000)
+fn expireat(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    write: &mut bool,
+) -> Response {
+    generic_expire(parser, db, dbindex, write, "exat", "expireat")
 }
 
-fn pexpire(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    validate_arguments_exact!(parser, 3);
-    let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let expiration = try_validate!(parser.get_i64(2), "Invalid expiration");
-    generic_expire(db, dbindex, key, mstime() + expiration)
+fn pexpire(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    write: &mut bool,
+) -> Response {
+    generic_expire(parser, db, dbindex, write, "px", "pexpire")
 }
 
-fn pexpireat(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    validate_arguments_exact!(parser, 3);
-    let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let expiration = try_validate!(parser.get_i64(2), "Invalid expiration");
-    generic_expire(db, dbindex, key, expiration)
+fn pexpireat(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    write: &mut bool,
+) -> Response {
+    generic_expire(parser, db, dbindex, write, "pxat", "pexpireat")
+}
+
+/// Whether FLUSHDB or FLUSHALL were called with ASYNC.
+fn flush_async(parser: &ParsedCommand) -> Result<bool, Response> {
+    let syntax_error = || Response::Error("ERR syntax error".to_owned());
+    match parser.argv.len() {
+        1 => Ok(false),
+        2 => match &*parser
+            .get_str(1)
+            .map_err(|_| syntax_error())?
+            .to_ascii_lowercase()
+        {
+            "async" => Ok(true),
+            "sync" => Ok(false),
+            _ => Err(syntax_error()),
+        },
+        _ => Err(syntax_error()),
+    }
 }
 
 fn flushdb(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    validate_arguments_exact!(parser, 1);
-    db.clear(dbindex);
+    let lazy = match flush_async(parser) {
+        Ok(lazy) => lazy,
+        Err(err) => return err,
+    };
+    db.clear(dbindex, lazy);
 
     Response::Status("OK".to_owned())
 }
@@ -345,11 +639,36 @@ fn pttl(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
     generic_ttl(db, dbindex, &key, 1)
 }
 
+fn generic_expiretime(db: &mut Database, dbindex: usize, key: &[u8], divisor: i64) -> Response {
+    Response::Integer(match db.get(dbindex, key) {
+        Some(_) => match db.get_msexpiration(dbindex, key) {
+            Some(exp) => exp / divisor,
+            None => -1,
+        },
+        None => -2,
+    })
+}
+
+fn expiretime(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_exact!(parser, 2);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    generic_expiretime(db, dbindex, &key, 1000)
+}
+
+fn pexpiretime(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_exact!(parser, 2);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    generic_expiretime(db, dbindex, &key, 1)
+}
+
 fn persist(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
     validate_arguments_exact!(parser, 2);
     let key = try_validate!(parser.get_vec(1), "Invalid key");
     let r = Response::Integer(match db.remove_msexpiration(dbindex, &key) {
-        Some(_) => 1,
+        Some(_) => {
+            db.notify_keyspace_event(NOTIFY_GENERIC, "persist", dbindex, &key);
+            1
+        }
         None => 0,
     });
     db.key_updated(dbindex, &key);
@@ -371,8 +690,11 @@ fn dbtype(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response
 }
 
 fn flushall(parser: &mut ParsedCommand, db: &mut Database, _: usize) -> Response {
-    validate_arguments_exact!(parser, 1);
-    db.clearall();
+    let lazy = match flush_async(parser) {
+        Ok(lazy) => lazy,
+        Err(err) => return err,
+    };
+    db.clearall(lazy);
 
     Response::Status("OK".to_owned())
 }
@@ -396,6 +718,9 @@ fn append(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Resp
             Err(err) => Response::Error(err.to_string()),
         }
     };
+    if !r.is_error() {
+        db.notify_keyspace_event(NOTIFY_STRING, "append", dbindex, &key);
+    }
     db.key_updated(dbindex, &key);
     r
 }
@@ -423,6 +748,164 @@ fn get(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
     generic_get(db, dbindex, key, true)
 }
 
+fn getdel(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_exact!(parser, 2);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let r = generic_get(db, dbindex, key.clone(), true);
+    if let Response::Data(_) = r {
+        let lazy = db.config.lazyfree_lazy_user_del;
+        db.delete(dbindex, &key, lazy);
+        db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, &key);
+        db.key_updated(dbindex, &key);
+    }
+    r
+}
+
+/// GETEX writes to the aof what it did to the key: PEXPIREAT, PERSIST or
+/// DEL. A relative expiration would be wrong when the aof is loaded.
+fn getex(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    write: &mut bool,
+) -> Response {
+    validate_arguments_gte!(parser, 2);
+    *write = false;
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let ttl = match parser.argv.len() {
+        2 => None,
+        3 => {
+            let option = try_validate!(parser.get_str(2), "ERR syntax error");
+            validate!(option.eq_ignore_ascii_case("persist"), "ERR syntax error");
+            Some(SetTtl::Clear)
+        }
+        4 => {
+            let option = try_validate!(parser.get_str(2), "ERR syntax error").to_ascii_lowercase();
+            match &*option {
+                "ex" | "px" | "exat" | "pxat" => {
+                    match parse_expiration(parser, &option, 3, "getex") {
+                        Ok(msexp) => Some(SetTtl::At(msexp)),
+                        Err(err) => return err,
+                    }
+                }
+                _ => return Response::Error("ERR syntax error".to_owned()),
+            }
+        }
+        _ => return Response::Error("ERR syntax error".to_owned()),
+    };
+
+    let r = generic_get(db, dbindex, key.clone(), true);
+    if let Response::Data(_) = r {
+        let propagate = match ttl {
+            Some(SetTtl::At(msexp)) if msexp <= mstime() => {
+                let lazy = db.config.lazyfree_lazy_expire;
+                db.delete(dbindex, &key, lazy);
+                db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, &key);
+                Some(vec![b"DEL".to_vec(), key.clone()])
+            }
+            Some(SetTtl::At(msexp)) => {
+                db.set_msexpiration(dbindex, key.clone(), msexp);
+                db.notify_keyspace_event(NOTIFY_GENERIC, "expire", dbindex, &key);
+                Some(vec![
+                    b"PEXPIREAT".to_vec(),
+                    key.clone(),
+                    format!("{}", msexp).into_bytes(),
+                ])
+            }
+            Some(SetTtl::Clear) if db.remove_msexpiration(dbindex, &key).is_some() => {
+                db.notify_keyspace_event(NOTIFY_GENERIC, "persist", dbindex, &key);
+                Some(vec![b"PERSIST".to_vec(), key.clone()])
+            }
+            _ => None,
+        };
+        if let Some(args) = propagate {
+            db.propagate(dbindex, &ParsedCommand::from_args(args));
+        }
+        db.key_updated(dbindex, &key);
+    }
+    r
+}
+
+fn lcs(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let key1 = try_validate!(parser.get_vec(1), "Invalid key");
+    let key2 = try_validate!(parser.get_vec(2), "Invalid key");
+    let mut len = false;
+    let mut idx = false;
+    let mut withmatchlen = false;
+    let mut minmatchlen = 0;
+    let mut i = 3;
+    while i < parser.argv.len() {
+        let option = try_validate!(parser.get_str(i), "ERR syntax error");
+        match &*option.to_ascii_lowercase() {
+            "len" => len = true,
+            "idx" => idx = true,
+            "withmatchlen" => withmatchlen = true,
+            "minmatchlen" if i + 1 < parser.argv.len() => {
+                let m = try_validate!(
+                    parser.get_i64(i + 1),
+                    "ERR value is not an integer or out of range"
+                );
+                minmatchlen = if m < 0 { 0 } else { m as usize };
+                i += 1;
+            }
+            _ => return Response::Error("ERR syntax error".to_owned()),
+        }
+        i += 1;
+    }
+    validate!(
+        !(len && idx),
+        "ERR If you want both the length and indexes, please just use IDX."
+    );
+
+    let string_error = "ERR The specified keys must contain string values";
+    let nil = Value::Nil;
+    let a = db.get(dbindex, &key1).unwrap_or(&nil);
+    let b = db.get(dbindex, &key2).unwrap_or(&nil);
+    let alen = try_validate!(a.strlen(), string_error);
+    let blen = try_validate!(b.strlen(), string_error);
+    // the lcs table uses four bytes for each pair of positions
+    let table_size = (alen + 1)
+        .checked_mul(blen + 1)
+        .and_then(|cells| cells.checked_mul(4));
+    validate!(
+        table_size.is_some_and(|size| size <= db.config.proto_max_bulk_len),
+        "ERR Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len"
+    );
+    let lcs = try_validate!(a.lcs(b), string_error);
+
+    if len {
+        return Response::Integer(lcs.subsequence.len() as i64);
+    }
+    if !idx {
+        return Response::Data(lcs.subsequence);
+    }
+    let range = |start: usize, end: usize| {
+        Response::Array(vec![
+            Response::Integer(start as i64),
+            Response::Integer(end as i64),
+        ])
+    };
+    let matches = lcs
+        .matches
+        .iter()
+        .filter(|((start, end), _)| end - start + 1 >= minmatchlen)
+        .map(|&((astart, aend), (bstart, bend))| {
+            let mut m = vec![range(astart, aend), range(bstart, bend)];
+            if withmatchlen {
+                m.push(Response::Integer((aend - astart + 1) as i64));
+            }
+            Response::Array(m)
+        })
+        .collect();
+    Response::Array(vec![
+        Response::Data(b"matches".to_vec()),
+        Response::Array(matches),
+        Response::Data(b"len".to_vec()),
+        Response::Integer(lcs.subsequence.len() as i64),
+    ])
+}
+
 fn mget(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
     validate!(parser.argv.len() >= 2, "Wrong number of parameters");
     let mut responses = Vec::with_capacity(parser.argv.len() - 1);
@@ -474,6 +957,9 @@ fn setrange(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Re
             Err(e) => Response::Error(e.to_string()),
         }
     };
+    if !r.is_error() {
+        db.notify_keyspace_event(NOTIFY_STRING, "setrange", dbindex, &key);
+    }
     db.key_updated(dbindex, &key);
     r
 }
@@ -504,6 +990,9 @@ fn setbit(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Resp
         Ok(s) => Response::Integer(if s { 1 } else { 0 }),
         Err(e) => Response::Error(e.to_string()),
     };
+    if !r.is_error() {
+        db.notify_keyspace_event(NOTIFY_STRING, "setbit", dbindex, &key);
+    }
     db.key_updated(dbindex, &key);
     r
 }
@@ -547,6 +1036,9 @@ fn generic_incr(
         Ok(val) => Response::Integer(val),
         Err(err) => Response::Error(err.to_string()),
     };
+    if !r.is_error() {
+        db.notify_keyspace_event(NOTIFY_STRING, "incrby", dbindex, &key);
+    }
     db.key_updated(dbindex, &key);
     r
 }
@@ -585,6 +1077,9 @@ fn incrbyfloat(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) ->
         Ok(val) => Response::Data(format!("{}", val).into_bytes()),
         Err(err) => Response::Error(err.to_string()),
     };
+    if !r.is_error() {
+        db.notify_keyspace_event(NOTIFY_STRING, "incrbyfloat", dbindex, &key);
+    }
     db.key_updated(dbindex, &key);
     r
 }
@@ -600,6 +1095,9 @@ fn pfadd(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respo
         Ok(val) => Response::Integer(if val { 1 } else { 0 }),
         Err(err) => Response::Error(err.to_string()),
     };
+    if r == Response::Integer(1) {
+        db.notify_keyspace_event(NOTIFY_STRING, "pfadd", dbindex, &key);
+    }
     db.key_updated(dbindex, &key);
     r
 }
@@ -662,6 +1160,9 @@ fn pfmerge(parser: &ParsedCommand, db: &mut Database, dbindex: usize) -> Respons
         let value = db.get_or_create(dbindex, &key);
         *value = val;
     }
+    if !r.is_error() {
+        db.notify_keyspace_event(NOTIFY_STRING, "pfadd", dbindex, &key);
+    }
     db.key_updated(dbindex, &key);
     r
 }
@@ -692,6 +1193,10 @@ fn generic_push(
             Err(err) => Response::Error(err.to_string()),
         }
     }
+    if !r.is_error() {
+        let event = if right { "rpush" } else { "lpush" };
+        db.notify_keyspace_event(NOTIFY_LIST, event, dbindex, &key);
+    }
     db.key_updated(dbindex, &key);
     r
 }
@@ -712,28 +1217,64 @@ fn rpushx(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Resp
     generic_push(parser, db, dbindex, true, false)
 }
 
-fn generic_pop(
-    parser: &mut ParsedCommand,
+/// Pops up to `count` elements from the list in `key`.
+/// Returns None if the key does not exist.
+fn pop_elements(
     db: &mut Database,
     dbindex: usize,
+    key: &[u8],
     right: bool,
-) -> Response {
-    validate_arguments_exact!(parser, 2);
-    let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let r = {
-        match db.get_mut(dbindex, &key) {
-            Some(list) => match list.pop(right) {
-                Ok(el) => match el {
-                    Some(val) => Response::Data(val),
-                    None => Response::Nil,
-                },
-                Err(err) => Response::Error(err.to_string()),
-            },
-            None => Response::Nil,
+    count: usize,
+) -> Result<Option<Vec<Vec<u8>>>, OperationError> {
+    let popped = match db.get_mut(dbindex, key) {
+        Some(list) => {
+            let mut popped = Vec::with_capacity(count.min(list.llen()?));
+            while popped.len() < count {
+                match list.pop(right)? {
+                    Some(el) => popped.push(el),
+                    None => break,
+                }
+            }
+            popped
         }
+        None => return Ok(None),
     };
-    db.key_updated(dbindex, &key);
-    r
+    if !popped.is_empty() {
+        let event = if right { "rpop" } else { "lpop" };
+        db.notify_keyspace_event(NOTIFY_LIST, event, dbindex, key);
+        db.key_updated(dbindex, key);
+    }
+    Ok(Some(popped))
+}
+
+fn generic_pop(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    right: bool,
+) -> Response {
+    validate_arguments_gte!(parser, 2);
+    validate_arguments_lte!(parser, 3);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let count = if parser.argv.len() == 3 {
+        let count = try_validate!(
+            parser.get_i64(2),
+            "ERR value is out of range, must be positive"
+        );
+        validate!(count >= 0, "ERR value is out of range, must be positive");
+        Some(count as usize)
+    } else {
+        None
+    };
+    let mut popped = match pop_elements(db, dbindex, &key, right, count.unwrap_or(1)) {
+        Ok(Some(popped)) => popped,
+        Ok(None) => return Response::Nil,
+        Err(err) => return Response::Error(err.to_string()),
+    };
+    match count {
+        Some(_) => Response::Array(popped.into_iter().map(Response::Data).collect()),
+        None => popped.pop().map_or(Response::Nil, Response::Data),
+    }
 }
 
 fn lpop(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
@@ -744,70 +1285,103 @@ fn rpop(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
     generic_pop(parser, db, dbindex, true)
 }
 
-fn generic_rpoplpush(
+/// Parses LEFT or RIGHT. Returns true for RIGHT.
+fn parse_direction(parser: &ParsedCommand, pos: usize) -> Result<bool, Response> {
+    match &*parser
+        .get_str(pos)
+        .map_err(|_| Response::Error("ERR syntax error".to_owned()))?
+        .to_ascii_lowercase()
+    {
+        "left" => Ok(false),
+        "right" => Ok(true),
+        _ => Err(Response::Error("ERR syntax error".to_owned())),
+    }
+}
+
+fn generic_move(
     db: &mut Database,
     dbindex: usize,
     source: &[u8],
     destination: &[u8],
+    from_right: bool,
+    to_right: bool,
 ) -> Response {
     if let Some(Err(_)) = db.get(dbindex, destination).map(|el| el.llen()) {
         return Response::Error("WRONGTYPE Destination is not a list".to_owned());
     }
 
-    let el = {
-        let sourcelist = match db.get_mut(dbindex, source) {
-            Some(sourcelist) => {
-                if sourcelist.llen().is_err() {
-                    return Response::Error("WRONGTYPE Source is not a list".to_owned());
-                }
-                sourcelist
-            }
+    let el = match pop_elements(db, dbindex, source, from_right, 1) {
+        Ok(Some(mut popped)) => match popped.pop() {
+            Some(el) => el,
             None => return Response::Nil,
-        };
-        match sourcelist.pop(true) {
-            Ok(el) => match el {
-                Some(el) => el,
-                None => return Response::Nil,
-            },
-            Err(err) => return Response::Error(err.to_string()),
-        }
+        },
+        Ok(None) => return Response::Nil,
+        Err(_) => return Response::Error("WRONGTYPE Source is not a list".to_owned()),
     };
 
-    let resp = {
-        let destinationlist = db.get_or_create(dbindex, destination);
-        if let Err(e) = destinationlist.push(el.clone(), false) {
-            return Response::Error(e.to_string());
-        }
-
-        Response::Data(el)
-    };
-    db.key_updated(dbindex, source);
+    if let Err(e) = db
+        .get_or_create(dbindex, destination)
+        .push(el.clone(), to_right)
+    {
+        return Response::Error(e.to_string());
+    }
+    let event = if to_right { "rpush" } else { "lpush" };
+    db.notify_keyspace_event(NOTIFY_LIST, event, dbindex, destination);
     db.key_updated(dbindex, destination);
-    resp
+    Response::Data(el)
 }
 
 fn rpoplpush(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
     validate_arguments_exact!(parser, 3);
     let source = try_validate!(parser.get_vec(1), "Invalid source");
     let destination = try_validate!(parser.get_vec(2), "Invalid destination");
-    generic_rpoplpush(db, dbindex, &source, &destination)
+    generic_move(db, dbindex, &source, &destination, true, false)
 }
 
-fn brpoplpush(
-    parser: &mut ParsedCommand,
+fn lmove(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_exact!(parser, 5);
+    let source = try_validate!(parser.get_vec(1), "Invalid source");
+    let destination = try_validate!(parser.get_vec(2), "Invalid destination");
+    let from_right = match parse_direction(parser, 3) {
+        Ok(right) => right,
+        Err(err) => return err,
+    };
+    let to_right = match parse_direction(parser, 4) {
+        Ok(right) => right,
+        Err(err) => return err,
+    };
+    generic_move(db, dbindex, &source, &destination, from_right, to_right)
+}
+
+/// Parses the timeout of a blocking command, in seconds. 0 waits forever.
+fn parse_timeout(parser: &ParsedCommand, pos: usize) -> Result<i64, Response> {
+    match parser.get_i64(pos) {
+        Ok(timeout) if timeout < 0 => Err(Response::Error("ERR timeout is negative".to_owned())),
+        Ok(timeout) => Ok(timeout),
+        Err(_) => Err(Response::Error("ERR timeout is not an integer".to_owned())),
+    }
+}
+
+/// Waits until one of `keys` is modified, to run the command again, or
+/// until `timeout` seconds pass. When the command runs again, the argument
+/// in `timeout_pos` is the time that was left.
+/// If the client cannot `block`, like inside EXEC, it replies nil right away.
+fn block_on_keys(
+    parser: &ParsedCommand,
     db: &mut Database,
     dbindex: usize,
+    keys: &[Vec<u8>],
+    timeout: i64,
+    timeout_pos: usize,
+    block: bool,
 ) -> Result<Response, ResponseError> {
-    opt_validate!(parser.argv.len() == 4, "Wrong number of parameters");
-
-    let source = try_opt_validate!(parser.get_vec(1), "Invalid source");
-    let destination = try_opt_validate!(parser.get_vec(2), "Invalid destination");
-    let timeout = try_opt_validate!(parser.get_i64(3), "ERR timeout is not an integer");
+    if !block {
+        return Ok(Response::Nil);
+    }
     let time = mstime();
-
-    let r = generic_rpoplpush(db, dbindex, &source, &destination);
-    if r != Response::Nil {
-        return Ok(r);
+    let mut args = Vec::with_capacity(parser.argv.len());
+    for i in 0..parser.argv.len() {
+        args.push(try_opt_validate!(parser.get_vec(i), "Invalid argument"));
     }
 
     let (txkey, rxkey) = channel();
@@ -819,151 +1393,331 @@ fn brpoplpush(
             let _ = tx.send(None);
         });
     }
-    let command_name = try_opt_validate!(parser.get_vec(0), "Invalid command");
-    db.key_subscribe(dbindex, &source, txkey);
+    for key in keys.iter() {
+        db.key_subscribe(dbindex, key, txkey.clone());
+    }
     thread::spawn(move || {
         let _ = rxkey.recv();
-        let newtimeout = if timeout == 0 {
-            0
-        } else {
-            let mut t = timeout as i64 * 1000 - mstime() + time;
-            if t <= 0 {
-                t = 1;
-            }
-            t
-        };
-        // This code is ugly. I was stuck for a week trying to figure out how
-        // to do this and this is the best I got. I'm sorry.
+        if timeout > 0 {
+            // whole seconds left, rounded up
+            let left = (timeout * 1000 - (mstime() - time) + 999) / 1000;
+            args[timeout_pos] = format!("{}", left.max(1)).into_bytes();
+        }
         let mut data = vec![];
         let mut arguments = vec![];
-        data.extend(command_name);
-        arguments.push(Argument {
-            pos: 0,
-            len: data.len(),
-        });
-        arguments.push(Argument {
-            pos: data.len(),
-            len: source.len(),
-        });
-        data.extend(source);
-        arguments.push(Argument {
-            pos: data.len(),
-            len: destination.len(),
-        });
-        data.extend(destination);
-        let timeout_formatted = format!("{}", newtimeout);
-        arguments.push(Argument {
-            pos: data.len(),
-            len: timeout_formatted.len(),
-        });
-        data.extend(timeout_formatted.into_bytes());
+        for arg in args {
+            arguments.push(Argument {
+                pos: data.len(),
+                len: arg.len(),
+            });
+            data.extend(arg);
+        }
         let _ = txcommand.send(Some(OwnedParsedCommand::new(data, arguments)));
     });
 
     Err(ResponseError::Wait(rxcommand))
 }
 
+/// Writes `args` to the aof in place of a blocking command that was served.
+/// The blocking command is not written as is, it could block when the aof
+/// is loaded.
+fn propagate_served(db: &mut Database, dbindex: usize, args: Vec<Vec<u8>>) {
+    db.propagate(dbindex, &ParsedCommand::from_args(args));
+}
+
+fn direction_arg(right: bool) -> Vec<u8> {
+    if right {
+        b"RIGHT".to_vec()
+    } else {
+        b"LEFT".to_vec()
+    }
+}
+
+fn generic_blmove(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    from_right: bool,
+    to_right: bool,
+    timeout_pos: usize,
+    block: bool,
+) -> Result<Response, ResponseError> {
+    let source = try_opt_validate!(parser.get_vec(1), "Invalid source");
+    let destination = try_opt_validate!(parser.get_vec(2), "Invalid destination");
+    let timeout = match parse_timeout(parser, timeout_pos) {
+        Ok(timeout) => timeout,
+        Err(err) => return Ok(err),
+    };
+    match generic_move(db, dbindex, &source, &destination, from_right, to_right) {
+        Response::Nil => block_on_keys(parser, db, dbindex, &[source], timeout, timeout_pos, block),
+        Response::Data(el) => {
+            let args = vec![
+                b"LMOVE".to_vec(),
+                source,
+                destination,
+                direction_arg(from_right),
+                direction_arg(to_right),
+            ];
+            propagate_served(db, dbindex, args);
+            Ok(Response::Data(el))
+        }
+        r => Ok(r),
+    }
+}
+
+fn brpoplpush(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    block: bool,
+) -> Result<Response, ResponseError> {
+    opt_validate!(parser.argv.len() == 4, "Wrong number of parameters");
+    generic_blmove(parser, db, dbindex, true, false, 3, block)
+}
+
+fn blmove(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    block: bool,
+) -> Result<Response, ResponseError> {
+    opt_validate!(parser.argv.len() == 6, "Wrong number of parameters");
+    let from_right = match parse_direction(parser, 3) {
+        Ok(right) => right,
+        Err(err) => return Ok(err),
+    };
+    let to_right = match parse_direction(parser, 4) {
+        Ok(right) => right,
+        Err(err) => return Ok(err),
+    };
+    generic_blmove(parser, db, dbindex, from_right, to_right, 5, block)
+}
+
 fn generic_bpop(
     parser: &mut ParsedCommand,
     db: &mut Database,
     dbindex: usize,
     right: bool,
+    block: bool,
 ) -> Result<Response, ResponseError> {
     opt_validate!(parser.argv.len() >= 3, "Wrong number of parameters");
-    let time = mstime();
+    let timeout_pos = parser.argv.len() - 1;
+    let timeout = match parse_timeout(parser, timeout_pos) {
+        Ok(timeout) => timeout,
+        Err(err) => return Ok(err),
+    };
 
     let mut keys = vec![];
-    for i in 1..parser.argv.len() - 1 {
+    for i in 1..timeout_pos {
         let key = try_opt_validate!(parser.get_vec(i), "Invalid key");
-        let val = match db.get_mut(dbindex, &key) {
-            Some(list) => match list.pop(right) {
-                Ok(el) => el,
-                Err(err) => return Ok(Response::Error(err.to_string())),
-            },
-            None => None,
-        };
-        match val {
-            Some(val) => {
-                db.key_updated(dbindex, &key);
-                return Ok(Response::Array(vec![
-                    Response::Data(key),
-                    Response::Data(val),
-                ]));
+        match pop_elements(db, dbindex, &key, right, 1) {
+            Ok(Some(mut popped)) => {
+                if let Some(val) = popped.pop() {
+                    let command = if right { b"RPOP" } else { b"LPOP" };
+                    propagate_served(db, dbindex, vec![command.to_vec(), key.clone()]);
+                    return Ok(Response::Array(vec![
+                        Response::Data(key),
+                        Response::Data(val),
+                    ]));
+                }
             }
-            None => keys.push(key),
+            Ok(None) => (),
+            Err(err) => return Ok(Response::Error(err.to_string())),
         }
+        keys.push(key);
     }
-    let timeout = try_opt_validate!(
-        parser.get_i64(parser.argv.len() - 1),
-        "ERR timeout is not an integer"
-    );
-
-    let (txkey, rxkey) = channel();
-    let (txcommand, rxcommand) = channel();
-    if timeout > 0 {
-        let tx = txcommand.clone();
-        thread::spawn(move || {
-            thread::sleep(Duration::from_secs(timeout as u64));
-            let _ = tx.send(None);
-        });
-    }
-    let command_name = try_opt_validate!(parser.get_vec(0), "Invalid command");
-    for key in keys.iter() {
-        db.key_subscribe(dbindex, key, txkey.clone());
-    }
-    thread::spawn(move || {
-        let _ = rxkey.recv();
-        let newtimeout = if timeout == 0 {
-            0
-        } else {
-            let mut t = timeout as i64 * 1000 - mstime() + time;
-            if t <= 0 {
-                t = 1;
-            }
-            t
-        };
-        // This code is ugly. I was stuck for a week trying to figure out how
-        // to do this and this is the best I got. I'm sorry.
-        let mut data = vec![];
-        let mut arguments = vec![];
-        data.extend(command_name);
-        arguments.push(Argument {
-            pos: 0,
-            len: data.len(),
-        });
-        for k in keys {
-            arguments.push(Argument {
-                pos: data.len(),
-                len: k.len(),
-            });
-            data.extend(k);
-        }
-        let timeout_formatted = format!("{}", newtimeout);
-        arguments.push(Argument {
-            pos: data.len(),
-            len: timeout_formatted.len(),
-        });
-        data.extend(timeout_formatted.into_bytes());
-        let _ = txcommand.send(Some(OwnedParsedCommand::new(data, arguments)));
-    });
-
-    Err(ResponseError::Wait(rxcommand))
+    block_on_keys(parser, db, dbindex, &keys, timeout, timeout_pos, block)
 }
 
 fn brpop(
     parser: &mut ParsedCommand,
     db: &mut Database,
     dbindex: usize,
+    block: bool,
 ) -> Result<Response, ResponseError> {
-    generic_bpop(parser, db, dbindex, true)
+    generic_bpop(parser, db, dbindex, true, block)
 }
 
 fn blpop(
     parser: &mut ParsedCommand,
     db: &mut Database,
     dbindex: usize,
+    block: bool,
+) -> Result<Response, ResponseError> {
+    generic_bpop(parser, db, dbindex, false, block)
+}
+
+/// Parses `numkeys key [key ...] where [COUNT count]`, starting at `pos`,
+/// into the keys, the side parsed by `parse_where`, and the count.
+fn parse_mpop(
+    parser: &ParsedCommand,
+    pos: usize,
+    parse_where: fn(&ParsedCommand, usize) -> Result<bool, Response>,
+) -> Result<(Vec<Vec<u8>>, bool, usize), Response> {
+    let syntax_error = || Response::Error("ERR syntax error".to_owned());
+    let numkeys = match parser.get_i64(pos) {
+        Ok(numkeys) if numkeys > 0 => numkeys as usize,
+        _ => {
+            return Err(Response::Error(
+                "ERR numkeys should be greater than 0".to_owned(),
+            ))
+        }
+    };
+    if numkeys > parser.argv.len() - pos - 2 {
+        return Err(syntax_error());
+    }
+    let mut keys = Vec::with_capacity(numkeys);
+    for i in pos + 1..pos + 1 + numkeys {
+        keys.push(parser.get_vec(i).map_err(|_| syntax_error())?);
+    }
+    let side = parse_where(parser, pos + 1 + numkeys)?;
+    let options = pos + 2 + numkeys;
+    let count = match parser.argv.len() - options {
+        0 => 1,
+        2 => {
+            let option = parser.get_str(options).map_err(|_| syntax_error())?;
+            if !option.eq_ignore_ascii_case("count") {
+                return Err(syntax_error());
+            }
+            match parser.get_i64(options + 1) {
+                Ok(count) if count > 0 => count as usize,
+                _ => {
+                    return Err(Response::Error(
+                        "ERR count should be greater than 0".to_owned(),
+                    ))
+                }
+            }
+        }
+        _ => return Err(syntax_error()),
+    };
+    Ok((keys, side, count))
+}
+
+/// A key and the elements popped from it.
+type Popped = (Vec<u8>, Vec<Vec<u8>>);
+
+/// Pops up to `count` elements from the first non empty list in `keys`.
+/// Returns the list key and the elements.
+fn generic_mpop(
+    db: &mut Database,
+    dbindex: usize,
+    keys: &[Vec<u8>],
+    right: bool,
+    count: usize,
+) -> Result<Option<Popped>, OperationError> {
+    for key in keys {
+        if let Some(popped) = pop_elements(db, dbindex, key, right, count)? {
+            if !popped.is_empty() {
+                return Ok(Some((key.clone(), popped)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn mpop_response(popped: Option<Popped>) -> Response {
+    match popped {
+        Some((key, elements)) => Response::Array(vec![
+            Response::Data(key),
+            Response::Array(elements.into_iter().map(Response::Data).collect()),
+        ]),
+        None => Response::Nil,
+    }
+}
+
+fn lmpop(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 4);
+    let (keys, right, count) = match parse_mpop(parser, 1, parse_direction) {
+        Ok(parsed) => parsed,
+        Err(err) => return err,
+    };
+    match generic_mpop(db, dbindex, &keys, right, count) {
+        Ok(popped) => mpop_response(popped),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+fn blmpop(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    block: bool,
 ) -> Result<Response, ResponseError> {
-    generic_bpop(parser, db, dbindex, false)
+    opt_validate!(parser.argv.len() >= 5, "Wrong number of parameters");
+    let timeout = match parse_timeout(parser, 1) {
+        Ok(timeout) => timeout,
+        Err(err) => return Ok(err),
+    };
+    let (keys, right, count) = match parse_mpop(parser, 2, parse_direction) {
+        Ok(parsed) => parsed,
+        Err(err) => return Ok(err),
+    };
+    match generic_mpop(db, dbindex, &keys, right, count) {
+        Ok(Some((key, elements))) => {
+            let command = if right { b"RPOP" } else { b"LPOP" };
+            let count = format!("{}", elements.len()).into_bytes();
+            propagate_served(db, dbindex, vec![command.to_vec(), key.clone(), count]);
+            Ok(mpop_response(Some((key, elements))))
+        }
+        Ok(None) => block_on_keys(parser, db, dbindex, &keys, timeout, 1, block),
+        Err(err) => Ok(Response::Error(err.to_string())),
+    }
+}
+
+fn lpos(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 3);
+    validate!(parser.argv.len() % 2 == 1, "ERR syntax error");
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let el = try_validate!(parser.get_vec(2), "Invalid element");
+    let mut rank = 1;
+    let mut count = None;
+    let mut maxlen = 0;
+    for i in (3..parser.argv.len()).step_by(2) {
+        let option = try_validate!(parser.get_str(i), "ERR syntax error").to_ascii_lowercase();
+        let value = try_validate!(
+            parser.get_i64(i + 1),
+            "ERR value is not an integer or out of range"
+        );
+        match &*option {
+            "rank" => {
+                validate!(
+                    value != 0,
+                    "ERR RANK can't be zero: use 1 to start from the first match, 2 from the \
+                     second ... or use negative to start from the end of the list"
+                );
+                validate!(value != i64::MIN, "ERR value is out of range");
+                rank = value;
+            }
+            "count" => {
+                validate!(value >= 0, "ERR COUNT can't be negative");
+                count = Some(value as usize);
+            }
+            "maxlen" => {
+                validate!(value >= 0, "ERR MAXLEN can't be negative");
+                maxlen = value as usize;
+            }
+            _ => return Response::Error("ERR syntax error".to_owned()),
+        }
+    }
+
+    let positions = match db.get(dbindex, &key) {
+        Some(list) => match list.lpos(&el, rank, count.unwrap_or(1), maxlen) {
+            Ok(positions) => positions,
+            Err(err) => return Response::Error(err.to_string()),
+        },
+        None => vec![],
+    };
+    match count {
+        Some(_) => Response::Array(
+            positions
+                .into_iter()
+                .map(|pos| Response::Integer(pos as i64))
+                .collect(),
+        ),
+        None => positions
+            .first()
+            .map_or(Response::Nil, |&pos| Response::Integer(pos as i64)),
+    }
 }
 
 fn lindex(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
@@ -998,7 +1752,10 @@ fn linsert(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Res
     let r = match db.get_mut(dbindex, &key) {
         Some(el) => match el.linsert(before, pivot, value) {
             Ok(r) => match r {
-                Some(listsize) => Response::Integer(listsize as i64),
+                Some(listsize) => {
+                    db.notify_keyspace_event(NOTIFY_LIST, "linsert", dbindex, &key);
+                    Response::Integer(listsize as i64)
+                }
                 None => Response::Integer(-1),
             },
             Err(err) => Response::Error(err.to_string()),
@@ -1046,7 +1803,12 @@ fn lrem(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
     let value = try_validate!(parser.get_vec(3), "Invalid value");
     let r = match db.get_mut(dbindex, &key) {
         Some(el) => match el.lrem(count < 0, count.abs() as usize, value) {
-            Ok(removed) => Response::Integer(removed as i64),
+            Ok(removed) => {
+                if removed > 0 {
+                    db.notify_keyspace_event(NOTIFY_LIST, "lrem", dbindex, &key);
+                }
+                Response::Integer(removed as i64)
+            }
             Err(err) => Response::Error(err.to_string()),
         },
         None => Response::Array(Vec::new()),
@@ -1062,7 +1824,10 @@ fn lset(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
     let value = try_validate!(parser.get_vec(3), "Invalid value");
     let r = match db.get_mut(dbindex, &key) {
         Some(el) => match el.lset(index, value) {
-            Ok(()) => Response::Status("OK".to_owned()),
+            Ok(()) => {
+                db.notify_keyspace_event(NOTIFY_LIST, "lset", dbindex, &key);
+                Response::Status("OK".to_owned())
+            }
             Err(err) => Response::Error(err.to_string()),
         },
         None => Response::Error("ERR no such key".to_owned()),
@@ -1078,7 +1843,10 @@ fn ltrim(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respo
     let stop = try_validate!(parser.get_i64(3), "Invalid stop");
     let r = match db.get_mut(dbindex, &key) {
         Some(el) => match el.ltrim(start, stop) {
-            Ok(()) => Response::Status("OK".to_owned()),
+            Ok(()) => {
+                db.notify_keyspace_event(NOTIFY_LIST, "ltrim", dbindex, &key);
+                Response::Status("OK".to_owned())
+            }
             Err(err) => Response::Error(err.to_string()),
         },
         None => Response::Status("OK".to_owned()),
@@ -1106,6 +1874,9 @@ fn sadd(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
             }
         }
     }
+    if count > 0 {
+        db.notify_keyspace_event(NOTIFY_SET, "sadd", dbindex, &key);
+    }
     db.key_updated(dbindex, &key);
 
     Response::Integer(count)
@@ -1129,6 +1900,9 @@ fn srem(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
             }
         }
     }
+    if count > 0 {
+        db.notify_keyspace_event(NOTIFY_SET, "srem", dbindex, &key);
+    }
     db.key_updated(dbindex, &key);
 
     Response::Integer(count)
@@ -1154,6 +1928,23 @@ fn sismember(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Respo
     })
 }
 
+fn smismember(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let nil = Value::Nil;
+    let value = db.get(dbindex, &key).unwrap_or(&nil);
+    let mut members = Vec::with_capacity(parser.argv.len() - 2);
+    for i in 2..parser.argv.len() {
+        let member = try_validate!(parser.get_vec(i), "Invalid member");
+        members.push(match value.sismember(&member) {
+            Ok(true) => Response::Integer(1),
+            Ok(false) => Response::Integer(0),
+            Err(err) => return Response::Error(err.to_string()),
+        });
+    }
+    Response::Array(members)
+}
+
 fn srandmember(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
     validate_arguments_gte!(parser, 2);
     validate_arguments_lte!(parser, 3);
@@ -1217,10 +2008,18 @@ fn smembers(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Respon
     }
 }
 
-fn spop(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+/// Removes random members of a set. The members are random, so it is written
+/// to the aof as a `SREM` of the members that were popped.
+fn spop(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    write: &mut bool,
+) -> Response {
     validate_arguments_gte!(parser, 2);
     validate_arguments_lte!(parser, 3);
     let key = try_validate!(parser.get_vec(1), "Invalid key");
+    *write = false;
     let r = {
         let value = match db.get_mut(dbindex, &key) {
             Some(el) => el,
@@ -1255,6 +2054,23 @@ fn spop(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
             }
         }
     };
+    let popped = match r {
+        Response::Data(ref el) => vec![el.clone()],
+        Response::Array(ref els) => els
+            .iter()
+            .filter_map(|el| match el {
+                Response::Data(el) => Some(el.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    if !popped.is_empty() {
+        db.notify_keyspace_event(NOTIFY_SET, "spop", dbindex, &key);
+        let mut args = vec![b"SREM".to_vec(), key.clone()];
+        args.extend(popped);
+        db.propagate(dbindex, &ParsedCommand::from_args(args));
+    }
     db.key_updated(dbindex, &key);
     r
 }
@@ -1301,7 +2117,9 @@ fn smove(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respo
         }
     }
 
+    db.notify_keyspace_event(NOTIFY_SET, "srem", dbindex, &source_key);
     db.key_updated(dbindex, &source_key);
+    db.notify_keyspace_event(NOTIFY_SET, "sadd", dbindex, &destination_key);
     db.key_updated(dbindex, &destination_key);
 
     Response::Integer(1)
@@ -1357,9 +2175,17 @@ fn sdiffstore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) ->
         }
     };
 
-    db.remove(dbindex, &destination_key);
+    let lazy = db.config.lazyfree_lazy_server_del;
+    let existed = db.delete(dbindex, &destination_key, lazy);
     let r = set.len() as i64;
-    db.get_or_create(dbindex, &destination_key).create_set(set);
+    if r > 0 {
+        let set_max_intset_entries = db.config.set_max_intset_entries;
+        db.get_or_create(dbindex, &destination_key)
+            .create_set(set, set_max_intset_entries);
+        db.notify_keyspace_event(NOTIFY_SET, "sdiffstore", dbindex, &destination_key);
+    } else if existed {
+        db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, &destination_key);
+    }
     db.key_updated(dbindex, &destination_key);
     Response::Integer(r)
 }
@@ -1382,6 +2208,20 @@ fn sinter(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response
     }
 }
 
+fn sintercard(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let (options, limit) = match parse_intercard(parser) {
+        Ok(r) => r,
+        Err(err) => return err,
+    };
+    let nil = Value::Nil;
+    let sets = get_values!(2, options, parser, db, dbindex, &nil);
+    match sets[0].sintercard(&sets[1..], limit) {
+        Ok(count) => Response::Integer(count as i64),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
 fn sinterstore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
     validate!(parser.argv.len() >= 3, "Wrong number of parameters");
     let destination_key = try_validate!(parser.get_vec(1), "Invalid destination");
@@ -1399,9 +2239,17 @@ fn sinterstore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) ->
         }
     };
 
-    db.remove(dbindex, &destination_key);
+    let lazy = db.config.lazyfree_lazy_server_del;
+    let existed = db.delete(dbindex, &destination_key, lazy);
     let r = set.len() as i64;
-    db.get_or_create(dbindex, &destination_key).create_set(set);
+    if r > 0 {
+        let set_max_intset_entries = db.config.set_max_intset_entries;
+        db.get_or_create(dbindex, &destination_key)
+            .create_set(set, set_max_intset_entries);
+        db.notify_keyspace_event(NOTIFY_SET, "sinterstore", dbindex, &destination_key);
+    } else if existed {
+        db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, &destination_key);
+    }
     db.key_updated(dbindex, &destination_key);
     Response::Integer(r)
 }
@@ -1444,9 +2292,17 @@ fn sunionstore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) ->
         }
     };
 
-    db.remove(dbindex, &destination_key);
+    let lazy = db.config.lazyfree_lazy_server_del;
+    let existed = db.delete(dbindex, &destination_key, lazy);
     let r = set.len() as i64;
-    db.get_or_create(dbindex, &destination_key).create_set(set);
+    if r > 0 {
+        let set_max_intset_entries = db.config.set_max_intset_entries;
+        db.get_or_create(dbindex, &destination_key)
+            .create_set(set, set_max_intset_entries);
+        db.notify_keyspace_event(NOTIFY_SET, "sunionstore", dbindex, &destination_key);
+    } else if existed {
+        db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, &destination_key);
+    }
     db.key_updated(dbindex, &destination_key);
     Response::Integer(r)
 }
@@ -1516,6 +2372,8 @@ fn zadd(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
         }
     }
     if count > 0 {
+        let event = if incr { "zincr" } else { "zadd" };
+        db.notify_keyspace_event(NOTIFY_ZSET, event, dbindex, &key);
         db.key_updated(dbindex, &key);
     }
 
@@ -1566,6 +2424,7 @@ fn zincrby(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Res
             Err(err) => return Response::Error(err.to_string()),
         }
     };
+    db.notify_keyspace_event(NOTIFY_ZSET, "zincr", dbindex, &key);
     db.key_updated(dbindex, &key);
 
     Response::Data(format!("{}", newscore).into_bytes())
@@ -1593,6 +2452,7 @@ fn zrem(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
         }
     }
     if count > 0 {
+        db.notify_keyspace_event(NOTIFY_ZSET, "zrem", dbindex, &key);
         db.key_updated(dbindex, &key);
     }
 
@@ -1615,6 +2475,7 @@ fn zremrangebyscore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usiz
         }
     };
     if c > 0 {
+        db.notify_keyspace_event(NOTIFY_ZSET, "zremrangebyscore", dbindex, &key);
         db.key_updated(dbindex, &key);
     }
     Response::Integer(c)
@@ -1654,6 +2515,7 @@ fn zremrangebylex(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize)
         }
     };
     if c > 0 {
+        db.notify_keyspace_event(NOTIFY_ZSET, "zremrangebylex", dbindex, &key);
         db.key_updated(dbindex, &key);
     }
     Response::Integer(c)
@@ -1675,6 +2537,7 @@ fn zremrangebyrank(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize
         }
     };
     if c > 0 {
+        db.notify_keyspace_event(NOTIFY_ZSET, "zremrangebyrank", dbindex, &key);
         db.key_updated(dbindex, &key);
     }
     Response::Integer(c)
@@ -1695,98 +2558,6 @@ fn zcount(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Resp
     }
 }
 
-fn generic_zrange(
-    parser: &mut ParsedCommand,
-    db: &mut Database,
-    dbindex: usize,
-    rev: bool,
-) -> Response {
-    validate_arguments_gte!(parser, 4);
-    validate_arguments_lte!(parser, 5);
-    let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let start = try_validate!(parser.get_i64(2), "Invalid start");
-    let stop = try_validate!(parser.get_i64(3), "Invalid stop");
-    let withscores = parser.argv.len() == 5;
-    if withscores {
-        let p4 = try_validate!(parser.get_str(4), "Syntax error");
-        validate!(p4.to_ascii_lowercase() == "withscores", "Syntax error");
-    }
-    let el = match db.get(dbindex, &key) {
-        Some(e) => e,
-        None => return Response::Array(Vec::new()),
-    };
-    match el.zrange(start, stop, withscores, rev) {
-        Ok(r) => Response::Array(
-            r.iter()
-                .map(|x| Response::Data(x.clone()))
-                .collect::<Vec<_>>(),
-        ),
-        Err(err) => Response::Error(err.to_string()),
-    }
-}
-
-fn zrange(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    generic_zrange(parser, db, dbindex, false)
-}
-
-fn zrevrange(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    generic_zrange(parser, db, dbindex, true)
-}
-
-fn generic_zrangebyscore(
-    parser: &mut ParsedCommand,
-    db: &mut Database,
-    dbindex: usize,
-    rev: bool,
-) -> Response {
-    let len = parser.argv.len();
-    validate!(len >= 4, "Wrong number of parameters");
-    let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let min = try_validate!(parser.get_f64_bound(2), "ERR min or max is not a float");
-    let max = try_validate!(parser.get_f64_bound(3), "ERR min or max is not a float");
-
-    let mut offset = 0;
-    let mut count = usize::MAX;
-    let mut withscores = false;
-    let mut i = 4;
-    while i < len {
-        let arg = &*try_validate!(parser.get_str(i), "syntax error").to_ascii_lowercase();
-        match arg {
-            "withscores" => {
-                i += 1;
-                withscores = true;
-            }
-            "limit" => {
-                offset = try_validate!(parser.get_i64(i + 1), "syntax error") as usize;
-                count = try_validate!(parser.get_i64(i + 2), "syntax error") as usize;
-                i += 3;
-            }
-            _ => return Response::Error("syntax error".to_owned()),
-        }
-    }
-
-    let el = match db.get(dbindex, &key) {
-        Some(e) => e,
-        None => return Response::Array(Vec::new()),
-    };
-    match el.zrangebyscore(min, max, withscores, offset, count, rev) {
-        Ok(r) => Response::Array(
-            r.iter()
-                .map(|x| Response::Data(x.clone()))
-                .collect::<Vec<_>>(),
-        ),
-        Err(err) => Response::Error(err.to_string()),
-    }
-}
-
-fn zrangebyscore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    generic_zrangebyscore(parser, db, dbindex, false)
-}
-
-fn zrevrangebyscore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    generic_zrangebyscore(parser, db, dbindex, true)
-}
-
 fn get_vec_bound(mut m: Vec<u8>) -> Result<Bound<Vec<u8>>, Response> {
     if m.is_empty() {
         return Err(Response::Error(
@@ -1821,66 +2592,178 @@ fn get_vec_bound(mut m: Vec<u8>) -> Result<Bound<Vec<u8>>, Response> {
     })
 }
 
-fn generic_zrangebylex(
-    parser: &mut ParsedCommand,
-    db: &mut Database,
+/// How ZRANGE selects the members between `start` and `stop`.
+#[derive(Clone, Copy, PartialEq)]
+enum ZRangeBy {
+    Rank,
+    Score,
+    Lex,
+}
+
+/// Runs the range query of ZRANGE and ZRANGESTORE, with the source key at
+/// `pos` followed by `start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count]
+/// [WITHSCORES]`. The legacy commands set `by` and `rev`, and the matching
+/// options are rejected then. WITHSCORES is rejected when storing.
+fn zrange_query(
+    parser: &ParsedCommand,
+    db: &Database,
     dbindex: usize,
-    rev: bool,
-) -> Response {
-    let len = parser.argv.len();
-    validate!(len == 4 || len == 7, "Wrong number of parameters");
-    let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let min = {
-        let m = try_validate!(
-            parser.get_vec(2),
-            "ERR min or max not valid string range item"
-        );
-        match get_vec_bound(m) {
-            Ok(v) => v,
-            Err(e) => return e,
+    pos: usize,
+    by: Option<ZRangeBy>,
+    rev: Option<bool>,
+    store: bool,
+) -> Result<Vec<Vec<u8>>, Response> {
+    let syntax_error = || Response::Error("ERR syntax error".to_owned());
+    let not_integer = |_| Response::Error("ERR value is not an integer or out of range".to_owned());
+    let key = parser.get_vec(pos).map_err(|_| syntax_error())?;
+    let mut by = by;
+    let mut rev = rev;
+    let mut withscores = false;
+    let mut limit = None;
+    let mut i = pos + 3;
+    while i < parser.argv.len() {
+        let option = parser.get_str(i).map_err(|_| syntax_error())?;
+        match &*option.to_ascii_lowercase() {
+            "withscores" if !store => withscores = true,
+            "limit" if i + 2 < parser.argv.len() => {
+                let offset = parser.get_i64(i + 1).map_err(not_integer)?;
+                let count = parser.get_i64(i + 2).map_err(not_integer)?;
+                limit = Some((offset, count));
+                i += 2;
+            }
+            "rev" if rev.is_none() => rev = Some(true),
+            "byscore" if by.is_none() => by = Some(ZRangeBy::Score),
+            "bylex" if by.is_none() => by = Some(ZRangeBy::Lex),
+            _ => return Err(syntax_error()),
         }
+        i += 1;
+    }
+    let by = by.unwrap_or(ZRangeBy::Rank);
+    let rev = rev.unwrap_or(false);
+    if limit.is_some() && by == ZRangeBy::Rank {
+        return Err(Response::Error(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or \
+             BYLEX"
+                .to_owned(),
+        ));
+    }
+    if withscores && by == ZRangeBy::Lex {
+        return Err(Response::Error(
+            "ERR syntax error, WITHSCORES not supported in combination with BYLEX".to_owned(),
+        ));
+    }
+    // a negative offset selects nothing, and a negative count everything
+    let (offset, count) = match limit {
+        Some((offset, _)) if offset < 0 => return Ok(vec![]),
+        Some((offset, count)) if count >= 0 => (offset as usize, count as usize),
+        Some((offset, _)) => (offset as usize, usize::MAX),
+        None => (0, usize::MAX),
     };
-    let max = {
-        let m = try_validate!(
-            parser.get_vec(3),
-            "ERR min or max not valid string range item"
-        );
-        match get_vec_bound(m) {
-            Ok(v) => v,
-            Err(e) => return e,
+
+    let r = match by {
+        ZRangeBy::Rank => {
+            let start = parser.get_i64(pos + 1).map_err(not_integer)?;
+            let stop = parser.get_i64(pos + 2).map_err(not_integer)?;
+            match db.get(dbindex, &key) {
+                Some(value) => value.zrange(start, stop, withscores, rev),
+                None => Ok(vec![]),
+            }
+        }
+        ZRangeBy::Score => {
+            let not_float = |_| Response::Error("ERR min or max is not a float".to_owned());
+            let min = parser.get_f64_bound(pos + 1).map_err(not_float)?;
+            let max = parser.get_f64_bound(pos + 2).map_err(not_float)?;
+            match db.get(dbindex, &key) {
+                Some(value) => value.zrangebyscore(min, max, withscores, offset, count, rev),
+                None => Ok(vec![]),
+            }
+        }
+        ZRangeBy::Lex => {
+            let not_valid =
+                |_| Response::Error("ERR min or max not valid string range item".to_owned());
+            let min = get_vec_bound(parser.get_vec(pos + 1).map_err(not_valid)?)?;
+            let max = get_vec_bound(parser.get_vec(pos + 2).map_err(not_valid)?)?;
+            match db.get(dbindex, &key) {
+                Some(value) => value.zrangebylex(min, max, offset, count, rev),
+                None => Ok(vec![]),
+            }
         }
     };
+    r.map_err(|err| Response::Error(err.to_string()))
+}
 
-    let mut offset = 0;
-    let mut count = usize::MAX;
-    let limit = len >= 7;
-    if limit {
-        let p = try_validate!(parser.get_str(len - 3), "Syntax error");
-        validate!(p.to_ascii_lowercase() == "limit", "Syntax error");
-        offset = try_validate!(parser.get_i64(len - 2), "Syntax error") as usize;
-        count = try_validate!(parser.get_i64(len - 1), "Syntax error") as usize;
+fn generic_zrange(
+    parser: &mut ParsedCommand,
+    db: &Database,
+    dbindex: usize,
+    by: Option<ZRangeBy>,
+    rev: Option<bool>,
+) -> Response {
+    validate_arguments_gte!(parser, 4);
+    match zrange_query(parser, db, dbindex, 1, by, rev, false) {
+        Ok(r) => Response::Array(r.into_iter().map(Response::Data).collect()),
+        Err(err) => err,
     }
+}
 
-    let el = match db.get(dbindex, &key) {
-        Some(e) => e,
-        None => return Response::Array(Vec::new()),
-    };
-    match el.zrangebylex(min, max, offset, count, rev) {
-        Ok(r) => Response::Array(
-            r.iter()
-                .map(|x| Response::Data(x.clone()))
-                .collect::<Vec<_>>(),
-        ),
-        Err(err) => Response::Error(err.to_string()),
-    }
+fn zrange(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    generic_zrange(parser, db, dbindex, None, None)
+}
+
+fn zrevrange(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    generic_zrange(parser, db, dbindex, Some(ZRangeBy::Rank), Some(true))
 }
 
-fn zrangebylex(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    generic_zrangebylex(parser, db, dbindex, false)
+fn zrangebyscore(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    generic_zrange(parser, db, dbindex, Some(ZRangeBy::Score), Some(false))
 }
 
-fn zrevrangebylex(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    generic_zrangebylex(parser, db, dbindex, true)
+fn zrevrangebyscore(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    generic_zrange(parser, db, dbindex, Some(ZRangeBy::Score), Some(true))
+}
+
+fn zrangebylex(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    generic_zrange(parser, db, dbindex, Some(ZRangeBy::Lex), Some(false))
+}
+
+fn zrevrangebylex(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    generic_zrange(parser, db, dbindex, Some(ZRangeBy::Lex), Some(true))
+}
+
+fn zrangestore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 5);
+    let destination = try_validate!(parser.get_vec(1), "Invalid destination");
+    let source = try_validate!(parser.get_vec(2), "Invalid key");
+    let members = match zrange_query(parser, db, dbindex, 2, None, None, true) {
+        Ok(members) => members,
+        Err(err) => return err,
+    };
+    let mut value = Value::Nil;
+    if let Some(zset) = db.get(dbindex, &source) {
+        for member in members {
+            if let Ok(Some(score)) = zset.zscore(member.clone()) {
+                if let Err(err) = value.zadd(score, member, false, false, false, false) {
+                    return Response::Error(err.to_string());
+                }
+            }
+        }
+    }
+    let r = match value.zcard() {
+        Ok(count) => Response::Integer(count as i64),
+        Err(err) => Response::Error(err.to_string()),
+    };
+    let lazy = db.config.lazyfree_lazy_server_del;
+    let existed = db.delete(dbindex, &destination, lazy);
+    if value.is_empty() {
+        if existed {
+            db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, &destination);
+        }
+    } else {
+        *db.get_or_create(dbindex, &destination) = value;
+        db.notify_keyspace_event(NOTIFY_ZSET, "zrangestore", dbindex, &destination);
+    }
+    db.key_updated(dbindex, &destination);
+    r
 }
 
 fn zlexcount(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
@@ -1954,176 +2837,570 @@ fn zrevrank(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Re
     generic_zrank(db, dbindex, &key, member, true)
 }
 
-fn zinter_union_store(
+/// Members and their scores, as popped from a sorted set.
+type ScoredMembers = Vec<(Vec<u8>, f64)>;
+
+/// Pops up to `count` members with the lowest scores from the sorted set
+/// in `key`, or with the highest if `max`.
+/// Returns None if the key does not exist.
+fn zpop_members(
+    db: &mut Database,
+    dbindex: usize,
+    key: &[u8],
+    max: bool,
+    count: usize,
+) -> Result<Option<ScoredMembers>, OperationError> {
+    let popped = match db.get_mut(dbindex, key) {
+        Some(zset) => zset.zpop(count, max)?,
+        None => return Ok(None),
+    };
+    if !popped.is_empty() {
+        let event = if max { "zpopmax" } else { "zpopmin" };
+        db.notify_keyspace_event(NOTIFY_ZSET, event, dbindex, key);
+        db.key_updated(dbindex, key);
+    }
+    Ok(Some(popped))
+}
+
+fn score_response(score: f64) -> Response {
+    Response::Data(format!("{}", score).into_bytes())
+}
+
+fn generic_zpop(
     parser: &mut ParsedCommand,
     db: &mut Database,
     dbindex: usize,
-    union: bool,
+    max: bool,
 ) -> Response {
-    validate!(parser.argv.len() >= 4, "Wrong number of parameters");
+    validate_arguments_gte!(parser, 2);
+    validate_arguments_lte!(parser, 3);
     let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let value = {
-        let numkeys = {
-            let n = try_validate!(parser.get_i64(2), "Invalid number of keys");
-            if n <= 0 {
-                return Response::Error(
-                    "at least 1 input key is needed for \
-                     ZUNIONSTORE/ZINTERSTORE"
-                        .to_string(),
-                );
-            }
-            n as usize
-        };
-        let nil = Value::Nil;
-        let zsets = get_values!(3, 3 + numkeys, parser, db, dbindex, &nil);
-        let mut pos = 3 + numkeys;
-        let mut weights = None;
-        let mut aggregate = zset::Aggregate::Sum;
-        if pos < parser.argv.len() {
-            let arg = try_validate!(parser.get_str(pos), "syntax error");
-            if arg.to_ascii_lowercase() == "weights" {
-                pos += 1;
-                validate!(
-                    parser.argv.len() >= pos + numkeys,
-                    "Wrong number of parameters"
-                );
-                let mut w = Vec::with_capacity(numkeys);
-                for i in 0..numkeys {
-                    w.push(try_validate!(
-                        parser.get_f64(pos + i),
-                        "ERR weight value is not a float"
-                    ));
-                }
-                weights = Some(w);
-                pos += numkeys;
-            }
-        };
-        if pos < parser.argv.len() {
-            let arg = try_validate!(parser.get_str(pos), "syntax error");
-            if arg.to_ascii_lowercase() == "aggregate" {
-                pos += 1;
-                validate!(parser.argv.len() != pos, "Wrong number of parameters");
-                aggregate = match &*try_validate!(parser.get_str(pos), "syntax error")
-                    .to_ascii_lowercase()
-                {
-                    "sum" => zset::Aggregate::Sum,
-                    "max" => zset::Aggregate::Max,
-                    "min" => zset::Aggregate::Min,
-                    _ => return Response::Error("syntax error".to_string()),
-                };
-                pos += 1;
-            }
-        };
-        validate!(pos == parser.argv.len(), "syntax error");
-        let n = Value::Nil;
-        match if union {
-            n.zunion(&zsets, weights, aggregate)
-        } else {
-            n.zinter(&zsets, weights, aggregate)
-        } {
-            Ok(v) => v,
-            Err(err) => return Response::Error(err.to_string()),
-        }
+    let count = if parser.argv.len() == 3 {
+        let count = try_validate!(
+            parser.get_i64(2),
+            "ERR value is out of range, must be positive"
+        );
+        validate!(count >= 0, "ERR value is out of range, must be positive");
+        count as usize
+    } else {
+        1
     };
-    let r = match value.zcard() {
-        Ok(count) => Response::Integer(count as i64),
+    match zpop_members(db, dbindex, &key, max, count) {
+        Ok(popped) => Response::Array(
+            popped
+                .unwrap_or_default()
+                .into_iter()
+                .flat_map(|(member, score)| vec![Response::Data(member), score_response(score)])
+                .collect(),
+        ),
         Err(err) => Response::Error(err.to_string()),
-    };
-    *db.get_or_create(dbindex, &key) = value;
-    db.key_updated(dbindex, &key);
-    r
+    }
 }
 
-fn zunionstore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    zinter_union_store(parser, db, dbindex, true)
+fn zpopmin(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    generic_zpop(parser, db, dbindex, false)
 }
 
-fn zinterstore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    zinter_union_store(parser, db, dbindex, false)
+fn zpopmax(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    generic_zpop(parser, db, dbindex, true)
 }
 
-fn ping(parser: &mut ParsedCommand, client: &mut Client) -> Response {
-    validate!(
-        parser.argv.len() <= 2,
-        format!(
-            "ERR wrong number of arguments for '{}' command",
-            parser.get_str(0).unwrap()
-        )
-    );
+fn generic_bzpop(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    max: bool,
+    block: bool,
+) -> Result<Response, ResponseError> {
+    opt_validate!(parser.argv.len() >= 3, "Wrong number of parameters");
+    let timeout_pos = parser.argv.len() - 1;
+    let timeout = match parse_timeout(parser, timeout_pos) {
+        Ok(timeout) => timeout,
+        Err(err) => return Ok(err),
+    };
 
-    if !client.subscriptions.is_empty() {
-        if parser.argv.len() == 2 {
-            match parser.get_vec(1) {
-                Ok(r) => Response::Array(vec![Response::Data(b"pong".to_vec()), Response::Data(r)]),
-                Err(err) => Response::Error(err.to_string()),
+    let mut keys = vec![];
+    for i in 1..timeout_pos {
+        let key = try_opt_validate!(parser.get_vec(i), "Invalid key");
+        match zpop_members(db, dbindex, &key, max, 1) {
+            Ok(Some(mut popped)) => {
+                if let Some((member, score)) = popped.pop() {
+                    let command = if max { b"ZPOPMAX" } else { b"ZPOPMIN" };
+                    propagate_served(db, dbindex, vec![command.to_vec(), key.clone()]);
+                    return Ok(Response::Array(vec![
+                        Response::Data(key),
+                        Response::Data(member),
+                        score_response(score),
+                    ]));
+                }
             }
-        } else {
-            Response::Array(vec![
-                Response::Data(b"pong".to_vec()),
-                Response::Data(vec![]),
-            ])
-        }
-    } else if parser.argv.len() == 2 {
-        match parser.get_vec(1) {
-            Ok(r) => Response::Data(r),
-            Err(err) => Response::Error(err.to_string()),
+            Ok(None) => (),
+            Err(err) => return Ok(Response::Error(err.to_string())),
         }
-    } else {
-        Response::Status("PONG".to_owned())
+        keys.push(key);
     }
+    block_on_keys(parser, db, dbindex, &keys, timeout, timeout_pos, block)
 }
 
-fn subscribe(
+fn bzpopmin(
     parser: &mut ParsedCommand,
     db: &mut Database,
-    subscriptions: &mut HashMap<Vec<u8>, usize>,
-    pattern_subscriptions_len: usize,
-    sender: &Sender<Option<Response>>,
+    dbindex: usize,
+    block: bool,
 ) -> Result<Response, ResponseError> {
-    opt_validate!(parser.argv.len() >= 2, "Wrong number of parameters");
-    for i in 1..parser.argv.len() {
-        let channel_name = try_opt_validate!(parser.get_vec(i), "Invalid channel");
-        if !subscriptions.contains_key(&channel_name) {
-            let subscriber_id = db.subscribe(channel_name.clone(), sender.clone());
-            subscriptions.insert(channel_name.clone(), subscriber_id);
-        }
-        match sender.send(Some(
-            PubsubEvent::Subscription(
-                channel_name.clone(),
-                pattern_subscriptions_len + subscriptions.len(),
-            )
-            .as_response(),
-        )) {
-            Ok(_) => None,
-            Err(_) => subscriptions.remove(&channel_name),
-        };
-    }
-    Err(ResponseError::NoReply)
+    generic_bzpop(parser, db, dbindex, false, block)
 }
 
-fn unsubscribe(
+fn bzpopmax(
     parser: &mut ParsedCommand,
     db: &mut Database,
-    subscriptions: &mut HashMap<Vec<u8>, usize>,
-    pattern_subscriptions_len: usize,
-    sender: &Sender<Option<Response>>,
+    dbindex: usize,
+    block: bool,
 ) -> Result<Response, ResponseError> {
-    if parser.argv.len() == 1 {
-        if subscriptions.is_empty() {
-            let _ = sender.send(Some(
-                PubsubEvent::Unsubscription(vec![], pattern_subscriptions_len).as_response(),
-            ));
-        } else {
-            for (channel_name, subscriber_id) in subscriptions.drain() {
-                db.unsubscribe(channel_name.clone(), subscriber_id);
-                let _ = sender.send(Some(
-                    PubsubEvent::Unsubscription(channel_name, pattern_subscriptions_len)
-                        .as_response(),
-                ));
+    generic_bzpop(parser, db, dbindex, true, block)
+}
+
+/// Parses MIN or MAX. Returns true for MAX.
+fn parse_minmax(parser: &ParsedCommand, pos: usize) -> Result<bool, Response> {
+    match &*parser
+        .get_str(pos)
+        .map_err(|_| Response::Error("ERR syntax error".to_owned()))?
+        .to_ascii_lowercase()
+    {
+        "min" => Ok(false),
+        "max" => Ok(true),
+        _ => Err(Response::Error("ERR syntax error".to_owned())),
+    }
+}
+
+/// Pops up to `count` members from the first non empty sorted set in
+/// `keys`. Returns the sorted set key and the members with their scores.
+fn generic_zmpop(
+    db: &mut Database,
+    dbindex: usize,
+    keys: &[Vec<u8>],
+    max: bool,
+    count: usize,
+) -> Result<Option<(Vec<u8>, ScoredMembers)>, OperationError> {
+    for key in keys {
+        if let Some(popped) = zpop_members(db, dbindex, key, max, count)? {
+            if !popped.is_empty() {
+                return Ok(Some((key.clone(), popped)));
             }
         }
-    } else {
-        for i in 1..parser.argv.len() {
+    }
+    Ok(None)
+}
+
+fn zmpop_response(popped: Option<(Vec<u8>, ScoredMembers)>) -> Response {
+    match popped {
+        Some((key, members)) => Response::Array(vec![
+            Response::Data(key),
+            Response::Array(
+                members
+                    .into_iter()
+                    .map(|(member, score)| {
+                        Response::Array(vec![Response::Data(member), score_response(score)])
+                    })
+                    .collect(),
+            ),
+        ]),
+        None => Response::Nil,
+    }
+}
+
+fn zmpop(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 4);
+    let (keys, max, count) = match parse_mpop(parser, 1, parse_minmax) {
+        Ok(parsed) => parsed,
+        Err(err) => return err,
+    };
+    match generic_zmpop(db, dbindex, &keys, max, count) {
+        Ok(popped) => zmpop_response(popped),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+fn bzmpop(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    block: bool,
+) -> Result<Response, ResponseError> {
+    opt_validate!(parser.argv.len() >= 5, "Wrong number of parameters");
+    let timeout = match parse_timeout(parser, 1) {
+        Ok(timeout) => timeout,
+        Err(err) => return Ok(err),
+    };
+    let (keys, max, count) = match parse_mpop(parser, 2, parse_minmax) {
+        Ok(parsed) => parsed,
+        Err(err) => return Ok(err),
+    };
+    match generic_zmpop(db, dbindex, &keys, max, count) {
+        Ok(Some((key, members))) => {
+            let command = if max { b"ZPOPMAX" } else { b"ZPOPMIN" };
+            let count = format!("{}", members.len()).into_bytes();
+            propagate_served(db, dbindex, vec![command.to_vec(), key.clone(), count]);
+            Ok(zmpop_response(Some((key, members))))
+        }
+        Ok(None) => block_on_keys(parser, db, dbindex, &keys, timeout, 1, block),
+        Err(err) => Ok(Response::Error(err.to_string())),
+    }
+}
+
+/// The operation of ZUNION, ZINTER, ZDIFF and their STORE variants.
+#[derive(Clone, Copy, PartialEq)]
+enum ZSetOp {
+    Union,
+    Inter,
+    Diff,
+}
+
+/// Runs `op` on the sorted sets given at `pos` as `numkeys key [key ...]`,
+/// followed by `[WEIGHTS weight ...] [AGGREGATE SUM|MIN|MAX]` unless it is a
+/// difference, and by `[WITHSCORES]` unless the result is stored.
+/// Returns the resulting sorted set and whether WITHSCORES was given.
+fn zsetop_query(
+    parser: &ParsedCommand,
+    db: &Database,
+    dbindex: usize,
+    pos: usize,
+    op: ZSetOp,
+    store: bool,
+) -> Result<(Value, bool), Response> {
+    let syntax_error = || Response::Error("ERR syntax error".to_owned());
+    let numkeys = match parser.get_i64(pos) {
+        Ok(numkeys) if numkeys > 0 => numkeys as usize,
+        Ok(_) => {
+            return Err(Response::Error(format!(
+                "ERR at least 1 input key is needed for '{}' command",
+                parser.get_str(0).unwrap_or_default().to_ascii_lowercase()
+            )))
+        }
+        Err(_) => {
+            return Err(Response::Error(
+                "ERR value is not an integer or out of range".to_owned(),
+            ))
+        }
+    };
+    if numkeys > parser.argv.len() - pos - 1 {
+        return Err(syntax_error());
+    }
+    let nil = Value::Nil;
+    let mut zsets = Vec::with_capacity(numkeys);
+    for i in pos + 1..pos + 1 + numkeys {
+        let key = parser.get_vec(i).map_err(|_| syntax_error())?;
+        zsets.push(db.get(dbindex, &key).unwrap_or(&nil));
+    }
+
+    let mut weights = None;
+    let mut aggregate = zset::Aggregate::Sum;
+    let mut withscores = false;
+    let mut i = pos + 1 + numkeys;
+    while i < parser.argv.len() {
+        let option = parser.get_str(i).map_err(|_| syntax_error())?;
+        match &*option.to_ascii_lowercase() {
+            "weights" if op != ZSetOp::Diff && i + numkeys < parser.argv.len() => {
+                let mut w = Vec::with_capacity(numkeys);
+                for j in i + 1..i + 1 + numkeys {
+                    w.push(parser.get_f64(j).map_err(|_| {
+                        Response::Error("ERR weight value is not a float".to_owned())
+                    })?);
+                }
+                weights = Some(w);
+                i += numkeys;
+            }
+            "aggregate" if op != ZSetOp::Diff && i + 1 < parser.argv.len() => {
+                aggregate = match &*parser
+                    .get_str(i + 1)
+                    .map_err(|_| syntax_error())?
+                    .to_ascii_lowercase()
+                {
+                    "sum" => zset::Aggregate::Sum,
+                    "max" => zset::Aggregate::Max,
+                    "min" => zset::Aggregate::Min,
+                    _ => return Err(syntax_error()),
+                };
+                i += 1;
+            }
+            "withscores" if !store => withscores = true,
+            _ => return Err(syntax_error()),
+        }
+        i += 1;
+    }
+
+    let n = Value::Nil;
+    let value = match op {
+        ZSetOp::Union => n.zunion(&zsets, weights, aggregate),
+        ZSetOp::Inter => n.zinter(&zsets, weights, aggregate),
+        ZSetOp::Diff => n.zdiff(&zsets),
+    };
+    value
+        .map(|value| (value, withscores))
+        .map_err(|err| Response::Error(err.to_string()))
+}
+
+fn zsetop(parser: &mut ParsedCommand, db: &Database, dbindex: usize, op: ZSetOp) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let (value, withscores) = match zsetop_query(parser, db, dbindex, 1, op, false) {
+        Ok(r) => r,
+        Err(err) => return err,
+    };
+    match value.zrange(0, -1, withscores, false) {
+        Ok(r) => Response::Array(r.into_iter().map(Response::Data).collect()),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+fn zsetop_store(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    op: ZSetOp,
+) -> Response {
+    validate_arguments_gte!(parser, 4);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let value = match zsetop_query(parser, db, dbindex, 2, op, true) {
+        Ok((value, _)) => value,
+        Err(err) => return err,
+    };
+    let r = match value.zcard() {
+        Ok(count) => Response::Integer(count as i64),
+        Err(err) => Response::Error(err.to_string()),
+    };
+    let lazy = db.config.lazyfree_lazy_server_del;
+    let existed = db.delete(dbindex, &key, lazy);
+    if value.is_empty() {
+        if existed {
+            db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, &key);
+        }
+    } else {
+        *db.get_or_create(dbindex, &key) = value;
+        let event = match op {
+            ZSetOp::Union => "zunionstore",
+            ZSetOp::Inter => "zinterstore",
+            ZSetOp::Diff => "zdiffstore",
+        };
+        db.notify_keyspace_event(NOTIFY_ZSET, event, dbindex, &key);
+    }
+    db.key_updated(dbindex, &key);
+    r
+}
+
+fn zunion(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    zsetop(parser, db, dbindex, ZSetOp::Union)
+}
+
+fn zinter(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    zsetop(parser, db, dbindex, ZSetOp::Inter)
+}
+
+fn zdiff(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    zsetop(parser, db, dbindex, ZSetOp::Diff)
+}
+
+fn zunionstore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    zsetop_store(parser, db, dbindex, ZSetOp::Union)
+}
+
+fn zinterstore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    zsetop_store(parser, db, dbindex, ZSetOp::Inter)
+}
+
+fn zdiffstore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    zsetop_store(parser, db, dbindex, ZSetOp::Diff)
+}
+
+/// Parses `numkeys key [key ...] [LIMIT limit]` starting at position 1.
+/// Returns the position after the last key and the limit, zero meaning
+/// no limit.
+fn parse_intercard(parser: &ParsedCommand) -> Result<(usize, usize), Response> {
+    let error = |msg: &str| Response::Error(msg.to_owned());
+    let numkeys = match parser.get_i64(1) {
+        Ok(numkeys) if numkeys > 0 => numkeys as usize,
+        _ => return Err(error("ERR numkeys should be greater than 0")),
+    };
+    if numkeys > parser.argv.len() - 2 {
+        return Err(error(
+            "ERR Number of keys can't be greater than number of args",
+        ));
+    }
+    let options = 2 + numkeys;
+    let limit = match parser.argv.len() - options {
+        0 => 0,
+        2 => {
+            let option = parser
+                .get_str(options)
+                .map_err(|_| error("ERR syntax error"))?;
+            if !option.eq_ignore_ascii_case("limit") {
+                return Err(error("ERR syntax error"));
+            }
+            match parser.get_i64(options + 1) {
+                Ok(limit) if limit >= 0 => limit as usize,
+                _ => return Err(error("ERR LIMIT can't be negative")),
+            }
+        }
+        _ => return Err(error("ERR syntax error")),
+    };
+    Ok((options, limit))
+}
+
+fn zintercard(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let (options, limit) = match parse_intercard(parser) {
+        Ok(r) => r,
+        Err(err) => return err,
+    };
+    let nil = Value::Nil;
+    let zsets = get_values!(2, options, parser, db, dbindex, &nil);
+    match nil.zintercard(&zsets, limit) {
+        Ok(count) => Response::Integer(count as i64),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+fn zmscore(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let nil = Value::Nil;
+    let value = db.get(dbindex, &key).unwrap_or(&nil);
+    let mut scores = Vec::with_capacity(parser.argv.len() - 2);
+    for i in 2..parser.argv.len() {
+        let member = try_validate!(parser.get_vec(i), "Invalid member");
+        scores.push(match value.zscore(member) {
+            Ok(Some(score)) => score_response(score),
+            Ok(None) => Response::Nil,
+            Err(err) => return Response::Error(err.to_string()),
+        });
+    }
+    Response::Array(scores)
+}
+
+fn zrandmember(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 2);
+    validate_arguments_lte!(parser, 4);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let nil = Value::Nil;
+    let value = db.get(dbindex, &key).unwrap_or(&nil);
+    if parser.argv.len() == 2 {
+        return match value.zrandmember(1, false) {
+            Ok(mut members) => members
+                .pop()
+                .map_or(Response::Nil, |(member, _)| Response::Data(member)),
+            Err(err) => Response::Error(err.to_string()),
+        };
+    }
+    let count = try_validate!(
+        parser.get_i64(2),
+        "ERR value is not an integer or out of range"
+    );
+    validate!(count != i64::MIN, "ERR value is out of range");
+    let withscores = parser.argv.len() == 4;
+    if withscores {
+        let option = try_validate!(parser.get_str(3), "ERR syntax error");
+        validate!(
+            option.eq_ignore_ascii_case("withscores"),
+            "ERR syntax error"
+        );
+    }
+    // a negative count may repeat members
+    match value.zrandmember(count.unsigned_abs() as usize, count < 0) {
+        Ok(members) => {
+            let mut r = Vec::with_capacity(members.len() * if withscores { 2 } else { 1 });
+            for (member, score) in members {
+                r.push(Response::Data(member));
+                if withscores {
+                    r.push(score_response(score));
+                }
+            }
+            Response::Array(r)
+        }
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+fn ping(parser: &mut ParsedCommand, client: &mut Client) -> Response {
+    validate!(
+        parser.argv.len() <= 2,
+        format!(
+            "ERR wrong number of arguments for '{}' command",
+            parser.get_str(0).unwrap()
+        )
+    );
+
+    if !client.subscriptions.is_empty() {
+        if parser.argv.len() == 2 {
+            match parser.get_vec(1) {
+                Ok(r) => Response::Array(vec![Response::Data(b"pong".to_vec()), Response::Data(r)]),
+                Err(err) => Response::Error(err.to_string()),
+            }
+        } else {
+            Response::Array(vec![
+                Response::Data(b"pong".to_vec()),
+                Response::Data(vec![]),
+            ])
+        }
+    } else if parser.argv.len() == 2 {
+        match parser.get_vec(1) {
+            Ok(r) => Response::Data(r),
+            Err(err) => Response::Error(err.to_string()),
+        }
+    } else {
+        Response::Status("PONG".to_owned())
+    }
+}
+
+fn subscribe(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    subscriptions: &mut HashMap<Vec<u8>, usize>,
+    pattern_subscriptions_len: usize,
+    sender: &Sender<Option<Response>>,
+) -> Result<Response, ResponseError> {
+    opt_validate!(parser.argv.len() >= 2, "Wrong number of parameters");
+    for i in 1..parser.argv.len() {
+        let channel_name = try_opt_validate!(parser.get_vec(i), "Invalid channel");
+        if !subscriptions.contains_key(&channel_name) {
+            let subscriber_id = db.subscribe(channel_name.clone(), sender.clone());
+            subscriptions.insert(channel_name.clone(), subscriber_id);
+        }
+        match sender.send(Some(
+            PubsubEvent::Subscription(
+                channel_name.clone(),
+                pattern_subscriptions_len + subscriptions.len(),
+            )
+            .as_response(),
+        )) {
+            Ok(_) => None,
+            Err(_) => subscriptions.remove(&channel_name),
+        };
+    }
+    Err(ResponseError::NoReply)
+}
+
+fn unsubscribe(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    subscriptions: &mut HashMap<Vec<u8>, usize>,
+    pattern_subscriptions_len: usize,
+    sender: &Sender<Option<Response>>,
+) -> Result<Response, ResponseError> {
+    if parser.argv.len() == 1 {
+        if subscriptions.is_empty() {
+            let _ = sender.send(Some(
+                PubsubEvent::Unsubscription(vec![], pattern_subscriptions_len).as_response(),
+            ));
+        } else {
+            for (channel_name, subscriber_id) in subscriptions.drain() {
+                db.unsubscribe(channel_name.clone(), subscriber_id);
+                let _ = sender.send(Some(
+                    PubsubEvent::Unsubscription(channel_name, pattern_subscriptions_len)
+                        .as_response(),
+                ));
+            }
+        }
+    } else {
+        for i in 1..parser.argv.len() {
             let channel_name = try_opt_validate!(parser.get_vec(i), "Invalid channel");
             if let Some(subscriber_id) = subscriptions.remove(&channel_name) {
                 db.unsubscribe(channel_name.clone(), subscriber_id);
@@ -2211,31 +3488,342 @@ fn publish(parser: &mut ParsedCommand, db: &mut Database) -> Response {
     Response::Integer(db.publish(&channel_name, &message) as i64)
 }
 
-fn monitor(
+fn ssubscribe(
     parser: &mut ParsedCommand,
     db: &mut Database,
-    rawsender: Sender<Option<Response>>,
-) -> Response {
-    validate_arguments_exact!(parser, 1);
-    let (tx, rx) = channel();
-    db.monitor_add(tx);
-    thread::spawn(move || {
-        while rx
-            .recv()
-            .ok()
-            .and_then(|r| rawsender.send(Some(Response::Status(r))).ok())
-            .is_some()
-        {}
-    });
-    Response::Status("OK".to_owned())
+    shard_subscriptions: &mut HashMap<Vec<u8>, usize>,
+    sender: &Sender<Option<Response>>,
+) -> Result<Response, ResponseError> {
+    opt_validate!(parser.argv.len() >= 2, "Wrong number of parameters");
+    for i in 1..parser.argv.len() {
+        let channel_name = try_opt_validate!(parser.get_vec(i), "Invalid channel");
+        if !shard_subscriptions.contains_key(&channel_name) {
+            let subscriber_id = db.ssubscribe(channel_name.clone(), sender.clone());
+            shard_subscriptions.insert(channel_name.clone(), subscriber_id);
+        }
+        if sender
+            .send(Some(
+                PubsubEvent::ShardSubscription(channel_name.clone(), shard_subscriptions.len())
+                    .as_response(),
+            ))
+            .is_err()
+        {
+            if let Some(subscriber_id) = shard_subscriptions.remove(&channel_name) {
+                db.sunsubscribe(channel_name, subscriber_id);
+            }
+        }
+    }
+    Err(ResponseError::NoReply)
 }
 
-#[cfg(all(target_pointer_width = "32"))]
-const BITS: usize = 32;
-#[cfg(all(target_pointer_width = "64"))]
-const BITS: usize = 64;
-
-fn info(parser: &mut ParsedCommand, db: &Database) -> Response {
+fn sunsubscribe(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    shard_subscriptions: &mut HashMap<Vec<u8>, usize>,
+    sender: &Sender<Option<Response>>,
+) -> Result<Response, ResponseError> {
+    if parser.argv.len() == 1 {
+        if shard_subscriptions.is_empty() {
+            let _ = sender.send(Some(
+                PubsubEvent::ShardUnsubscription(vec![], 0).as_response(),
+            ));
+        } else {
+            let mut remaining = shard_subscriptions.len();
+            for (channel_name, subscriber_id) in shard_subscriptions.drain() {
+                db.sunsubscribe(channel_name.clone(), subscriber_id);
+                remaining -= 1;
+                let _ = sender.send(Some(
+                    PubsubEvent::ShardUnsubscription(channel_name, remaining).as_response(),
+                ));
+            }
+        }
+    } else {
+        for i in 1..parser.argv.len() {
+            let channel_name = try_opt_validate!(parser.get_vec(i), "Invalid channel");
+            if let Some(subscriber_id) = shard_subscriptions.remove(&channel_name) {
+                db.sunsubscribe(channel_name.clone(), subscriber_id);
+            }
+            let _ = sender.send(Some(
+                PubsubEvent::ShardUnsubscription(channel_name, shard_subscriptions.len())
+                    .as_response(),
+            ));
+        }
+    }
+    Err(ResponseError::NoReply)
+}
+
+fn spublish(parser: &mut ParsedCommand, db: &mut Database) -> Response {
+    validate_arguments_exact!(parser, 3);
+    let channel_name = try_validate!(parser.get_vec(1), "Invalid channel");
+    let message = try_validate!(parser.get_vec(2), "Invalid message");
+    Response::Integer(db.spublish(&channel_name, &message) as i64)
+}
+
+fn pubsub(parser: &mut ParsedCommand, db: &Database) -> Response {
+    let subcommand = try_validate!(parser.get_str(1), "Invalid subcommand").to_ascii_lowercase();
+    match (&*subcommand, parser.argv.len()) {
+        ("channels", 2) | ("channels", 3) => {
+            let pattern = if parser.argv.len() == 3 {
+                Some(try_validate!(parser.get_vec(2), "Invalid pattern"))
+            } else {
+                None
+            };
+            Response::Array(
+                db.pubsub_channels(pattern.as_ref().map(|p| &p[..]))
+                    .into_iter()
+                    .map(Response::Data)
+                    .collect(),
+            )
+        }
+        ("shardchannels", 2) | ("shardchannels", 3) => {
+            let pattern = if parser.argv.len() == 3 {
+                Some(try_validate!(parser.get_vec(2), "Invalid pattern"))
+            } else {
+                None
+            };
+            Response::Array(
+                db.pubsub_shardchannels(pattern.as_ref().map(|p| &p[..]))
+                    .into_iter()
+                    .map(Response::Data)
+                    .collect(),
+            )
+        }
+        ("numsub", _) => {
+            let mut r = Vec::with_capacity((parser.argv.len() - 2) * 2);
+            for i in 2..parser.argv.len() {
+                let channel = try_validate!(parser.get_vec(i), "Invalid channel");
+                let count = db.pubsub_numsub(&channel);
+                r.push(Response::Data(channel));
+                r.push(Response::Integer(count as i64));
+            }
+            Response::Array(r)
+        }
+        ("shardnumsub", _) => {
+            let mut r = Vec::with_capacity((parser.argv.len() - 2) * 2);
+            for i in 2..parser.argv.len() {
+                let channel = try_validate!(parser.get_vec(i), "Invalid channel");
+                let count = db.pubsub_shardnumsub(&channel);
+                r.push(Response::Data(channel));
+                r.push(Response::Integer(count as i64));
+            }
+            Response::Array(r)
+        }
+        ("numpat", 2) => Response::Integer(db.pubsub_numpat() as i64),
+        _ => Response::Error(format!(
+            "ERR Unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        )),
+    }
+}
+
+fn monitor(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    rawsender: Sender<Option<Response>>,
+) -> Response {
+    validate_arguments_exact!(parser, 1);
+    let (tx, rx) = channel();
+    db.monitor_add(tx);
+    thread::spawn(move || {
+        while rx
+            .recv()
+            .ok()
+            .and_then(|r| rawsender.send(Some(Response::Status(r))).ok())
+            .is_some()
+        {}
+    });
+    Response::Status("OK".to_owned())
+}
+
+fn shutdown(parser: &mut ParsedCommand, db: &mut Database) -> Result<Response, ResponseError> {
+    let mut save = None;
+    let mut force = false;
+    let mut abort = false;
+    for i in 1..parser.argv.len() {
+        match &*try_opt_validate!(parser.get_str(i), "ERR syntax error").to_ascii_lowercase() {
+            "nosave" => {
+                opt_validate!(save != Some(true), "ERR syntax error");
+                save = Some(false);
+            }
+            "save" => {
+                opt_validate!(save != Some(false), "ERR syntax error");
+                save = Some(true);
+            }
+            // there are no replicas to wait for
+            "now" => (),
+            "force" => force = true,
+            "abort" => abort = true,
+            _ => return Ok(Response::Error("ERR syntax error".to_owned())),
+        }
+    }
+    if abort {
+        opt_validate!(parser.argv.len() == 2, "ERR syntax error");
+        return Ok(Response::Error("ERR No shutdown in progress.".to_owned()));
+    }
+
+    logger::log!(db.config.logger, Warning, "User requested shutdown...");
+    if !db.shutdown(save, force) {
+        return Ok(Response::Error(
+            "ERR Errors trying to SHUTDOWN. Check logs.".to_owned(),
+        ));
+    }
+    Err(ResponseError::NoReply)
+}
+
+#[cfg(all(target_pointer_width = "32"))]
+const BITS: usize = 32;
+#[cfg(all(target_pointer_width = "64"))]
+const BITS: usize = 64;
+
+/// Collection elements measured by MEMORY USAGE unless SAMPLES is given.
+const MEMORY_USAGE_SAMPLES: usize = 5;
+
+/// Memory used by the keyspace tables and by the keys and values of every
+/// database.
+struct MemoryStats {
+    /// Main and expires table bytes of each database with keys
+    databases: Vec<(usize, usize, usize)>,
+    overhead: usize,
+    keys: usize,
+    dataset: usize,
+}
+
+impl MemoryStats {
+    fn new(db: &Database) -> MemoryStats {
+        let mut stats = MemoryStats {
+            databases: vec![],
+            overhead: 0,
+            keys: 0,
+            dataset: 0,
+        };
+        for index in 0..db.config.databases as usize {
+            let keys = db.dbsize(index);
+            if keys == 0 {
+                continue;
+            }
+            let (main, expires) = db.memory_overhead(index);
+            stats.databases.push((index, main, expires));
+            stats.overhead += main + expires;
+            stats.keys += keys;
+            stats.dataset += db.dataset_memory(index, MEMORY_USAGE_SAMPLES);
+        }
+        stats
+    }
+
+    fn total(&self) -> usize {
+        self.overhead + self.dataset
+    }
+}
+
+/// MEMORY USAGE, STATS, DOCTOR and PURGE. The sizes are estimated from the
+/// data structures, there are no allocator statistics.
+fn memory(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response {
+    let subcommand = try_validate!(parser.get_str(1), "Invalid subcommand").to_ascii_lowercase();
+    match (&*subcommand, parser.argv.len()) {
+        ("usage", argc) if argc >= 3 => {
+            let key = try_validate!(parser.get_vec(2), "Invalid key");
+            let mut samples = MEMORY_USAGE_SAMPLES;
+            let mut i = 3;
+            while i < argc {
+                let option = try_validate!(parser.get_str(i), "ERR syntax error");
+                validate!(
+                    option.eq_ignore_ascii_case("samples") && i + 1 < argc,
+                    "ERR syntax error"
+                );
+                let n = try_validate!(
+                    parser.get_i64(i + 1),
+                    "ERR value is not an integer or out of range"
+                );
+                validate!(n >= 0, "ERR syntax error");
+                samples = n as usize;
+                i += 2;
+            }
+            match db.memory_usage(dbindex, &key, samples) {
+                Some(usage) => Response::Integer(usage as i64),
+                None => Response::Nil,
+            }
+        }
+        ("stats", 2) => {
+            let stats = MemoryStats::new(db);
+            let mut r = vec![];
+            for &(index, main, expires) in &stats.databases {
+                r.push(Response::Data(format!("db.{}", index).into_bytes()));
+                r.push(Response::Array(vec![
+                    Response::Data(b"overhead.hashtable.main".to_vec()),
+                    Response::Integer(main as i64),
+                    Response::Data(b"overhead.hashtable.expires".to_vec()),
+                    Response::Integer(expires as i64),
+                ]));
+            }
+            let bytes_per_key = stats.total().checked_div(stats.keys).unwrap_or(0);
+            let percentage = if stats.total() == 0 {
+                0.0
+            } else {
+                stats.dataset as f64 * 100.0 / stats.total() as f64
+            };
+            r.extend(vec![
+                Response::Data(b"overhead.total".to_vec()),
+                Response::Integer(stats.overhead as i64),
+                Response::Data(b"keys.count".to_vec()),
+                Response::Integer(stats.keys as i64),
+                Response::Data(b"keys.bytes-per-key".to_vec()),
+                Response::Integer(bytes_per_key as i64),
+                Response::Data(b"dataset.bytes".to_vec()),
+                Response::Integer(stats.dataset as i64),
+                Response::Data(b"dataset.percentage".to_vec()),
+                Response::Data(format!("{}", percentage).into_bytes()),
+            ]);
+            Response::Array(r)
+        }
+        ("doctor", 2) => {
+            let stats = MemoryStats::new(db);
+            if stats.keys == 0 {
+                return Response::Data(
+                    b"This instance is empty, there is no memory usage to analyze.".to_vec(),
+                );
+            }
+            let mut issues = vec![];
+            if stats.overhead > stats.dataset {
+                issues.push(format!(
+                    "High overhead: the keyspace tables use {} bytes, more than the {} bytes \
+                     of the keys and values. This happens with many small keys, or when most \
+                     of the keys of a big database were deleted.",
+                    stats.overhead, stats.dataset
+                ));
+            }
+            let pending = db.lazyfree_pending_objects();
+            if pending > 0 {
+                issues.push(format!(
+                    "Lazy free: {} objects are still waiting to be freed in the background.",
+                    pending
+                ));
+            }
+            let report = if issues.is_empty() {
+                format!(
+                    "No memory issues detected. There are {} keys using about {} bytes each. \
+                     Use MEMORY USAGE to find the biggest ones.",
+                    stats.keys,
+                    stats.total() / stats.keys
+                )
+            } else {
+                let mut report = "Memory issues detected:\n".to_owned();
+                for issue in issues {
+                    report.push_str(&format!("\n * {}", issue));
+                }
+                report
+            };
+            Response::Data(report.into_bytes())
+        }
+        // memory is returned to the allocator as soon as it is freed
+        ("purge", 2) => Response::Status("OK".to_owned()),
+        _ => Response::Error(format!(
+            "ERR Unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        )),
+    }
+}
+
+fn info(parser: &mut ParsedCommand, db: &Database) -> Response {
     validate_arguments_lte!(parser, 2);
     let section = &*(if parser.argv.len() == 1 {
         "default".to_owned()
@@ -2276,7 +3864,7 @@ fn info(parser: &mut ParsedCommand, db: &Database) -> Response {
                 BITS,
                 db.rustc_version,
                 getpid(),
-                db.run_id,
+                db.run_id(),
                 db.config.port,
                 uptime / 1000,
                 uptime / (1000 * 60 * 60 * 24),
@@ -2285,6 +3873,84 @@ fn info(parser: &mut ParsedCommand, db: &Database) -> Response {
         );
     }
 
+    if section == "default" || section == "all" || section == "memory" {
+        try_validate!(
+            write!(
+                out,
+                "\
+                 # Memory\r\n\
+                 lazyfree_pending_objects:{}\r\n\
+                 \r\n\
+                 ",
+                db.lazyfree_pending_objects(),
+            ),
+            "ERR unexpected"
+        );
+    }
+
+    if section == "default" || section == "all" || section == "stats" {
+        try_validate!(
+            write!(
+                out,
+                "\
+                 # Stats\r\n\
+                 pubsub_channels:{}\r\n\
+                 pubsub_patterns:{}\r\n\
+                 pubsubshard_channels:{}\r\n\
+                 \r\n\
+                 ",
+                db.pubsub_channels(None).len(),
+                db.pubsub_numpat(),
+                db.pubsub_shardchannels(None).len(),
+            ),
+            "ERR unexpected"
+        );
+    }
+
+    if section == "default" || section == "all" || section == "replication" {
+        // replication is not supported, every server is a master
+        try_validate!(
+            write!(
+                out,
+                "\
+                 # Replication\r\n\
+                 role:master\r\n\
+                 connected_slaves:0\r\n\
+                 \r\n\
+                 "
+            ),
+            "ERR unexpected"
+        );
+    }
+
+    if let Some(sentinel) = db.sentinel().as_ref() {
+        if section == "default" || section == "all" || section == "sentinel" {
+            try_validate!(
+                write!(
+                    out,
+                    "# Sentinel\r\n{}\r\n\r\n",
+                    sentinel.info().join("\r\n")
+                ),
+                "ERR unexpected"
+            );
+        }
+    }
+
+    if section == "default" || section == "all" || section == "cluster" {
+        try_validate!(
+            write!(
+                out,
+                "\
+                 # Cluster\r\n\
+                 cluster_enabled:{}\r\n\
+                 \r\n\
+                 ",
+                if db.is_cluster() { 1 } else { 0 },
+            ),
+            "ERR unexpected"
+        );
+    }
+
     if section == "default" || section == "all" || section == "keyspace" {
         try_validate!(write!(out, "# Keyspace\r\n"), "ERR unexpected");
         for dbindex in 0..(db.config.databases as usize) {
@@ -2310,9 +3976,20 @@ fn info(parser: &mut ParsedCommand, db: &Database) -> Response {
 pub struct Client {
     pub dbindex: usize,
     pub auth: bool,
+    /// ACL user the client is authenticated as
+    pub user: String,
     pub subscriptions: HashMap<Vec<u8>, usize>,
     pub pattern_subscriptions: HashMap<Vec<u8>, usize>,
+    pub shard_subscriptions: HashMap<Vec<u8>, usize>,
+    /// Set by CLIENT CACHING, applies to the next command only
+    pub tracking_caching: Option<bool>,
+    /// Peer address, or `unix:<path>` for unix sockets, as shown by MONITOR
+    pub addr: String,
+    /// Set by ASKING, lets the next command access a slot being imported
+    pub asking: bool,
     pub multi: bool,
+    /// Set while EXEC runs the queued commands, which cannot block
+    pub in_exec: bool,
     pub multi_commands: Vec<OwnedParsedCommand>,
     pub watched_keys: HashSet<(usize, Vec<u8>)>,
     pub id: usize,
@@ -2328,9 +4005,15 @@ impl Client {
         Client {
             dbindex: 0,
             auth: false,
+            user: DEFAULT_USER.to_owned(),
             subscriptions: HashMap::new(),
             pattern_subscriptions: HashMap::new(),
+            shard_subscriptions: HashMap::new(),
+            tracking_caching: None,
+            addr: String::new(),
+            asking: false,
             multi: false,
+            in_exec: false,
             multi_commands: Vec::new(),
             id,
             watched_keys: HashSet::new(),
@@ -2339,6 +4022,152 @@ impl Client {
     }
 }
 
+fn client_tracking(parser: &mut ParsedCommand, db: &mut Database, client: &Client) -> Response {
+    let mode = try_validate!(parser.get_str(2), "ERR syntax error").to_ascii_lowercase();
+    let enable = match &*mode {
+        "on" => true,
+        "off" => false,
+        _ => return Response::Error("ERR syntax error".to_owned()),
+    };
+    let mut options = TrackingOptions::default();
+    let mut i = 3;
+    while i < parser.argv.len() {
+        let option = try_validate!(parser.get_str(i), "ERR syntax error").to_ascii_lowercase();
+        match &*option {
+            "redirect" if i + 1 < parser.argv.len() => {
+                if options.redirect.is_some() {
+                    return Response::Error(
+                        "ERR A client can only redirect to a single other client".to_owned(),
+                    );
+                }
+                i += 1;
+                let id = try_validate!(
+                    parser.get_i64(i),
+                    "ERR value is not an integer or out of range"
+                );
+                options.redirect = Some(id as usize);
+            }
+            "prefix" if i + 1 < parser.argv.len() => {
+                i += 1;
+                options
+                    .prefixes
+                    .push(try_validate!(parser.get_vec(i), "ERR syntax error"));
+            }
+            "bcast" => options.bcast = true,
+            "optin" => options.optin = true,
+            "optout" => options.optout = true,
+            "noloop" => options.noloop = true,
+            _ => return Response::Error("ERR syntax error".to_owned()),
+        }
+        i += 1;
+    }
+
+    if !enable {
+        db.tracking().disable(client.id);
+        return Response::Status("OK".to_owned());
+    }
+    if !options.bcast && !options.prefixes.is_empty() {
+        return Response::Error("ERR PREFIX option requires BCAST mode to be enabled".to_owned());
+    }
+    if options.optin && options.optout {
+        return Response::Error("ERR You can't use both OPTIN and OPTOUT".to_owned());
+    }
+    if options.bcast && (options.optin || options.optout) {
+        return Response::Error("ERR OPTIN and OPTOUT are not compatible with BCAST".to_owned());
+    }
+    if let Some(current) = db.tracking().options(client.id) {
+        if current.bcast != options.bcast {
+            return Response::Error(
+                "ERR You can't switch BCAST mode on/off before disabling tracking for this \
+                 client, and then re-enabling it with a different mode."
+                    .to_owned(),
+            );
+        }
+        if current.optin != options.optin || current.optout != options.optout {
+            return Response::Error(
+                "ERR You can't switch OPTIN/OPTOUT mode before disabling tracking for this \
+                 client, and then re-enabling it with a different mode."
+                    .to_owned(),
+            );
+        }
+    }
+    match db.tracking().enable(client.id, options) {
+        Ok(()) => Response::Status("OK".to_owned()),
+        Err(err) => Response::Error(err),
+    }
+}
+
+fn client_caching(parser: &mut ParsedCommand, db: &Database, client: &mut Client) -> Response {
+    let (optin, optout) = match db.tracking().options(client.id) {
+        Some(options) if options.optin || options.optout => (options.optin, options.optout),
+        _ => {
+            return Response::Error(
+                "ERR CLIENT CACHING can be called only when the client is in tracking mode \
+                 with OPTIN or OPTOUT mode enabled"
+                    .to_owned(),
+            )
+        }
+    };
+    let caching = try_validate!(parser.get_str(2), "ERR syntax error").to_ascii_lowercase();
+    match &*caching {
+        "yes" if optin => client.tracking_caching = Some(true),
+        "yes" => {
+            return Response::Error(
+                "ERR CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode."
+                    .to_owned(),
+            )
+        }
+        "no" if optout => client.tracking_caching = Some(false),
+        "no" => {
+            return Response::Error(
+                "ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode."
+                    .to_owned(),
+            )
+        }
+        _ => return Response::Error("ERR syntax error".to_owned()),
+    }
+    Response::Status("OK".to_owned())
+}
+
+fn client_command(parser: &mut ParsedCommand, db: &mut Database, client: &mut Client) -> Response {
+    let subcommand = try_validate!(parser.get_str(1), "Invalid subcommand").to_ascii_lowercase();
+    match (&*subcommand, parser.argv.len()) {
+        ("id", 2) => Response::Integer(client.id as i64),
+        ("tracking", argc) if argc >= 3 => client_tracking(parser, db, client),
+        ("caching", 3) => client_caching(parser, db, client),
+        _ => Response::Error(format!(
+            "ERR Unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        )),
+    }
+}
+
+/// Remembers the keys read by a command, so the client is notified when
+/// they change.
+fn track_keys(
+    parser: &ParsedCommand,
+    db: &mut Database,
+    client_id: usize,
+    command_name: &str,
+    caching: Option<bool>,
+) {
+    let track = match db.tracking().options(client_id) {
+        Some(options) if options.bcast => false,
+        Some(options) if options.optin => caching == Some(true),
+        Some(options) if options.optout => caching != Some(false),
+        Some(_) => true,
+        None => false,
+    };
+    if !track {
+        return;
+    }
+    for i in acl::command_key_positions(command_name, parser) {
+        if let Ok(key) = parser.get_slice(i) {
+            db.tracking().remember(client_id, key);
+        }
+    }
+}
+
 fn keys(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
     validate_arguments_exact!(parser, 2);
     let pattern = try_validate!(parser.get_vec(1), "Invalid pattern");
@@ -2412,11 +4241,14 @@ fn exec(db: &mut Database, client: &mut Client) -> Response {
     if !generic_unwatch(db, client.id, &mut client.watched_keys) {
         return Response::Nil;
     }
-    Response::Array(
+    client.in_exec = true;
+    let r = Response::Array(
         c.iter()
             .map(|c| command(c.get_command(), db, client).unwrap())
             .collect(),
-    )
+    );
+    client.in_exec = false;
+    r
 }
 
 fn discard(db: &mut Database, client: &mut Client) -> Response {
@@ -2431,7 +4263,7 @@ fn discard(db: &mut Database, client: &mut Client) -> Response {
 }
 
 bitflags! {
-    struct CommandFlags: u16 {
+    pub(crate) struct CommandFlags: u16 {
         /// write command (may modify the key space).
         const WRITE = 1;
         /// read command  (will never modify the key space).
@@ -2467,27 +4299,237 @@ bitflags! {
         /// Note that commands that may trigger a DEL as a side effect (like SET)
         /// are not fast commands.
         const FAST = 4096;
+        /// may block the client until a key is modified. It is not written to
+        /// the aof as is: when served, it propagates the command it ran.
+        const BLOCKING = 8192;
     }
 }
 
 // TODO: Only `flags` is ever used
 #[allow(dead_code)]
-struct CommandProperties {
-    arity: i64,
+pub(crate) struct CommandProperties {
+    pub(crate) arity: i64,
     /// Flags as bitmask. Computed by Redis using the 'sflags' field.
-    flags: CommandFlags,
+    pub(crate) flags: CommandFlags,
     /// First argument that is a key
-    first_key_index: i64,
+    pub(crate) first_key_index: i64,
     /// Last argument that is a key
-    last_key_index: i64,
+    pub(crate) last_key_index: i64,
     /// Step to get all the keys from first to last argument. For instance
     ///           in MSET the step is two since arguments are key,val,key,val,...
-    key_step: i64,
-}
-
-fn command_properties(command_name: &str) -> CommandProperties {
+    pub(crate) key_step: i64,
+}
+
+/// Every command with properties.
+pub(crate) const COMMAND_NAMES: &[&str] = &[
+    "get",
+    "getex",
+    "getdel",
+    "set",
+    "setnx",
+    "setex",
+    "psetex",
+    "append",
+    "strlen",
+    "del",
+    "unlink",
+    "exists",
+    "setbit",
+    "getbit",
+    "setrange",
+    "getrange",
+    "lcs",
+    "substr",
+    "incr",
+    "decr",
+    "mget",
+    "rpush",
+    "lpush",
+    "rpushx",
+    "lpushx",
+    "linsert",
+    "rpop",
+    "lpop",
+    "rpoplpush",
+    "brpop",
+    "blpop",
+    "brpoplpush",
+    "lmove",
+    "blmove",
+    "lmpop",
+    "blmpop",
+    "lpos",
+    "llen",
+    "lindex",
+    "lset",
+    "lrange",
+    "ltrim",
+    "lrem",
+    "sadd",
+    "srem",
+    "smove",
+    "sismember",
+    "smismember",
+    "scard",
+    "spop",
+    "srandmember",
+    "sinter",
+    "sinterstore",
+    "sintercard",
+    "sunion",
+    "sunionstore",
+    "sdiff",
+    "sdiffstore",
+    "smembers",
+    "sscan",
+    "zadd",
+    "zincrby",
+    "zrem",
+    "zremrangebyscore",
+    "zremrangebyrank",
+    "zremrangebylex",
+    "zunionstore",
+    "zinterstore",
+    "zunion",
+    "zinter",
+    "zdiff",
+    "zdiffstore",
+    "zintercard",
+    "zmscore",
+    "zrandmember",
+    "zpopmin",
+    "zpopmax",
+    "bzpopmin",
+    "bzpopmax",
+    "zmpop",
+    "bzmpop",
+    "zrange",
+    "zrevrange",
+    "zrangebyscore",
+    "zrevrangebyscore",
+    "zrangebylex",
+    "zrevrangebylex",
+    "zrangestore",
+    "zcount",
+    "zlexcount",
+    "zcard",
+    "zscore",
+    "zrank",
+    "zrevrank",
+    "zscan",
+    "hset",
+    "hsetnx",
+    "hget",
+    "hmset",
+    "hmget",
+    "hincrby",
+    "hincrbyfloat",
+    "hdel",
+    "hlen",
+    "hstrlen",
+    "hkeys",
+    "hvals",
+    "hgetall",
+    "hexists",
+    "hscan",
+    "incrby",
+    "decrby",
+    "incrbyfloat",
+    "getset",
+    "mset",
+    "msetnx",
+    "randomkey",
+    "select",
+    "move",
+    "rename",
+    "renamenx",
+    "expire",
+    "expireat",
+    "pexpire",
+    "pexpireat",
+    "keys",
+    "scan",
+    "dbsize",
+    "auth",
+    "acl",
+    "ping",
+    "echo",
+    "save",
+    "bgsave",
+    "bgrewriteaof",
+    "shutdown",
+    "lastsave",
+    "type",
+    "multi",
+    "exec",
+    "discard",
+    "sync",
+    "psync",
+    "replconf",
+    "flushdb",
+    "flushall",
+    "sort",
+    "info",
+    "monitor",
+    "ttl",
+    "pttl",
+    "expiretime",
+    "pexpiretime",
+    "persist",
+    "slaveof",
+    "role",
+    "debug",
+    "config",
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "publish",
+    "pubsub",
+    "ssubscribe",
+    "sunsubscribe",
+    "spublish",
+    "watch",
+    "unwatch",
+    "cluster",
+    "restore",
+    "migrate",
+    "asking",
+    "readonly",
+    "readwrite",
+    "sentinel",
+    "dump",
+    "object",
+    "memory",
+    "client",
+    "eval",
+    "evalsha",
+    "slowlog",
+    "script",
+    "time",
+    "bitop",
+    "bitcount",
+    "bitpos",
+    "wait",
+    "command",
+    "geoadd",
+    "georadius",
+    "georadiusbymember",
+    "geohash",
+    "geopos",
+    "geodist",
+    "pfselftest",
+    "pfadd",
+    "pfcount",
+    "pfmerge",
+    "pfdebug",
+    "latency",
+];
+
+pub(crate) fn command_properties(command_name: &str) -> CommandProperties {
     const ADMIN: CommandFlags = CommandFlags::ADMIN;
     const ASKING: CommandFlags = CommandFlags::ASKING;
+    const BLOCKING: CommandFlags = CommandFlags::BLOCKING;
     const DENYOOM: CommandFlags = CommandFlags::DENYOOM;
     const FAST: CommandFlags = CommandFlags::FAST;
     const LOADING: CommandFlags = CommandFlags::LOADING;
@@ -2509,18 +4551,22 @@ fn command_properties(command_name: &str) -> CommandProperties {
     let sr = READONLY | SORT_FOR_SCRIPT;
     let (arity, flags, first_key_index, last_key_index, key_step) = match command_name {
         "get" => (2, fr, 1, 1, 1),
-        "set" => (3, wm, 1, 1, 1),
+        "getex" => (-2, wf, 1, 1, 1),
+        "getdel" => (2, wf, 1, 1, 1),
+        "set" => (-3, wm, 1, 1, 1),
         "setnx" => (3, wmf, 1, 1, 1),
         "setex" => (4, wm, 1, 1, 1),
         "psetex" => (4, wm, 1, 1, 1),
         "append" => (3, wm, 1, 1, 1),
         "strlen" => (2, fr, 1, 1, 1),
         "del" => (-2, WRITE, 1, -1, 1),
+        "unlink" => (-2, wf, 1, -1, 1),
         "exists" => (-2, fr, 1, -1, 1),
         "setbit" => (4, wm, 1, 1, 1),
         "getbit" => (3, fr, 1, 1, 1),
         "setrange" => (4, wm, 1, 1, 1),
         "getrange" => (4, READONLY, 1, 1, 1),
+        "lcs" => (-3, READONLY, 1, 2, 1),
         "substr" => (4, READONLY, 1, 1, 1),
         "incr" => (2, wmf, 1, 1, 1),
         "decr" => (2, wmf, 1, 1, 1),
@@ -2530,27 +4576,34 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "rpushx" => (-3, wmf, 1, 1, 1),
         "lpushx" => (-3, wmf, 1, 1, 1),
         "linsert" => (5, wm, 1, 1, 1),
-        "rpop" => (2, wf, 1, 1, 1),
-        "lpop" => (2, wf, 1, 1, 1),
+        "rpop" => (-2, wf, 1, 1, 1),
+        "lpop" => (-2, wf, 1, 1, 1),
         "rpoplpush" => (3, wm, 1, 2, 1),
-        "brpop" => (-3, WRITE | NOSCRIPT, 1, -2, 1),
-        "blpop" => (-3, WRITE | NOSCRIPT, 1, -2, 1),
-        "brpoplpush" => (4, wm | NOSCRIPT, 1, 2, 1),
+        "brpop" => (-3, WRITE | NOSCRIPT | BLOCKING, 1, -2, 1),
+        "blpop" => (-3, WRITE | NOSCRIPT | BLOCKING, 1, -2, 1),
+        "brpoplpush" => (4, wm | NOSCRIPT | BLOCKING, 1, 2, 1),
+        "lmove" => (5, wm, 1, 2, 1),
+        "blmove" => (6, wm | NOSCRIPT | BLOCKING, 1, 2, 1),
+        "lmpop" => (-4, WRITE, 0, 0, 0),
+        "blmpop" => (-5, WRITE | NOSCRIPT | BLOCKING, 0, 0, 0),
+        "lpos" => (-3, READONLY, 1, 1, 1),
         "llen" => (2, fr, 1, 1, 1),
         "lindex" => (3, READONLY, 1, 1, 1),
         "lset" => (4, wm, 1, 1, 1),
         "lrange" => (4, READONLY, 1, 1, 1),
-        "ltrim" => (4, READONLY, 1, 1, 1),
-        "lrem" => (4, READONLY, 1, 1, 1),
+        "ltrim" => (4, WRITE, 1, 1, 1),
+        "lrem" => (4, WRITE, 1, 1, 1),
         "sadd" => (-3, wmf, 1, 1, 1),
         "srem" => (-3, wf, 1, 1, 1),
         "smove" => (4, wf, 1, 2, 1),
         "sismember" => (3, fr, 1, 1, 1),
+        "smismember" => (-3, fr, 1, 1, 1),
         "scard" => (2, fr, 1, 1, 1),
-        "spop" => (-2, fr | RANDOM | NOSCRIPT, 1, 1, 1),
+        "spop" => (-2, wf | RANDOM | NOSCRIPT, 1, 1, 1),
         "srandmember" => (-2, READONLY | RANDOM, 1, 1, 1),
         "sinter" => (-2, sr, 1, -1, 1),
         "sinterstore" => (-3, wm, 1, -1, 1),
+        "sintercard" => (-3, READONLY, 0, 0, 0),
         "sunion" => (-2, sr, 1, -1, 1),
         "sunionstore" => (-3, wm, 1, -1, 1),
         "sdiff" => (-2, sr, 1, -1, 1),
@@ -2563,14 +4616,28 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "zremrangebyscore" => (4, WRITE, 1, 1, 1),
         "zremrangebyrank" => (4, WRITE, 1, 1, 1),
         "zremrangebylex" => (4, WRITE, 1, 1, 1),
-        "zunionstore" => (-4, wm, 0, 0, 0),
-        "zinterstore" => (-4, wm, 0, 0, 0),
+        "zunionstore" => (-4, wm, 1, 1, 1),
+        "zinterstore" => (-4, wm, 1, 1, 1),
+        "zunion" => (-3, READONLY, 0, 0, 0),
+        "zinter" => (-3, READONLY, 0, 0, 0),
+        "zdiff" => (-3, READONLY, 0, 0, 0),
+        "zdiffstore" => (-4, wm, 1, 1, 1),
+        "zintercard" => (-3, READONLY, 0, 0, 0),
+        "zmscore" => (-3, fr, 1, 1, 1),
+        "zrandmember" => (-2, READONLY | RANDOM, 1, 1, 1),
+        "zpopmin" => (-2, wf, 1, 1, 1),
+        "zpopmax" => (-2, wf, 1, 1, 1),
+        "bzpopmin" => (-3, WRITE | NOSCRIPT | BLOCKING, 1, -2, 1),
+        "bzpopmax" => (-3, WRITE | NOSCRIPT | BLOCKING, 1, -2, 1),
+        "zmpop" => (-4, WRITE, 0, 0, 0),
+        "bzmpop" => (-5, WRITE | NOSCRIPT | BLOCKING, 0, 0, 0),
         "zrange" => (-4, READONLY, 1, 1, 1),
         "zrevrange" => (-4, READONLY, 1, 1, 1),
         "zrangebyscore" => (-4, READONLY, 1, 1, 1),
         "zrevrangebyscore" => (-4, READONLY, 1, 1, 1),
         "zrangebylex" => (-4, READONLY, 1, 1, 1),
         "zrevrangebylex" => (-4, READONLY, 1, 1, 1),
+        "zrangestore" => (-5, wm, 1, 2, 1),
         "zcount" => (4, fr, 1, 1, 1),
         "zlexcount" => (4, fr, 1, 1, 1),
         "zcard" => (2, fr, 1, 1, 1),
@@ -2604,14 +4671,15 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "move" => (3, wf, 1, 1, 1),
         "rename" => (3, WRITE, 1, 2, 1),
         "renamenx" => (3, wf, 1, 2, 1),
-        "expire" => (3, wf, 1, 1, 1),
-        "expireat" => (3, wf, 1, 1, 1),
-        "pexpire" => (3, wf, 1, 1, 1),
-        "pexpireat" => (3, wf, 1, 1, 1),
+        "expire" => (-3, wf, 1, 1, 1),
+        "expireat" => (-3, wf, 1, 1, 1),
+        "pexpire" => (-3, wf, 1, 1, 1),
+        "pexpireat" => (-3, wf, 1, 1, 1),
         "keys" => (2, sr, 0, 0, 0),
         "scan" => (-2, READONLY | RANDOM, 0, 0, 0),
         "dbsize" => (1, fr, 0, 0, 0),
-        "auth" => (2, fr | NOSCRIPT | ls, 0, 0, 0),
+        "auth" => (-2, fr | NOSCRIPT | ls, 0, 0, 0),
+        "acl" => (-2, ADMIN | NOSCRIPT | ls, 0, 0, 0),
         "ping" => (-1, fr | STALE, 0, 0, 0),
         "echo" => (2, fr, 0, 0, 0),
         "save" => (1, ars, 0, 0, 0),
@@ -2626,13 +4694,15 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "sync" => (1, ars, 0, 0, 0),
         "psync" => (3, ars, 0, 0, 0),
         "replconf" => (-1, ars | ls, 0, 0, 0),
-        "flushdb" => (1, WRITE, 0, 0, 0),
-        "flushall" => (1, WRITE, 0, 0, 0),
+        "flushdb" => (-1, WRITE, 0, 0, 0),
+        "flushall" => (-1, WRITE, 0, 0, 0),
         "sort" => (-2, wm, 1, 1, 1),
         "info" => (-1, READONLY | ls, 0, 0, 0),
         "monitor" => (1, ars, 0, 0, 0),
         "ttl" => (2, fr, 1, 1, 1),
         "pttl" => (2, fr, 1, 1, 1),
+        "expiretime" => (2, fr, 1, 1, 1),
+        "pexpiretime" => (2, fr, 1, 1, 1),
         "persist" => (2, wf, 1, 1, 1),
         "slaveof" => (3, ADMIN | NOSCRIPT | STALE, 0, 0, 0),
         "role" => (1, STALE | LOADING | NOSCRIPT, 0, 0, 0),
@@ -2643,7 +4713,10 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "psubscribe" => (-2, READONLY | PUBSUB | NOSCRIPT | LOADING | STALE, 0, 0, 0),
         "punsubscribe" => (-1, READONLY | PUBSUB | NOSCRIPT | LOADING | STALE, 0, 0, 0),
         "publish" => (-1, READONLY | PUBSUB | LOADING | STALE | FAST, 0, 0, 0),
-        "pubsub" => (-1, READONLY | PUBSUB | LOADING | STALE | RANDOM, 0, 0, 0),
+        "pubsub" => (-2, READONLY | PUBSUB | LOADING | STALE | RANDOM, 0, 0, 0),
+        "ssubscribe" => (-2, READONLY | PUBSUB | NOSCRIPT | LOADING | STALE, 0, 0, 0),
+        "sunsubscribe" => (-1, READONLY | PUBSUB | NOSCRIPT | LOADING | STALE, 0, 0, 0),
+        "spublish" => (3, READONLY | PUBSUB | LOADING | STALE | FAST, 0, 0, 0),
         "watch" => (-2, fr | NOSCRIPT, 1, -1, 1),
         "unwatch" => (1, fr | NOSCRIPT, 0, 0, 0),
         "cluster" => (-2, ADMIN | READONLY, 0, 0, 0),
@@ -2653,8 +4726,10 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "asking" => (1, READONLY, 0, 0, 0),
         "readonly" => (1, fr, 0, 0, 0),
         "readwrite" => (1, fr, 0, 0, 0),
+        "sentinel" => (-2, ADMIN | READONLY | LOADING | STALE, 0, 0, 0),
         "dump" => (2, READONLY, 1, 1, 1),
-        "object" => (3, READONLY, 2, 2, 2),
+        "object" => (-2, READONLY, 2, 2, 1),
+        "memory" => (-2, READONLY, 2, 2, 1),
         "client" => (-2, READONLY | NOSCRIPT, 0, 0, 0),
         "eval" => (-3, NOSCRIPT, 0, 0, 0),
         "evalsha" => (-3, NOSCRIPT, 0, 0, 0),
@@ -2665,7 +4740,7 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "bitcount" => (-2, READONLY, 1, 1, 1),
         "bitpos" => (-3, READONLY, 1, 1, 1),
         "wait" => (3, READONLY | NOSCRIPT, 0, 0, 0),
-        "command" => (0, READONLY | LOADING | STALE, 0, 0, 0),
+        "command" => (-1, READONLY | LOADING | STALE, 0, 0, 0),
         "geoadd" => (-5, wm, 1, 1, 1),
         "georadius" => (-6, READONLY, 1, 1, 1),
         "georadiusbymember" => (-5, READONLY, 1, 1, 1),
@@ -2701,6 +4776,9 @@ fn command_has_flags_test() {
     assert!(!command_properties("append")
         .flags
         .contains(CommandFlags::READONLY));
+    for name in &["ltrim", "lrem", "spop"] {
+        assert!(command_properties(name).flags.contains(CommandFlags::WRITE));
+    }
 }
 
 fn execute_command(
@@ -2719,31 +4797,39 @@ fn execute_command(
         Some(c) => c,
         None => return Ok(Response::Error("unknown command".to_owned())),
     };
+    if db.is_sentinel() && !sentinel::allowed(command_name) {
+        return Ok(Response::Error(format!(
+            "ERR unknown command \"{}\"",
+            command_name
+        )));
+    }
 
-    *write = !command_properties(command_name)
-        .flags
-        .contains(CommandFlags::READONLY);
+    let flags = command_properties(command_name).flags;
+    let readonly = flags.contains(CommandFlags::READONLY);
+    *write = !readonly;
+    let blocking = flags.contains(CommandFlags::BLOCKING);
+    if blocking {
+        // it propagates what it did when it is served
+        *write = false;
+    }
+    if flags.contains(CommandFlags::SKIP_MONITOR) {
+        *log = false;
+    }
 
-    if db.config.requirepass.is_none() {
-        client.auth = true;
+    if client.auth && db.acl().get_user(&client.user).is_none() {
+        // the user was deleted
+        client.auth = false;
+        client.user = DEFAULT_USER.to_owned();
+    }
+    if !client.auth {
+        client.auth = db
+            .acl()
+            .get_user(DEFAULT_USER)
+            .is_some_and(|u| u.enabled && u.nopass);
     }
     // commands that are not executed before AUTH
     if command_name == "auth" {
-        opt_validate!(parser.argv.len() == 2, "Wrong number of parameters");
-        let password = try_opt_validate!(parser.get_str(1), "Invalid password");
-        if Some(password.to_owned()) == db.config.requirepass {
-            client.auth = true;
-            return Ok(Response::Status("OK".to_owned()));
-        } else {
-            return Ok(Response::Error(
-                if db.config.requirepass.is_none() {
-                    "ERR Client sent AUTH, but no password is set"
-                } else {
-                    "ERR invalid password"
-                }
-                .to_owned(),
-            ));
-        }
+        return Ok(acl::auth(parser, db, client));
     }
 
     if !client.auth {
@@ -2752,6 +4838,18 @@ fn execute_command(
         ));
     }
 
+    if let Some(err) = acl::permission_error(parser, db, client, command_name) {
+        return Ok(err);
+    }
+
+    let redirect = cluster::redirect(parser, db, client, command_name);
+    if command_name != "asking" {
+        client.asking = false;
+    }
+    if let Some(err) = redirect {
+        return Ok(err);
+    }
+
     // commands that are not executed inside MULTI
     match command_name {
         "multi" => return Ok(multi(client)),
@@ -2765,27 +4863,38 @@ fn execute_command(
                 "ERR WATCH not allowed inside MULTI".to_owned(),
             ));
         }
+        // queued commands are logged when EXEC runs them
+        *log = false;
         client.multi_commands.push(parser.to_owned());
         return Ok(Response::Status("QUEUED".to_owned()));
     }
     if command_name == "select" {
         opt_validate!(parser.argv.len() == 2, "Wrong number of parameters");
         let dbindex = try_opt_validate!(parser.get_i64(1), "Invalid dbindex") as usize;
-        if dbindex > db.config.databases as usize {
+        if dbindex >= db.config.databases as usize {
             return Ok(Response::Error("ERR invalid DB index".to_owned()));
         }
+        if dbindex != 0 && db.is_cluster() {
+            return Ok(Response::Error(
+                "ERR SELECT is not allowed in cluster mode".to_owned(),
+            ));
+        }
         client.dbindex = dbindex;
         return Ok(Response::Status("OK".to_owned()));
     }
     let dbindex = client.dbindex;
-    Ok(match command_name {
-        "pexpireat" => pexpireat(parser, db, dbindex),
-        "pexpire" => pexpire(parser, db, dbindex),
-        "expireat" => expireat(parser, db, dbindex),
-        "expire" => expire(parser, db, dbindex),
+    let block = !client.in_exec;
+    let caching = client.tracking_caching.take();
+    let response = match command_name {
+        "pexpireat" => pexpireat(parser, db, dbindex, write),
+        "pexpire" => pexpire(parser, db, dbindex, write),
+        "expireat" => expireat(parser, db, dbindex, write),
+        "expire" => expire(parser, db, dbindex, write),
         "echo" => echo(parser),
         "ttl" => ttl(parser, db, dbindex),
         "pttl" => pttl(parser, db, dbindex),
+        "expiretime" => expiretime(parser, db, dbindex),
+        "pexpiretime" => pexpiretime(parser, db, dbindex),
         "persist" => persist(parser, db, dbindex),
         "type" => dbtype(parser, db, dbindex),
         "set" => set(parser, db, dbindex),
@@ -2794,10 +4903,14 @@ fn execute_command(
         "psetex" => psetex(parser, db, dbindex),
         "debug" => debug(parser, db, dbindex),
         "del" => del(parser, db, dbindex),
+        "unlink" => unlink(parser, db, dbindex),
         "dbsize" => dbsize(parser, db, dbindex),
         "append" => append(parser, db, dbindex),
         "get" => get(parser, db, dbindex),
+        "getex" => getex(parser, db, dbindex, write),
+        "getdel" => getdel(parser, db, dbindex),
         "getrange" => getrange(parser, db, dbindex),
+        "lcs" => lcs(parser, db, dbindex),
         "mget" => mget(parser, db, dbindex),
         "substr" => getrange(parser, db, dbindex),
         "setrange" => setrange(parser, db, dbindex),
@@ -2830,21 +4943,28 @@ fn execute_command(
         "lset" => lset(parser, db, dbindex),
         "ltrim" => ltrim(parser, db, dbindex),
         "rpoplpush" => rpoplpush(parser, db, dbindex),
-        "brpoplpush" => brpoplpush(parser, db, dbindex)?,
-        "brpop" => brpop(parser, db, dbindex)?,
-        "blpop" => blpop(parser, db, dbindex)?,
+        "brpoplpush" => brpoplpush(parser, db, dbindex, block)?,
+        "brpop" => brpop(parser, db, dbindex, block)?,
+        "blpop" => blpop(parser, db, dbindex, block)?,
+        "lmove" => lmove(parser, db, dbindex),
+        "blmove" => blmove(parser, db, dbindex, block)?,
+        "lmpop" => lmpop(parser, db, dbindex),
+        "blmpop" => blmpop(parser, db, dbindex, block)?,
+        "lpos" => lpos(parser, db, dbindex),
         "sadd" => sadd(parser, db, dbindex),
         "srem" => srem(parser, db, dbindex),
         "sismember" => sismember(parser, db, dbindex),
+        "smismember" => smismember(parser, db, dbindex),
         "smembers" => smembers(parser, db, dbindex),
         "srandmember" => srandmember(parser, db, dbindex),
-        "spop" => spop(parser, db, dbindex),
+        "spop" => spop(parser, db, dbindex, write),
         "smove" => smove(parser, db, dbindex),
         "scard" => scard(parser, db, dbindex),
         "sdiff" => sdiff(parser, db, dbindex),
         "sdiffstore" => sdiffstore(parser, db, dbindex),
         "sinter" => sinter(parser, db, dbindex),
         "sinterstore" => sinterstore(parser, db, dbindex),
+        "sintercard" => sintercard(parser, db, dbindex),
         "sunion" => sunion(parser, db, dbindex),
         "sunionstore" => sunionstore(parser, db, dbindex),
         "zadd" => zadd(parser, db, dbindex),
@@ -2863,11 +4983,29 @@ fn execute_command(
         "zrevrangebyscore" => zrevrangebyscore(parser, db, dbindex),
         "zrangebylex" => zrangebylex(parser, db, dbindex),
         "zrevrangebylex" => zrevrangebylex(parser, db, dbindex),
+        "zrangestore" => zrangestore(parser, db, dbindex),
         "zrank" => zrank(parser, db, dbindex),
         "zrevrank" => zrevrank(parser, db, dbindex),
         "zunionstore" => zunionstore(parser, db, dbindex),
         "zinterstore" => zinterstore(parser, db, dbindex),
+        "zunion" => zunion(parser, db, dbindex),
+        "zinter" => zinter(parser, db, dbindex),
+        "zdiff" => zdiff(parser, db, dbindex),
+        "zdiffstore" => zdiffstore(parser, db, dbindex),
+        "zintercard" => zintercard(parser, db, dbindex),
+        "zmscore" => zmscore(parser, db, dbindex),
+        "zrandmember" => zrandmember(parser, db, dbindex),
+        "zpopmin" => zpopmin(parser, db, dbindex),
+        "zpopmax" => zpopmax(parser, db, dbindex),
+        "bzpopmin" => bzpopmin(parser, db, dbindex, block)?,
+        "bzpopmax" => bzpopmax(parser, db, dbindex, block)?,
+        "zmpop" => zmpop(parser, db, dbindex),
+        "bzmpop" => bzmpop(parser, db, dbindex, block)?,
         "dump" => dump(parser, db, dbindex),
+        "object" => object(parser, db, dbindex),
+        "memory" => memory(parser, db, dbindex),
+        "restore" | "restore-asking" => restore(parser, db, dbindex),
+        "migrate" => migrate::migrate(parser, db, dbindex, write),
         "keys" => keys(parser, db, dbindex),
         "watch" => watch(parser, db, dbindex, client.id, &mut client.watched_keys),
         "unwatch" => unwatch(parser, db, client.id, &mut client.watched_keys),
@@ -2899,27 +5037,165 @@ fn execute_command(
             &mut client.pattern_subscriptions,
             &client.rawsender,
         )?,
+        "ssubscribe" => ssubscribe(
+            parser,
+            db,
+            &mut client.shard_subscriptions,
+            &client.rawsender,
+        )?,
+        "sunsubscribe" => sunsubscribe(
+            parser,
+            db,
+            &mut client.shard_subscriptions,
+            &client.rawsender,
+        )?,
         "publish" => publish(parser, db),
+        "spublish" => spublish(parser, db),
+        "pubsub" => pubsub(parser, db),
         "monitor" => {
             *log = false;
             monitor(parser, db, client.rawsender.clone())
         }
         "info" => info(parser, db),
+        "shutdown" => shutdown(parser, db)?,
+        "acl" => acl::acl(parser, db, client),
+        "client" => client_command(parser, db, client),
+        "cluster" => cluster::cluster(parser, db),
+        "asking" => cluster::asking(db, client),
+        "readonly" | "readwrite" => cluster::readonly(db),
+        "sentinel" => sentinel::sentinel(parser, db),
+        "command" => introspection::command(parser, db),
         cmd => Response::Error(format!("ERR unknown command \"{}\"", cmd)),
-    })
+    };
+    // commands may clear `write` to propagate something else, but they
+    // still wrote
+    if readonly && !response.is_error() {
+        track_keys(parser, db, client.id, command_name, caching);
+    }
+    Ok(response)
 }
 
-pub fn command(
-    mut parser: ParsedCommand,
-    db: &mut Database,
-    client: &mut Client,
-) -> Result<Response, ResponseError> {
-    let mut log = true;
-    let mut write = false;
+/// Positions of the arguments that may hold secrets and are not shown to
+/// MONITOR.
+fn redacted_arguments(parser: &ParsedCommand, db: &Database) -> Vec<usize> {
+    let argc = parser.argv.len();
+    let argument = |i| match parser.get_str(i) {
+        Ok(s) => s.to_ascii_lowercase(),
+        Err(_) => String::new(),
+    };
+    match db.mapped_command(&argument(0)).as_deref() {
+        Some("auth") => (1..argc).collect(),
+        Some("acl") if argument(1) == "setuser" => (3..argc).collect(),
+        Some("config") if argument(1) == "set" => (2..argc)
+            .step_by(2)
+            .filter(|&i| i + 1 < argc && ["requirepass", "masterauth"].contains(&&*argument(i)))
+            .map(|i| i + 1)
+            .collect(),
+        Some("migrate") => {
+            let mut redacted = vec![];
+            let mut i = 6;
+            while i < argc && argument(i) != "keys" {
+                match &*argument(i) {
+                    "auth" => {
+                        redacted.push(i + 1);
+                        i += 1;
+                    }
+                    "auth2" => {
+                        redacted.push(i + 2);
+                        i += 2;
+                    }
+                    _ => (),
+                }
+                i += 1;
+            }
+            redacted.into_iter().filter(|&i| i < argc).collect()
+        }
+        _ => vec![],
+    }
+}
+
+/// Indexes of the databases a command uses. They have to be locked with
+/// `Database::lock` before running it.
+pub fn databases(parser: &ParsedCommand, db: &Database, client: &Client) -> Vec<usize> {
+    let command_name = match parser.get_str(0) {
+        Ok(name) => db.mapped_command(&name.to_ascii_lowercase()),
+        Err(_) => None,
+    };
+    match command_name.as_deref() {
+        // a transaction can select any database
+        Some("exec") | Some("flushall") | Some("info") | Some("memory") | Some("shutdown") => {
+            (0..db.config.databases as usize).collect()
+        }
+        // the watched keys may be in other databases
+        Some("discard") | Some("unwatch") => client
+            .watched_keys
+            .iter()
+            .map(|&(dbindex, _)| dbindex)
+            .chain(Some(client.dbindex))
+            .collect(),
+        // commands that take other databases as arguments
+        Some("move") => db_arguments(parser, client, &[2]),
+        Some("swapdb") => db_arguments(parser, client, &[1, 2]),
+        Some("copy") => {
+            let positions = (3..parser.argv.len())
+                .filter(|&i| {
+                    parser
+                        .get_str(i)
+                        .is_ok_and(|s| s.eq_ignore_ascii_case("db"))
+                })
+                .map(|i| i + 1)
+                .collect::<Vec<_>>();
+            db_arguments(parser, client, &positions)
+        }
+        _ => vec![client.dbindex],
+    }
+}
+
+/// The selected database and the ones given at `positions` in the command.
+/// Arguments that are not database indexes are left for the command to
+/// reject.
+fn db_arguments(parser: &ParsedCommand, client: &Client, positions: &[usize]) -> Vec<usize> {
+    positions
+        .iter()
+        .filter_map(|&pos| parser.get_i64(pos).ok())
+        .filter(|&index| index >= 0)
+        .map(|index| index as usize)
+        .chain(Some(client.dbindex))
+        .collect()
+}
+
+/// Whether the command can share its databases with other readers, see
+/// `Database::lock_read`. WATCH, UNWATCH and DISCARD do not modify any key,
+/// but they keep the watched keys in the database.
+pub fn read_only(parser: &ParsedCommand, db: &Database) -> bool {
+    let command_name = match parser.get_str(0) {
+        Ok(name) => db.mapped_command(&name.to_ascii_lowercase()),
+        Err(_) => None,
+    };
+    match command_name.as_deref() {
+        Some("watch") | Some("unwatch") | Some("discard") => false,
+        Some(name) => command_properties(name)
+            .flags
+            .contains(CommandFlags::READONLY),
+        None => false,
+    }
+}
+
+pub fn command(
+    mut parser: ParsedCommand,
+    db: &mut Database,
+    client: &mut Client,
+) -> Result<Response, ResponseError> {
+    let mut log = true;
+    let mut write = false;
+    let dbindex = client.dbindex;
+    db.current_client = Some(client.id);
     let r = execute_command(&mut parser, db, client, &mut log, &mut write);
+    db.current_client = None;
     // TODO: only log if there's anyone listening
     if log {
-        db.log_command(client.dbindex, &parser, write);
+        let redacted = redacted_arguments(&parser, db);
+        db.log_command(dbindex, &client.addr, &parser, &redacted, write);
     }
     r
 }
@@ -2927,19 +5203,22 @@ pub fn command(
 #[cfg(test)]
 mod test_command {
     use std::collections::HashSet;
+    use std::env::temp_dir;
+    use std::fs::remove_file;
     use std::str::from_utf8;
     use std::sync::mpsc::channel;
-    use std::sync::{Arc, Mutex};
     use std::thread;
 
-    use config::Config;
+    use config::{keyspace_events_from_str, Config};
+    use database::cluster::{Cluster, CLUSTER_SLOTS};
+    use database::sentinel::Sentinel;
     use database::{Database, Value};
     use logger::{Level, Logger};
     use parser::{Argument, ParsedCommand};
     use response::{Response, ResponseError};
     use util::mstime;
 
-    use super::{command, Client};
+    use super::{command, databases, read_only, Client, COMMAND_NAMES};
     use std::time::Duration;
 
     macro_rules! parser {
@@ -2959,7 +5238,7 @@ mod test_command {
 
     fn getstr(database: &Database, key: &[u8]) -> String {
         match database.get(0, &key.to_vec()).unwrap() {
-            &Value::String(value) => from_utf8(&*value.to_vec()).unwrap().to_owned(),
+            Value::String(value) => from_utf8(&value.to_vec()).unwrap().to_owned(),
             _ => panic!("Got non-string"),
         }
     }
@@ -3088,6 +5367,289 @@ mod test_command {
         assert!(exp <= now + 1234);
     }
 
+    #[test]
+    fn set_ttl_options() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(
+            parser!(b"set key value ex 100"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        command(
+            parser!(b"set key value2 keepttl"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        assert!(db.get_msexpiration(0, b"key").is_some());
+        command(parser!(b"set key value3"), &mut db, &mut Client::mock()).unwrap();
+        assert!(db.get_msexpiration(0, b"key").is_none());
+
+        let exat = format!("set key value exat {}", mstime() / 1000 + 100);
+        command(parser!(exat.as_bytes()), &mut db, &mut Client::mock()).unwrap();
+        let exp = *db.get_msexpiration(0, b"key").unwrap();
+        assert!(exp > mstime() + 98 * 1000 && exp <= mstime() + 100 * 1000);
+        let pxat = mstime() + 5000;
+        let cmd = format!("set key value pxat {}", pxat);
+        command(parser!(cmd.as_bytes()), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(db.get_msexpiration(0, b"key"), Some(&pxat));
+
+        for cmd in &[
+            &b"set key value ex 10 px 10"[..],
+            b"set key value keepttl ex 10",
+            b"set key value nx xx",
+            b"set key value ex",
+            b"set key value foo",
+        ] {
+            assert_eq!(
+                command(parser!(cmd), &mut db, &mut Client::mock()).unwrap(),
+                Response::Error("ERR syntax error".to_owned())
+            );
+        }
+        assert_eq!(
+            command(parser!(b"set key value ex 0"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR invalid expire time in 'set' command".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"set key value px a"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR value is not an integer or out of range".to_owned())
+        );
+    }
+
+    #[test]
+    fn set_get_option() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"set key value get"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Nil
+        );
+        assert_eq!(
+            command(parser!(b"set key value2 get"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"value".to_vec())
+        );
+        assert_eq!(
+            command(
+                parser!(b"set key value3 nx get"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Data(b"value2".to_vec())
+        );
+        assert_eq!("value2", getstr(&db, b"key"));
+        command(parser!(b"rpush list a"), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(
+            command(parser!(b"set list value get"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_owned()
+            )
+        );
+        assert_eq!(db.get(0, b"list").unwrap().llen().unwrap(), 1);
+    }
+
+    #[test]
+    fn getex_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"getex key ex 10"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Nil
+        );
+        command(parser!(b"set key value"), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(
+            command(parser!(b"getex key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"value".to_vec())
+        );
+        assert!(db.get_msexpiration(0, b"key").is_none());
+        assert_eq!(
+            command(parser!(b"getex key px 10000"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"value".to_vec())
+        );
+        let exp = *db.get_msexpiration(0, b"key").unwrap();
+        assert!(exp > mstime() + 9000 && exp <= mstime() + 10000);
+        command(parser!(b"getex key persist"), &mut db, &mut Client::mock()).unwrap();
+        assert!(db.get_msexpiration(0, b"key").is_none());
+        assert_eq!(
+            command(parser!(b"getex key pxat 1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"value".to_vec())
+        );
+        assert!(db.get(0, b"key").is_none());
+        assert_eq!(
+            command(
+                parser!(b"getex key ex 1 px 1"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR syntax error".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"getex key ex -1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR invalid expire time in 'getex' command".to_owned())
+        );
+    }
+
+    #[test]
+    fn getex_propagation() {
+        let mut path = temp_dir();
+        path.push("getex_propagation.aof");
+        let _ = remove_file(&path);
+        let mut config = Config::new(Logger::new(Level::Warning));
+        config.appendonly = true;
+        config.appendfilename = path.to_str().unwrap().to_owned();
+        let mut db = Database::new(config);
+        let mut client = Client::mock();
+        command(parser!(b"set key value"), &mut db, &mut client).unwrap();
+        command(parser!(b"getex key"), &mut db, &mut client).unwrap();
+        command(
+            parser!(b"getex key pxat 9999999999999"),
+            &mut db,
+            &mut client,
+        )
+        .unwrap();
+        command(parser!(b"getex key persist"), &mut db, &mut client).unwrap();
+        command(parser!(b"getex key persist"), &mut db, &mut client).unwrap();
+        command(parser!(b"getex key ex 0"), &mut db, &mut client).unwrap();
+        command(parser!(b"getex key pxat 1"), &mut db, &mut client).unwrap();
+
+        let aof = std::fs::read(&path).unwrap();
+        let _ = remove_file(&path);
+        let mut expected = b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n".to_vec();
+        for args in &[
+            &["set", "key", "value"][..],
+            &["PEXPIREAT", "key", "9999999999999"],
+            &["PERSIST", "key"],
+            &["DEL", "key"],
+        ] {
+            expected.extend(format!("*{}\r\n", args.len()).into_bytes());
+            for arg in args.iter() {
+                expected.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+            }
+        }
+        assert_eq!(from_utf8(&aof).unwrap(), from_utf8(&expected).unwrap());
+    }
+
+    #[test]
+    fn spop_propagation() {
+        let mut path = temp_dir();
+        path.push("spop_propagation.aof");
+        let _ = remove_file(&path);
+        let mut config = Config::new(Logger::new(Level::Warning));
+        config.appendonly = true;
+        config.appendfilename = path.to_str().unwrap().to_owned();
+        let mut db = Database::new(config);
+        let mut client = Client::mock();
+        command(parser!(b"sadd key a"), &mut db, &mut client).unwrap();
+        command(parser!(b"spop key"), &mut db, &mut client).unwrap();
+        command(parser!(b"spop key"), &mut db, &mut client).unwrap();
+        command(parser!(b"sadd key b"), &mut db, &mut client).unwrap();
+        command(parser!(b"spop key 3"), &mut db, &mut client).unwrap();
+
+        let aof = std::fs::read(&path).unwrap();
+        let _ = remove_file(&path);
+        let mut expected = b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n".to_vec();
+        for args in &[
+            &["sadd", "key", "a"][..],
+            &["SREM", "key", "a"],
+            &["sadd", "key", "b"],
+            &["SREM", "key", "b"],
+        ] {
+            expected.extend(format!("*{}\r\n", args.len()).into_bytes());
+            for arg in args.iter() {
+                expected.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+            }
+        }
+        assert_eq!(from_utf8(&aof).unwrap(), from_utf8(&expected).unwrap());
+    }
+
+    #[test]
+    fn getdel_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"set key value"), &mut db, &mut Client::mock()).unwrap();
+        command(parser!(b"rpush list a"), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(
+            command(parser!(b"getdel key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"value".to_vec())
+        );
+        assert!(db.get(0, b"key").is_none());
+        assert_eq!(
+            command(parser!(b"getdel key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Nil
+        );
+        assert!(
+            command(parser!(b"getdel list"), &mut db, &mut Client::mock())
+                .unwrap()
+                .is_error()
+        );
+        assert!(db.get(0, b"list").is_some());
+    }
+
+    #[test]
+    fn lcs_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"set key1 ohmytext"), &mut db, &mut Client::mock()).unwrap();
+        command(parser!(b"set key2 mynewtext"), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(
+            command(parser!(b"lcs key1 key2"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"mytext".to_vec())
+        );
+        assert_eq!(
+            command(parser!(b"lcs key1 key2 len"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(6)
+        );
+        let range = |a, b| Response::Array(vec![Response::Integer(a), Response::Integer(b)]);
+        assert_eq!(
+            command(parser!(b"lcs key1 key2 idx"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"matches".to_vec()),
+                Response::Array(vec![
+                    Response::Array(vec![range(4, 7), range(5, 8)]),
+                    Response::Array(vec![range(2, 3), range(0, 1)]),
+                ]),
+                Response::Data(b"len".to_vec()),
+                Response::Integer(6),
+            ])
+        );
+        assert_eq!(
+            command(
+                parser!(b"lcs key1 key2 idx minmatchlen 4 withmatchlen"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Array(vec![
+                Response::Data(b"matches".to_vec()),
+                Response::Array(vec![Response::Array(vec![
+                    range(4, 7),
+                    range(5, 8),
+                    Response::Integer(4),
+                ])]),
+                Response::Data(b"len".to_vec()),
+                Response::Integer(6),
+            ])
+        );
+        assert_eq!(
+            command(parser!(b"lcs key1 nokey"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(vec![])
+        );
+        assert_eq!(
+            command(
+                parser!(b"lcs key1 key2 len idx"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error(
+                "ERR If you want both the length and indexes, please just use IDX.".to_owned()
+            )
+        );
+        command(parser!(b"rpush list a"), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(
+            command(parser!(b"lcs key1 list"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR The specified keys must contain string values".to_owned())
+        );
+    }
+
     #[test]
     fn get_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
@@ -3222,6 +5784,28 @@ mod test_command {
         );
     }
 
+    #[test]
+    fn unlink_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        for i in 0..100 {
+            db.get_or_create(0, b"list")
+                .push(format!("{}", i).into_bytes(), true)
+                .unwrap();
+        }
+        db.get_or_create(0, b"key").set(b"value".to_vec()).unwrap();
+        assert_eq!(
+            command(
+                parser!(b"unlink list key key2"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(2)
+        );
+        assert!(db.get(0, b"list").is_none());
+        assert!(db.get(0, b"key").is_none());
+    }
+
     #[test]
     fn debug_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
@@ -3367,80 +5951,290 @@ mod test_command {
     }
 
     #[test]
-    fn ttl_command() {
+    fn expire_options() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"set key value"), &mut db, &mut Client::mock()).unwrap();
+        let mut expire = |cmd: &[u8]| command(parser!(cmd), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(expire(b"expire key 100 xx"), Response::Integer(0));
+        assert_eq!(expire(b"expire key 100 gt"), Response::Integer(0));
+        assert_eq!(expire(b"expire key 100 nx"), Response::Integer(1));
+        assert_eq!(expire(b"expire key 200 nx"), Response::Integer(0));
+        assert_eq!(expire(b"expire key 50 gt"), Response::Integer(0));
+        assert_eq!(expire(b"expire key 200 gt"), Response::Integer(1));
+        assert_eq!(expire(b"expire key 300 lt"), Response::Integer(0));
+        assert_eq!(expire(b"expire key 150 xx lt"), Response::Integer(1));
+        assert_eq!(expire(b"persist key"), Response::Integer(1));
+        assert_eq!(expire(b"pexpire key 100000 LT"), Response::Integer(1));
+
+        assert_eq!(
+            expire(b"expire key 100 nx xx"),
+            Response::Error(
+                "ERR NX and XX, GT or LT options at the same time are not compatible".to_owned()
+            )
+        );
         assert_eq!(
-            command(parser!(b"ttl key"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(-2)
+            expire(b"expire key 100 gt lt"),
+            Response::Error("ERR GT and LT options at the same time are not compatible".to_owned())
         );
-        assert!(db
-            .get_or_create(0, &b"key".to_vec())
-            .set(b"value".to_vec())
-            .is_ok());
         assert_eq!(
-            command(parser!(b"ttl key"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(-1)
+            expire(b"expire key 100 foo"),
+            Response::Error("ERR Unsupported option foo".to_owned())
         );
-        db.set_msexpiration(0, b"key".to_vec(), mstime() + 100 * 1000);
-        match command(parser!(b"ttl key"), &mut db, &mut Client::mock()).unwrap() {
-            Response::Integer(i) => assert!(i <= 100 && i > 80),
-            _ => panic!("Expected integer"),
-        }
+        assert_eq!(
+            expire(b"expire key 9223372036854775807"),
+            Response::Error("ERR invalid expire time in 'expire' command".to_owned())
+        );
+        assert_eq!(expire(b"expire key 0"), Response::Integer(1));
+        assert_eq!(expire(b"exists key"), Response::Integer(0));
     }
 
     #[test]
-    fn pttl_command() {
+    fn expiretime_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
-            command(parser!(b"pttl key"), &mut db, &mut Client::mock()).unwrap(),
+            command(parser!(b"expiretime key"), &mut db, &mut Client::mock()).unwrap(),
             Response::Integer(-2)
         );
-        assert!(db
-            .get_or_create(0, &b"key".to_vec())
-            .set(b"value".to_vec())
-            .is_ok());
+        command(parser!(b"set key value"), &mut db, &mut Client::mock()).unwrap();
         assert_eq!(
-            command(parser!(b"pttl key"), &mut db, &mut Client::mock()).unwrap(),
+            command(parser!(b"pexpiretime key"), &mut db, &mut Client::mock()).unwrap(),
             Response::Integer(-1)
         );
-        db.set_msexpiration(0, b"key".to_vec(), mstime() + 100 * 1000);
-        match command(parser!(b"pttl key"), &mut db, &mut Client::mock()).unwrap() {
-            Response::Integer(i) => assert!(i <= 100 * 1000 && i > 80 * 1000),
-            _ => panic!("Expected integer"),
-        }
+        command(
+            parser!(b"pexpireat key 33177600000123"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        assert_eq!(
+            command(parser!(b"expiretime key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(33177600000)
+        );
+        assert_eq!(
+            command(parser!(b"pexpiretime key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(33177600000123)
+        );
     }
 
     #[test]
-    fn persist_command() {
+    fn object_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut run = |c: &[u8]| command(parser!(c), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(run(b"object encoding key"), Response::Nil);
+        for c in &[
+            &b"set int 123"[..],
+            b"set raw abc",
+            b"rpush list a",
+            b"sadd intset 1",
+            b"sadd set a",
+            b"zadd zset 1 a",
+        ] {
+            run(c);
+        }
+        for &(key, encoding) in &[
+            (&b"int"[..], &b"int"[..]),
+            (b"raw", b"raw"),
+            (b"list", b"linkedlist"),
+            (b"intset", b"intset"),
+            (b"set", b"hashtable"),
+            (b"zset", b"skiplist"),
+        ] {
+            assert_eq!(
+                run(&[&b"object encoding "[..], key].concat()),
+                Response::Data(encoding.to_vec())
+            );
+        }
+        assert_eq!(run(b"object refcount int"), Response::Integer(1));
+        assert_eq!(run(b"object idletime int"), Response::Integer(0));
+        assert_eq!(run(b"object freq int"), Response::Integer(5));
+        assert_eq!(run(b"object idletime missing"), Response::Nil);
         assert_eq!(
-            command(parser!(b"persist key"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(0)
+            run(b"object foo int"),
+            Response::Error(
+                "ERR Unknown subcommand or wrong number of arguments for 'foo'".to_owned()
+            )
         );
-        assert!(db
-            .get_or_create(0, &b"key".to_vec())
-            .set(b"value".to_vec())
-            .is_ok());
+        assert!(run(b"object encoding").is_error());
+    }
+
+    #[test]
+    fn command_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
-            command(parser!(b"persist key"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(0)
+            command(
+                parser!(b"command getkeys zinterstore dst 2 a b"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Array(vec![
+                Response::Data(b"dst".to_vec()),
+                Response::Data(b"a".to_vec()),
+                Response::Data(b"b".to_vec()),
+            ])
         );
-        db.set_msexpiration(0, b"key".to_vec(), mstime() + 100 * 1000);
         assert_eq!(
-            command(parser!(b"persist key"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(1)
+            command(parser!(b"command count"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(COMMAND_NAMES.len() as i64)
         );
     }
 
     #[test]
-    fn type_command() {
+    fn memory_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
-        let set_max_intset_entries = db.config.set_max_intset_entries;
+        let mut run = |c: &[u8]| command(parser!(c), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(run(b"memory usage key"), Response::Nil);
+        run(b"set small a");
+        run(&[&b"set big "[..], &[b'a'; 1000][..]].concat());
+        let usage = |r| match r {
+            Response::Integer(i) => i,
+            r => panic!("Unexpected response {:?}", r),
+        };
+        let small = usage(run(b"memory usage small"));
+        assert!(usage(run(b"memory usage big")) >= small + 990);
+        assert_eq!(usage(run(b"memory usage small samples 0")), small);
         assert_eq!(
-            command(parser!(b"type key"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Data(b"none".to_vec())
+            run(b"memory usage small samples"),
+            Response::Error("ERR syntax error".to_owned())
         );
-
+        assert_eq!(
+            run(b"memory usage small samples -1"),
+            Response::Error("ERR syntax error".to_owned())
+        );
+        assert_eq!(run(b"memory purge"), Response::Status("OK".to_owned()));
+
+        match run(b"memory stats") {
+            Response::Array(stats) => {
+                assert_eq!(stats[0], Response::Data(b"db.0".to_vec()));
+                let pos = stats
+                    .iter()
+                    .position(|r| *r == Response::Data(b"keys.count".to_vec()))
+                    .unwrap();
+                assert_eq!(stats[pos + 1], Response::Integer(2));
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+        match run(b"memory doctor") {
+            Response::Data(report) => assert!(!report.is_empty()),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        run(b"flushall");
+        assert_eq!(
+            run(b"memory doctor"),
+            Response::Data(
+                b"This instance is empty, there is no memory usage to analyze.".to_vec()
+            )
+        );
+    }
+
+    #[test]
+    fn expire_propagation() {
+        let mut path = temp_dir();
+        path.push("expire_propagation.aof");
+        let _ = remove_file(&path);
+        let mut config = Config::new(Logger::new(Level::Warning));
+        config.appendonly = true;
+        config.appendfilename = path.to_str().unwrap().to_owned();
+        let mut db = Database::new(config);
+        let mut client = Client::mock();
+        command(parser!(b"set key value"), &mut db, &mut client).unwrap();
+        command(parser!(b"expireat key 33177600000"), &mut db, &mut client).unwrap();
+        // not applied
+        command(parser!(b"expire key 100 nx"), &mut db, &mut client).unwrap();
+        command(parser!(b"expire nokey 100"), &mut db, &mut client).unwrap();
+        command(parser!(b"pexpire key -1"), &mut db, &mut client).unwrap();
+
+        let aof = std::fs::read(&path).unwrap();
+        let _ = remove_file(&path);
+        let mut expected = b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n".to_vec();
+        for args in &[
+            &["set", "key", "value"][..],
+            &["PEXPIREAT", "key", "33177600000000"],
+            &["DEL", "key"],
+        ] {
+            expected.extend(format!("*{}\r\n", args.len()).into_bytes());
+            for arg in args.iter() {
+                expected.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+            }
+        }
+        assert_eq!(from_utf8(&aof).unwrap(), from_utf8(&expected).unwrap());
+    }
+
+    #[test]
+    fn ttl_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"ttl key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(-2)
+        );
+        assert!(db
+            .get_or_create(0, &b"key".to_vec())
+            .set(b"value".to_vec())
+            .is_ok());
+        assert_eq!(
+            command(parser!(b"ttl key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(-1)
+        );
+        db.set_msexpiration(0, b"key".to_vec(), mstime() + 100 * 1000);
+        match command(parser!(b"ttl key"), &mut db, &mut Client::mock()).unwrap() {
+            Response::Integer(i) => assert!(i <= 100 && i > 80),
+            _ => panic!("Expected integer"),
+        }
+    }
+
+    #[test]
+    fn pttl_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"pttl key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(-2)
+        );
+        assert!(db
+            .get_or_create(0, &b"key".to_vec())
+            .set(b"value".to_vec())
+            .is_ok());
+        assert_eq!(
+            command(parser!(b"pttl key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(-1)
+        );
+        db.set_msexpiration(0, b"key".to_vec(), mstime() + 100 * 1000);
+        match command(parser!(b"pttl key"), &mut db, &mut Client::mock()).unwrap() {
+            Response::Integer(i) => assert!(i <= 100 * 1000 && i > 80 * 1000),
+            _ => panic!("Expected integer"),
+        }
+    }
+
+    #[test]
+    fn persist_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"persist key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
+        );
+        assert!(db
+            .get_or_create(0, &b"key".to_vec())
+            .set(b"value".to_vec())
+            .is_ok());
+        assert_eq!(
+            command(parser!(b"persist key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
+        );
+        db.set_msexpiration(0, b"key".to_vec(), mstime() + 100 * 1000);
+        assert_eq!(
+            command(parser!(b"persist key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(1)
+        );
+    }
+
+    #[test]
+    fn type_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let set_max_intset_entries = db.config.set_max_intset_entries;
+        assert_eq!(
+            command(parser!(b"type key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"none".to_vec())
+        );
+
         assert!(db
             .get_or_create(0, &b"key".to_vec())
             .set(b"value".to_vec())
@@ -3938,15 +6732,16 @@ mod test_command {
 
     #[test]
     fn brpoplpush_waiting() {
-        let db = Arc::new(Mutex::new(Database::new(Config::new(Logger::new(
-            Level::Warning,
-        )))));
+        let mut db2 = Database::new(Config::new(Logger::new(Level::Warning)));
+        db2.unlock();
+        let shared = db2.shared();
         let (tx, rx) = channel();
-        let db2 = db.clone();
         thread::spawn(move || {
+            let mut db = shared.handle();
+            db.lock(&[0]);
             let r = match command(
                 parser!(b"brpoplpush key1 key2 0"),
-                &mut db.lock().unwrap(),
+                &mut db,
                 &mut Client::mock(),
             )
             .unwrap_err()
@@ -3957,11 +6752,14 @@ mod test_command {
                 }
                 _ => panic!("Unexpected error"),
             };
+            // the lock is not held while waiting
+            db.unlock();
             r.recv().unwrap();
+            db.lock(&[0]);
             assert_eq!(
                 command(
                     parser!(b"brpoplpush key1 key2 0"),
-                    &mut db.lock().unwrap(),
+                    &mut db,
                     &mut Client::mock()
                 )
                 .unwrap(),
@@ -3971,20 +6769,13 @@ mod test_command {
         });
         assert_eq!(rx.recv().unwrap(), 1);
 
-        command(
-            parser!(b"rpush key1 value"),
-            &mut db2.lock().unwrap(),
-            &mut Client::mock(),
-        )
-        .unwrap();
+        db2.lock(&[0]);
+        command(parser!(b"rpush key1 value"), &mut db2, &mut Client::mock()).unwrap();
+        db2.unlock();
         assert_eq!(rx.recv().unwrap(), 2);
+        db2.lock(&[0]);
         assert_eq!(
-            command(
-                parser!(b"lrange key2 0 -1"),
-                &mut db2.lock().unwrap(),
-                &mut Client::mock()
-            )
-            .unwrap(),
+            command(parser!(b"lrange key2 0 -1"), &mut db2, &mut Client::mock()).unwrap(),
             Response::Array(vec![Response::Data("value".to_owned().into_bytes()),])
         );
     }
@@ -4022,18 +6813,15 @@ mod test_command {
 
     #[test]
     fn brpop_waiting() {
-        let db = Arc::new(Mutex::new(Database::new(Config::new(Logger::new(
-            Level::Warning,
-        )))));
+        let mut db2 = Database::new(Config::new(Logger::new(Level::Warning)));
+        db2.unlock();
+        let shared = db2.shared();
         let (tx, rx) = channel();
-        let db2 = db.clone();
         thread::spawn(move || {
-            let r = match command(
-                parser!(b"brpop key1 key2 0"),
-                &mut db.lock().unwrap(),
-                &mut Client::mock(),
-            )
-            .unwrap_err()
+            let mut db = shared.handle();
+            db.lock(&[0]);
+            let r = match command(parser!(b"brpop key1 key2 0"), &mut db, &mut Client::mock())
+                .unwrap_err()
             {
                 ResponseError::Wait(receiver) => {
                     tx.send(1).unwrap();
@@ -4041,14 +6829,12 @@ mod test_command {
                 }
                 _ => panic!("Unexpected error"),
             };
+            // the lock is not held while waiting
+            db.unlock();
             r.recv().unwrap();
+            db.lock(&[0]);
             assert_eq!(
-                command(
-                    parser!(b"brpop key1 key2 0"),
-                    &mut db.lock().unwrap(),
-                    &mut Client::mock()
-                )
-                .unwrap(),
+                command(parser!(b"brpop key1 key2 0"), &mut db, &mut Client::mock()).unwrap(),
                 Response::Array(vec![
                     Response::Data("key2".to_owned().into_bytes()),
                     Response::Data("value".to_owned().into_bytes()),
@@ -4058,27 +6844,16 @@ mod test_command {
         });
         assert_eq!(rx.recv().unwrap(), 1);
 
-        {
-            command(
-                parser!(b"rpush key2 value"),
-                &mut db2.lock().unwrap(),
-                &mut Client::mock(),
-            )
-            .unwrap();
-            assert_eq!(rx.recv().unwrap(), 2);
-        }
+        db2.lock(&[0]);
+        command(parser!(b"rpush key2 value"), &mut db2, &mut Client::mock()).unwrap();
+        db2.unlock();
+        assert_eq!(rx.recv().unwrap(), 2);
 
-        {
-            assert_eq!(
-                command(
-                    parser!(b"llen key2"),
-                    &mut db2.lock().unwrap(),
-                    &mut Client::mock()
-                )
-                .unwrap(),
-                Response::Integer(0)
-            );
-        }
+        db2.lock(&[0]);
+        assert_eq!(
+            command(parser!(b"llen key2"), &mut db2, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
+        );
     }
 
     #[test]
@@ -4096,119 +6871,418 @@ mod test_command {
     }
 
     #[test]
-    fn ltrim_command() {
+    fn counted_pop() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
-        command(parser!(b"rpush key value"), &mut db, &mut Client::mock()).unwrap();
-        command(parser!(b"rpush key value"), &mut db, &mut Client::mock()).unwrap();
-        command(parser!(b"rpush key valuf"), &mut db, &mut Client::mock()).unwrap();
-        command(parser!(b"rpush key valuf"), &mut db, &mut Client::mock()).unwrap();
-        assert_eq!(
-            command(parser!(b"ltrim key 1 -2"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Status("OK".to_owned())
-        );
+        command(parser!(b"rpush key a b c d"), &mut db, &mut Client::mock()).unwrap();
         assert_eq!(
-            command(parser!(b"lrange key 0 -1"), &mut db, &mut Client::mock()).unwrap(),
+            command(parser!(b"lpop key 2"), &mut db, &mut Client::mock()).unwrap(),
             Response::Array(vec![
-                Response::Data("value".to_owned().into_bytes()),
-                Response::Data("valuf".to_owned().into_bytes()),
+                Response::Data(b"a".to_vec()),
+                Response::Data(b"b".to_vec()),
             ])
         );
-    }
-
-    #[test]
-    fn sadd_command() {
-        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
-            command(parser!(b"sadd key 1 1 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(3)
+            command(parser!(b"rpop key 0"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![])
         );
         assert_eq!(
-            command(parser!(b"sadd key 1 1 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(0)
+            command(parser!(b"rpop key 5"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"d".to_vec()),
+                Response::Data(b"c".to_vec()),
+            ])
         );
-    }
-
-    #[test]
-    fn srem_command() {
-        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
-            command(parser!(b"sadd key 1 1 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(3)
+            command(parser!(b"exists key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
         );
         assert_eq!(
-            command(parser!(b"srem key 2 3 4"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(2)
+            command(parser!(b"lpop key 1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Nil
         );
         assert_eq!(
-            command(parser!(b"srem key 2 3 4"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(0)
+            command(parser!(b"lpop key -1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR value is out of range, must be positive".to_owned())
         );
     }
 
     #[test]
-    fn sismember_command() {
+    fn lmove_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"rpush key a b c"), &mut db, &mut Client::mock()).unwrap();
         assert_eq!(
-            command(parser!(b"sadd key 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(3)
+            command(
+                parser!(b"lmove key key2 left right"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Data(b"a".to_vec())
         );
         assert_eq!(
-            command(parser!(b"sismember key 2"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(1)
+            command(
+                parser!(b"lmove key key2 RIGHT RIGHT"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Data(b"c".to_vec())
         );
         assert_eq!(
-            command(parser!(b"sismember key 4"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(0)
+            command(
+                parser!(b"lmove key key left left"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Data(b"b".to_vec())
         );
-    }
-
-    #[test]
-    fn smembers_command() {
-        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
-            command(parser!(b"sadd key 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(3)
+            command(parser!(b"lrange key2 0 -1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"a".to_vec()),
+                Response::Data(b"c".to_vec()),
+            ])
+        );
+        assert_eq!(
+            command(
+                parser!(b"lmove key key2 up right"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR syntax error".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"lmove nokey key2 left right"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Nil
         );
-        match command(parser!(b"smembers key"), &mut db, &mut Client::mock()).unwrap() {
-            Response::Array(arr) => {
-                let mut array = arr
-                    .iter()
-                    .map(|x| match x {
-                        &Response::Data(d) => d.clone(),
-                        _ => panic!("Expected data"),
-                    })
-                    .collect::<Vec<_>>();
-                array.sort_by(|a, b| a.cmp(b));
-                assert_eq!(array, vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
-            }
-            _ => panic!("Expected array"),
-        }
     }
 
     #[test]
-    fn srandmember_command1() {
+    fn blmove_waiting() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let receiver = match command(
+            parser!(b"blmove key1 key2 left right 10"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap_err()
+        {
+            ResponseError::Wait(receiver) => receiver,
+            _ => panic!("Unexpected response"),
+        };
+        command(parser!(b"rpush key1 value"), &mut db, &mut Client::mock()).unwrap();
+        let reissued = receiver.recv().unwrap().unwrap();
+        // the timeout is what was left of it, in seconds
+        assert_eq!(reissued.get_command().get_str(5).unwrap(), "10");
         assert_eq!(
-            command(parser!(b"sadd key 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(3)
-        );
-        let r = command(parser!(b"srandmember key"), &mut db, &mut Client::mock()).unwrap();
-        assert!(
-            r == Response::Data(b"1".to_vec())
-                || r == Response::Data(b"2".to_vec())
-                || r == Response::Data(b"3".to_vec())
+            command(reissued.get_command(), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"value".to_vec())
         );
         assert_eq!(
-            command(parser!(b"scard key"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(3)
+            command(parser!(b"lrange key2 0 -1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![Response::Data(b"value".to_vec())])
         );
     }
 
     #[test]
-    fn srandmember_command2() {
+    fn lmpop_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"rpush key2 a b c"), &mut db, &mut Client::mock()).unwrap();
         assert_eq!(
-            command(parser!(b"sadd key 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
+            command(
+                parser!(b"lmpop 2 key1 key2 right count 2"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Array(vec![
+                Response::Data(b"key2".to_vec()),
+                Response::Array(vec![
+                    Response::Data(b"c".to_vec()),
+                    Response::Data(b"b".to_vec()),
+                ]),
+            ])
+        );
+        assert_eq!(
+            command(parser!(b"lmpop 1 key2 left"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"key2".to_vec()),
+                Response::Array(vec![Response::Data(b"a".to_vec())]),
+            ])
+        );
+        assert_eq!(
+            command(
+                parser!(b"lmpop 2 key1 key2 left"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Nil
+        );
+        assert_eq!(
+            command(parser!(b"lmpop 0 key1 left"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR numkeys should be greater than 0".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"lmpop 3 key1 key2 left"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR syntax error".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"lmpop 1 key1 left count 0"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR count should be greater than 0".to_owned())
+        );
+    }
+
+    #[test]
+    fn blmpop_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"rpush key2 a"), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(
+            command(
+                parser!(b"blmpop 0 2 key1 key2 left"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Array(vec![
+                Response::Data(b"key2".to_vec()),
+                Response::Array(vec![Response::Data(b"a".to_vec())]),
+            ])
+        );
+        let receiver = match command(
+            parser!(b"blmpop 1 2 key1 key2 left"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap_err()
+        {
+            ResponseError::Wait(receiver) => receiver,
+            _ => panic!("Unexpected response"),
+        };
+        thread::sleep(Duration::from_millis(1400));
+        assert!(receiver.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn blocking_pop_in_exec() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut client = Client::mock();
+        command(parser!(b"multi"), &mut db, &mut client).unwrap();
+        command(parser!(b"blpop key 0"), &mut db, &mut client).unwrap();
+        command(
+            parser!(b"blmove key key2 left left 0"),
+            &mut db,
+            &mut client,
+        )
+        .unwrap();
+        assert_eq!(
+            command(parser!(b"exec"), &mut db, &mut client).unwrap(),
+            Response::Array(vec![Response::Nil, Response::Nil])
+        );
+        assert!(!client.in_exec);
+    }
+
+    #[test]
+    fn lpos_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(
+            parser!(b"rpush key a b c a b c a"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        assert_eq!(
+            command(parser!(b"lpos key b"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(parser!(b"lpos key a rank -2"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(parser!(b"lpos key a count 0"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Integer(0),
+                Response::Integer(3),
+                Response::Integer(6),
+            ])
+        );
+        assert_eq!(
+            command(
+                parser!(b"lpos key a rank 2 count 2 maxlen 4"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Array(vec![Response::Integer(3)])
+        );
+        assert_eq!(
+            command(parser!(b"lpos key d"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Nil
+        );
+        assert_eq!(
+            command(
+                parser!(b"lpos nokey a count 1"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Array(vec![])
+        );
+        assert_eq!(
+            command(parser!(b"lpos key a rank 0"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error(
+                "ERR RANK can't be zero: use 1 to start from the first match, 2 from the second \
+                 ... or use negative to start from the end of the list"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            command(
+                parser!(b"lpos key a count -1"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR COUNT can't be negative".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"lpos key a foo 1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR syntax error".to_owned())
+        );
+    }
+
+    #[test]
+    fn ltrim_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"rpush key value"), &mut db, &mut Client::mock()).unwrap();
+        command(parser!(b"rpush key value"), &mut db, &mut Client::mock()).unwrap();
+        command(parser!(b"rpush key valuf"), &mut db, &mut Client::mock()).unwrap();
+        command(parser!(b"rpush key valuf"), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(
+            command(parser!(b"ltrim key 1 -2"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"lrange key 0 -1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data("value".to_owned().into_bytes()),
+                Response::Data("valuf".to_owned().into_bytes()),
+            ])
+        );
+    }
+
+    #[test]
+    fn sadd_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"sadd key 1 1 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(parser!(b"sadd key 1 1 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
+        );
+    }
+
+    #[test]
+    fn srem_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"sadd key 1 1 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(parser!(b"srem key 2 3 4"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(2)
+        );
+        assert_eq!(
+            command(parser!(b"srem key 2 3 4"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
+        );
+    }
+
+    #[test]
+    fn sismember_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"sadd key 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(parser!(b"sismember key 2"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(parser!(b"sismember key 4"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
+        );
+    }
+
+    #[test]
+    fn smembers_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"sadd key 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(3)
+        );
+        match command(parser!(b"smembers key"), &mut db, &mut Client::mock()).unwrap() {
+            Response::Array(arr) => {
+                let mut array = arr
+                    .iter()
+                    .map(|x| match x {
+                        Response::Data(d) => d.clone(),
+                        _ => panic!("Expected data"),
+                    })
+                    .collect::<Vec<_>>();
+                array.sort_by(|a, b| a.cmp(b));
+                assert_eq!(array, vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+            }
+            _ => panic!("Expected array"),
+        }
+    }
+
+    #[test]
+    fn srandmember_command1() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"sadd key 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(3)
+        );
+        let r = command(parser!(b"srandmember key"), &mut db, &mut Client::mock()).unwrap();
+        assert!(
+            r == Response::Data(b"1".to_vec())
+                || r == Response::Data(b"2".to_vec())
+                || r == Response::Data(b"3".to_vec())
+        );
+        assert_eq!(
+            command(parser!(b"scard key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(3)
+        );
+    }
+
+    #[test]
+    fn srandmember_command2() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"sadd key 1 2 3"), &mut db, &mut Client::mock()).unwrap(),
             Response::Integer(3)
         );
         let r = command(parser!(b"srandmember key 1"), &mut db, &mut Client::mock()).unwrap();
@@ -4328,7 +7402,7 @@ mod test_command {
         let mut r = arr
             .iter()
             .map(|el| match el {
-                &Response::Data(el) => el.clone(),
+                Response::Data(el) => el.clone(),
                 _ => panic!("Expected data"),
             })
             .collect::<Vec<_>>();
@@ -4356,7 +7430,7 @@ mod test_command {
             .cloned()
             .collect::<HashSet<_>>();
         let mut set2 = Value::Nil;
-        set2.create_set(set);
+        set2.create_set(set, 512);
         assert_eq!(db.get(0, &b"target".to_vec()).unwrap(), &set2);
     }
 
@@ -4383,7 +7457,7 @@ mod test_command {
         let mut r = arr
             .iter()
             .map(|el| match el {
-                &Response::Data(el) => el.clone(),
+                Response::Data(el) => el.clone(),
                 _ => panic!("Expected data"),
             })
             .collect::<Vec<_>>();
@@ -4405,7 +7479,7 @@ mod test_command {
         let mut r = arr
             .iter()
             .map(|el| match el {
-                &Response::Data(el) => el.clone(),
+                Response::Data(el) => el.clone(),
                 _ => panic!("Expected data"),
             })
             .collect::<Vec<_>>();
@@ -4433,26 +7507,141 @@ mod test_command {
     }
 
     #[test]
-    fn sinterstore_command() {
+    fn sintercard_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
-        command(parser!(b"sadd key1 1 2 3"), &mut db, &mut Client::mock()).unwrap();
-        command(parser!(b"sadd key2 2 3 5"), &mut db, &mut Client::mock()).unwrap();
+        command(parser!(b"sadd key1 -1 2 3 a"), &mut db, &mut Client::mock()).unwrap();
+        command(parser!(b"sadd key2 -1 3 a b"), &mut db, &mut Client::mock()).unwrap();
+        command(parser!(b"set str a"), &mut db, &mut Client::mock()).unwrap();
         assert_eq!(
             command(
-                parser!(b"sinterstore target key1 key2"),
+                parser!(b"sintercard 2 key1 key2"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"sintercard 2 key1 key2 LIMIT 2"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
             Response::Integer(2)
         );
-
-        let set = vec![b"3".to_vec(), b"2".to_vec()]
-            .iter()
-            .cloned()
-            .collect::<HashSet<_>>();
+        assert_eq!(
+            command(
+                parser!(b"sintercard 2 key1 nokey"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(0)
+        );
+        assert_eq!(
+            command(
+                parser!(b"sintercard 2 nokey str"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_owned()
+            )
+        );
+        assert_eq!(
+            command(parser!(b"sintercard 0 key1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR numkeys should be greater than 0".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"sintercard 3 key1 key2"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR Number of keys can't be greater than number of args".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"sintercard 1 key1 limit -1"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR LIMIT can't be negative".to_owned())
+        );
+    }
+
+    #[test]
+    fn smismember_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"sadd key -5 7 foo"), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(
+            command(
+                parser!(b"smismember key -5 8 foo -05"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Array(vec![
+                Response::Integer(1),
+                Response::Integer(0),
+                Response::Integer(1),
+                Response::Integer(0),
+            ])
+        );
+        assert_eq!(
+            command(
+                parser!(b"smismember nokey a b"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Array(vec![Response::Integer(0), Response::Integer(0)])
+        );
+    }
+
+    #[test]
+    fn sadd_negative_intset() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"sadd key -3 -1 -2"), &mut db, &mut Client::mock()).unwrap();
+        assert!(match db.get(0, b"key").unwrap() {
+            Value::Set(set) => set.is_intset(),
+            _ => false,
+        });
+        assert_eq!(
+            command(parser!(b"smembers key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"-3".to_vec()),
+                Response::Data(b"-2".to_vec()),
+                Response::Data(b"-1".to_vec()),
+            ])
+        );
+    }
+
+    #[test]
+    fn sinterstore_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"sadd key1 1 2 3"), &mut db, &mut Client::mock()).unwrap();
+        command(parser!(b"sadd key2 2 3 5"), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(
+            command(
+                parser!(b"sinterstore target key1 key2"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(2)
+        );
+
+        let set = vec![b"3".to_vec(), b"2".to_vec()]
+            .iter()
+            .cloned()
+            .collect::<HashSet<_>>();
         let mut set2 = Value::Nil;
-        set2.create_set(set);
+        set2.create_set(set, 512);
         assert_eq!(db.get(0, &b"target".to_vec()).unwrap(), &set2);
     }
 
@@ -4468,7 +7657,7 @@ mod test_command {
         let mut r = arr
             .iter()
             .map(|el| match el {
-                &Response::Data(el) => el.clone(),
+                Response::Data(el) => el.clone(),
                 _ => panic!("Expected data"),
             })
             .collect::<Vec<_>>();
@@ -4490,7 +7679,7 @@ mod test_command {
         let mut r = arr
             .iter()
             .map(|el| match el {
-                &Response::Data(el) => el.clone(),
+                Response::Data(el) => el.clone(),
                 _ => panic!("Expected data"),
             })
             .collect::<Vec<_>>();
@@ -4521,7 +7710,7 @@ mod test_command {
             .cloned()
             .collect::<HashSet<_>>();
         let mut set2 = Value::Nil;
-        set2.create_set(set);
+        set2.create_set(set, 512);
         assert_eq!(db.get(0, &b"target".to_vec()).unwrap(), &set2);
     }
 
@@ -5160,530 +8349,1782 @@ mod test_command {
     }
 
     #[test]
-    fn zrank_command() {
+    fn zrange_unified() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(
+            parser!(b"zadd key 1 a 2 b 3 c 4 d"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        command(
+            parser!(b"zadd lex 0 a 0 b 0 c 0 d"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
         assert_eq!(
             command(
-                parser!(b"zadd key 1 a 2 b 3 c 4 d"),
+                parser!(b"zrange key (4 1 byscore rev limit 1 -1 withscores"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(4)
-        );
-        assert_eq!(
-            command(parser!(b"zrank key a"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(0)
-        );
-        assert_eq!(
-            command(parser!(b"zrank key b"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(1)
+            Response::Array(vec![
+                Response::Data(b"b".to_vec()),
+                Response::Data(b"2".to_vec()),
+                Response::Data(b"a".to_vec()),
+                Response::Data(b"1".to_vec()),
+            ])
         );
         assert_eq!(
-            command(parser!(b"zrank key e"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Nil
+            command(parser!(b"zrange key 0 1 rev"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"d".to_vec()),
+                Response::Data(b"c".to_vec()),
+            ])
         );
-    }
-
-    #[test]
-    fn zrevrank_command() {
-        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
             command(
-                parser!(b"zadd key 1 a 2 b 3 c 4 d"),
+                parser!(b"zrange lex - [c BYLEX LIMIT 1 1"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(4)
-        );
-        assert_eq!(
-            command(parser!(b"zrevrank key a"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(3)
-        );
-        assert_eq!(
-            command(parser!(b"zrevrank key b"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(2)
-        );
-        assert_eq!(
-            command(parser!(b"zrevrank key e"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Nil
+            Response::Array(vec![Response::Data(b"b".to_vec())])
         );
-    }
-
-    #[test]
-    fn zrem_command() {
-        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
             command(
-                parser!(b"zadd key 1 a 2 b 3 c"),
+                parser!(b"zrange lex + - bylex rev"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
-        );
-        assert_eq!(
-            command(parser!(b"zrem key a b d e"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(2)
-        );
-        assert_eq!(
-            command(parser!(b"zrem key c"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(1)
+            Response::Array(vec![
+                Response::Data(b"d".to_vec()),
+                Response::Data(b"c".to_vec()),
+                Response::Data(b"b".to_vec()),
+                Response::Data(b"a".to_vec()),
+            ])
         );
-    }
-
-    #[test]
-    fn zunionstore_command_short() {
-        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
             command(
-                parser!(b"zadd key1 1 a 2 b 3 c"),
+                parser!(b"zrange key 0 -1 limit 0 1"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
-        );
-        assert_eq!(
-            command(parser!(b"zadd key2 4 d 5 e"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(2)
+            Response::Error(
+                "ERR syntax error, LIMIT is only supported in combination with either BYSCORE \
+                 or BYLEX"
+                    .to_owned()
+            )
         );
         assert_eq!(
             command(
-                parser!(b"zunionstore key 3 key1 key2 key3"),
+                parser!(b"zrange key - + bylex withscores"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(5)
+            Response::Error(
+                "ERR syntax error, WITHSCORES not supported in combination with BYLEX".to_owned()
+            )
         );
-    }
-
-    #[test]
-    fn zunionstore_command_short2() {
-        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
             command(
-                parser!(b"zadd key1 1 a 2 b 3 c"),
+                parser!(b"zrange key 0 1 byscore bylex"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
+            Response::Error("ERR syntax error".to_owned())
         );
+        // the legacy commands do not take the options they imply
         assert_eq!(
-            command(parser!(b"zadd key2 4 d 5 e"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(2)
+            command(
+                parser!(b"zrevrange key 0 1 rev"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR syntax error".to_owned())
         );
         assert_eq!(
             command(
-                parser!(b"zunionstore key 2 key1 key2"),
+                parser!(b"zrangebyscore key 1 2 bylex"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(5)
+            Response::Error("ERR syntax error".to_owned())
         );
     }
 
     #[test]
-    fn zunionstore_command_weights() {
+    fn zrangestore_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(
+            parser!(b"zadd src 1 a 2 b 3 c"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
         assert_eq!(
             command(
-                parser!(b"zadd key1 1 a 2 b 3 c"),
+                parser!(b"zrangestore dst src 2 +inf byscore"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
-        );
-        assert_eq!(
-            command(parser!(b"zadd key2 4 d 5 e"), &mut db, &mut Client::mock()).unwrap(),
             Response::Integer(2)
         );
         assert_eq!(
             command(
-                parser!(b"zunionstore key 3 key1 key2 key3 Weights 1 2 3"),
+                parser!(b"zrange dst 0 -1 withscores"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(5)
-        );
-        assert_eq!(
-            command(parser!(b"zscore key d"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Data(b"8".to_vec())
+            Response::Array(vec![
+                Response::Data(b"b".to_vec()),
+                Response::Data(b"2".to_vec()),
+                Response::Data(b"c".to_vec()),
+                Response::Data(b"3".to_vec()),
+            ])
         );
-    }
-
-    #[test]
-    fn zunionstore_command_aggregate() {
-        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
             command(
-                parser!(b"zadd key1 1 a 2 b 3 c"),
+                parser!(b"zrangestore dst src 0 0 rev"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
+            Response::Integer(1)
         );
         assert_eq!(
             command(
-                parser!(b"zadd key2 9 c 4 d 5 e"),
+                parser!(b"zrange dst 0 -1 withscores"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
+            Response::Array(vec![
+                Response::Data(b"c".to_vec()),
+                Response::Data(b"3".to_vec()),
+            ])
         );
         assert_eq!(
             command(
-                parser!(b"zunionstore key 3 key1 key2 key3 aggregate max"),
+                parser!(b"zrangestore dst src 5 10"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(5)
+            Response::Integer(0)
         );
         assert_eq!(
-            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Data(b"9".to_vec())
+            command(parser!(b"exists dst"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
         );
         assert_eq!(
             command(
-                parser!(b"zunionstore key 3 key1 key2 key3 aggregate min"),
+                parser!(b"zrangestore dst src 0 -1 withscores"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(5)
-        );
-        assert_eq!(
-            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Data(b"3".to_vec())
+            Response::Error("ERR syntax error".to_owned())
         );
     }
 
     #[test]
-    fn zunionstore_command_weights_aggregate() {
+    fn zrank_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
             command(
-                parser!(b"zadd key1 1 a 2 b 3 c"),
+                parser!(b"zadd key 1 a 2 b 3 c 4 d"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
+            Response::Integer(4)
         );
         assert_eq!(
-            command(
-                parser!(b"zadd key2 3 c 4 d 5 e"),
-                &mut db,
-                &mut Client::mock()
-            )
-            .unwrap(),
-            Response::Integer(3)
+            command(parser!(b"zrank key a"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
         );
         assert_eq!(
-            command(
-                parser!(b"zunionstore key 3 key1 key2 key3 weights 1 2 3 aggregate max"),
-                &mut db,
-                &mut Client::mock()
-            )
-            .unwrap(),
-            Response::Integer(5)
+            command(parser!(b"zrank key b"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(1)
         );
         assert_eq!(
-            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Data(b"6".to_vec())
+            command(parser!(b"zrank key e"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Nil
         );
     }
 
     #[test]
-    fn zinterstore_command_short() {
+    fn zrevrank_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
             command(
-                parser!(b"zadd key1 1 a 2 b 3 c"),
+                parser!(b"zadd key 1 a 2 b 3 c 4 d"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
+            Response::Integer(4)
+        );
+        assert_eq!(
+            command(parser!(b"zrevrank key a"), &mut db, &mut Client::mock()).unwrap(),
             Response::Integer(3)
         );
+        assert_eq!(
+            command(parser!(b"zrevrank key b"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(2)
+        );
+        assert_eq!(
+            command(parser!(b"zrevrank key e"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Nil
+        );
+    }
+
+    #[test]
+    fn zrem_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         assert_eq!(
             command(
-                parser!(b"zadd key2 3 c 4 d 5 e"),
+                parser!(b"zadd key 1 a 2 b 3 c"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
             Response::Integer(3)
         );
+        assert_eq!(
+            command(parser!(b"zrem key a b d e"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(2)
+        );
+        assert_eq!(
+            command(parser!(b"zrem key c"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(1)
+        );
+    }
+
+    #[test]
+    fn zpop_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(
+            parser!(b"zadd key 1 a 2 b 3 c 4 d"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        assert_eq!(
+            command(parser!(b"zpopmin key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"a".to_vec()),
+                Response::Data(b"1".to_vec()),
+            ])
+        );
+        assert_eq!(
+            command(parser!(b"zpopmax key 2"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"d".to_vec()),
+                Response::Data(b"4".to_vec()),
+                Response::Data(b"c".to_vec()),
+                Response::Data(b"3".to_vec()),
+            ])
+        );
+        assert_eq!(
+            command(parser!(b"zpopmin key 0"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![])
+        );
+        assert_eq!(
+            command(parser!(b"zpopmin key 10"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"b".to_vec()),
+                Response::Data(b"2".to_vec()),
+            ])
+        );
+        assert_eq!(
+            command(parser!(b"exists key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
+        );
+        assert_eq!(
+            command(parser!(b"zpopmax key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![])
+        );
+        assert_eq!(
+            command(parser!(b"zpopmin key -1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR value is out of range, must be positive".to_owned())
+        );
+        command(parser!(b"set string value"), &mut db, &mut Client::mock()).unwrap();
+        assert!(
+            command(parser!(b"zpopmin string"), &mut db, &mut Client::mock())
+                .unwrap()
+                .is_error()
+        );
+    }
+
+    #[test]
+    fn bzpop_nowait() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"zadd key2 1 a 2 b"), &mut db, &mut Client::mock()).unwrap();
         assert_eq!(
             command(
-                parser!(b"zinterstore key 2 key1 key2"),
+                parser!(b"bzpopmax key1 key2 0"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(1)
+            Response::Array(vec![
+                Response::Data(b"key2".to_vec()),
+                Response::Data(b"b".to_vec()),
+                Response::Data(b"2".to_vec()),
+            ])
+        );
+        assert_eq!(
+            command(parser!(b"bzpopmin key2 0"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"key2".to_vec()),
+                Response::Data(b"a".to_vec()),
+                Response::Data(b"1".to_vec()),
+            ])
         );
     }
 
     #[test]
-    fn zinterstore_command_weights() {
+    fn bzpop_waiting() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let receiver =
+            match command(parser!(b"bzpopmin key 0"), &mut db, &mut Client::mock()).unwrap_err() {
+                ResponseError::Wait(receiver) => receiver,
+                _ => panic!("Unexpected response"),
+            };
+        command(parser!(b"zadd key 5 a"), &mut db, &mut Client::mock()).unwrap();
+        let reissued = receiver.recv().unwrap().unwrap();
+        assert_eq!(
+            command(reissued.get_command(), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"key".to_vec()),
+                Response::Data(b"a".to_vec()),
+                Response::Data(b"5".to_vec()),
+            ])
+        );
+    }
+
+    #[test]
+    fn bzpop_timeout() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let receiver =
+            match command(parser!(b"bzpopmax key 1"), &mut db, &mut Client::mock()).unwrap_err() {
+                ResponseError::Wait(receiver) => receiver,
+                _ => panic!("Unexpected response"),
+            };
+        assert!(receiver.try_recv().is_err());
+        thread::sleep(Duration::from_millis(1400));
+        assert!(receiver.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn zmpop_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(
+            parser!(b"zadd key2 1 a 2 b 3 c"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
         assert_eq!(
             command(
-                parser!(b"zadd key1 1 a 2 b 3 c 4 d"),
+                parser!(b"zmpop 2 key1 key2 max count 2"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(4)
+            Response::Array(vec![
+                Response::Data(b"key2".to_vec()),
+                Response::Array(vec![
+                    Response::Array(vec![
+                        Response::Data(b"c".to_vec()),
+                        Response::Data(b"3".to_vec()),
+                    ]),
+                    Response::Array(vec![
+                        Response::Data(b"b".to_vec()),
+                        Response::Data(b"2".to_vec()),
+                    ]),
+                ]),
+            ])
         );
         assert_eq!(
-            command(parser!(b"zadd key2 4 d 5 e"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(2)
+            command(parser!(b"zmpop 1 key2 MIN"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"key2".to_vec()),
+                Response::Array(vec![Response::Array(vec![
+                    Response::Data(b"a".to_vec()),
+                    Response::Data(b"1".to_vec()),
+                ])]),
+            ])
         );
         assert_eq!(
-            command(parser!(b"zadd key3 0 d"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Integer(1)
+            command(parser!(b"zmpop 1 key2 min"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Nil
+        );
+        assert_eq!(
+            command(parser!(b"zmpop 1 key2 left"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR syntax error".to_owned())
         );
         assert_eq!(
             command(
-                parser!(b"zinterstore key 3 key1 key2 key3 Weights 1 2 3"),
+                parser!(b"zmpop 1 key2 min count -1"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(1)
+            Response::Error("ERR count should be greater than 0".to_owned())
         );
+    }
+
+    #[test]
+    fn bzmpop_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"zadd key 1 a"), &mut db, &mut Client::mock()).unwrap();
         assert_eq!(
-            command(parser!(b"zscore key d"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Data(b"12".to_vec())
+            command(parser!(b"bzmpop 0 1 key min"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"key".to_vec()),
+                Response::Array(vec![Response::Array(vec![
+                    Response::Data(b"a".to_vec()),
+                    Response::Data(b"1".to_vec()),
+                ])]),
+            ])
         );
+        match command(parser!(b"bzmpop 0 1 key min"), &mut db, &mut Client::mock()).unwrap_err() {
+            ResponseError::Wait(_) => (),
+            _ => panic!("Unexpected response"),
+        }
     }
 
     #[test]
-    fn zinterstore_command_aggregate() {
+    fn blocking_commands_propagation() {
+        let mut path = temp_dir();
+        path.push("blocking_commands_propagation.aof");
+        let _ = remove_file(&path);
+        let mut config = Config::new(Logger::new(Level::Warning));
+        config.appendonly = true;
+        config.appendfilename = path.to_str().unwrap().to_owned();
+        let mut db = Database::new(config);
+        let mut client = Client::mock();
+        command(parser!(b"zadd zset 1 a 2 b"), &mut db, &mut client).unwrap();
+        command(parser!(b"rpush list a b c"), &mut db, &mut client).unwrap();
+        command(parser!(b"bzpopmax zset 0"), &mut db, &mut client).unwrap();
+        command(
+            parser!(b"bzmpop 0 1 zset min count 5"),
+            &mut db,
+            &mut client,
+        )
+        .unwrap();
+        command(parser!(b"blpop list 0"), &mut db, &mut client).unwrap();
+        command(
+            parser!(b"blmove list other right left 0"),
+            &mut db,
+            &mut client,
+        )
+        .unwrap();
+        command(
+            parser!(b"blmpop 0 1 list left count 5"),
+            &mut db,
+            &mut client,
+        )
+        .unwrap();
+        // blocked commands write nothing
+        command(parser!(b"bzpopmin zset 0"), &mut db, &mut client).unwrap_err();
+        command(parser!(b"brpop list 0"), &mut db, &mut client).unwrap_err();
+
+        let aof = std::fs::read(&path).unwrap();
+        let _ = remove_file(&path);
+        let mut expected = b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n".to_vec();
+        for args in &[
+            &["zadd", "zset", "1", "a", "2", "b"][..],
+            &["rpush", "list", "a", "b", "c"],
+            &["ZPOPMAX", "zset"],
+            &["ZPOPMIN", "zset", "1"],
+            &["LPOP", "list"],
+            &["LMOVE", "list", "other", "RIGHT", "LEFT"],
+            &["LPOP", "list", "1"],
+        ] {
+            expected.extend(format!("*{}\r\n", args.len()).into_bytes());
+            for arg in args.iter() {
+                expected.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+            }
+        }
+        assert_eq!(from_utf8(&aof).unwrap(), from_utf8(&expected).unwrap());
+    }
+
+    #[test]
+    fn zunion_zinter_zdiff_commands() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(
+            parser!(b"zadd key1 1 a 2 b 3 c"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        command(
+            parser!(b"zadd key2 10 b 20 c 30 d"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
         assert_eq!(
             command(
-                parser!(b"zadd key1 1 a 2 b 3 c"),
+                parser!(b"zunion 2 key1 key2 weights 1 2 aggregate max withscores"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
+            Response::Array(
+                vec!["a", "1", "b", "20", "c", "40", "d", "60"]
+                    .into_iter()
+                    .map(|s| Response::Data(s.as_bytes().to_vec()))
+                    .collect()
+            )
+        );
+        assert_eq!(
+            command(parser!(b"zinter 2 key1 key2"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"b".to_vec()),
+                Response::Data(b"c".to_vec()),
+            ])
         );
         assert_eq!(
             command(
-                parser!(b"zadd key2 9 c 4 d 5 e"),
+                parser!(b"zdiff 3 key1 key2 nokey withscores"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
+            Response::Array(vec![
+                Response::Data(b"a".to_vec()),
+                Response::Data(b"1".to_vec()),
+            ])
         );
         assert_eq!(
             command(
-                parser!(b"zinterstore key 2 key1 key2 aggregate max"),
+                parser!(b"zdiff 1 key1 weights 1"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(1)
+            Response::Error("ERR syntax error".to_owned())
         );
         assert_eq!(
-            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Data(b"9".to_vec())
+            command(parser!(b"zunion 0 key1"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR at least 1 input key is needed for 'zunion' command".to_owned())
         );
         assert_eq!(
-            command(
-                parser!(b"zinterstore key 2 key1 key2 aggregate min"),
-                &mut db,
-                &mut Client::mock()
-            )
-            .unwrap(),
-            Response::Integer(1)
+            command(parser!(b"zinter 3 key1 key2"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Error("ERR syntax error".to_owned())
         );
+        // nothing is written
         assert_eq!(
-            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Data(b"3".to_vec())
+            command(parser!(b"dbsize"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(2)
         );
     }
 
     #[test]
-    fn zinterstore_command_weights_aggregate() {
+    fn zdiffstore_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(
+            parser!(b"zadd key1 1 a 2 b 3 c"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        command(parser!(b"zadd key2 10 b"), &mut db, &mut Client::mock()).unwrap();
         assert_eq!(
             command(
-                parser!(b"zadd key1 1 a 2 b 3 c"),
+                parser!(b"zdiffstore dst 2 key1 key2"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
+            Response::Integer(2)
         );
         assert_eq!(
             command(
-                parser!(b"zadd key2 3 c 4 d 5 e"),
+                parser!(b"zrange dst 0 -1 withscores"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(3)
+            Response::Array(vec![
+                Response::Data(b"a".to_vec()),
+                Response::Data(b"1".to_vec()),
+                Response::Data(b"c".to_vec()),
+                Response::Data(b"3".to_vec()),
+            ])
         );
         assert_eq!(
             command(
-                parser!(b"zinterstore key 2 key1 key2 weights 1 2 aggregate max"),
+                parser!(b"zdiffstore dst 2 key1 key1"),
                 &mut db,
                 &mut Client::mock()
             )
             .unwrap(),
-            Response::Integer(1)
+            Response::Integer(0)
         );
         assert_eq!(
-            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Data(b"6".to_vec())
+            command(parser!(b"exists dst"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(0)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zdiffstore dst 1 key1 withscores"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR syntax error".to_owned())
         );
     }
 
     #[test]
-    fn select_command() {
-        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
-        let mut client = Client::mock();
-        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
-        assert_eq!(client.dbindex, 1);
-    }
-
-    #[test]
-    fn flushdb_command() {
+    fn zintercard_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
-        let mut client = Client::mock();
-        command(parser!(b"select 0"), &mut db, &mut client).unwrap();
-        assert_eq!(
+        command(
+            parser!(b"zadd key1 1 a 2 b 3 c"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        command(
+            parser!(b"zadd key2 1 b 2 c 3 d"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        assert_eq!(
+            command(
+                parser!(b"zintercard 2 key1 key2"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(2)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zintercard 2 key1 key2 limit 1"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zintercard 2 key1 nokey"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(0)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zintercard 3 key1 key2"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR Number of keys can't be greater than number of args".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"zintercard 1 key1 limit -1"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR LIMIT can't be negative".to_owned())
+        );
+    }
+
+    #[test]
+    fn zmscore_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        command(parser!(b"zadd key 1.5 a 2 b"), &mut db, &mut Client::mock()).unwrap();
+        assert_eq!(
+            command(parser!(b"zmscore key a c b"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"1.5".to_vec()),
+                Response::Nil,
+                Response::Data(b"2".to_vec()),
+            ])
+        );
+        assert_eq!(
+            command(parser!(b"zmscore nokey a"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![Response::Nil])
+        );
+    }
+
+    #[test]
+    fn zrandmember_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(parser!(b"zrandmember key"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Nil
+        );
+        assert_eq!(
+            command(parser!(b"zrandmember key 3"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Array(vec![])
+        );
+        command(
+            parser!(b"zadd key 1 a 2 b 3 c"),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        let data = |r| match r {
+            Response::Array(r) => r
+                .into_iter()
+                .map(|el| match el {
+                    Response::Data(el) => el,
+                    el => panic!("Unexpected element {:?}", el),
+                })
+                .collect::<Vec<_>>(),
+            r => panic!("Unexpected response {:?}", r),
+        };
+        let members = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        match command(parser!(b"zrandmember key"), &mut db, &mut Client::mock()).unwrap() {
+            Response::Data(member) => assert!(members.contains(&member)),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        let mut r =
+            data(command(parser!(b"zrandmember key 5"), &mut db, &mut Client::mock()).unwrap());
+        r.sort();
+        assert_eq!(r, members);
+        let r =
+            data(command(parser!(b"zrandmember key -5"), &mut db, &mut Client::mock()).unwrap());
+        assert_eq!(r.len(), 5);
+        assert!(r.iter().all(|member| members.contains(member)));
+        let r = data(
+            command(
+                parser!(b"zrandmember key 1 withscores"),
+                &mut db,
+                &mut Client::mock(),
+            )
+            .unwrap(),
+        );
+        assert_eq!(r[1], format!("{}", r[0][0] - b'a' + 1).into_bytes());
+        assert_eq!(
+            command(
+                parser!(b"zrandmember key 1 scores"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Error("ERR syntax error".to_owned())
+        );
+    }
+
+    #[test]
+    fn zunionstore_command_short() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(
+                parser!(b"zadd key1 1 a 2 b 3 c"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(parser!(b"zadd key2 4 d 5 e"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(2)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zunionstore key 3 key1 key2 key3"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(5)
+        );
+    }
+
+    #[test]
+    fn zunionstore_command_short2() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(
+                parser!(b"zadd key1 1 a 2 b 3 c"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(parser!(b"zadd key2 4 d 5 e"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(2)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zunionstore key 2 key1 key2"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(5)
+        );
+    }
+
+    #[test]
+    fn zunionstore_command_weights() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(
+                parser!(b"zadd key1 1 a 2 b 3 c"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(parser!(b"zadd key2 4 d 5 e"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(2)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zunionstore key 3 key1 key2 key3 Weights 1 2 3"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(5)
+        );
+        assert_eq!(
+            command(parser!(b"zscore key d"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"8".to_vec())
+        );
+    }
+
+    #[test]
+    fn zunionstore_command_aggregate() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(
+                parser!(b"zadd key1 1 a 2 b 3 c"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zadd key2 9 c 4 d 5 e"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zunionstore key 3 key1 key2 key3 aggregate max"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(5)
+        );
+        assert_eq!(
+            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"9".to_vec())
+        );
+        assert_eq!(
+            command(
+                parser!(b"zunionstore key 3 key1 key2 key3 aggregate min"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(5)
+        );
+        assert_eq!(
+            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"3".to_vec())
+        );
+    }
+
+    #[test]
+    fn zunionstore_command_weights_aggregate() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(
+                parser!(b"zadd key1 1 a 2 b 3 c"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zadd key2 3 c 4 d 5 e"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zunionstore key 3 key1 key2 key3 weights 1 2 3 aggregate max"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(5)
+        );
+        assert_eq!(
+            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"6".to_vec())
+        );
+    }
+
+    #[test]
+    fn zinterstore_command_short() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(
+                parser!(b"zadd key1 1 a 2 b 3 c"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zadd key2 3 c 4 d 5 e"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zinterstore key 2 key1 key2"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(1)
+        );
+    }
+
+    #[test]
+    fn zinterstore_command_weights() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(
+                parser!(b"zadd key1 1 a 2 b 3 c 4 d"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(4)
+        );
+        assert_eq!(
+            command(parser!(b"zadd key2 4 d 5 e"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(2)
+        );
+        assert_eq!(
+            command(parser!(b"zadd key3 0 d"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zinterstore key 3 key1 key2 key3 Weights 1 2 3"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(parser!(b"zscore key d"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"12".to_vec())
+        );
+    }
+
+    #[test]
+    fn zinterstore_command_aggregate() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(
+                parser!(b"zadd key1 1 a 2 b 3 c"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zadd key2 9 c 4 d 5 e"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zinterstore key 2 key1 key2 aggregate max"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"9".to_vec())
+        );
+        assert_eq!(
+            command(
+                parser!(b"zinterstore key 2 key1 key2 aggregate min"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"3".to_vec())
+        );
+    }
+
+    #[test]
+    fn zinterstore_command_weights_aggregate() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(
+            command(
+                parser!(b"zadd key1 1 a 2 b 3 c"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zadd key2 3 c 4 d 5 e"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        assert_eq!(
+            command(
+                parser!(b"zinterstore key 2 key1 key2 weights 1 2 aggregate max"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(parser!(b"zscore key c"), &mut db, &mut Client::mock()).unwrap(),
+            Response::Data(b"6".to_vec())
+        );
+    }
+
+    #[test]
+    fn select_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut client = Client::mock();
+        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
+        assert_eq!(client.dbindex, 1);
+    }
+
+    #[test]
+    fn databases_to_lock() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut client = Client::mock();
+        command(parser!(b"select 2"), &mut db, &mut client).unwrap();
+        assert_eq!(databases(&parser!(b"get key"), &db, &client), vec![2]);
+        assert_eq!(
+            databases(&parser!(b"flushall"), &db, &client).len(),
+            db.config.databases as usize
+        );
+
+        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
+        command(parser!(b"watch key"), &mut db, &mut client).unwrap();
+        command(parser!(b"select 3"), &mut db, &mut client).unwrap();
+        let mut indexes = databases(&parser!(b"unwatch"), &db, &client);
+        indexes.sort();
+        assert_eq!(indexes, vec![1, 3]);
+
+        let mut indexes = databases(&parser!(b"move key 5"), &db, &client);
+        indexes.sort();
+        assert_eq!(indexes, vec![3, 5]);
+        let mut indexes = databases(&parser!(b"swapdb 4 0"), &db, &client);
+        indexes.sort();
+        assert_eq!(indexes, vec![0, 3, 4]);
+        let mut indexes = databases(&parser!(b"copy a b db 7 replace"), &db, &client);
+        indexes.sort();
+        assert_eq!(indexes, vec![3, 7]);
+        assert_eq!(databases(&parser!(b"move key x"), &db, &client), vec![3]);
+    }
+
+    #[test]
+    fn read_only_commands_share_databases() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut client = Client::mock();
+        for c in &[
+            &b"set s 1"[..],
+            b"rpush l a b c",
+            b"sadd t a b c",
+            b"zadd z 1 a 2 b",
+            b"psetex e 1 v",
+            b"rpush le a",
+            b"pexpire le 1",
+        ] {
+            command(parser!(*c), &mut db, &mut client).unwrap();
+        }
+        thread::sleep(Duration::from_millis(5));
+        db.unlock();
+        let mut other = db.shared().handle();
+        other.lock_read(&[0]);
+        for c in &[
+            &b"get s"[..],
+            b"get e",
+            b"strlen s",
+            b"exists s e",
+            b"getbit s 0",
+            b"getrange s 0 -1",
+            b"mget s e",
+            b"llen l",
+            b"llen le",
+            b"lindex l 0",
+            b"lrange l 0 -1",
+            b"lrange le 0 -1",
+            b"sismember t a",
+            b"scard t",
+            b"srandmember t 2",
+            b"sinter t t",
+            b"sunion t t",
+            b"sdiff t t",
+            b"smembers t",
+            b"sscan t 0",
+            b"zrange z 0 -1",
+            b"zrevrange z 0 -1",
+            b"zrangebyscore z -inf +inf",
+            b"zrevrangebyscore z +inf -inf",
+            b"zrangebylex z - +",
+            b"zrevrangebylex z + -",
+            b"zcount z -inf +inf",
+            b"zlexcount z - +",
+            b"zcard z",
+            b"zscore z a",
+            b"zrank z a",
+            b"zrevrank z a",
+            b"zscan z 0",
+            b"randomkey",
+            b"keys *",
+            b"scan 0",
+            b"dbsize",
+            b"type s",
+            b"type e",
+            b"ttl s",
+            b"pttl e",
+            b"dump s",
+            b"info",
+            b"ping",
+            b"echo a",
+            b"publish c m",
+            b"pubsub channels",
+        ] {
+            let parser = parser!(*c);
+            assert!(read_only(&parser, &db), "{:?}", c);
+            db.lock_read(&databases(&parser, &db, &client));
+            command(parser, &mut db, &mut client).unwrap();
+        }
+        assert_eq!(
+            command(parser!(b"dbsize"), &mut other, &mut Client::mock()).unwrap(),
+            Response::Integer(6)
+        );
+        assert!(!read_only(&parser!(b"watch s"), &db));
+        assert!(!read_only(&parser!(b"ltrim l 0 1"), &db));
+    }
+
+    #[test]
+    fn flushdb_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut client = Client::mock();
+        command(parser!(b"select 0"), &mut db, &mut client).unwrap();
+        assert_eq!(
+            command(parser!(b"set key value"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
+        assert_eq!(
+            command(parser!(b"set key valuf"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"flushdb"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        command(parser!(b"select 0"), &mut db, &mut client).unwrap();
+        assert_eq!(
+            command(parser!(b"get key"), &mut db, &mut client).unwrap(),
+            Response::Data("value".to_owned().into_bytes())
+        );
+        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
+        assert_eq!(
+            command(parser!(b"get key"), &mut db, &mut client).unwrap(),
+            Response::Nil
+        );
+    }
+
+    #[test]
+    fn flushall_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut client = Client::mock();
+        command(parser!(b"select 0"), &mut db, &mut client).unwrap();
+        assert_eq!(
             command(parser!(b"set key value"), &mut db, &mut client).unwrap(),
             Response::Status("OK".to_owned())
         );
-        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
+        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
+        assert_eq!(
+            command(parser!(b"set key valuf"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"flushall"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        command(parser!(b"select 0"), &mut db, &mut client).unwrap();
+        assert_eq!(
+            command(parser!(b"get key"), &mut db, &mut client).unwrap(),
+            Response::Nil
+        );
+        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
+        assert_eq!(
+            command(parser!(b"get key"), &mut db, &mut client).unwrap(),
+            Response::Nil
+        );
+    }
+
+    #[test]
+    fn flushall_async_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut client = Client::mock();
+        command(parser!(b"set key value"), &mut db, &mut client).unwrap();
+        assert_eq!(
+            command(parser!(b"flushall async"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"get key"), &mut db, &mut client).unwrap(),
+            Response::Nil
+        );
+        assert_eq!(
+            command(parser!(b"flushdb SYNC"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"flushall later"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR syntax error".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"flushdb async sync"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR syntax error".to_owned())
+        );
+    }
+
+    #[test]
+    fn subscribe_publish_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let (tx, rx) = channel();
+        let mut client = Client::new(tx, 0);
+        assert!(command(parser!(b"subscribe channel"), &mut db, &mut client).is_err());
+        assert_eq!(
+            command(
+                parser!(b"publish channel hello-world"),
+                &mut db,
+                &mut Client::mock()
+            )
+            .unwrap(),
+            Response::Integer(1)
+        );
+        assert!(command(parser!(b"unsubscribe channel"), &mut db, &mut client).is_err());
+
         assert_eq!(
-            command(parser!(b"set key valuf"), &mut db, &mut client).unwrap(),
+            rx.try_recv().unwrap().unwrap(),
+            Response::Array(vec![
+                Response::Data(b"subscribe".to_vec()),
+                Response::Data(b"channel".to_vec()),
+                Response::Integer(1),
+            ])
+        );
+        assert_eq!(
+            rx.try_recv().unwrap().unwrap(),
+            Response::Array(vec![
+                Response::Data(b"message".to_vec()),
+                Response::Data(b"channel".to_vec()),
+                Response::Data(b"hello-world".to_vec()),
+            ])
+        );
+        assert_eq!(
+            rx.try_recv().unwrap().unwrap(),
+            Response::Array(vec![
+                Response::Data(b"unsubscribe".to_vec()),
+                Response::Data(b"channel".to_vec()),
+                Response::Integer(0),
+            ])
+        );
+    }
+
+    #[test]
+    fn pubsub_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let (tx, _rx) = channel();
+        let mut client = Client::new(tx, 0);
+        assert!(command(parser!(b"subscribe foo bar"), &mut db, &mut client).is_err());
+        assert!(command(parser!(b"psubscribe f* b*"), &mut db, &mut client).is_err());
+        assert!(command(parser!(b"punsubscribe b*"), &mut db, &mut client).is_err());
+
+        let mut other = Client::mock();
+        assert_eq!(
+            command(parser!(b"pubsub channels f*"), &mut db, &mut other).unwrap(),
+            Response::Array(vec![Response::Data(b"foo".to_vec())])
+        );
+        assert_eq!(
+            command(parser!(b"pubsub numsub foo baz"), &mut db, &mut other).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"foo".to_vec()),
+                Response::Integer(1),
+                Response::Data(b"baz".to_vec()),
+                Response::Integer(0),
+            ])
+        );
+        assert_eq!(
+            command(parser!(b"pubsub numpat"), &mut db, &mut other).unwrap(),
+            Response::Integer(1)
+        );
+        assert!(command(parser!(b"pubsub numpat x"), &mut db, &mut other)
+            .unwrap()
+            .is_error());
+
+        assert!(command(parser!(b"unsubscribe foo"), &mut db, &mut client).is_err());
+        assert_eq!(
+            command(parser!(b"pubsub channels"), &mut db, &mut other).unwrap(),
+            Response::Array(vec![Response::Data(b"bar".to_vec())])
+        );
+        match command(parser!(b"info stats"), &mut db, &mut other).unwrap() {
+            Response::Data(d) => {
+                let s = from_utf8(&d).unwrap();
+                assert!(s.contains("pubsub_channels:1\r\n"));
+                assert!(s.contains("pubsub_patterns:1\r\n"));
+            }
+            r => panic!("Expected data, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn sharded_pubsub_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let (tx, rx) = channel();
+        let mut client = Client::new(tx, 0);
+        assert!(command(parser!(b"ssubscribe shard"), &mut db, &mut client).is_err());
+        assert!(command(parser!(b"subscribe shard"), &mut db, &mut client).is_err());
+
+        let mut other = Client::mock();
+        assert_eq!(
+            command(parser!(b"spublish shard hello"), &mut db, &mut other).unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(parser!(b"publish shard world"), &mut db, &mut other).unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(parser!(b"pubsub shardchannels"), &mut db, &mut other).unwrap(),
+            Response::Array(vec![Response::Data(b"shard".to_vec())])
+        );
+        assert_eq!(
+            command(parser!(b"pubsub shardnumsub shard"), &mut db, &mut other).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"shard".to_vec()),
+                Response::Integer(1)
+            ])
+        );
+        assert!(command(parser!(b"sunsubscribe"), &mut db, &mut client).is_err());
+        assert!(command(parser!(b"sunsubscribe"), &mut db, &mut client).is_err());
+        assert_eq!(
+            command(parser!(b"spublish shard hello"), &mut db, &mut other).unwrap(),
+            Response::Integer(0)
+        );
+
+        let replies = vec![
+            (
+                "ssubscribe",
+                Response::Data(b"shard".to_vec()),
+                Response::Integer(1),
+            ),
+            (
+                "subscribe",
+                Response::Data(b"shard".to_vec()),
+                Response::Integer(1),
+            ),
+            (
+                "smessage",
+                Response::Data(b"shard".to_vec()),
+                Response::Data(b"hello".to_vec()),
+            ),
+            (
+                "message",
+                Response::Data(b"shard".to_vec()),
+                Response::Data(b"world".to_vec()),
+            ),
+            (
+                "sunsubscribe",
+                Response::Data(b"shard".to_vec()),
+                Response::Integer(0),
+            ),
+            ("sunsubscribe", Response::Data(vec![]), Response::Integer(0)),
+        ];
+        for (kind, channel, last) in replies {
+            assert_eq!(
+                rx.try_recv().unwrap().unwrap(),
+                Response::Array(vec![
+                    Response::Data(kind.as_bytes().to_vec()),
+                    channel,
+                    last
+                ])
+            );
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn client_tracking() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let (tx, rx) = channel();
+        db.tracking().connect(1, tx);
+        let mut client = Client::new(channel().0, 2);
+        assert_eq!(
+            command(parser!(b"client id"), &mut db, &mut client).unwrap(),
+            Response::Integer(2)
+        );
+        assert!(command(
+            parser!(b"client tracking on redirect 3"),
+            &mut db,
+            &mut client
+        )
+        .unwrap()
+        .is_error());
+        assert!(command(
+            parser!(b"client tracking on prefix a"),
+            &mut db,
+            &mut client
+        )
+        .unwrap()
+        .is_error());
+        assert!(
+            command(parser!(b"client caching yes"), &mut db, &mut client)
+                .unwrap()
+                .is_error()
+        );
+        assert_eq!(
+            command(
+                parser!(b"client tracking on redirect 1"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
             Response::Status("OK".to_owned())
         );
+
+        let mut other = Client::mock();
+        command(parser!(b"set key value"), &mut db, &mut other).unwrap();
+        command(parser!(b"get key"), &mut db, &mut client).unwrap();
+        assert!(rx.try_recv().is_err());
+        command(parser!(b"set key value2"), &mut db, &mut other).unwrap();
         assert_eq!(
-            command(parser!(b"flushdb"), &mut db, &mut client).unwrap(),
+            rx.try_recv().unwrap().unwrap(),
+            Response::Array(vec![
+                Response::Data(b"message".to_vec()),
+                Response::Data(b"__redis__:invalidate".to_vec()),
+                Response::Array(vec![Response::Data(b"key".to_vec())]),
+            ])
+        );
+        // the key is no longer tracked until it is read again
+        command(parser!(b"set key value3"), &mut db, &mut other).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        command(parser!(b"get key"), &mut db, &mut client).unwrap();
+        command(parser!(b"flushdb"), &mut db, &mut other).unwrap();
+        assert_eq!(
+            rx.try_recv().unwrap().unwrap(),
+            Response::Array(vec![
+                Response::Data(b"message".to_vec()),
+                Response::Data(b"__redis__:invalidate".to_vec()),
+                Response::Nil,
+            ])
+        );
+
+        assert_eq!(
+            command(parser!(b"client tracking off"), &mut db, &mut client).unwrap(),
             Response::Status("OK".to_owned())
         );
-        command(parser!(b"select 0"), &mut db, &mut client).unwrap();
+        command(parser!(b"get key"), &mut db, &mut client).unwrap();
+        command(parser!(b"set key value"), &mut db, &mut other).unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn client_tracking_optin() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let (tx, rx) = channel();
+        db.tracking().connect(1, tx);
+        let mut client = Client::new(channel().0, 2);
         assert_eq!(
-            command(parser!(b"get key"), &mut db, &mut client).unwrap(),
-            Response::Data("value".to_owned().into_bytes())
+            command(
+                parser!(b"client tracking on redirect 1 optin"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
+            Response::Status("OK".to_owned())
         );
-        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
+        assert!(command(parser!(b"client caching no"), &mut db, &mut client)
+            .unwrap()
+            .is_error());
+        command(parser!(b"get a"), &mut db, &mut client).unwrap();
+        command(parser!(b"client caching yes"), &mut db, &mut client).unwrap();
+        command(parser!(b"get b"), &mut db, &mut client).unwrap();
+
+        let mut other = Client::mock();
+        command(parser!(b"set a 1"), &mut db, &mut other).unwrap();
+        command(parser!(b"set b 1"), &mut db, &mut other).unwrap();
         assert_eq!(
-            command(parser!(b"get key"), &mut db, &mut client).unwrap(),
-            Response::Nil
+            rx.try_recv().unwrap().unwrap(),
+            Response::Array(vec![
+                Response::Data(b"message".to_vec()),
+                Response::Data(b"__redis__:invalidate".to_vec()),
+                Response::Array(vec![Response::Data(b"b".to_vec())]),
+            ])
         );
+        assert!(rx.try_recv().is_err());
     }
 
     #[test]
-    fn flushall_command() {
+    fn keyspace_notifications() {
+        let mut config = Config::new(Logger::new(Level::Warning));
+        config.notify_keyspace_events = keyspace_events_from_str("KEA").unwrap();
+        let mut db = Database::new(config);
+        let (tx, rx) = channel();
+        let mut client = Client::new(tx, 0);
+        assert!(command(parser!(b"psubscribe __key*__:*"), &mut db, &mut client).is_err());
+        rx.try_recv().unwrap().unwrap();
+
+        let expect = |channel: &[u8], message: &[u8]| {
+            assert_eq!(
+                rx.try_recv().unwrap().unwrap(),
+                Response::Array(vec![
+                    Response::Data(b"pmessage".to_vec()),
+                    Response::Data(b"__key*__:*".to_vec()),
+                    Response::Data(channel.to_vec()),
+                    Response::Data(message.to_vec()),
+                ])
+            );
+        };
+
+        let mut other = Client::mock();
+        command(parser!(b"set key value"), &mut db, &mut other).unwrap();
+        expect(b"__keyspace@0__:key", b"set");
+        expect(b"__keyevent@0__:set", b"key");
+
+        command(parser!(b"rpush list a"), &mut db, &mut other).unwrap();
+        expect(b"__keyspace@0__:list", b"rpush");
+        expect(b"__keyevent@0__:rpush", b"list");
+
+        command(parser!(b"rpop list"), &mut db, &mut other).unwrap();
+        expect(b"__keyspace@0__:list", b"rpop");
+        expect(b"__keyevent@0__:rpop", b"list");
+        expect(b"__keyspace@0__:list", b"del");
+        expect(b"__keyevent@0__:del", b"list");
+
+        command(parser!(b"expire key 100"), &mut db, &mut other).unwrap();
+        expect(b"__keyspace@0__:key", b"expire");
+        expect(b"__keyevent@0__:expire", b"key");
+
+        command(parser!(b"del key missing"), &mut db, &mut other).unwrap();
+        expect(b"__keyspace@0__:key", b"del");
+        expect(b"__keyevent@0__:del", b"key");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn auth_command() {
+        let mut config = Config::new(Logger::new(Level::Warning));
+        config.requirepass = Some("helloworld".to_owned());
+        let mut db = Database::new(config);
+        let mut client = Client::mock();
+        assert!(command(parser!(b"get key"), &mut db, &mut client)
+            .unwrap()
+            .is_error());
+        assert_eq!(client.auth, false);
+        assert!(command(parser!(b"auth channel"), &mut db, &mut client)
+            .unwrap()
+            .is_error());
+        assert_eq!(client.auth, false);
+        assert!(!command(parser!(b"auth helloworld"), &mut db, &mut client)
+            .unwrap()
+            .is_error());
+        assert_eq!(client.auth, true);
+    }
+
+    #[test]
+    fn auth_username_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
         let mut client = Client::mock();
-        command(parser!(b"select 0"), &mut db, &mut client).unwrap();
         assert_eq!(
-            command(parser!(b"set key value"), &mut db, &mut client).unwrap(),
+            command(
+                parser!(b"acl setuser user on >secret ~foo:* +get"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
             Response::Status("OK".to_owned())
         );
-        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
         assert_eq!(
-            command(parser!(b"set key valuf"), &mut db, &mut client).unwrap(),
-            Response::Status("OK".to_owned())
+            command(parser!(b"auth user wrong"), &mut db, &mut client).unwrap(),
+            Response::Error(
+                "WRONGPASS invalid username-password pair or user is disabled.".to_owned()
+            )
         );
         assert_eq!(
-            command(parser!(b"flushall"), &mut db, &mut client).unwrap(),
+            command(parser!(b"auth user secret"), &mut db, &mut client).unwrap(),
             Response::Status("OK".to_owned())
         );
-        command(parser!(b"select 0"), &mut db, &mut client).unwrap();
         assert_eq!(
-            command(parser!(b"get key"), &mut db, &mut client).unwrap(),
-            Response::Nil
+            command(parser!(b"acl whoami"), &mut db, &mut client).unwrap(),
+            Response::Error(
+                "NOPERM User user has no permissions to run the 'acl' command".to_owned()
+            )
         );
-        command(parser!(b"select 1"), &mut db, &mut client).unwrap();
         assert_eq!(
-            command(parser!(b"get key"), &mut db, &mut client).unwrap(),
+            command(parser!(b"get foo:1"), &mut db, &mut client).unwrap(),
             Response::Nil
         );
+        assert_eq!(
+            command(parser!(b"get bar"), &mut db, &mut client).unwrap(),
+            Response::Error("NOPERM No permissions to access a key".to_owned())
+        );
+
+        let mut admin = Client::mock();
+        match command(parser!(b"acl log"), &mut db, &mut admin).unwrap() {
+            Response::Array(entries) => {
+                assert_eq!(entries.len(), 3);
+                match entries[0] {
+                    Response::Array(ref entry) => {
+                        assert_eq!(entry[3], Response::Data(b"key".to_vec()));
+                        assert_eq!(entry[7], Response::Data(b"bar".to_vec()));
+                    }
+                    _ => panic!("Expected array"),
+                }
+            }
+            _ => panic!("Expected array"),
+        }
+        assert_eq!(
+            command(parser!(b"acl deluser user"), &mut db, &mut admin).unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(parser!(b"acl whoami"), &mut db, &mut client).unwrap(),
+            Response::Data(b"default".to_vec())
+        );
     }
 
     #[test]
-    fn subscribe_publish_command() {
+    fn acl_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
-        let (tx, rx) = channel();
-        let mut client = Client::new(tx, 0);
-        assert!(command(parser!(b"subscribe channel"), &mut db, &mut client).is_err());
+        let mut client = Client::mock();
         assert_eq!(
             command(
-                parser!(b"publish channel hello-world"),
+                parser!(b"acl setuser user &news.* -@all +publish"),
                 &mut db,
-                &mut Client::mock()
+                &mut client
             )
             .unwrap(),
-            Response::Integer(1)
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"acl setuser user +nothing"), &mut db, &mut client).unwrap(),
+            Response::Error(
+                "ERR Error in ACL SETUSER modifier '+nothing': Unknown command or category name \
+                 in ACL"
+                    .to_owned()
+            )
         );
-        assert!(command(parser!(b"unsubscribe channel"), &mut db, &mut client).is_err());
-
         assert_eq!(
-            rx.try_recv().unwrap().unwrap(),
+            command(parser!(b"acl getuser user"), &mut db, &mut client).unwrap(),
             Response::Array(vec![
-                Response::Data(b"subscribe".to_vec()),
-                Response::Data(b"channel".to_vec()),
-                Response::Integer(1),
+                Response::Data(b"flags".to_vec()),
+                Response::Array(vec![Response::Data(b"off".to_vec())]),
+                Response::Data(b"passwords".to_vec()),
+                Response::Array(vec![]),
+                Response::Data(b"commands".to_vec()),
+                Response::Data(b"-@all +publish".to_vec()),
+                Response::Data(b"keys".to_vec()),
+                Response::Data(b"".to_vec()),
+                Response::Data(b"channels".to_vec()),
+                Response::Data(b"&news.*".to_vec()),
             ])
         );
         assert_eq!(
-            rx.try_recv().unwrap().unwrap(),
-            Response::Array(vec![
-                Response::Data(b"message".to_vec()),
-                Response::Data(b"channel".to_vec()),
-                Response::Data(b"hello-world".to_vec()),
-            ])
+            command(parser!(b"acl getuser nobody"), &mut db, &mut client).unwrap(),
+            Response::Nil
         );
         assert_eq!(
-            rx.try_recv().unwrap().unwrap(),
+            command(parser!(b"acl list"), &mut db, &mut client).unwrap(),
             Response::Array(vec![
-                Response::Data(b"unsubscribe".to_vec()),
-                Response::Data(b"channel".to_vec()),
-                Response::Integer(0),
+                Response::Data(b"user default on nopass ~* &* +@all".to_vec()),
+                Response::Data(b"user user off &news.* -@all +publish".to_vec()),
             ])
         );
-    }
-
-    #[test]
-    fn auth_command() {
-        let mut config = Config::new(Logger::new(Level::Warning));
-        config.requirepass = Some("helloworld".to_owned());
-        let mut db = Database::new(config);
-        let mut client = Client::mock();
-        assert!(command(parser!(b"get key"), &mut db, &mut client)
-            .unwrap()
-            .is_error());
-        assert_eq!(client.auth, false);
-        assert!(command(parser!(b"auth channel"), &mut db, &mut client)
+        assert_eq!(
+            command(parser!(b"acl deluser default"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR The 'default' user cannot be removed".to_owned())
+        );
+        assert!(command(parser!(b"acl cat pubsub"), &mut db, &mut client)
             .unwrap()
-            .is_error());
-        assert_eq!(client.auth, false);
-        assert!(!command(parser!(b"auth helloworld"), &mut db, &mut client)
+            .as_bytes()
+            .windows(9)
+            .any(|w| w == b"subscribe"));
+        assert!(command(parser!(b"acl save"), &mut db, &mut client)
             .unwrap()
             .is_error());
-        assert_eq!(client.auth, true);
     }
 
     #[test]
@@ -5699,6 +10140,82 @@ mod test_command {
         );
     }
 
+    #[test]
+    fn restore_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut client = Client::mock();
+        command(parser!(b"rpush list a b 1"), &mut db, &mut client).unwrap();
+        let payload = match command(parser!(b"dump list"), &mut db, &mut client).unwrap() {
+            Response::Data(payload) => payload,
+            _ => panic!("Expected data"),
+        };
+        let restore = |key: &[u8], ttl: &[u8], options: &[&[u8]]| {
+            let mut args = vec![b"restore".to_vec(), key.to_vec(), ttl.to_vec()];
+            args.push(payload.clone());
+            args.extend(options.iter().map(|option| option.to_vec()));
+            ParsedCommand::from_args(args)
+        };
+
+        assert_eq!(
+            command(restore(b"list", b"0", &[]), &mut db, &mut client).unwrap(),
+            Response::Error("BUSYKEY Target key name already exists.".to_owned())
+        );
+        assert_eq!(
+            command(restore(b"copy", b"-1", &[]), &mut db, &mut client).unwrap(),
+            Response::Error("ERR Invalid TTL value, must be >= 0".to_owned())
+        );
+        assert_eq!(
+            command(restore(b"copy", b"100000", &[]), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"lrange copy 0 -1"), &mut db, &mut client).unwrap(),
+            Response::Array(vec![
+                Response::Data(b"a".to_vec()),
+                Response::Data(b"b".to_vec()),
+                Response::Data(b"1".to_vec()),
+            ])
+        );
+        match command(parser!(b"pttl copy"), &mut db, &mut client).unwrap() {
+            Response::Integer(i) => assert!(i <= 100 * 1000 && i > 80 * 1000),
+            _ => panic!("Expected integer"),
+        }
+
+        command(parser!(b"set list value"), &mut db, &mut client).unwrap();
+        assert_eq!(
+            command(restore(b"list", b"0", &[]), &mut db, &mut client).unwrap(),
+            Response::Error("BUSYKEY Target key name already exists.".to_owned())
+        );
+        assert_eq!(
+            command(restore(b"list", b"0", &[b"REPLACE"]), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"llen list"), &mut db, &mut client).unwrap(),
+            Response::Integer(3)
+        );
+
+        // an absolute time in the past creates nothing
+        assert_eq!(
+            command(
+                restore(b"expired", b"1", &[b"ABSTTL"]),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"exists expired"), &mut db, &mut client).unwrap(),
+            Response::Integer(0)
+        );
+
+        assert_eq!(
+            command(parser!(b"restore key 0 invalid"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR DUMP payload version or checksum are wrong".to_owned())
+        );
+    }
+
     #[test]
     fn keys_command() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
@@ -5921,6 +10438,8 @@ mod test_command {
         let (tx, rx) = channel();
         let mut client1 = Client::new(tx, 0);
         let mut client2 = Client::mock();
+        client2.addr = "127.0.0.1:6380".to_owned();
+        client2.dbindex = 1;
         assert_eq!(
             command(parser!(b"monitor"), &mut db, &mut client1).unwrap(),
             Response::Status("OK".to_owned())
@@ -5929,10 +10448,25 @@ mod test_command {
             command(parser!(b"get key"), &mut db, &mut client2).unwrap(),
             Response::Nil
         );
-        assert_eq!(
-            rx.recv().unwrap(),
-            Some(Response::Status("\"get\" \"key\" ".to_owned()))
-        );
+        command(parser!(b"auth user secret"), &mut db, &mut client2).unwrap();
+        command(parser!(b"multi"), &mut db, &mut client2).unwrap();
+        command(parser!(b"get key"), &mut db, &mut client2).unwrap();
+        command(parser!(b"exec"), &mut db, &mut client2).unwrap();
+
+        for expected in &[
+            "\"get\" \"key\"",
+            "\"auth\" \"(redacted)\" \"(redacted)\"",
+            "\"multi\"",
+            "\"get\" \"key\"",
+        ] {
+            match rx.recv().unwrap() {
+                Some(Response::Status(line)) => {
+                    let (_timestamp, rest) = line.split_at(line.find(' ').unwrap());
+                    assert_eq!(rest, format!(" [1 127.0.0.1:6380] {}", expected));
+                }
+                r => panic!("Unexpected monitor line {:?}", r),
+            }
+        }
         assert!(rx.try_recv().is_err());
     }
 
@@ -5944,8 +10478,298 @@ mod test_command {
             let s = from_utf8(&*d).unwrap();
             assert!(s.contains("rsedis_git_sha1"));
             assert!(s.contains("rsedis_git_dirty"));
+            assert!(s.contains("lazyfree_pending_objects:0"));
         } else {
             panic!("Expected data");
         }
     }
+
+    #[test]
+    fn shutdown() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let (tx, rx) = channel();
+        db.set_shutdown_sender(tx);
+        let mut client = Client::mock();
+        assert_eq!(
+            command(parser!(b"shutdown save nosave"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR syntax error".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"shutdown abort"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR No shutdown in progress.".to_owned())
+        );
+        assert!(rx.try_recv().is_err());
+        match command(parser!(b"shutdown nosave now"), &mut db, &mut client).unwrap_err() {
+            ResponseError::NoReply => (),
+            e => panic!("Unexpected error {:?}", e),
+        };
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn sentinel_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut client = Client::mock();
+        assert_eq!(
+            command(parser!(b"sentinel masters"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR This instance is not running in sentinel mode".to_owned())
+        );
+
+        *db.sentinel() = Some(Sentinel::new(26379));
+        assert_eq!(
+            command(parser!(b"get key"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR unknown command \"get\"".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"sentinel monitor mymaster 127.0.0.1 6379 2"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"sentinel monitor other 127.0.0.1 6380 0"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
+            Response::Error("ERR Quorum must be 1 or greater.".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"sentinel set mymaster down-after-milliseconds 1000 quorum 1"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        match command(parser!(b"sentinel master mymaster"), &mut db, &mut client).unwrap() {
+            Response::Array(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|field| match field {
+                        Response::Data(data) => from_utf8(data).unwrap(),
+                        r => panic!("unexpected field {:?}", r),
+                    })
+                    .collect::<Vec<_>>();
+                let field = |name: &str| {
+                    fields
+                        .chunks(2)
+                        .find(|pair| pair[0] == name)
+                        .map(|pair| pair[1])
+                };
+                assert_eq!(field("name"), Some("mymaster"));
+                assert_eq!(field("quorum"), Some("1"));
+                assert_eq!(field("down-after-milliseconds"), Some("1000"));
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+        assert_eq!(
+            command(
+                parser!(b"sentinel get-master-addr-by-name mymaster"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
+            Response::Array(vec![
+                Response::Data(b"127.0.0.1".to_vec()),
+                Response::Data(b"6379".to_vec()),
+            ])
+        );
+        assert_eq!(
+            command(parser!(b"sentinel replicas mymaster"), &mut db, &mut client).unwrap(),
+            Response::Array(vec![])
+        );
+        assert_eq!(
+            command(parser!(b"sentinel failover mymaster"), &mut db, &mut client).unwrap(),
+            Response::Error("NOGOODSLAVE No suitable replica to promote".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"sentinel remove mymaster"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"sentinel get-master-addr-by-name mymaster"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
+            Response::Nil
+        );
+    }
+
+    #[test]
+    fn cluster_command() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut client = Client::mock();
+        assert_eq!(
+            command(parser!(b"cluster info"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR This instance has cluster support disabled".to_owned())
+        );
+
+        let mut path = temp_dir();
+        path.push("cluster_command-nodes.conf");
+        db.config.cluster_config_file = path.to_str().unwrap().to_owned();
+        let mut myself = Cluster::new(6379, 15000);
+        let mut other = Cluster::new(6380, 15000);
+        other
+            .add_slots(&(8192..CLUSTER_SLOTS).collect::<Vec<_>>())
+            .unwrap();
+        myself
+            .process_message(&other.message("MEET"), "127.0.0.1", "127.0.0.1", None, 0)
+            .unwrap();
+        let other_id = other.myself().id.clone();
+        *db.cluster() = Some(myself);
+
+        assert_eq!(
+            command(
+                parser!(b"cluster keyslot {user1000}.following"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
+            Response::Integer(3443)
+        );
+        assert_eq!(
+            command(parser!(b"cluster addslots 0 3443"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"cluster addslots 3443"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR Slot 3443 is already busy".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"cluster addslots 16384"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR Invalid or out of range slot".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"set user1000 1"), &mut db, &mut client).unwrap(),
+            Response::Error("CLUSTERDOWN The cluster is down".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"cluster delslots 0"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        db.cluster()
+            .as_mut()
+            .unwrap()
+            .add_slots(&(0..8192).filter(|slot| *slot != 3443).collect::<Vec<_>>())
+            .unwrap();
+
+        assert_eq!(
+            command(parser!(b"set foo 1"), &mut db, &mut client).unwrap(),
+            Response::Error("MOVED 12182 127.0.0.1:6380".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"set user1000 1"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"sadd {user1000}a 1"), &mut db, &mut client).unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(
+                parser!(b"sunionstore {user1000}b {user1000}a"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            command(parser!(b"mget a b"), &mut db, &mut client).unwrap(),
+            Response::Error("CROSSSLOT Keys in request don't hash to the same slot".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"ping"), &mut db, &mut client).unwrap(),
+            Response::Status("PONG".to_owned())
+        );
+        assert_eq!(
+            command(
+                parser!(b"cluster countkeysinslot 3443"),
+                &mut db,
+                &mut client
+            )
+            .unwrap(),
+            Response::Integer(3)
+        );
+        match command(
+            parser!(b"cluster getkeysinslot 3443 1"),
+            &mut db,
+            &mut client,
+        )
+        .unwrap()
+        {
+            Response::Array(keys) => assert_eq!(keys.len(), 1),
+            r => panic!("Expected array, got {:?}", r),
+        }
+        assert_eq!(
+            command(parser!(b"select 1"), &mut db, &mut client).unwrap(),
+            Response::Error("ERR SELECT is not allowed in cluster mode".to_owned())
+        );
+
+        // moving slot 3443 out
+        let setslot = format!("cluster setslot 3443 migrating {}", other_id);
+        assert_eq!(
+            command(parser!(setslot.as_bytes()), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"get user1000"), &mut db, &mut client).unwrap(),
+            Response::Data(b"1".to_vec())
+        );
+        assert_eq!(
+            command(parser!(b"get {user1000}c"), &mut db, &mut client).unwrap(),
+            Response::Error("ASK 3443 127.0.0.1:6380".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"mget user1000 {user1000}c"), &mut db, &mut client).unwrap(),
+            Response::Error("TRYAGAIN Multiple keys request during rehashing of slot".to_owned())
+        );
+        let setslot = format!("cluster setslot 3443 node {}", other_id);
+        assert_eq!(
+            command(parser!(setslot.as_bytes()), &mut db, &mut client).unwrap(),
+            Response::Error(
+                "ERR Can't assign hashslot 3443 to a different node while I still hold keys \
+                 for this hash slot."
+                    .to_owned()
+            )
+        );
+
+        // moving slot 12182 in
+        let setslot = format!("cluster setslot 12182 importing {}", other_id);
+        assert_eq!(
+            command(parser!(setslot.as_bytes()), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"asking"), &mut db, &mut client).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            command(parser!(b"get foo"), &mut db, &mut client).unwrap(),
+            Response::Nil
+        );
+        assert_eq!(
+            command(parser!(b"get foo"), &mut db, &mut client).unwrap(),
+            Response::Error("MOVED 12182 127.0.0.1:6380".to_owned())
+        );
+
+        match command(parser!(b"cluster info"), &mut db, &mut client).unwrap() {
+            Response::Data(d) => {
+                let s = from_utf8(&d).unwrap();
+                assert!(s.contains("cluster_state:ok\r\n"));
+                assert!(s.contains("cluster_known_nodes:2\r\n"));
+                assert!(s.contains("cluster_size:2\r\n"));
+            }
+            r => panic!("Expected data, got {:?}", r),
+        }
+        let _ = remove_file(&path);
+    }
 }
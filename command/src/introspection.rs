@@ -0,0 +1,742 @@
+use database::Database;
+use parser::ParsedCommand;
+use response::Response;
+use util::glob_match;
+
+use crate::acl::{command_categories, command_key_positions, has_movable_keys, numkeys_position};
+use crate::command::{command_properties, CommandFlags, COMMAND_NAMES};
+
+/// Names of the command flags, as reported by `COMMAND INFO`.
+const FLAG_NAMES: &[(CommandFlags, &str)] = &[
+    (CommandFlags::WRITE, "write"),
+    (CommandFlags::READONLY, "readonly"),
+    (CommandFlags::DENYOOM, "denyoom"),
+    (CommandFlags::ADMIN, "admin"),
+    (CommandFlags::PUBSUB, "pubsub"),
+    (CommandFlags::NOSCRIPT, "noscript"),
+    (CommandFlags::RANDOM, "random"),
+    (CommandFlags::SORT_FOR_SCRIPT, "sort_for_script"),
+    (CommandFlags::LOADING, "loading"),
+    (CommandFlags::STALE, "stale"),
+    (CommandFlags::SKIP_MONITOR, "skip_monitor"),
+    (CommandFlags::ASKING, "asking"),
+    (CommandFlags::FAST, "fast"),
+    (CommandFlags::BLOCKING, "blocking"),
+];
+
+/// The group and a one line summary of every command, as reported by
+/// `COMMAND DOCS`.
+const DOCS: &[(&str, &str, &str)] = &[
+    ("get", "string", "Returns the string value of a key."),
+    ("getex", "string", "Returns the string value of a key after setting its expiration time."),
+    ("getdel", "string", "Returns the string value of a key after deleting the key."),
+    ("set", "string", "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist."),
+    ("setnx", "string", "Sets the string value of a key only when the key doesn't exist."),
+    ("setex", "string", "Sets the string value and expiration time of a key. Creates the key if it doesn't exist."),
+    ("psetex", "string", "Sets both string value and expiration time in milliseconds of a key. The key is created if it doesn't exist."),
+    ("append", "string", "Appends a string to the value of a key. Creates the key if it doesn't exist."),
+    ("strlen", "string", "Returns the length of a string value."),
+    ("del", "generic", "Deletes one or more keys."),
+    ("unlink", "generic", "Asynchronously deletes one or more keys."),
+    ("exists", "generic", "Determines whether one or more keys exist."),
+    ("setbit", "bitmap", "Sets or clears the bit at offset of the string value. Creates the key if it doesn't exist."),
+    ("getbit", "bitmap", "Returns a bit value by offset."),
+    ("setrange", "string", "Overwrites a part of a string value with another by an offset. Creates the key if it doesn't exist."),
+    ("getrange", "string", "Returns a substring of the string stored at a key."),
+    ("lcs", "string", "Finds the longest common substring."),
+    ("substr", "string", "Returns a substring from a string value."),
+    ("incr", "string", "Increments the integer value of a key by one. Uses 0 as initial value if the key doesn't exist."),
+    ("decr", "string", "Decrements the integer value of a key by one. Uses 0 as initial value if the key doesn't exist."),
+    ("mget", "string", "Atomically returns the string values of one or more keys."),
+    ("rpush", "list", "Appends one or more elements to a list. Creates the key if it doesn't exist."),
+    ("lpush", "list", "Prepends one or more elements to a list. Creates the key if it doesn't exist."),
+    ("rpushx", "list", "Appends an element to a list only when the list exists."),
+    ("lpushx", "list", "Prepends one or more elements to a list only when the list exists."),
+    ("linsert", "list", "Inserts an element before or after another element in a list."),
+    ("rpop", "list", "Returns and removes the last elements of a list. Deletes the list if the last element was popped."),
+    ("lpop", "list", "Returns the first elements in a list after removing it. Deletes the list if the last element was popped."),
+    ("rpoplpush", "list", "Returns the last element of a list after removing and pushing it to another list. Deletes the list if the last element was popped."),
+    ("brpop", "list", "Removes and returns the last element in a list. Blocks until an element is available otherwise. Deletes the list if the last element was popped."),
+    ("blpop", "list", "Removes and returns the first element in a list. Blocks until an element is available otherwise. Deletes the list if the last element was popped."),
+    ("brpoplpush", "list", "Pops an element from a list, pushes it to another list and returns it. Blocks until an element is available otherwise. Deletes the list if the last element was popped."),
+    ("lmove", "list", "Returns an element after popping it from one list and pushing it to another. Deletes the list if the last element was moved."),
+    ("blmove", "list", "Pops an element from a list, pushes it to another list and returns it. Blocks until an element is available otherwise. Deletes the list if the last element was moved."),
+    ("lmpop", "list", "Returns multiple elements from a list after removing them. Deletes the list if the last element was popped."),
+    ("blmpop", "list", "Pops the first element from one of multiple lists. Blocks until an element is available otherwise. Deletes the list if the last element was popped."),
+    ("lpos", "list", "Returns the index of matching elements in a list."),
+    ("llen", "list", "Returns the length of a list."),
+    ("lindex", "list", "Returns an element from a list by its index."),
+    ("lset", "list", "Sets the value of an element in a list by its index."),
+    ("lrange", "list", "Returns a range of elements from a list."),
+    ("ltrim", "list", "Removes elements from both ends a list. Deletes the list if all elements were trimmed."),
+    ("lrem", "list", "Removes elements from a list. Deletes the list if the last element was removed."),
+    ("sadd", "set", "Adds one or more members to a set. Creates the key if it doesn't exist."),
+    ("srem", "set", "Removes one or more members from a set. Deletes the set if the last member was removed."),
+    ("smove", "set", "Moves a member from one set to another."),
+    ("sismember", "set", "Determines whether a member belongs to a set."),
+    ("smismember", "set", "Determines whether multiple members belong to a set."),
+    ("scard", "set", "Returns the number of members in a set."),
+    ("spop", "set", "Returns one or more random members from a set after removing them. Deletes the set if the last member was popped."),
+    ("srandmember", "set", "Gets one or multiple random members from a set."),
+    ("sinter", "set", "Returns the intersect of multiple sets."),
+    ("sinterstore", "set", "Stores the intersect of multiple sets in a key."),
+    ("sintercard", "set", "Returns the number of members of the intersect of multiple sets."),
+    ("sunion", "set", "Returns the union of multiple sets."),
+    ("sunionstore", "set", "Stores the union of multiple sets in a key."),
+    ("sdiff", "set", "Returns the difference of multiple sets."),
+    ("sdiffstore", "set", "Stores the difference of multiple sets in a key."),
+    ("smembers", "set", "Returns all members of a set."),
+    ("sscan", "set", "Iterates over members of a set."),
+    ("zadd", "sorted-set", "Adds one or more members to a sorted set, or updates their scores. Creates the key if it doesn't exist."),
+    ("zincrby", "sorted-set", "Increments the score of a member in a sorted set."),
+    ("zrem", "sorted-set", "Removes one or more members from a sorted set. Deletes the sorted set if all members were removed."),
+    ("zremrangebyscore", "sorted-set", "Removes members in a sorted set within a range of scores. Deletes the sorted set if all members were removed."),
+    ("zremrangebyrank", "sorted-set", "Removes members in a sorted set within a range of indexes. Deletes the sorted set if all members were removed."),
+    ("zremrangebylex", "sorted-set", "Removes members in a sorted set within a lexicographical range. Deletes the sorted set if all members were removed."),
+    ("zunionstore", "sorted-set", "Stores the union of multiple sorted sets in a key."),
+    ("zinterstore", "sorted-set", "Stores the intersect of multiple sorted sets in a key."),
+    ("zunion", "sorted-set", "Returns the union of multiple sorted sets."),
+    ("zinter", "sorted-set", "Returns the intersect of multiple sorted sets."),
+    ("zdiff", "sorted-set", "Returns the difference between multiple sorted sets."),
+    ("zdiffstore", "sorted-set", "Stores the difference of multiple sorted sets in a key."),
+    ("zintercard", "sorted-set", "Returns the number of members of the intersect of multiple sorted sets."),
+    ("zmscore", "sorted-set", "Returns the score of one or more members in a sorted set."),
+    ("zrandmember", "sorted-set", "Returns one or more random members from a sorted set."),
+    ("zpopmin", "sorted-set", "Returns the lowest-scoring members from a sorted set after removing them. Deletes the sorted set if the last member was popped."),
+    ("zpopmax", "sorted-set", "Returns the highest-scoring members from a sorted set after removing them. Deletes the sorted set if the last member was popped."),
+    ("bzpopmin", "sorted-set", "Removes and returns the member with the lowest score from one or more sorted sets. Blocks until a member is available otherwise. Deletes the sorted set if the last element was popped."),
+    ("bzpopmax", "sorted-set", "Removes and returns the member with the highest score from one or more sorted sets. Blocks until a member is available otherwise. Deletes the sorted set if the last element was popped."),
+    ("zmpop", "sorted-set", "Returns the highest- or lowest-scoring members from one or more sorted sets after removing them. Deletes the sorted set if the last member was popped."),
+    ("bzmpop", "sorted-set", "Removes and returns a member by score from one or more sorted sets. Blocks until a member is available otherwise. Deletes the sorted set if the last element was popped."),
+    ("zrange", "sorted-set", "Returns members in a sorted set within a range of indexes."),
+    ("zrevrange", "sorted-set", "Returns members in a sorted set within a range of indexes in reverse order."),
+    ("zrangebyscore", "sorted-set", "Returns members in a sorted set within a range of scores."),
+    ("zrevrangebyscore", "sorted-set", "Returns members in a sorted set within a range of scores in reverse order."),
+    ("zrangebylex", "sorted-set", "Returns members in a sorted set within a lexicographical range."),
+    ("zrevrangebylex", "sorted-set", "Returns members in a sorted set within a lexicographical range in reverse order."),
+    ("zrangestore", "sorted-set", "Stores a range of members from sorted set in a key."),
+    ("zcount", "sorted-set", "Returns the count of members in a sorted set that have scores within a range."),
+    ("zlexcount", "sorted-set", "Returns the number of members in a sorted set within a lexicographical range."),
+    ("zcard", "sorted-set", "Returns the number of members in a sorted set."),
+    ("zscore", "sorted-set", "Returns the score of a member in a sorted set."),
+    ("zrank", "sorted-set", "Returns the index of a member in a sorted set ordered by ascending scores."),
+    ("zrevrank", "sorted-set", "Returns the index of a member in a sorted set ordered by descending scores."),
+    ("zscan", "sorted-set", "Iterates over members and scores of a sorted set."),
+    ("hset", "hash", "Creates or modifies the value of a field in a hash."),
+    ("hsetnx", "hash", "Sets the value of a field in a hash only when the field doesn't exist."),
+    ("hget", "hash", "Returns the value of a field in a hash."),
+    ("hmset", "hash", "Sets the values of multiple fields."),
+    ("hmget", "hash", "Returns the values of all fields in a hash."),
+    ("hincrby", "hash", "Increments the integer value of a field in a hash by a number. Uses 0 as initial value if the field doesn't exist."),
+    ("hincrbyfloat", "hash", "Increments the floating point value of a field by a number. Uses 0 as initial value if the field doesn't exist."),
+    ("hdel", "hash", "Deletes one or more fields and their values from a hash. Deletes the hash if no fields remain."),
+    ("hlen", "hash", "Returns the number of fields in a hash."),
+    ("hstrlen", "hash", "Returns the length of the value of a field."),
+    ("hkeys", "hash", "Returns all fields in a hash."),
+    ("hvals", "hash", "Returns all values in a hash."),
+    ("hgetall", "hash", "Returns all fields and values in a hash."),
+    ("hexists", "hash", "Determines whether a field exists in a hash."),
+    ("hscan", "hash", "Iterates over fields and values of a hash."),
+    ("incrby", "string", "Increments the integer value of a key by a number. Uses 0 as initial value if the key doesn't exist."),
+    ("decrby", "string", "Decrements a number from the integer value of a key. Uses 0 as initial value if the key doesn't exist."),
+    ("incrbyfloat", "string", "Increment the floating point value of a key by a number. Uses 0 as initial value if the key doesn't exist."),
+    ("getset", "string", "Returns the previous string value of a key after setting it to a new value."),
+    ("mset", "string", "Atomically creates or modifies the string values of one or more keys."),
+    ("msetnx", "string", "Atomically modifies the string values of one or more keys only when all keys don't exist."),
+    ("randomkey", "generic", "Returns a random key name from the database."),
+    ("select", "connection", "Changes the selected database."),
+    ("move", "generic", "Moves a key to another database."),
+    ("rename", "generic", "Renames a key and overwrites the destination."),
+    ("renamenx", "generic", "Renames a key only when the target key name doesn't exist."),
+    ("expire", "generic", "Sets the expiration time of a key in seconds."),
+    ("expireat", "generic", "Sets the expiration time of a key to a Unix timestamp."),
+    ("pexpire", "generic", "Sets the expiration time of a key in milliseconds."),
+    ("pexpireat", "generic", "Sets the expiration time of a key to a Unix milliseconds timestamp."),
+    ("keys", "generic", "Returns all key names that match a pattern."),
+    ("scan", "generic", "Iterates over the key names in the database."),
+    ("dbsize", "server", "Returns the number of keys in the database."),
+    ("auth", "connection", "Authenticates the connection."),
+    ("acl", "server", "A container for Access List Control commands."),
+    ("ping", "connection", "Returns the server's liveliness response."),
+    ("echo", "connection", "Returns the given string."),
+    ("save", "server", "Synchronously saves the database(s) to disk."),
+    ("bgsave", "server", "Asynchronously saves the database(s) to disk."),
+    ("bgrewriteaof", "server", "Asynchronously rewrites the append-only file to disk."),
+    ("shutdown", "server", "Synchronously saves the database(s) to disk and shuts down the server."),
+    ("lastsave", "server", "Returns the Unix timestamp of the last successful save to disk."),
+    ("type", "generic", "Determines the type of value stored at a key."),
+    ("multi", "transactions", "Starts a transaction."),
+    ("exec", "transactions", "Executes all commands in a transaction."),
+    ("discard", "transactions", "Discards a transaction."),
+    ("sync", "server", "An internal command used in replication."),
+    ("psync", "server", "An internal command used in replication."),
+    ("replconf", "server", "An internal command for configuring the replication stream."),
+    ("flushdb", "server", "Removes all keys from the current database."),
+    ("flushall", "server", "Removes all keys from all databases."),
+    ("sort", "generic", "Sorts the elements in a list, a set, or a sorted set, optionally storing the result."),
+    ("info", "server", "Returns information and statistics about the server."),
+    ("monitor", "server", "Listens for all requests received by the server in real-time."),
+    ("ttl", "generic", "Returns the expiration time in seconds of a key."),
+    ("pttl", "generic", "Returns the expiration time in milliseconds of a key."),
+    ("expiretime", "generic", "Returns the expiration time of a key as a Unix timestamp."),
+    ("pexpiretime", "generic", "Returns the expiration time of a key as a Unix milliseconds timestamp."),
+    ("persist", "generic", "Removes the expiration time of a key."),
+    ("slaveof", "server", "Sets the server as a replica of another, or promotes it to being a master."),
+    ("role", "server", "Returns the replication role."),
+    ("debug", "server", "A container for debugging commands."),
+    ("config", "server", "A container for server configuration commands."),
+    ("subscribe", "pubsub", "Listens for messages published to channels."),
+    ("unsubscribe", "pubsub", "Stops listening to messages posted to channels."),
+    ("psubscribe", "pubsub", "Listens for messages published to channels that match one or more patterns."),
+    ("punsubscribe", "pubsub", "Stops listening to messages published to channels that match one or more patterns."),
+    ("publish", "pubsub", "Posts a message to a channel."),
+    ("pubsub", "pubsub", "A container for Pub/Sub commands."),
+    ("ssubscribe", "pubsub", "Listens for messages published to shard channels."),
+    ("sunsubscribe", "pubsub", "Stops listening to messages posted to shard channels."),
+    ("spublish", "pubsub", "Posts a message to a shard channel."),
+    ("watch", "transactions", "Monitors changes to keys to determine the execution of a transaction."),
+    ("unwatch", "transactions", "Forgets about watched keys of a transaction."),
+    ("cluster", "cluster", "A container for cluster commands."),
+    ("restore", "generic", "Creates a key from the serialized representation of a value."),
+    ("migrate", "generic", "Atomically transfers a key to another server."),
+    ("asking", "cluster", "Signals that a cluster client is following an -ASK redirect."),
+    ("readonly", "cluster", "Enables read-only queries for a connection to a cluster replica node."),
+    ("readwrite", "cluster", "Enables read-write queries for a connection to a cluster replica node."),
+    ("sentinel", "sentinel", "A container for sentinel commands."),
+    ("dump", "generic", "Returns a serialized representation of the value stored at a key."),
+    ("object", "generic", "A container for object introspection commands."),
+    ("memory", "server", "A container for memory diagnostics commands."),
+    ("client", "connection", "A container for client connection commands."),
+    ("eval", "scripting", "Executes a server-side Lua script."),
+    ("evalsha", "scripting", "Executes a server-side Lua script by SHA1 digest."),
+    ("slowlog", "server", "A container for slow log commands."),
+    ("script", "scripting", "A container for Lua scripts management commands."),
+    ("time", "server", "Returns the server time."),
+    ("bitop", "bitmap", "Performs bitwise operations on multiple strings, and stores the result."),
+    ("bitcount", "bitmap", "Counts the number of set bits (population counting) in a string."),
+    ("bitpos", "bitmap", "Finds the first set (1) or clear (0) bit in a string."),
+    ("wait", "generic", "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed."),
+    ("command", "server", "Returns detailed information about all commands."),
+    ("geoadd", "geo", "Adds one or more members to a geospatial index. The key is created if it doesn't exist."),
+    ("georadius", "geo", "Queries a geospatial index for members within a distance from a coordinate, optionally stores the result."),
+    ("georadiusbymember", "geo", "Queries a geospatial index for members within a distance from a member, optionally stores the result."),
+    ("geohash", "geo", "Returns members from a geospatial index as geohash strings."),
+    ("geopos", "geo", "Returns the longitude and latitude of members from a geospatial index."),
+    ("geodist", "geo", "Returns the distance between two members of a geospatial index."),
+    ("pfselftest", "hyperloglog", "An internal command for testing HyperLogLog values."),
+    ("pfadd", "hyperloglog", "Adds elements to a HyperLogLog key. Creates the key if it doesn't exist."),
+    ("pfcount", "hyperloglog", "Returns the approximated cardinality of the set(s) observed by the HyperLogLog key(s)."),
+    ("pfmerge", "hyperloglog", "Merges one or more HyperLogLog values into a single key."),
+    ("pfdebug", "hyperloglog", "Internal commands for debugging HyperLogLog values."),
+    ("latency", "server", "A container for latency diagnostics commands."),
+];
+
+/// The name clients use for a command, if it was not disabled with
+/// `rename-command`.
+fn visible_name(db: &Database, name: &str) -> Option<String> {
+    if db.mapped_command(name).as_deref() == Some(name) {
+        return Some(name.to_owned());
+    }
+    db.config
+        .rename_commands
+        .iter()
+        .find(|(_, command)| command.as_deref() == Some(name))
+        .map(|(newname, _)| newname.clone())
+}
+
+/// Every command as `(visible name, name)`.
+fn commands(db: &Database) -> Vec<(String, &'static str)> {
+    COMMAND_NAMES
+        .iter()
+        .filter_map(|name| visible_name(db, name).map(|visible| (visible, *name)))
+        .collect()
+}
+
+/// Maps a name sent by a client to a command.
+fn lookup(db: &Database, name: &str) -> Option<&'static str> {
+    let name = db.mapped_command(&name.to_ascii_lowercase())?;
+    COMMAND_NAMES.iter().find(|c| **c == name).cloned()
+}
+
+fn data(s: &str) -> Response {
+    Response::Data(s.as_bytes().to_vec())
+}
+
+/// A key specification, as a flat list of fields.
+fn key_spec(write: bool, begin_search: Vec<Response>, find_keys: Vec<Response>) -> Response {
+    Response::Array(vec![
+        data("flags"),
+        Response::Array(vec![Response::Status(
+            if write { "RW" } else { "RO" }.to_owned(),
+        )]),
+        data("begin_search"),
+        Response::Array(begin_search),
+        data("find_keys"),
+        Response::Array(find_keys),
+    ])
+}
+
+fn begin_index(index: i64) -> Vec<Response> {
+    vec![
+        data("type"),
+        data("index"),
+        data("spec"),
+        Response::Array(vec![data("index"), Response::Integer(index)]),
+    ]
+}
+
+fn find_range(lastkey: i64, keystep: i64) -> Vec<Response> {
+    vec![
+        data("type"),
+        data("range"),
+        data("spec"),
+        Response::Array(vec![
+            data("lastkey"),
+            Response::Integer(lastkey),
+            data("keystep"),
+            Response::Integer(keystep),
+            data("limit"),
+            Response::Integer(0),
+        ]),
+    ]
+}
+
+/// Where the keys of a command are, derived from its properties.
+fn key_specs(name: &str) -> Vec<Response> {
+    let properties = command_properties(name);
+    let write = properties.flags.contains(CommandFlags::WRITE);
+    let mut specs = vec![];
+    if name == "migrate" {
+        specs.push(key_spec(write, begin_index(3), find_range(0, 1)));
+        specs.push(key_spec(
+            write,
+            vec![
+                data("type"),
+                data("keyword"),
+                data("spec"),
+                Response::Array(vec![
+                    data("keyword"),
+                    data("KEYS"),
+                    data("startfrom"),
+                    Response::Integer(-2),
+                ]),
+            ],
+            find_range(-1, 1),
+        ));
+        return specs;
+    }
+    if properties.first_key_index > 0 {
+        let lastkey = if properties.last_key_index < 0 {
+            properties.last_key_index
+        } else {
+            properties.last_key_index - properties.first_key_index
+        };
+        specs.push(key_spec(
+            write,
+            begin_index(properties.first_key_index),
+            find_range(lastkey, properties.key_step),
+        ));
+    }
+    if let Some(pos) = numkeys_position(name) {
+        specs.push(key_spec(
+            write,
+            begin_index(pos as i64),
+            vec![
+                data("type"),
+                data("keynum"),
+                data("spec"),
+                Response::Array(vec![
+                    data("keynumidx"),
+                    Response::Integer(0),
+                    data("firstkey"),
+                    Response::Integer(1),
+                    data("keystep"),
+                    Response::Integer(1),
+                ]),
+            ],
+        ));
+    }
+    specs
+}
+
+fn flags(name: &str) -> Response {
+    let properties = command_properties(name);
+    let mut flags = FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| properties.flags.contains(*flag))
+        .map(|(_, flag)| Response::Status((*flag).to_owned()))
+        .collect::<Vec<_>>();
+    if has_movable_keys(name) {
+        flags.push(Response::Status("movablekeys".to_owned()));
+    }
+    Response::Array(flags)
+}
+
+/// A command description in the shape of Redis' `COMMAND INFO`: name,
+/// arity, flags, first key, last key, step, ACL categories, tips, key
+/// specifications and subcommands.
+fn command_info(visible: &str, name: &str) -> Response {
+    let properties = command_properties(name);
+    Response::Array(vec![
+        data(visible),
+        Response::Integer(properties.arity),
+        flags(name),
+        Response::Integer(properties.first_key_index),
+        Response::Integer(properties.last_key_index),
+        Response::Integer(properties.key_step),
+        Response::Array(
+            command_categories(properties.flags)
+                .into_iter()
+                .map(|category| Response::Status(format!("@{}", category)))
+                .collect(),
+        ),
+        Response::Array(vec![]),
+        Response::Array(key_specs(name)),
+        Response::Array(vec![]),
+    ])
+}
+
+/// A command documentation as a map of summary, arity, flags and group.
+fn command_docs(name: &str) -> Response {
+    let (group, summary) = DOCS
+        .iter()
+        .find(|(command, _, _)| *command == name)
+        .map_or(("", ""), |&(_, group, summary)| (group, summary));
+    Response::Array(vec![
+        data("summary"),
+        data(summary),
+        data("arity"),
+        Response::Integer(command_properties(name).arity),
+        data("flags"),
+        flags(name),
+        data("group"),
+        data(group),
+    ])
+}
+
+fn getkeys(parser: &ParsedCommand, db: &Database) -> Response {
+    let args = match (2..parser.argv.len())
+        .map(|i| parser.get_vec(i))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(args) => args,
+        Err(_) => return Response::Error("ERR Invalid command specified".to_owned()),
+    };
+    let command = ParsedCommand::from_args(args);
+    let name = match command.get_str(0).ok().and_then(|name| lookup(db, name)) {
+        Some(name) => name,
+        None => return Response::Error("ERR Invalid command specified".to_owned()),
+    };
+    let properties = command_properties(name);
+    let argc = command.argv.len() as i64;
+    if (properties.arity > 0 && argc != properties.arity) || argc < -properties.arity {
+        return Response::Error("ERR Invalid number of arguments specified for command".to_owned());
+    }
+    if properties.first_key_index <= 0 && !has_movable_keys(name) {
+        return Response::Error("ERR The command has no key arguments".to_owned());
+    }
+    let positions = command_key_positions(name, &command);
+    if positions.is_empty() {
+        return Response::Error("ERR Invalid arguments specified for command".to_owned());
+    }
+    Response::Array(
+        positions
+            .into_iter()
+            .filter_map(|i| command.get_vec(i).ok())
+            .map(Response::Data)
+            .collect(),
+    )
+}
+
+/// `COMMAND LIST FILTERBY MODULE name|ACLCAT category|PATTERN pattern`.
+/// There are no modules, filtering by one lists nothing.
+fn list(parser: &ParsedCommand, db: &Database) -> Response {
+    let commands = commands(db);
+    let names = if parser.argv.len() == 2 {
+        commands
+    } else {
+        let filterby = parser.get_str(2).unwrap_or_default();
+        let filter = parser.get_str(3).unwrap_or_default().to_ascii_lowercase();
+        let value = match parser.get_vec(4) {
+            Ok(value) if filterby.eq_ignore_ascii_case("filterby") => value,
+            _ => return Response::Error("ERR syntax error".to_owned()),
+        };
+        match &*filter {
+            "module" => vec![],
+            "aclcat" => {
+                let category = String::from_utf8_lossy(&value).to_ascii_lowercase();
+                commands
+                    .into_iter()
+                    .filter(|(_, name)| {
+                        command_categories(command_properties(name).flags).contains(&&*category)
+                    })
+                    .collect()
+            }
+            "pattern" => commands
+                .into_iter()
+                .filter(|(visible, _)| glob_match(&value, visible.as_bytes(), true))
+                .collect(),
+            _ => return Response::Error("ERR syntax error".to_owned()),
+        }
+    };
+    Response::Array(
+        names
+            .into_iter()
+            .map(|(visible, _)| data(&visible))
+            .collect(),
+    )
+}
+
+fn table(db: &Database) -> Response {
+    Response::Array(
+        commands(db)
+            .into_iter()
+            .map(|(visible, name)| command_info(&visible, name))
+            .collect(),
+    )
+}
+
+/// Describes the commands, so clients can learn their arity and where their
+/// keys are.
+pub(crate) fn command(parser: &ParsedCommand, db: &Database) -> Response {
+    let argc = parser.argv.len();
+    if argc == 1 {
+        return table(db);
+    }
+    let subcommand = match parser.get_str(1) {
+        Ok(s) => s.to_ascii_lowercase(),
+        Err(_) => return Response::Error("ERR Invalid subcommand".to_owned()),
+    };
+    match (&*subcommand, argc) {
+        ("count", 2) => Response::Integer(commands(db).len() as i64),
+        ("info", 2) => table(db),
+        ("info", _) => Response::Array(
+            (2..argc)
+                .map(|i| {
+                    let name = parser.get_str(i).unwrap_or_default();
+                    match lookup(db, name) {
+                        Some(command) => command_info(&name.to_ascii_lowercase(), command),
+                        None => Response::Nil,
+                    }
+                })
+                .collect(),
+        ),
+        ("getkeys", _) if argc >= 3 => getkeys(parser, db),
+        ("list", 2) | ("list", 5) => list(parser, db),
+        ("docs", _) => {
+            let names = if argc == 2 {
+                commands(db)
+            } else {
+                (2..argc)
+                    .filter_map(|i| parser.get_str(i).ok())
+                    .filter_map(|name| lookup(db, name).map(|c| (name.to_ascii_lowercase(), c)))
+                    .collect()
+            };
+            let mut r = Vec::with_capacity(names.len() * 2);
+            for (visible, name) in names {
+                r.push(data(&visible));
+                r.push(command_docs(name));
+            }
+            Response::Array(r)
+        }
+        _ => Response::Error(format!(
+            "ERR Unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test_introspection {
+    use database::Database;
+    use parser::ParsedCommand;
+    use response::Response;
+
+    use super::command;
+    use crate::command::COMMAND_NAMES;
+
+    fn run(db: &Database, c: &[u8]) -> Response {
+        let parser =
+            ParsedCommand::from_args(c.split(|c| *c == b' ').map(|arg| arg.to_vec()).collect());
+        command(&parser, db)
+    }
+
+    fn data(s: &str) -> Response {
+        Response::Data(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn info() {
+        let db = Database::mock();
+        let count = match run(&db, b"command count") {
+            Response::Integer(count) => count,
+            r => panic!("Unexpected response {:?}", r),
+        };
+        match run(&db, b"command") {
+            Response::Array(commands) => assert_eq!(commands.len() as i64, count),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        match run(&db, b"command info GET nosuchcommand") {
+            Response::Array(commands) => {
+                assert_eq!(commands[1], Response::Nil);
+                match &commands[0] {
+                    Response::Array(info) => {
+                        assert_eq!(info.len(), 10);
+                        assert_eq!(info[0], data("get"));
+                        assert_eq!(info[1], Response::Integer(2));
+                        assert_eq!(
+                            info[2],
+                            Response::Array(vec![
+                                Response::Status("readonly".to_owned()),
+                                Response::Status("fast".to_owned()),
+                            ])
+                        );
+                        for position in &info[3..6] {
+                            assert_eq!(*position, Response::Integer(1));
+                        }
+                        assert_eq!(
+                            info[6],
+                            Response::Array(vec![
+                                Response::Status("@read".to_owned()),
+                                Response::Status("@fast".to_owned()),
+                            ])
+                        );
+                    }
+                    r => panic!("Unexpected response {:?}", r),
+                }
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn movablekeys() {
+        let db = Database::mock();
+        match run(&db, b"command info zunion") {
+            Response::Array(commands) => match &commands[0] {
+                Response::Array(info) => match &info[2] {
+                    Response::Array(flags) => {
+                        assert!(flags.contains(&Response::Status("movablekeys".to_owned())))
+                    }
+                    r => panic!("Unexpected response {:?}", r),
+                },
+                r => panic!("Unexpected response {:?}", r),
+            },
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn getkeys() {
+        let db = Database::mock();
+        assert_eq!(
+            run(&db, b"command getkeys mset a 1 b 2"),
+            Response::Array(vec![data("a"), data("b")])
+        );
+        assert_eq!(
+            run(&db, b"command getkeys zunionstore dst 2 a b weights 1 2"),
+            Response::Array(vec![data("dst"), data("a"), data("b")])
+        );
+        assert_eq!(
+            run(&db, b"command getkeys zinterstore dst 3 a b"),
+            Response::Error("ERR Invalid arguments specified for command".to_owned())
+        );
+        assert_eq!(
+            run(&db, b"command getkeys ping"),
+            Response::Error("ERR The command has no key arguments".to_owned())
+        );
+        assert_eq!(
+            run(&db, b"command getkeys get"),
+            Response::Error("ERR Invalid number of arguments specified for command".to_owned())
+        );
+        assert_eq!(
+            run(&db, b"command getkeys nosuchcommand a"),
+            Response::Error("ERR Invalid command specified".to_owned())
+        );
+    }
+
+    #[test]
+    fn list() {
+        let mut db = Database::mock();
+        assert_eq!(
+            run(&db, b"command list filterby pattern zrange*"),
+            Response::Array(vec![
+                data("zrange"),
+                data("zrangebyscore"),
+                data("zrangebylex"),
+                data("zrangestore"),
+            ])
+        );
+        match run(&db, b"command list filterby aclcat pubsub") {
+            Response::Array(names) => assert!(names.contains(&data("publish"))),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        assert_eq!(
+            run(&db, b"command list filterby module foo"),
+            Response::Array(vec![])
+        );
+        assert_eq!(
+            run(&db, b"command list filterby foo bar"),
+            Response::Error("ERR syntax error".to_owned())
+        );
+
+        db.config
+            .rename_commands
+            .insert("zrangestore".to_owned(), None);
+        db.config
+            .rename_commands
+            .insert("zrange2".to_owned(), Some("zrangebylex".to_owned()));
+        db.config
+            .rename_commands
+            .insert("zrangebylex".to_owned(), None);
+        assert_eq!(
+            run(&db, b"command list filterby pattern zrange*"),
+            Response::Array(vec![data("zrange"), data("zrangebyscore"), data("zrange2"),])
+        );
+        assert_eq!(
+            run(&db, b"command info zrangestore"),
+            Response::Array(vec![Response::Nil])
+        );
+    }
+
+    #[test]
+    fn docs() {
+        let db = Database::mock();
+        assert_eq!(
+            run(&db, b"command docs GET nosuchcommand"),
+            Response::Array(vec![
+                data("get"),
+                Response::Array(vec![
+                    data("summary"),
+                    data("Returns the string value of a key."),
+                    data("arity"),
+                    Response::Integer(2),
+                    data("flags"),
+                    Response::Array(vec![
+                        Response::Status("readonly".to_owned()),
+                        Response::Status("fast".to_owned()),
+                    ]),
+                    data("group"),
+                    data("string"),
+                ]),
+            ])
+        );
+
+        // every command is documented
+        match run(&db, b"command docs") {
+            Response::Array(docs) => {
+                assert_eq!(docs.len(), COMMAND_NAMES.len() * 2);
+                for doc in docs.iter().skip(1).step_by(2) {
+                    match doc {
+                        Response::Array(fields) => {
+                            assert_ne!(fields[1], data(""));
+                            assert_ne!(fields[7], data(""));
+                        }
+                        r => panic!("Unexpected response {:?}", r),
+                    }
+                }
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+}
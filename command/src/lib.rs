@@ -1,4 +1,9 @@
+pub mod acl;
 pub mod aof;
+pub mod cluster;
 pub mod command;
+pub mod introspection;
+pub mod migrate;
+pub mod sentinel;
 
 pub use command::*;
@@ -0,0 +1,506 @@
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use config::NOTIFY_GENERIC;
+use database::{Database, MigrateSocket};
+use parser::ParsedCommand;
+use response::Response;
+use util::mstime;
+
+/// Where a MIGRATE failed talking to the target instance.
+enum MigrateError {
+    Connect,
+    Write(io::Error),
+    Read(io::Error),
+    /// The target replied with an error to a command that must succeed
+    Target(String),
+}
+
+impl MigrateError {
+    fn response(&self) -> Response {
+        Response::Error(match self {
+            MigrateError::Connect => "IOERR error or timeout connecting to the client".to_owned(),
+            MigrateError::Write(_) => {
+                "IOERR error or timeout writing to target instance".to_owned()
+            }
+            MigrateError::Read(_) => "IOERR error or timeout reading to target instance".to_owned(),
+            MigrateError::Target(err) => format!("ERR Target instance replied with error: {}", err),
+        })
+    }
+
+    fn is_timeout(&self) -> bool {
+        match self {
+            MigrateError::Write(err) | MigrateError::Read(err) => matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+}
+
+struct MigrateOptions {
+    host: String,
+    port: u16,
+    keys: Vec<Vec<u8>>,
+    dbindex: usize,
+    timeout: Duration,
+    copy: bool,
+    replace: bool,
+    auth: Option<Vec<Vec<u8>>>,
+}
+
+fn parse_options(parser: &ParsedCommand) -> Result<MigrateOptions, Response> {
+    let syntax_error = || Response::Error("ERR syntax error".to_owned());
+    let not_an_integer =
+        || Response::Error("ERR value is not an integer or out of range".to_owned());
+    if parser.argv.len() < 6 {
+        return Err(Response::Error("Wrong number of parameters".to_owned()));
+    }
+    let host = parser.get_str(1).map_err(|_| syntax_error())?.to_owned();
+    let port = parser.get_i64(2).map_err(|_| not_an_integer())?;
+    let key = parser.get_vec(3).map_err(|_| syntax_error())?;
+    let dbindex = parser.get_i64(4).map_err(|_| not_an_integer())?;
+    let timeout = parser.get_i64(5).map_err(|_| not_an_integer())?;
+    if !(0..=65535).contains(&port) || dbindex < 0 {
+        return Err(not_an_integer());
+    }
+
+    let mut options = MigrateOptions {
+        host,
+        port: port as u16,
+        keys: vec![key],
+        dbindex: dbindex as usize,
+        timeout: Duration::from_millis(if timeout <= 0 { 1000 } else { timeout as u64 }),
+        copy: false,
+        replace: false,
+        auth: None,
+    };
+    let mut i = 6;
+    while i < parser.argv.len() {
+        let param = parser.get_str(i).map_err(|_| syntax_error())?;
+        match &*param.to_ascii_lowercase() {
+            "copy" => options.copy = true,
+            "replace" => options.replace = true,
+            "auth" if i + 1 < parser.argv.len() => {
+                options.auth = Some(vec![parser.get_vec(i + 1).map_err(|_| syntax_error())?]);
+                i += 1;
+            }
+            "auth2" if i + 2 < parser.argv.len() => {
+                options.auth = Some(vec![
+                    parser.get_vec(i + 1).map_err(|_| syntax_error())?,
+                    parser.get_vec(i + 2).map_err(|_| syntax_error())?,
+                ]);
+                i += 2;
+            }
+            "keys" => {
+                if !options.keys[0].is_empty() {
+                    return Err(Response::Error(
+                        "ERR When using MIGRATE KEYS option, the key argument must be set to \
+                         the empty string"
+                            .to_owned(),
+                    ));
+                }
+                options.keys = ((i + 1)..parser.argv.len())
+                    .map(|j| parser.get_vec(j))
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| syntax_error())?;
+                break;
+            }
+            _ => return Err(syntax_error()),
+        }
+        i += 1;
+    }
+    Ok(options)
+}
+
+fn write_command(stream: &mut TcpStream, args: Vec<Vec<u8>>) -> Result<(), MigrateError> {
+    let command = Response::Array(args.into_iter().map(Response::Data).collect());
+    stream
+        .write_all(&command.as_bytes())
+        .map_err(MigrateError::Write)
+}
+
+/// Reads a single line reply. Returns the error message if the target
+/// replied with an error.
+fn read_reply(stream: &mut BufReader<TcpStream>) -> Result<Result<(), String>, MigrateError> {
+    let mut line = String::new();
+    match stream.read_line(&mut line) {
+        Ok(0) => Err(MigrateError::Read(io::ErrorKind::UnexpectedEof.into())),
+        Ok(_) if line.starts_with('-') => Ok(Err(line[1..].trim_end().to_owned())),
+        Ok(_) => Ok(Ok(())),
+        Err(err) => Err(MigrateError::Read(err)),
+    }
+}
+
+fn connect(options: &MigrateOptions) -> Result<MigrateSocket, MigrateError> {
+    let addr = (&*options.host, options.port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or(MigrateError::Connect)?;
+    let stream =
+        TcpStream::connect_timeout(&addr, options.timeout).map_err(|_| MigrateError::Connect)?;
+    stream
+        .set_read_timeout(Some(options.timeout))
+        .map_err(|_| MigrateError::Connect)?;
+    stream
+        .set_write_timeout(Some(options.timeout))
+        .map_err(|_| MigrateError::Connect)?;
+    Ok(MigrateSocket {
+        stream: BufReader::new(stream),
+        last_dbindex: None,
+        last_use: mstime(),
+    })
+}
+
+/// The outcome of sending the keys to the target.
+struct Migrated {
+    /// Keys that were restored in the target and removed locally
+    removed: Vec<Vec<u8>>,
+    /// The first error replied by the target or, if none, the error reading
+    /// the replies
+    error: Option<MigrateError>,
+    /// Whether reading the replies failed, leaving the connection unusable
+    socket_error: bool,
+}
+
+/// Sends the keys to the target and removes them locally, unless `COPY` is
+/// used.
+/// Fails if there was an IO error before reading any key reply, in which case
+/// it is safe to retry.
+fn migrate_keys(
+    db: &mut Database,
+    dbindex: usize,
+    options: &MigrateOptions,
+    socket: &mut MigrateSocket,
+    keys: &[(Vec<u8>, Vec<u8>, i64)],
+) -> Result<Migrated, MigrateError> {
+    let restore = if db.is_cluster() {
+        "RESTORE-ASKING"
+    } else {
+        "RESTORE"
+    };
+    let select = socket.last_dbindex != Some(options.dbindex);
+
+    // all the commands are pipelined and then the replies read in order
+    let stream = socket.stream.get_mut();
+    if let Some(auth) = &options.auth {
+        let mut args = vec![b"AUTH".to_vec()];
+        args.extend(auth.iter().cloned());
+        write_command(stream, args)?;
+    }
+    if select {
+        let dbindex = format!("{}", options.dbindex).into_bytes();
+        write_command(stream, vec![b"SELECT".to_vec(), dbindex])?;
+    }
+    for (key, payload, ttl) in keys {
+        let mut args = vec![
+            restore.as_bytes().to_vec(),
+            key.clone(),
+            format!("{}", ttl).into_bytes(),
+            payload.clone(),
+        ];
+        if options.replace {
+            args.push(b"REPLACE".to_vec());
+        }
+        write_command(stream, args)?;
+    }
+
+    let mut migrated = Migrated {
+        removed: vec![],
+        error: None,
+        socket_error: false,
+    };
+    let mut setup_error = None;
+    if options.auth.is_some() {
+        setup_error = read_reply(&mut socket.stream)?.err();
+    }
+    if select {
+        if let Err(err) = read_reply(&mut socket.stream)? {
+            setup_error = setup_error.or(Some(err));
+        }
+    }
+    match setup_error {
+        Some(err) => {
+            // the selected database is no longer known
+            socket.last_dbindex = None;
+            migrated.error = Some(MigrateError::Target(err));
+        }
+        None => socket.last_dbindex = Some(options.dbindex),
+    }
+    for (key, _, _) in keys {
+        match read_reply(&mut socket.stream) {
+            Ok(Ok(())) => {
+                let lazy = db.config.lazyfree_lazy_server_del;
+                if !options.copy && db.delete(dbindex, key, lazy) {
+                    db.notify_keyspace_event(NOTIFY_GENERIC, "del", dbindex, key);
+                    db.key_updated(dbindex, key);
+                    migrated.removed.push(key.clone());
+                }
+            }
+            Ok(Err(err)) => {
+                if migrated.error.is_none() {
+                    migrated.error = Some(MigrateError::Target(err));
+                }
+            }
+            Err(err) => {
+                migrated.socket_error = true;
+                if migrated.error.is_none() {
+                    migrated.error = Some(err);
+                }
+                break;
+            }
+        }
+    }
+    Ok(migrated)
+}
+
+fn del_command(keys: &[Vec<u8>]) -> ParsedCommand<'static> {
+    let mut args = vec![b"DEL".to_vec()];
+    args.extend(keys.iter().cloned());
+    ParsedCommand::from_args(args)
+}
+
+/// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+/// [AUTH password] [AUTH2 username password] [KEYS key [key ...]]
+///
+/// Moves keys to another instance using DUMP/RESTORE. The connection to
+/// each target is kept open and reused by following calls.
+/// The command is propagated as a `DEL` of the keys that were moved.
+pub fn migrate(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    write: &mut bool,
+) -> Response {
+    *write = false;
+    let options = match parse_options(parser) {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+
+    let now = mstime();
+    let mut keys = vec![];
+    for key in options.keys.iter() {
+        let mut payload = vec![];
+        match db.get(dbindex, key) {
+            Some(value) => {
+                if let Err(err) = value.dump(&mut payload) {
+                    return Response::Error(err.to_string());
+                }
+            }
+            None => continue,
+        }
+        let ttl = match db.get_msexpiration(dbindex, key) {
+            Some(expiration) => (expiration - now).max(1),
+            None => 0,
+        };
+        keys.push((key.clone(), payload, ttl));
+    }
+    if keys.is_empty() {
+        return Response::Status("NOKEY".to_owned());
+    }
+
+    let target = format!("{}:{}", options.host, options.port);
+    let mut may_retry = true;
+    loop {
+        let cached = db.migrate_sockets().remove(&target);
+        let mut socket = match cached {
+            Some(socket) => socket,
+            None => match connect(&options) {
+                Ok(socket) => socket,
+                Err(err) => return err.response(),
+            },
+        };
+        match migrate_keys(db, dbindex, &options, &mut socket, &keys) {
+            Ok(migrated) => {
+                if !migrated.removed.is_empty() {
+                    db.propagate(dbindex, &del_command(&migrated.removed));
+                }
+                // the connection is dropped after an IO error
+                if !migrated.socket_error {
+                    socket.last_use = mstime();
+                    db.migrate_sockets().insert(target, socket);
+                }
+                return match migrated.error {
+                    Some(err) => err.response(),
+                    None => Response::Status("OK".to_owned()),
+                };
+            }
+            // a cached connection may have been closed by the target
+            Err(ref err) if may_retry && !err.is_timeout() => may_retry = false,
+            Err(err) => return err.response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_migrate {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use database::{Database, SharedDatabase};
+    use parser::{parse, ParseError, ParsedCommand};
+    use response::Response;
+
+    use crate::command::{command, Client};
+
+    /// Serves a single connection, running the commands on a new database.
+    fn target() -> (u16, thread::JoinHandle<SharedDatabase>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut db = Database::mock();
+            let mut client = Client::mock();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = vec![];
+            let mut chunk = [0; 4096];
+            loop {
+                let len = stream.read(&mut chunk).unwrap();
+                if len == 0 {
+                    return db.shared();
+                }
+                buf.extend(&chunk[..len]);
+                loop {
+                    let (parser, consumed) = match parse(&buf) {
+                        Ok((parser, consumed)) => (parser.into_owned(), consumed),
+                        Err(ParseError::Incomplete) => break,
+                        Err(err) => panic!("{:?}", err),
+                    };
+                    let response = command(parser.get_command(), &mut db, &mut client).unwrap();
+                    stream.write_all(&response.as_bytes()).unwrap();
+                    buf.drain(..consumed);
+                }
+            }
+        });
+        (port, handle)
+    }
+
+    fn migrate(db: &mut Database, args: &[&str]) -> Response {
+        let mut args = args
+            .iter()
+            .map(|arg| arg.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        args.insert(0, b"migrate".to_vec());
+        command(ParsedCommand::from_args(args), db, &mut Client::mock()).unwrap()
+    }
+
+    #[test]
+    fn migrate_keys() {
+        let (port, handle) = target();
+        let port = format!("{}", port);
+        let mut db = Database::mock();
+        let mut client = Client::mock();
+        for args in &[
+            &b"set a 1"[..],
+            b"set b 2",
+            b"rpush c x y",
+            b"pexpire a 100000",
+        ] {
+            let args = args.split(|c| *c == b' ').map(|arg| arg.to_vec());
+            command(
+                ParsedCommand::from_args(args.collect()),
+                &mut db,
+                &mut client,
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            migrate(&mut db, &["127.0.0.1", &port, "a", "0", "1000"]),
+            Response::Status("OK".to_owned())
+        );
+        assert!(db.get(0, b"a").is_none());
+        assert_eq!(
+            migrate(&mut db, &["127.0.0.1", &port, "missing", "0", "1000"]),
+            Response::Status("NOKEY".to_owned())
+        );
+        // the connection is reused, the target only accepts one
+        assert_eq!(
+            migrate(
+                &mut db,
+                &[
+                    "127.0.0.1",
+                    &port,
+                    "",
+                    "1",
+                    "1000",
+                    "COPY",
+                    "KEYS",
+                    "b",
+                    "c",
+                    "missing"
+                ]
+            ),
+            Response::Status("OK".to_owned())
+        );
+        assert!(db.get(0, b"b").is_some());
+        assert_eq!(
+            migrate(&mut db, &["127.0.0.1", &port, "b", "1", "1000"]),
+            Response::Error(
+                "ERR Target instance replied with error: BUSYKEY Target key name already exists."
+                    .to_owned()
+            )
+        );
+        assert!(db.get(0, b"b").is_some());
+        assert_eq!(
+            migrate(&mut db, &["127.0.0.1", &port, "b", "1", "1000", "REPLACE"]),
+            Response::Status("OK".to_owned())
+        );
+        assert!(db.get(0, b"b").is_none());
+
+        db.migrate_sockets().clear();
+        let mut target = handle.join().unwrap().handle();
+        target.lock_all();
+        assert!(target.get(0, b"a").is_some());
+        let expiration = *target.get_msexpiration(0, b"a").unwrap();
+        assert!(expiration > util::mstime() + 80 * 1000);
+        assert!(target.get(1, b"b").is_some());
+        assert_eq!(target.get(1, b"c").unwrap().llen().unwrap(), 2);
+    }
+
+    #[test]
+    fn migrate_errors() {
+        let mut db = Database::mock();
+        command(
+            ParsedCommand::from_args(vec![b"set".to_vec(), b"a".to_vec(), b"1".to_vec()]),
+            &mut db,
+            &mut Client::mock(),
+        )
+        .unwrap();
+        assert_eq!(
+            migrate(
+                &mut db,
+                &["127.0.0.1", "6379", "a", "0", "1000", "KEYS", "b"]
+            ),
+            Response::Error(
+                "ERR When using MIGRATE KEYS option, the key argument must be set to the empty \
+                 string"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            migrate(&mut db, &["127.0.0.1", "6379", "a", "0", "1000", "AUTH"]),
+            Response::Error("ERR syntax error".to_owned())
+        );
+        assert_eq!(
+            migrate(&mut db, &["127.0.0.1", "6379", "a", "db", "1000"]),
+            Response::Error("ERR value is not an integer or out of range".to_owned())
+        );
+
+        // nothing is listening in a port that was just released
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("{}", listener.local_addr().unwrap().port())
+        };
+        assert_eq!(
+            migrate(&mut db, &["127.0.0.1", &port, "a", "0", "1000"]),
+            Response::Error("IOERR error or timeout connecting to the client".to_owned())
+        );
+        assert!(db.get(0, b"a").is_some());
+    }
+}
@@ -0,0 +1,248 @@
+use std::net::IpAddr;
+
+use database::sentinel::{Master, Sentinel};
+use database::Database;
+use logger::Level;
+use parser::ParsedCommand;
+use response::Response;
+use util::mstime;
+
+const NO_SUCH_MASTER: &str = "ERR No such master with that name";
+
+/// Commands a sentinel replies to. Everything else is unknown to it.
+const SENTINEL_COMMANDS: &[&str] = &[
+    "ping",
+    "sentinel",
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "publish",
+    "info",
+    "client",
+    "shutdown",
+    "auth",
+    "acl",
+];
+
+/// Creates the sentinel state and applies the `sentinel` directives of the
+/// configuration file.
+pub fn load(db: &mut Database) -> Result<(), String> {
+    let mut sentinel = Sentinel::new(db.config.port);
+    let now = mstime();
+    for directive in db.config.sentinel.iter() {
+        let args = directive.iter().map(|s| &**s).collect::<Vec<_>>();
+        let r = match (&*args[0].to_ascii_lowercase(), args.len()) {
+            ("monitor", 5) => match (args[3].parse(), args[4].parse()) {
+                (Ok(port), Ok(quorum)) => sentinel.monitor(args[1], args[2], port, quorum, now),
+                _ => Err("Invalid port or quorum".to_owned()),
+            },
+            ("myid", 2) => sentinel.set_myid(args[1]),
+            ("announce-ip", 2) => {
+                sentinel.set_announce_ip(args[1]);
+                Ok(())
+            }
+            ("down-after-milliseconds", 3)
+            | ("failover-timeout", 3)
+            | ("parallel-syncs", 3)
+            | ("auth-pass", 3)
+            | ("auth-user", 3) => sentinel.set(args[1], args[0], args[2]),
+            _ => Err("Unrecognized sentinel configuration statement".to_owned()),
+        };
+        r.map_err(|err| format!("sentinel {}: {}", directive.join(" "), err))?;
+    }
+    // the configuration is not an event
+    sentinel.take_events();
+    logger::log!(
+        db.config.logger,
+        Warning,
+        "Sentinel ID is {}",
+        sentinel.myid()
+    );
+    for master in sentinel.masters() {
+        logger::log!(
+            db.config.logger,
+            Warning,
+            "+monitor master {} {} {} quorum {}",
+            master.name,
+            master.instance.ip,
+            master.instance.port,
+            master.quorum
+        );
+    }
+    *db.sentinel() = Some(sentinel);
+    Ok(())
+}
+
+/// Logs the sentinel events, and publishes each one in a channel named like
+/// its type.
+pub fn flush_events(db: &mut Database) {
+    let events = match db.sentinel().as_mut() {
+        Some(sentinel) => sentinel.take_events(),
+        None => return,
+    };
+    for (kind, description) in events {
+        logger::log!(db.config.logger, Warning, "{} {}", kind, description);
+        db.publish(kind.as_bytes(), description.as_bytes());
+    }
+}
+
+/// Whether a command is available in sentinel mode.
+pub(crate) fn allowed(command_name: &str) -> bool {
+    SENTINEL_COMMANDS.contains(&command_name)
+}
+
+fn status(r: Result<(), String>) -> Response {
+    match r {
+        Ok(_) => Response::Status("OK".to_owned()),
+        Err(err) => Response::Error(err),
+    }
+}
+
+fn fields(fields: Vec<(&'static str, String)>) -> Response {
+    Response::Array(
+        fields
+            .into_iter()
+            .flat_map(|(field, value)| {
+                vec![
+                    Response::Data(field.as_bytes().to_vec()),
+                    Response::Data(value.into_bytes()),
+                ]
+            })
+            .collect(),
+    )
+}
+
+fn master<'a>(parser: &ParsedCommand, sentinel: &'a Sentinel) -> Result<&'a Master, Response> {
+    parser
+        .get_str(2)
+        .ok()
+        .and_then(|name| sentinel.master(name))
+        .ok_or_else(|| Response::Error(NO_SUCH_MASTER.to_owned()))
+}
+
+fn monitor(parser: &ParsedCommand, sentinel: &mut Sentinel) -> Response {
+    let name = parser.get_str(2).unwrap_or("");
+    let ip = parser.get_str(3).unwrap_or("");
+    if ip.parse::<IpAddr>().is_err() {
+        return Response::Error("ERR Invalid IP address or hostname specified".to_owned());
+    }
+    let port = match parser.get_i64(4) {
+        Ok(port) if port > 0 && port <= 65535 => port as u16,
+        _ => return Response::Error("ERR Invalid port".to_owned()),
+    };
+    let quorum = match parser.get_i64(5) {
+        Ok(quorum) if quorum > 0 => quorum as usize,
+        _ => return Response::Error("ERR Quorum must be 1 or greater.".to_owned()),
+    };
+    status(sentinel.monitor(name, ip, port, quorum, mstime()))
+}
+
+fn set(parser: &ParsedCommand, sentinel: &mut Sentinel) -> Response {
+    let name = parser.get_str(2).unwrap_or("");
+    if sentinel.master(name).is_none() {
+        return Response::Error(NO_SUCH_MASTER.to_owned());
+    }
+    for i in (3..parser.argv.len()).step_by(2) {
+        let (option, value) = match (parser.get_str(i), parser.get_str(i + 1)) {
+            (Ok(option), Ok(value)) => (option, value),
+            _ => return Response::Error("ERR wrong number of arguments for 'set'".to_owned()),
+        };
+        if let Err(err) = sentinel.set(name, option, value) {
+            return Response::Error(err);
+        }
+    }
+    Response::Status("OK".to_owned())
+}
+
+fn is_master_down_by_addr(parser: &ParsedCommand, sentinel: &mut Sentinel) -> Response {
+    let ip = parser.get_str(2).unwrap_or("");
+    let (port, epoch) = match (parser.get_i64(3), parser.get_i64(4)) {
+        (Ok(port), Ok(epoch)) if port > 0 && port <= 65535 && epoch >= 0 => {
+            (port as u16, epoch as u64)
+        }
+        _ => return Response::Error("ERR value is not an integer or out of range".to_owned()),
+    };
+    let runid = parser.get_str(5).unwrap_or("*");
+    let (down, leader, leader_epoch) =
+        sentinel.is_master_down_by_addr(ip, port, epoch, runid, mstime());
+    Response::Array(vec![
+        Response::Integer(if down { 1 } else { 0 }),
+        Response::Data(leader.into_bytes()),
+        Response::Integer(leader_epoch as i64),
+    ])
+}
+
+pub(crate) fn sentinel(parser: &ParsedCommand, db: &mut Database) -> Response {
+    let subcommand = match parser.get_str(1) {
+        Ok(s) => s.to_ascii_lowercase(),
+        Err(_) => return Response::Error("ERR Invalid subcommand".to_owned()),
+    };
+    let mut guard = db.sentinel();
+    let sentinel = match guard.as_mut() {
+        Some(sentinel) => sentinel,
+        None => {
+            return Response::Error("ERR This instance is not running in sentinel mode".to_owned())
+        }
+    };
+    let now = mstime();
+    let argc = parser.argv.len();
+    let response = match (&*subcommand, argc) {
+        ("masters", 2) => Response::Array(
+            sentinel
+                .masters()
+                .map(|master| fields(master.describe(now)))
+                .collect(),
+        ),
+        ("master", 3) => match master(parser, sentinel) {
+            Ok(master) => fields(master.describe(now)),
+            Err(err) => err,
+        },
+        ("replicas", 3) | ("slaves", 3) => match master(parser, sentinel) {
+            Ok(master) => Response::Array(
+                master
+                    .describe_replicas(now)
+                    .into_iter()
+                    .map(fields)
+                    .collect(),
+            ),
+            Err(err) => err,
+        },
+        ("sentinels", 3) => match master(parser, sentinel) {
+            Ok(master) => Response::Array(
+                master
+                    .describe_sentinels(now)
+                    .into_iter()
+                    .map(fields)
+                    .collect(),
+            ),
+            Err(err) => err,
+        },
+        ("get-master-addr-by-name", 3) => {
+            match sentinel.get_master_addr(parser.get_str(2).unwrap_or("")) {
+                Some((ip, port)) => Response::Array(vec![
+                    Response::Data(ip.into_bytes()),
+                    Response::Data(format!("{}", port).into_bytes()),
+                ]),
+                None => Response::Nil,
+            }
+        }
+        ("failover", 3) => status(sentinel.failover(parser.get_str(2).unwrap_or(""), now)),
+        ("ckquorum", 3) => match sentinel.ckquorum(parser.get_str(2).unwrap_or(""), now) {
+            Ok(s) => Response::Status(s),
+            Err(err) => Response::Error(err),
+        },
+        ("monitor", 6) => monitor(parser, sentinel),
+        ("remove", 3) => status(sentinel.remove(parser.get_str(2).unwrap_or(""))),
+        ("set", _) if argc >= 5 && argc % 2 == 1 => set(parser, sentinel),
+        ("is-master-down-by-addr", 6) => is_master_down_by_addr(parser, sentinel),
+        ("myid", 2) => Response::Data(sentinel.myid().as_bytes().to_vec()),
+        _ => Response::Error(format!(
+            "ERR Unknown subcommand or wrong number of arguments for '{}'. Try SENTINEL HELP.",
+            subcommand
+        )),
+    };
+    drop(guard);
+    flush_events(db);
+    response
+}
@@ -19,6 +19,42 @@ use std::str::Utf8Error;
 use logger::{Level, Logger};
 use util::splitargs;
 
+/// Keyspace events, published with __keyspace@<db>__ prefix.
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0;
+/// Keyevent events, published with __keyevent@<db>__ prefix.
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1;
+/// Generic commands (non-type specific) like DEL, EXPIRE, RENAME, ...
+pub const NOTIFY_GENERIC: u32 = 1 << 2;
+/// String commands
+pub const NOTIFY_STRING: u32 = 1 << 3;
+/// List commands
+pub const NOTIFY_LIST: u32 = 1 << 4;
+/// Set commands
+pub const NOTIFY_SET: u32 = 1 << 5;
+/// Hash commands
+pub const NOTIFY_HASH: u32 = 1 << 6;
+/// Sorted set commands
+pub const NOTIFY_ZSET: u32 = 1 << 7;
+/// Expired events (events generated every time a key expires)
+pub const NOTIFY_EXPIRED: u32 = 1 << 8;
+/// Evicted events (events generated when a key is evicted for maxmemory)
+pub const NOTIFY_EVICTED: u32 = 1 << 9;
+/// Stream commands
+pub const NOTIFY_STREAM: u32 = 1 << 10;
+/// Key-miss events (events generated when a key that doesn't exist is accessed)
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11;
+/// Alias for g$lshzxet, every class but key-miss events
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED
+    | NOTIFY_STREAM;
+
+#[derive(Clone)]
 pub struct Config {
     pub logger: Logger,
     pub daemonize: bool,
@@ -43,6 +79,48 @@ pub struct Config {
     pub appendonly: bool,
     pub appendfilename: String,
     pub aof_load_truncated: bool,
+    pub proto_max_bulk_len: usize,
+    pub client_query_buffer_limit: usize,
+    /// Snapshot loaded at startup, unless the append only file is enabled
+    pub dbfilename: String,
+    /// Save points, as (seconds, changes) pairs. They are not checked while
+    /// running yet: any save point only makes a shutdown save a snapshot.
+    pub save: Vec<(u64, u64)>,
+    /// File where ACL users are loaded from and saved to
+    pub aclfile: Option<String>,
+    /// Maximum number of entries in the ACL log
+    pub acllog_max_len: usize,
+    /// Classes of keyspace events to publish, as `NOTIFY_*` flags
+    pub notify_keyspace_events: u32,
+    /// Maximum number of keys in the client side caching invalidation table,
+    /// 0 for no limit
+    pub tracking_table_max_keys: usize,
+    /// Whether the server runs as a cluster node
+    pub cluster_enabled: bool,
+    /// File where the cluster node persists its view of the cluster
+    pub cluster_config_file: String,
+    /// Milliseconds a node can be unreachable before it is considered failing
+    pub cluster_node_timeout: u64,
+    /// Whether the server runs as a sentinel, monitoring other servers
+    pub sentinel_mode: bool,
+    /// Arguments of the `sentinel` directives, applied when the sentinel starts
+    pub sentinel: Vec<Vec<String>>,
+    /// Whether the values evicted to free memory are dropped in the
+    /// background
+    pub lazyfree_lazy_eviction: bool,
+    /// Whether expired values are dropped in the background
+    pub lazyfree_lazy_expire: bool,
+    /// Whether the values a command deletes as a side effect, like the
+    /// destination of SUNIONSTORE, are dropped in the background
+    pub lazyfree_lazy_server_del: bool,
+    /// Whether DEL drops the values in the background, like UNLINK
+    pub lazyfree_lazy_user_del: bool,
+    /// How many accesses it takes to saturate the access frequency counter
+    /// of a key, the higher the more
+    pub lfu_log_factor: u64,
+    /// Minutes a key has to be idle to decrement its access frequency
+    /// counter by one. Zero never decrements it.
+    pub lfu_decay_time: u64,
 }
 
 #[derive(Debug)]
@@ -72,6 +150,64 @@ where
     }
 }
 
+/// Reads a memory size, with an optional unit (k, kb, m, mb, g, gb).
+fn read_memory(args: Vec<Vec<u8>>) -> Result<usize, ConfigError> {
+    let s = read_string(args)?.to_lowercase();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let multiplier = match &s[digits..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(ConfigError::InvalidParameter),
+    };
+    match s[..digits].parse::<usize>()?.checked_mul(multiplier) {
+        Some(v) => Ok(v),
+        None => Err(ConfigError::InvalidParameter),
+    }
+}
+
+/// Parses the classes of keyspace events, like "KEA" or "Elg".
+///
+/// # Examples
+///
+/// ```
+/// # use config::*;
+/// #
+/// assert_eq!(
+///     keyspace_events_from_str("Elg"),
+///     Some(NOTIFY_KEYEVENT | NOTIFY_LIST | NOTIFY_GENERIC)
+/// );
+/// assert_eq!(keyspace_events_from_str("KA"), Some(NOTIFY_KEYSPACE | NOTIFY_ALL));
+/// assert_eq!(keyspace_events_from_str(""), Some(0));
+/// assert_eq!(keyspace_events_from_str("Kq"), None);
+/// ```
+pub fn keyspace_events_from_str(classes: &str) -> Option<u32> {
+    let mut flags = 0;
+    for c in classes.chars() {
+        flags |= match c {
+            'A' => NOTIFY_ALL,
+            'g' => NOTIFY_GENERIC,
+            '$' => NOTIFY_STRING,
+            'l' => NOTIFY_LIST,
+            's' => NOTIFY_SET,
+            'h' => NOTIFY_HASH,
+            'z' => NOTIFY_ZSET,
+            'x' => NOTIFY_EXPIRED,
+            'e' => NOTIFY_EVICTED,
+            't' => NOTIFY_STREAM,
+            'm' => NOTIFY_KEY_MISS,
+            'K' => NOTIFY_KEYSPACE,
+            'E' => NOTIFY_KEYEVENT,
+            _ => return None,
+        };
+    }
+    Some(flags)
+}
+
 fn read_bool(args: Vec<Vec<u8>>) -> Result<bool, ConfigError> {
     Ok(match &*read_string(args)? {
         "yes" => true,
@@ -106,6 +242,25 @@ impl Config {
             appendonly: false,
             appendfilename: "appendonly.aof".to_owned(),
             aof_load_truncated: false,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            client_query_buffer_limit: 1024 * 1024 * 1024,
+            dbfilename: "dump.rdb".to_owned(),
+            save: vec![],
+            aclfile: None,
+            acllog_max_len: 128,
+            notify_keyspace_events: 0,
+            tracking_table_max_keys: 1000000,
+            cluster_enabled: false,
+            cluster_config_file: "nodes.conf".to_owned(),
+            cluster_node_timeout: 15000,
+            sentinel_mode: false,
+            sentinel: vec![],
+            lazyfree_lazy_eviction: false,
+            lazyfree_lazy_expire: false,
+            lazyfree_lazy_server_del: false,
+            lazyfree_lazy_user_del: false,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
         }
     }
 
@@ -204,6 +359,52 @@ impl Config {
                 b"appendonly" => self.appendonly = read_bool(args)?,
                 b"appendfilename" => self.appendfilename = read_string(args)?.to_owned(),
                 b"aof-load-truncated" => self.aof_load_truncated = read_bool(args)?,
+                b"proto-max-bulk-len" => self.proto_max_bulk_len = read_memory(args)?,
+                b"client-query-buffer-limit" => self.client_query_buffer_limit = read_memory(args)?,
+                b"dbfilename" => self.dbfilename = read_string(args)?.to_owned(),
+                b"save" => {
+                    if args.len() == 2 && args[1].is_empty() {
+                        self.save.clear();
+                    } else if args.len() % 2 == 1 {
+                        for pair in args[1..].chunks(2) {
+                            self.save.push((
+                                from_utf8(&pair[0])?.parse()?,
+                                from_utf8(&pair[1])?.parse()?,
+                            ));
+                        }
+                    } else {
+                        return Err(ConfigError::InvalidFormat);
+                    }
+                }
+                b"aclfile" => self.aclfile = Some(read_string(args)?.to_owned()),
+                b"acllog-max-len" => self.acllog_max_len = read_parse(args)?,
+                b"notify-keyspace-events" => {
+                    self.notify_keyspace_events =
+                        match keyspace_events_from_str(&read_string(args)?) {
+                            Some(flags) => flags,
+                            None => return Err(ConfigError::InvalidParameter),
+                        }
+                }
+                b"tracking-table-max-keys" => self.tracking_table_max_keys = read_parse(args)?,
+                b"cluster-enabled" => self.cluster_enabled = read_bool(args)?,
+                b"cluster-config-file" => self.cluster_config_file = read_string(args)?.to_owned(),
+                b"cluster-node-timeout" => self.cluster_node_timeout = read_parse(args)?,
+                b"sentinel" => {
+                    if !self.sentinel_mode || args.len() < 2 {
+                        return Err(ConfigError::InvalidParameter);
+                    }
+                    let mut directive = vec![];
+                    for arg in &args[1..] {
+                        directive.push(from_utf8(arg)?.to_owned());
+                    }
+                    self.sentinel.push(directive);
+                }
+                b"lazyfree-lazy-eviction" => self.lazyfree_lazy_eviction = read_bool(args)?,
+                b"lazyfree-lazy-expire" => self.lazyfree_lazy_expire = read_bool(args)?,
+                b"lazyfree-lazy-server-del" => self.lazyfree_lazy_server_del = read_bool(args)?,
+                b"lazyfree-lazy-user-del" => self.lazyfree_lazy_user_del = read_bool(args)?,
+                b"lfu-log-factor" => self.lfu_log_factor = read_parse(args)?,
+                b"lfu-decay-time" => self.lfu_decay_time = read_parse(args)?,
                 b"include" => {
                     if args.len() != 2 {
                         return Err(ConfigError::InvalidFormat);
@@ -394,4 +595,119 @@ mod tests {
         );
         assert_eq!(config.requirepass, Some("THISISASTRONGPASSWORD".to_owned()));
     }
+
+    #[test]
+    fn parse_proto_max_bulk_len() {
+        let config = config!(b"proto-max-bulk-len 1mb", Logger::new(Level::Warning));
+        assert_eq!(config.proto_max_bulk_len, 1024 * 1024);
+    }
+
+    #[test]
+    fn parse_client_query_buffer_limit() {
+        let config = config!(
+            b"client-query-buffer-limit 2GB",
+            Logger::new(Level::Warning)
+        );
+        assert_eq!(config.client_query_buffer_limit, 2 * 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn parse_dbfilename() {
+        let config = config!(b"dbfilename snapshot.rdb", Logger::new(Level::Warning));
+        assert_eq!(config.dbfilename, "snapshot.rdb");
+    }
+
+    #[test]
+    fn parse_save() {
+        let config = config!(
+            b"save 900 1\nsave 300 10 60 10000",
+            Logger::new(Level::Warning)
+        );
+        assert_eq!(config.save, vec![(900, 1), (300, 10), (60, 10000)]);
+    }
+
+    #[test]
+    fn parse_save_empty() {
+        let config = config!(b"save 900 1\nsave \"\"", Logger::new(Level::Warning));
+        assert_eq!(config.save, vec![]);
+    }
+
+    #[test]
+    fn parse_aclfile() {
+        let config = config!(
+            b"aclfile /etc/rsedis/users.acl\nacllog-max-len 16",
+            Logger::new(Level::Warning)
+        );
+        assert_eq!(config.aclfile, Some("/etc/rsedis/users.acl".to_owned()));
+        assert_eq!(config.acllog_max_len, 16);
+    }
+
+    #[test]
+    fn parse_notify_keyspace_events() {
+        let config = config!(b"notify-keyspace-events Ex", Logger::new(Level::Warning));
+        assert_eq!(
+            config.notify_keyspace_events,
+            NOTIFY_KEYEVENT | NOTIFY_EXPIRED
+        );
+    }
+
+    #[test]
+    fn parse_tracking_table_max_keys() {
+        let config = config!(b"tracking-table-max-keys 0", Logger::new(Level::Warning));
+        assert_eq!(config.tracking_table_max_keys, 0);
+    }
+
+    #[test]
+    fn parse_cluster() {
+        let config = config!(
+            b"cluster-enabled yes\ncluster-config-file nodes-6380.conf\ncluster-node-timeout 5000",
+            Logger::new(Level::Warning)
+        );
+        assert!(config.cluster_enabled);
+        assert_eq!(config.cluster_config_file, "nodes-6380.conf".to_owned());
+        assert_eq!(config.cluster_node_timeout, 5000);
+    }
+
+    #[test]
+    fn parse_lazyfree() {
+        let config = config!(
+            b"lazyfree-lazy-expire yes\nlazyfree-lazy-server-del yes\nlazyfree-lazy-user-del yes",
+            Logger::new(Level::Warning)
+        );
+        assert!(!config.lazyfree_lazy_eviction);
+        assert!(config.lazyfree_lazy_expire);
+        assert!(config.lazyfree_lazy_server_del);
+        assert!(config.lazyfree_lazy_user_del);
+    }
+
+    #[test]
+    fn parse_lfu() {
+        let config = config!(
+            b"lfu-log-factor 20\nlfu-decay-time 0",
+            Logger::new(Level::Warning)
+        );
+        assert_eq!(config.lfu_log_factor, 20);
+        assert_eq!(config.lfu_decay_time, 0);
+    }
+
+    #[test]
+    fn parse_sentinel() {
+        let dirpath = format!("tmp/{}", mstime());
+        let filepath = format!("{}/{}.conf", dirpath, random::<u64>());
+        let _ = create_dir("tmp");
+        let _ = create_dir(dirpath);
+        File::create(filepath.clone())
+            .unwrap()
+            .write_all(b"port 26380\nsentinel monitor mymaster 127.0.0.1 6379 2")
+            .unwrap();
+        let mut config = Config::new(Logger::new(Level::Warning));
+        assert!(config.parsefile(filepath.clone()).is_err());
+        config.sentinel_mode = true;
+        config.parsefile(filepath).unwrap();
+        assert_eq!(config.port, 26380);
+        assert_eq!(
+            config.sentinel,
+            vec![vec!["monitor", "mymaster", "127.0.0.1", "6379", "2"]]
+        );
+    }
 }
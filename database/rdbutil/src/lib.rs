@@ -143,7 +143,9 @@ pub fn encode_slice_u8<W: io::Write>(
     if as_int && data.len() <= 11 {
         if let Some(()) = from_utf8(data)
             .ok()
-            .and_then(|s| s.parse().ok())
+            .and_then(|s| s.parse::<i64>().ok())
+            // only canonical numbers, otherwise "007" would decode as "7"
+            .filter(|i| i.to_string().as_bytes() == data)
             .and_then(|i| encode_i64(i, enc).ok())
         {
             return Ok(());
@@ -158,24 +160,97 @@ pub fn encode_slice_u8<W: io::Write>(
     Ok(())
 }
 
+fn read_bytes<R: io::Read>(dec: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    dec.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn decode_u16_from_slice_u8<R: io::Read>(dec: &mut R) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    dec.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+pub fn decode_u32_from_slice_u8<R: io::Read>(dec: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    dec.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn decode_u64_from_slice_u8<R: io::Read>(dec: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    dec.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+// Returns whether the first byte is a special encoding and either the length
+// or the encoding type
+fn decode_len_or_encoding<R: io::Read>(first: u8, dec: &mut R) -> io::Result<(bool, usize)> {
+    Ok(match first >> 6 {
+        BITLEN6 => (false, (first & 0x3F) as usize),
+        BITLEN14 => {
+            let mut buf = [0; 1];
+            dec.read_exact(&mut buf)?;
+            (false, (((first & 0x3F) as usize) << 8) | buf[0] as usize)
+        }
+        BITLEN32 => {
+            let mut buf = [0; 4];
+            dec.read_exact(&mut buf)?;
+            (false, u32::from_be_bytes(buf) as usize)
+        }
+        _ => (true, (first & 0x3F) as usize),
+    })
+}
+
+pub fn decode_len<R: io::Read>(dec: &mut R) -> io::Result<usize> {
+    let mut buf = [0; 1];
+    dec.read_exact(&mut buf)?;
+    match decode_len_or_encoding(buf[0], dec)? {
+        (false, len) => Ok(len),
+        (true, _) => Err(invalid_data("unexpected encoded value")),
+    }
+}
+
+pub fn decode_slice_u8<R: io::Read>(dec: &mut R) -> io::Result<Vec<u8>> {
+    let mut buf = [0; 1];
+    dec.read_exact(&mut buf)?;
+    let value = match decode_len_or_encoding(buf[0], dec)? {
+        (false, len) => return read_bytes(dec, len),
+        (true, len) if len == ENC_INT8 as usize => {
+            let mut buf = [0; 1];
+            dec.read_exact(&mut buf)?;
+            buf[0] as i8 as i64
+        }
+        (true, len) if len == ENC_INT16 as usize => decode_u16_from_slice_u8(dec)? as i16 as i64,
+        (true, len) if len == ENC_INT32 as usize => decode_u32_from_slice_u8(dec)? as i32 as i64,
+        (true, _) => return Err(invalid_data("unsupported string encoding")),
+    };
+    Ok(value.to_string().into_bytes())
+}
+
 #[test]
 fn test_encode_i64() {
     let mut v = vec![];
-    assert_eq!(encode_i64(1, &mut v).unwrap(), 2);
+    encode_i64(1, &mut v).unwrap();
     assert_eq!(v, vec![192, 1]);
 }
 
 #[test]
 fn test_encode_i64_2bytes() {
     let mut v = vec![];
-    assert_eq!(encode_i64(260, &mut v).unwrap(), 3);
+    encode_i64(260, &mut v).unwrap();
     assert_eq!(v, b"\xc1\x04\x01");
 }
 
 #[test]
 fn test_encode_i64_4bytes() {
     let mut v = vec![];
-    assert_eq!(encode_i64(70000, &mut v).unwrap(), 5);
+    encode_i64(70000, &mut v).unwrap();
     assert_eq!(v, b"\xc2p\x11\x01\x00");
 }
 
@@ -191,7 +266,7 @@ fn test_encode_i64_overflow() {
 #[test]
 fn test_encode_usize() {
     let mut v = vec![];
-    assert_eq!(encode_usize(123, &mut v).unwrap(), 2);
+    encode_usize(123, &mut v).unwrap();
     assert_eq!(v, vec![192, 123]);
 }
 
@@ -207,13 +282,43 @@ fn test_encode_usize_overflow() {
 #[test]
 fn test_encode_slice_u8_integer() {
     let mut v = vec![];
-    assert_eq!(encode_slice_u8(b"1", &mut v, true).unwrap(), 2);
+    encode_slice_u8(b"1", &mut v, true).unwrap();
     assert_eq!(v, vec![192, 1]);
 }
 
 #[test]
 fn test_encode_slice_u8_data() {
     let mut v = vec![];
-    assert_eq!(encode_slice_u8(b"hello world", &mut v, true).unwrap(), 12);
+    encode_slice_u8(b"hello world", &mut v, true).unwrap();
     assert_eq!(v, b"\x0bhello world");
 }
+
+#[test]
+fn test_decode_len() {
+    for len in &[0, 63, 64, 16383, 16384, 70000] {
+        let mut v = vec![];
+        encode_len(*len, &mut v).unwrap();
+        assert_eq!(decode_len(&mut &v[..]).unwrap(), *len);
+    }
+    assert!(decode_len(&mut &b"\xc0\x01"[..]).is_err());
+    assert!(decode_len(&mut &b""[..]).is_err());
+}
+
+#[test]
+fn test_decode_slice_u8() {
+    for data in &[
+        &b"0"[..],
+        b"-128",
+        b"260",
+        b"-70000",
+        b"007",
+        b"hello world",
+        b"12345678901",
+    ] {
+        let mut v = vec![];
+        encode_slice_u8(data, &mut v, true).unwrap();
+        assert_eq!(decode_slice_u8(&mut &v[..]).unwrap(), data.to_vec());
+    }
+    assert!(decode_slice_u8(&mut &b"\x05abc"[..]).is_err());
+    assert!(decode_slice_u8(&mut &b"\xc3\x00"[..]).is_err());
+}
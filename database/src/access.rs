@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicI64, AtomicU8, Ordering};
+
+use rand::random;
+
+/// Access frequency counter of a new key, so it is not considered cold
+/// before it has a chance to be used.
+pub const LFU_INIT_VAL: u8 = 5;
+
+/// When a key was last used and a logarithmic counter of how often it is
+/// used, modelled after Redis' LRU clock and LFU counter.
+/// They are updated by readers holding a shared lock, hence the atomics.
+#[derive(Debug)]
+pub struct KeyAccess {
+    /// Milliseconds of the last access
+    last_access_ms: AtomicI64,
+    /// Logarithmic access frequency counter
+    counter: AtomicU8,
+}
+
+/// Counter decremented by the number of `decay_time` minutes elapsed since
+/// `last_access_ms`.
+fn decayed_counter(counter: u8, last_access_ms: i64, now: i64, decay_time: u64) -> u8 {
+    if decay_time == 0 {
+        return counter;
+    }
+    let minutes = (now - last_access_ms).max(0) as u64 / 60000;
+    let periods = minutes / decay_time;
+    if periods > u64::from(counter) {
+        0
+    } else {
+        counter - periods as u8
+    }
+}
+
+impl KeyAccess {
+    pub fn new(now: i64) -> KeyAccess {
+        KeyAccess {
+            last_access_ms: AtomicI64::new(now),
+            counter: AtomicU8::new(LFU_INIT_VAL),
+        }
+    }
+
+    /// Records an access. The counter is first decayed and then incremented
+    /// with a probability that gets lower the higher it is, so that
+    /// `log_factor` controls how many accesses it takes to saturate it.
+    pub fn touch(&self, now: i64, log_factor: u64, decay_time: u64) {
+        let last = self.last_access_ms.swap(now, Ordering::Relaxed);
+        let mut counter =
+            decayed_counter(self.counter.load(Ordering::Relaxed), last, now, decay_time);
+        if counter < u8::MAX {
+            let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+            let p = 1.0 / (base * log_factor as f64 + 1.0);
+            if random::<f64>() < p {
+                counter += 1;
+            }
+        }
+        self.counter.store(counter, Ordering::Relaxed);
+    }
+
+    /// Milliseconds since the last access.
+    pub fn idle_ms(&self, now: i64) -> i64 {
+        (now - self.last_access_ms.load(Ordering::Relaxed)).max(0)
+    }
+
+    /// Access frequency counter, decayed to `now` without recording an access.
+    pub fn freq(&self, now: i64, decay_time: u64) -> u8 {
+        decayed_counter(
+            self.counter.load(Ordering::Relaxed),
+            self.last_access_ms.load(Ordering::Relaxed),
+            now,
+            decay_time,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test_access {
+    use super::*;
+
+    #[test]
+    fn idle() {
+        let access = KeyAccess::new(1000);
+        assert_eq!(access.idle_ms(1500), 500);
+        access.touch(2000, 10, 1);
+        assert_eq!(access.idle_ms(2500), 500);
+        assert_eq!(access.idle_ms(0), 0);
+    }
+
+    #[test]
+    fn counter_grows() {
+        let access = KeyAccess::new(0);
+        assert_eq!(access.freq(0, 1), LFU_INIT_VAL);
+        // with no log factor every access increments the counter
+        for _ in 0..10 {
+            access.touch(0, 0, 1);
+        }
+        assert_eq!(access.freq(0, 1), LFU_INIT_VAL + 10);
+        for _ in 0..300 {
+            access.touch(0, 0, 1);
+        }
+        assert_eq!(access.freq(0, 1), 255);
+    }
+
+    #[test]
+    fn counter_decays() {
+        let access = KeyAccess::new(0);
+        assert_eq!(access.freq(60000 * 3, 1), LFU_INIT_VAL - 3);
+        assert_eq!(access.freq(60000 * 3, 2), LFU_INIT_VAL - 1);
+        assert_eq!(access.freq(60000 * 100, 1), 0);
+        assert_eq!(access.freq(60000 * 100, 0), LFU_INIT_VAL);
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::btree_map;
+use std::collections::vec_deque;
+use std::collections::{BTreeMap, VecDeque};
+
+use sha2::{Digest, Sha256};
+
+use util::{glob_match, mstime};
+
+/// Name of the user new connections are authenticated as.
+pub const DEFAULT_USER: &str = "default";
+
+/// Two denials for the same reason, context, object and user in this many
+/// milliseconds are grouped in the same log entry.
+const ACL_LOG_GROUPING_MAX_TIME_DELTA: i64 = 60000;
+
+/// Hashes a password the way it is stored and displayed, as a lowercase hex
+/// SHA-256 digest.
+///
+/// # Examples
+///
+/// ```
+/// # use database::acl::hash_password;
+/// #
+/// assert_eq!(
+///     hash_password(b"foo"),
+///     "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+/// );
+/// ```
+pub fn hash_password(password: &[u8]) -> String {
+    Sha256::digest(password)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A set of commands a rule applies to.
+#[derive(PartialEq, Clone, Debug)]
+pub enum CommandRule {
+    All,
+    Category(String),
+    Command(String),
+}
+
+impl CommandRule {
+    fn matches(&self, command: &str, categories: &[&str]) -> bool {
+        match *self {
+            CommandRule::All => true,
+            CommandRule::Category(ref c) => categories.contains(&&**c),
+            CommandRule::Command(ref c) => c == command,
+        }
+    }
+}
+
+/// A user clients can authenticate as, and what it is allowed to do.
+#[derive(PartialEq, Clone, Debug)]
+pub struct User {
+    pub name: String,
+    pub enabled: bool,
+    /// Any password is accepted.
+    pub nopass: bool,
+    /// Password hashes, as returned by `hash_password`.
+    pub passwords: Vec<String>,
+    /// Rules to allow or deny commands, the last one matching a command wins.
+    /// Commands not matched by any rule are denied.
+    pub commands: Vec<(bool, CommandRule)>,
+    /// Glob patterns of the keys the user can access.
+    pub keys: Vec<Vec<u8>>,
+    /// Glob patterns of the pubsub channels the user can access.
+    pub channels: Vec<Vec<u8>>,
+}
+
+impl User {
+    /// Creates a disabled user that cannot run any command.
+    pub fn new(name: &str) -> Self {
+        User {
+            name: name.to_owned(),
+            enabled: false,
+            nopass: false,
+            passwords: vec![],
+            commands: vec![],
+            keys: vec![],
+            channels: vec![],
+        }
+    }
+
+    /// Creates the user that is available when no other is configured. It can
+    /// do anything and, unless `requirepass` is set, it has no password.
+    pub fn default_user(requirepass: Option<&str>) -> Self {
+        let mut user = User::new(DEFAULT_USER);
+        user.enabled = true;
+        match requirepass {
+            Some(password) => user.add_password(hash_password(password.as_bytes())),
+            None => user.nopass = true,
+        }
+        user.set_command_rule(true, CommandRule::All);
+        user.keys.push(b"*".to_vec());
+        user.channels.push(b"*".to_vec());
+        user
+    }
+
+    /// Whether `password` can be used to authenticate as this user.
+    pub fn check_password(&self, password: &[u8]) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash_password(password)))
+    }
+
+    pub fn add_password(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&hash) {
+            self.passwords.push(hash);
+        }
+    }
+
+    /// Removes a password hash. Returns false if the user did not have it.
+    pub fn remove_password(&mut self, hash: &str) -> bool {
+        let len = self.passwords.len();
+        self.passwords.retain(|p| p != hash);
+        self.passwords.len() != len
+    }
+
+    /// Allows or denies a set of commands. Rules for all the commands replace
+    /// every previous rule.
+    pub fn set_command_rule(&mut self, allow: bool, rule: CommandRule) {
+        if rule == CommandRule::All {
+            self.commands.clear();
+            if allow {
+                self.commands.push((allow, rule));
+            }
+            return;
+        }
+        self.commands.retain(|(_, r)| *r != rule);
+        self.commands.push((allow, rule));
+    }
+
+    /// Whether the user can run `command`, which belongs to `categories`.
+    pub fn can_execute(&self, command: &str, categories: &[&str]) -> bool {
+        self.commands
+            .iter()
+            .rev()
+            .find(|(_, rule)| rule.matches(command, categories))
+            .is_some_and(|&(allow, _)| allow)
+    }
+
+    pub fn can_access_key(&self, key: &[u8]) -> bool {
+        self.keys.iter().any(|p| glob_match(p, key, false))
+    }
+
+    /// Whether the user can access a channel. A pattern subscription is only
+    /// allowed if it is literally one of the user patterns.
+    pub fn can_access_channel(&self, channel: &[u8], is_pattern: bool) -> bool {
+        self.channels.iter().any(|p| {
+            &**p == b"*"
+                || if is_pattern {
+                    &**p == channel
+                } else {
+                    glob_match(p, channel, false)
+                }
+        })
+    }
+
+    /// Describes the allowed commands as a list of rules.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use database::acl::{CommandRule, User};
+    /// #
+    /// let mut user = User::new("foo");
+    /// assert_eq!(user.describe_commands(), "-@all");
+    /// user.set_command_rule(true, CommandRule::All);
+    /// user.set_command_rule(false, CommandRule::Command("debug".to_owned()));
+    /// assert_eq!(user.describe_commands(), "+@all -debug");
+    /// ```
+    pub fn describe_commands(&self) -> String {
+        let mut rules = vec![];
+        if self.commands.first() != Some(&(true, CommandRule::All)) {
+            rules.push("-@all".to_owned());
+        }
+        for &(allow, ref rule) in self.commands.iter() {
+            let sign = if allow { '+' } else { '-' };
+            rules.push(match *rule {
+                CommandRule::All => format!("{}@all", sign),
+                CommandRule::Category(ref c) => format!("{}@{}", sign, c),
+                CommandRule::Command(ref c) => format!("{}{}", sign, c),
+            });
+        }
+        rules.join(" ")
+    }
+
+    pub fn describe_keys(&self) -> String {
+        describe_patterns('~', &self.keys)
+    }
+
+    pub fn describe_channels(&self) -> String {
+        describe_patterns('&', &self.channels)
+    }
+
+    /// Describes the user as the list of rules that would create it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use database::acl::User;
+    /// #
+    /// assert_eq!(
+    ///     User::default_user(None).describe(),
+    ///     "user default on nopass ~* &* +@all"
+    /// );
+    /// ```
+    pub fn describe(&self) -> String {
+        let mut rules = vec![
+            "user".to_owned(),
+            self.name.clone(),
+            if self.enabled { "on" } else { "off" }.to_owned(),
+        ];
+        if self.nopass {
+            rules.push("nopass".to_owned());
+        }
+        for hash in self.passwords.iter() {
+            rules.push(format!("#{}", hash));
+        }
+        if !self.keys.is_empty() {
+            rules.push(self.describe_keys());
+        }
+        if self.channels.is_empty() {
+            rules.push("resetchannels".to_owned());
+        } else {
+            rules.push(self.describe_channels());
+        }
+        rules.push(self.describe_commands());
+        rules.join(" ")
+    }
+}
+
+fn describe_patterns(prefix: char, patterns: &[Vec<u8>]) -> String {
+    patterns
+        .iter()
+        .map(|p| format!("{}{}", prefix, String::from_utf8_lossy(p)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Why a command was denied.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum LogReason {
+    Auth,
+    Command,
+    Key,
+    Channel,
+}
+
+impl LogReason {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            LogReason::Auth => "auth",
+            LogReason::Command => "command",
+            LogReason::Key => "key",
+            LogReason::Channel => "channel",
+        }
+    }
+}
+
+/// A denied command or authentication, as listed by ACL LOG.
+#[derive(PartialEq, Clone, Debug)]
+pub struct LogEntry {
+    /// Number of times it happened.
+    pub count: usize,
+    pub reason: LogReason,
+    /// "toplevel" or "multi".
+    pub context: &'static str,
+    /// The command, key or channel name.
+    pub object: String,
+    pub username: String,
+    /// Last time it happened, in milliseconds.
+    pub mstime: i64,
+    pub client_info: String,
+}
+
+/// Users and the log of denied commands.
+pub struct Acl {
+    users: BTreeMap<String, User>,
+    log: VecDeque<LogEntry>,
+    log_max_len: usize,
+}
+
+impl Acl {
+    pub fn new(requirepass: Option<&str>, log_max_len: usize) -> Self {
+        let mut users = BTreeMap::new();
+        users.insert(DEFAULT_USER.to_owned(), User::default_user(requirepass));
+        Acl {
+            users,
+            log: VecDeque::new(),
+            log_max_len,
+        }
+    }
+
+    pub fn get_user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    /// Creates a user or replaces the one with the same name.
+    pub fn set_user(&mut self, user: User) {
+        self.users.insert(user.name.clone(), user);
+    }
+
+    /// Removes a user. The default user cannot be removed.
+    pub fn remove_user(&mut self, name: &str) -> bool {
+        name != DEFAULT_USER && self.users.remove(name).is_some()
+    }
+
+    /// Replaces every user. The default user is kept unless it is replaced.
+    pub fn set_users(&mut self, users: Vec<User>) {
+        let default_user = self.users.remove(DEFAULT_USER).unwrap();
+        self.users.clear();
+        self.users.insert(DEFAULT_USER.to_owned(), default_user);
+        for user in users {
+            self.set_user(user);
+        }
+    }
+
+    /// Iterates over every user, sorted by name.
+    pub fn users(&self) -> btree_map::Values<'_, String, User> {
+        self.users.values()
+    }
+
+    /// Adds a denial to the log, or increments the counter of a recent
+    /// entry for the same thing.
+    pub fn log(
+        &mut self,
+        reason: LogReason,
+        context: &'static str,
+        object: String,
+        username: String,
+        client_info: String,
+    ) {
+        let now = mstime();
+        let position = self.log.iter().position(|e| {
+            e.reason == reason
+                && e.context == context
+                && e.object == object
+                && e.username == username
+                && now - e.mstime < ACL_LOG_GROUPING_MAX_TIME_DELTA
+        });
+        let entry = match position {
+            Some(pos) => {
+                let mut entry = self.log.remove(pos).unwrap();
+                entry.count += 1;
+                entry.mstime = now;
+                entry.client_info = client_info;
+                entry
+            }
+            None => LogEntry {
+                count: 1,
+                reason,
+                context,
+                object,
+                username,
+                mstime: now,
+                client_info,
+            },
+        };
+        self.log.push_front(entry);
+        self.log.truncate(self.log_max_len);
+    }
+
+    /// Iterates over the log, newest entries first.
+    pub fn log_entries(&self) -> vec_deque::Iter<'_, LogEntry> {
+        self.log.iter()
+    }
+
+    pub fn reset_log(&mut self) {
+        self.log.clear();
+    }
+}
+
+#[cfg(test)]
+mod test_acl {
+    use super::{hash_password, Acl, CommandRule, LogReason, User, DEFAULT_USER};
+
+    #[test]
+    fn default_user() {
+        let acl = Acl::new(None, 128);
+        let user = acl.get_user(DEFAULT_USER).unwrap();
+        assert!(user.check_password(b"anything"));
+        assert!(user.can_execute("flushall", &["write"]));
+        assert!(user.can_access_key(b"key"));
+        assert!(user.can_access_channel(b"pattern*", true));
+
+        let acl = Acl::new(Some("secret"), 128);
+        let user = acl.get_user(DEFAULT_USER).unwrap();
+        assert!(!user.check_password(b"anything"));
+        assert!(user.check_password(b"secret"));
+        assert_eq!(user.passwords, vec![hash_password(b"secret")]);
+    }
+
+    #[test]
+    fn command_rules() {
+        let mut user = User::new("user");
+        assert!(!user.can_execute("get", &["read", "fast"]));
+        user.set_command_rule(true, CommandRule::Category("read".to_owned()));
+        user.set_command_rule(false, CommandRule::Command("keys".to_owned()));
+        assert!(user.can_execute("get", &["read", "fast"]));
+        assert!(!user.can_execute("keys", &["read", "slow"]));
+        assert!(!user.can_execute("set", &["write", "slow"]));
+        assert_eq!(user.describe_commands(), "-@all +@read -keys");
+
+        user.set_command_rule(true, CommandRule::Command("keys".to_owned()));
+        assert!(user.can_execute("keys", &["read", "slow"]));
+        assert_eq!(user.describe_commands(), "-@all +@read +keys");
+
+        user.set_command_rule(false, CommandRule::All);
+        assert!(!user.can_execute("get", &["read", "fast"]));
+        assert_eq!(user.describe_commands(), "-@all");
+    }
+
+    #[test]
+    fn keys_and_channels() {
+        let mut user = User::new("user");
+        user.keys.push(b"foo:*".to_vec());
+        user.channels.push(b"news.*".to_vec());
+        assert!(user.can_access_key(b"foo:1"));
+        assert!(!user.can_access_key(b"bar:1"));
+        assert!(user.can_access_channel(b"news.sports", false));
+        assert!(!user.can_access_channel(b"news.sports", true));
+        assert!(user.can_access_channel(b"news.*", true));
+        assert!(!user.can_access_channel(b"weather", false));
+    }
+
+    #[test]
+    fn remove_user() {
+        let mut acl = Acl::new(None, 128);
+        acl.set_user(User::new("user"));
+        assert!(!acl.remove_user(DEFAULT_USER));
+        assert!(acl.remove_user("user"));
+        assert!(!acl.remove_user("user"));
+    }
+
+    #[test]
+    fn log() {
+        let mut acl = Acl::new(None, 2);
+        acl.log(
+            LogReason::Command,
+            "toplevel",
+            "get".to_owned(),
+            "user".to_owned(),
+            "id=1".to_owned(),
+        );
+        acl.log(
+            LogReason::Key,
+            "toplevel",
+            "key".to_owned(),
+            "user".to_owned(),
+            "id=1".to_owned(),
+        );
+        acl.log(
+            LogReason::Command,
+            "toplevel",
+            "get".to_owned(),
+            "user".to_owned(),
+            "id=2".to_owned(),
+        );
+        let entries = acl.log_entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].reason, LogReason::Command);
+        assert_eq!(entries[0].count, 2);
+        assert_eq!(entries[0].client_info, "id=2");
+        assert_eq!(entries[1].reason, LogReason::Key);
+
+        acl.log(
+            LogReason::Auth,
+            "toplevel",
+            "AUTH".to_owned(),
+            "user".to_owned(),
+            "id=1".to_owned(),
+        );
+        let entries = acl.log_entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].reason, LogReason::Command);
+
+        acl.reset_log();
+        assert_eq!(acl.log_entries().count(), 0);
+    }
+}
@@ -0,0 +1,1021 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use util::get_random_hex_chars;
+
+/// Number of hash slots the keyspace is split into.
+pub const CLUSTER_SLOTS: usize = 16384;
+
+/// The cluster bus listens on the client port plus this offset.
+pub const CLUSTER_PORT_INCR: u16 = 10000;
+
+/// Length of a node id, in hex characters.
+const CLUSTER_NAMELEN: usize = 40;
+
+/// Milliseconds between two pings to the same node.
+const CLUSTER_PING_INTERVAL: i64 = 1000;
+
+/// CRC16 (XMODEM) of a buffer, as used to map keys to slots.
+///
+/// # Examples
+///
+/// ```
+/// # use database::cluster::crc16;
+/// #
+/// assert_eq!(crc16(b"123456789"), 0x31c3);
+/// ```
+pub fn crc16(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for b in buf {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Slot a key belongs to. If the key has a non empty `{tag}`, only the tag is
+/// hashed, so related keys can be forced into the same slot.
+///
+/// # Examples
+///
+/// ```
+/// # use database::cluster::key_hash_slot;
+/// #
+/// assert_eq!(key_hash_slot(b"foo"), 12182);
+/// assert_eq!(key_hash_slot(b"{foo}.bar"), 12182);
+/// ```
+pub fn key_hash_slot(key: &[u8]) -> usize {
+    let hashed = match key.iter().position(|c| *c == b'{') {
+        Some(start) => match key[start + 1..].iter().position(|c| *c == b'}') {
+            Some(0) | None => key,
+            Some(len) => &key[start + 1..start + 1 + len],
+        },
+        None => key,
+    };
+    crc16(hashed) as usize & (CLUSTER_SLOTS - 1)
+}
+
+/// A member of the cluster, as seen by this node.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Node {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    /// Cluster bus port
+    pub cport: u16,
+    /// The node did not answer a ping in time
+    pub pfail: bool,
+    /// Enough masters agree the node is not reachable
+    pub fail: bool,
+    /// We met the node but it did not tell us its id yet
+    pub handshake: bool,
+    /// Milliseconds when the pending ping was sent, 0 if none
+    pub ping_sent: i64,
+    /// Milliseconds when the last pong was received
+    pub pong_received: i64,
+    pub config_epoch: u64,
+    /// Milliseconds when the node was added
+    ctime: i64,
+    /// Milliseconds when the last message was sent to the node
+    last_ping: i64,
+    /// Masters that reported the node as failing, and when
+    fail_reports: HashMap<String, i64>,
+}
+
+impl Node {
+    fn new(id: String, ip: &str, port: u16, cport: u16, now: i64) -> Node {
+        Node {
+            id,
+            ip: ip.to_owned(),
+            port,
+            cport,
+            pfail: false,
+            fail: false,
+            handshake: false,
+            ping_sent: 0,
+            pong_received: 0,
+            config_epoch: 0,
+            ctime: now,
+            last_ping: 0,
+            fail_reports: HashMap::new(),
+        }
+    }
+
+    /// Address clients connect to, as `ip:port`.
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+/// A node as described in `nodes.conf`, CLUSTER NODES or a bus message.
+struct NodeLine {
+    id: String,
+    ip: String,
+    port: u16,
+    cport: u16,
+    flags: Vec<String>,
+    config_epoch: u64,
+    slots: Vec<usize>,
+    migrating: Vec<(usize, String)>,
+    importing: Vec<(usize, String)>,
+}
+
+impl NodeLine {
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+}
+
+fn parse_number<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse()
+        .map_err(|_| format!("Invalid number '{}' in node description", s))
+}
+
+fn parse_slot(s: &str) -> Result<usize, String> {
+    let slot = parse_number(s)?;
+    if slot >= CLUSTER_SLOTS {
+        return Err(format!("Invalid slot '{}' in node description", s));
+    }
+    Ok(slot)
+}
+
+fn parse_node_line(line: &str) -> Result<NodeLine, String> {
+    let fields = line.split(' ').collect::<Vec<_>>();
+    if fields.len() < 8 {
+        return Err(format!("Invalid node description '{}'", line));
+    }
+    let (ip, ports) = match fields[1].rfind(':') {
+        Some(pos) => (&fields[1][..pos], &fields[1][pos + 1..]),
+        None => return Err(format!("Invalid node address '{}'", fields[1])),
+    };
+    let (port, cport) = match ports.find('@') {
+        Some(pos) => (
+            parse_number(&ports[..pos])?,
+            parse_number(&ports[pos + 1..])?,
+        ),
+        None => {
+            let port: u16 = parse_number(ports)?;
+            (port, port.wrapping_add(CLUSTER_PORT_INCR))
+        }
+    };
+    let mut node = NodeLine {
+        id: fields[0].to_owned(),
+        ip: ip.to_owned(),
+        port,
+        cport,
+        flags: fields[2].split(',').map(|f| f.to_owned()).collect(),
+        config_epoch: parse_number(fields[6])?,
+        slots: vec![],
+        migrating: vec![],
+        importing: vec![],
+    };
+    for range in &fields[8..] {
+        if range.starts_with('[') && range.ends_with(']') {
+            let range = &range[1..range.len() - 1];
+            if let Some(pos) = range.find("->-") {
+                node.migrating
+                    .push((parse_slot(&range[..pos])?, range[pos + 3..].to_owned()));
+            } else if let Some(pos) = range.find("-<-") {
+                node.importing
+                    .push((parse_slot(&range[..pos])?, range[pos + 3..].to_owned()));
+            } else {
+                return Err(format!("Invalid open slot '{}'", range));
+            }
+        } else if let Some(pos) = range.find('-') {
+            let start = parse_slot(&range[..pos])?;
+            let end = parse_slot(&range[pos + 1..])?;
+            node.slots.extend(start..=end);
+        } else if !range.is_empty() {
+            node.slots.push(parse_slot(range)?);
+        }
+    }
+    Ok(node)
+}
+
+/// The cluster configuration known by this node: its members, which node
+/// serves each slot, and the slots being moved between nodes.
+pub struct Cluster {
+    /// Id of this node
+    myself: String,
+    nodes: HashMap<String, Node>,
+    /// Id of the node serving each slot
+    slots: Vec<Option<String>>,
+    /// Slots this node is moving out, and the node receiving them
+    migrating: HashMap<usize, String>,
+    /// Slots this node is receiving, and the node sending them
+    importing: HashMap<usize, String>,
+    pub current_epoch: u64,
+    pub last_vote_epoch: u64,
+    /// Whether every slot is served by a reachable node
+    state_ok: bool,
+    /// Milliseconds a node can be unreachable before it is flagged
+    node_timeout: i64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// The configuration changed since it was last saved
+    pub dirty: bool,
+}
+
+impl Cluster {
+    /// Creates a cluster with a single node, this one, with a new random id.
+    pub fn new(port: u16, node_timeout: u64) -> Cluster {
+        let myself = get_random_hex_chars(CLUSTER_NAMELEN);
+        let node = Node::new(
+            myself.clone(),
+            "",
+            port,
+            port.wrapping_add(CLUSTER_PORT_INCR),
+            0,
+        );
+        let mut nodes = HashMap::new();
+        nodes.insert(myself.clone(), node);
+        Cluster {
+            myself,
+            nodes,
+            slots: vec![None; CLUSTER_SLOTS],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            state_ok: false,
+            node_timeout: node_timeout as i64,
+            messages_sent: 0,
+            messages_received: 0,
+            dirty: true,
+        }
+    }
+
+    /// Loads a cluster from the contents of a `nodes.conf` file.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use database::cluster::Cluster;
+    /// #
+    /// let cluster = Cluster::new(6379, 15000);
+    /// let loaded = Cluster::from_config(&cluster.config_string(), 6379, 15000).unwrap();
+    /// assert_eq!(loaded.myself().id, cluster.myself().id);
+    /// ```
+    pub fn from_config(contents: &str, port: u16, node_timeout: u64) -> Result<Cluster, String> {
+        let mut cluster = Cluster::new(port, node_timeout);
+        cluster.nodes.clear();
+        let mut myself = None;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with("vars ") {
+                let vars = line.split(' ').skip(1).collect::<Vec<_>>();
+                for var in vars.chunks(2) {
+                    match (var[0], var.get(1)) {
+                        ("currentEpoch", Some(v)) => cluster.current_epoch = parse_number(v)?,
+                        ("lastVoteEpoch", Some(v)) => cluster.last_vote_epoch = parse_number(v)?,
+                        _ => return Err(format!("Invalid vars line '{}'", line)),
+                    }
+                }
+                continue;
+            }
+            let info = parse_node_line(line)?;
+            let mut node = Node::new(info.id.clone(), &info.ip, info.port, info.cport, 0);
+            node.config_epoch = info.config_epoch;
+            node.pfail = info.has_flag("fail?");
+            node.fail = info.has_flag("fail");
+            if info.has_flag("myself") {
+                if myself.is_some() {
+                    return Err("More than one node flagged as myself".to_owned());
+                }
+                node.port = port;
+                node.cport = port.wrapping_add(CLUSTER_PORT_INCR);
+                myself = Some(info.id.clone());
+                cluster.migrating.extend(info.migrating);
+                cluster.importing.extend(info.importing);
+            }
+            for slot in info.slots {
+                cluster.slots[slot] = Some(info.id.clone());
+            }
+            cluster.nodes.insert(info.id, node);
+        }
+        cluster.myself = match myself {
+            Some(myself) => myself,
+            None => return Err("No node flagged as myself".to_owned()),
+        };
+        cluster.dirty = false;
+        cluster.update_state();
+        Ok(cluster)
+    }
+
+    /// Contents of the `nodes.conf` file describing this cluster.
+    pub fn config_string(&self) -> String {
+        let mut out = String::new();
+        for node in self.nodes() {
+            if !node.handshake {
+                out.push_str(&self.node_line(node, true));
+                out.push('\n');
+            }
+        }
+        out.push_str(&format!(
+            "vars currentEpoch {} lastVoteEpoch {}\n",
+            self.current_epoch, self.last_vote_epoch
+        ));
+        out
+    }
+
+    /// Cluster description as returned by CLUSTER NODES.
+    pub fn nodes_description(&self) -> String {
+        let mut out = String::new();
+        for node in self.nodes() {
+            out.push_str(&self.node_line(node, true));
+            out.push('\n');
+        }
+        out
+    }
+
+    fn node_line(&self, node: &Node, open_slots: bool) -> String {
+        let mut flags = vec![];
+        if node.id == self.myself {
+            flags.push("myself");
+        }
+        if node.handshake {
+            flags.push("handshake");
+        } else {
+            flags.push("master");
+        }
+        if node.pfail {
+            flags.push("fail?");
+        }
+        if node.fail {
+            flags.push("fail");
+        }
+        let connected = node.id == self.myself || !(node.pfail || node.fail || node.handshake);
+        let mut line = format!(
+            "{} {}:{}@{} {} - {} {} {} {}",
+            node.id,
+            node.ip,
+            node.port,
+            node.cport,
+            flags.join(","),
+            node.ping_sent,
+            node.pong_received,
+            node.config_epoch,
+            if connected {
+                "connected"
+            } else {
+                "disconnected"
+            }
+        );
+        for (start, end) in self.slot_ranges(&node.id) {
+            if start == end {
+                line.push_str(&format!(" {}", start));
+            } else {
+                line.push_str(&format!(" {}-{}", start, end));
+            }
+        }
+        if open_slots && node.id == self.myself {
+            let mut migrating = self.migrating.iter().collect::<Vec<_>>();
+            migrating.sort();
+            for (slot, id) in migrating {
+                line.push_str(&format!(" [{}->-{}]", slot, id));
+            }
+            let mut importing = self.importing.iter().collect::<Vec<_>>();
+            importing.sort();
+            for (slot, id) in importing {
+                line.push_str(&format!(" [{}-<-{}]", slot, id));
+            }
+        }
+        line
+    }
+
+    pub fn myself(&self) -> &Node {
+        &self.nodes[&self.myself]
+    }
+
+    fn myself_mut(&mut self) -> &mut Node {
+        self.nodes.get_mut(&self.myself).unwrap()
+    }
+
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    /// Known nodes, sorted by id.
+    pub fn nodes(&self) -> Vec<&Node> {
+        let mut nodes = self.nodes.values().collect::<Vec<_>>();
+        nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        nodes
+    }
+
+    /// Node serving a slot, if any.
+    pub fn slot_owner(&self, slot: usize) -> Option<&Node> {
+        self.slots[slot].as_ref().and_then(|id| self.nodes.get(id))
+    }
+
+    /// Node a slot served by this node is being moved to.
+    pub fn migrating(&self, slot: usize) -> Option<&Node> {
+        self.migrating.get(&slot).and_then(|id| self.nodes.get(id))
+    }
+
+    /// Node a slot is being moved from, into this node.
+    pub fn importing(&self, slot: usize) -> Option<&Node> {
+        self.importing.get(&slot).and_then(|id| self.nodes.get(id))
+    }
+
+    /// Contiguous ranges of slots served by a node.
+    pub fn slot_ranges(&self, id: &str) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if owner.as_ref().is_none_or(|o| o != id) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(range) if range.1 + 1 == slot => range.1 = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    /// Number of masters serving at least one slot.
+    pub fn size(&self) -> usize {
+        self.nodes
+            .keys()
+            .filter(|id| self.slots.iter().any(|o| o.as_ref() == Some(*id)))
+            .count()
+    }
+
+    /// Whether every slot is served by a node that is not failing.
+    pub fn state_ok(&self) -> bool {
+        self.state_ok
+    }
+
+    /// Number of slots served by any node, by nodes flagged as possibly
+    /// failing, and by nodes flagged as failing.
+    pub fn slots_stats(&self) -> (usize, usize, usize) {
+        let (mut assigned, mut pfail, mut fail) = (0, 0, 0);
+        for slot in 0..CLUSTER_SLOTS {
+            if let Some(node) = self.slot_owner(slot) {
+                assigned += 1;
+                if node.fail {
+                    fail += 1;
+                } else if node.pfail {
+                    pfail += 1;
+                }
+            }
+        }
+        (assigned, pfail, fail)
+    }
+
+    fn update_state(&mut self) {
+        self.state_ok = (0..CLUSTER_SLOTS).all(|slot| match self.slot_owner(slot) {
+            Some(node) => !node.fail,
+            None => false,
+        });
+    }
+
+    /// Makes this node serve the given slots.
+    pub fn add_slots(&mut self, slots: &[usize]) -> Result<(), String> {
+        let mut seen = vec![false; CLUSTER_SLOTS];
+        for slot in slots {
+            if seen[*slot] {
+                return Err(format!("ERR Slot {} specified multiple times", slot));
+            }
+            seen[*slot] = true;
+            if self.slots[*slot].is_some() {
+                return Err(format!("ERR Slot {} is already busy", slot));
+            }
+        }
+        for slot in slots {
+            self.importing.remove(slot);
+            self.slots[*slot] = Some(self.myself.clone());
+        }
+        self.dirty = true;
+        self.update_state();
+        Ok(())
+    }
+
+    /// Forgets which node serves the given slots.
+    pub fn del_slots(&mut self, slots: &[usize]) -> Result<(), String> {
+        let mut seen = vec![false; CLUSTER_SLOTS];
+        for slot in slots {
+            if seen[*slot] {
+                return Err(format!("ERR Slot {} specified multiple times", slot));
+            }
+            seen[*slot] = true;
+            if self.slots[*slot].is_none() {
+                return Err(format!("ERR Slot {} is already unassigned", slot));
+            }
+        }
+        for slot in slots {
+            self.slots[*slot] = None;
+        }
+        self.dirty = true;
+        self.update_state();
+        Ok(())
+    }
+
+    fn check_node(&self, id: &str) -> Result<(), String> {
+        if self.nodes.contains_key(id) {
+            Ok(())
+        } else {
+            Err(format!("ERR I don't know about node {}", id))
+        }
+    }
+
+    /// Starts moving a slot this node serves to another node.
+    pub fn set_slot_migrating(&mut self, slot: usize, id: &str) -> Result<(), String> {
+        if self.slots[slot].as_ref() != Some(&self.myself) {
+            return Err(format!("ERR I'm not the owner of hash slot {}", slot));
+        }
+        self.check_node(id)?;
+        self.migrating.insert(slot, id.to_owned());
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Starts receiving a slot served by another node.
+    pub fn set_slot_importing(&mut self, slot: usize, id: &str) -> Result<(), String> {
+        if self.slots[slot].as_ref() == Some(&self.myself) {
+            return Err(format!("ERR I'm already the owner of hash slot {}", slot));
+        }
+        self.check_node(id)?;
+        self.importing.insert(slot, id.to_owned());
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Stops moving a slot in or out of this node.
+    pub fn set_slot_stable(&mut self, slot: usize) {
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+        self.dirty = true;
+    }
+
+    /// Assigns a slot to a node, closing the migration if this node was
+    /// moving it out or in. When this node takes a slot over, its config
+    /// epoch is bumped so the rest of the cluster accepts the change.
+    pub fn set_slot_node(&mut self, slot: usize, id: &str) -> Result<(), String> {
+        self.check_node(id)?;
+        if id != self.myself {
+            self.migrating.remove(&slot);
+        } else if self.importing.remove(&slot).is_some() {
+            self.bump_epoch();
+        }
+        self.slots[slot] = Some(id.to_owned());
+        self.dirty = true;
+        self.update_state();
+        Ok(())
+    }
+
+    /// Gives this node a config epoch greater than any other node's, unless
+    /// it already has the greatest one.
+    fn bump_epoch(&mut self) {
+        let max_epoch = self
+            .nodes
+            .values()
+            .map(|n| n.config_epoch)
+            .max()
+            .unwrap_or(0)
+            .max(self.current_epoch);
+        let myself_epoch = self.myself().config_epoch;
+        let unique = self
+            .nodes
+            .values()
+            .all(|n| n.id == self.myself || n.config_epoch != myself_epoch);
+        if myself_epoch == 0 || myself_epoch != max_epoch || !unique {
+            self.current_epoch = max_epoch + 1;
+            let current_epoch = self.current_epoch;
+            self.myself_mut().config_epoch = current_epoch;
+            self.dirty = true;
+        }
+    }
+
+    /// Starts a handshake with a node, unless one is in progress already.
+    pub fn meet(&mut self, ip: &str, port: u16, cport: u16, now: i64) {
+        if self
+            .nodes
+            .values()
+            .any(|n| n.handshake && n.ip == ip && n.port == port)
+        {
+            return;
+        }
+        let mut node = Node::new(get_random_hex_chars(CLUSTER_NAMELEN), ip, port, cport, now);
+        node.handshake = true;
+        self.nodes.insert(node.id.clone(), node);
+    }
+
+    /// A bus message of the given kind: a header with the current epoch, the
+    /// description of this node, and what it knows about the other nodes.
+    pub fn message(&self, kind: &str) -> String {
+        let mut out = format!(
+            "{} {}\n{}\n",
+            kind,
+            self.current_epoch,
+            self.node_line(self.myself(), false)
+        );
+        for node in self.nodes() {
+            if node.id != self.myself && !node.handshake {
+                out.push_str(&self.node_line(node, false));
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// Messages to send: a MEET to nodes in handshake and a PING to the
+    /// others, to each node once per ping interval. Returns the target node
+    /// id, its bus address and the message.
+    ///
+    /// Nodes that did not answer a ping within the node timeout are flagged
+    /// as possibly failing, and handshakes that did not complete are dropped.
+    pub fn cron(&mut self, now: i64) -> Vec<(String, String, String)> {
+        let handshake_timeout = self.node_timeout.max(1000);
+        let myself = self.myself.clone();
+        self.nodes
+            .retain(|id, n| *id == myself || !n.handshake || now - n.ctime <= handshake_timeout);
+
+        let mut targets = vec![];
+        for node in self.nodes.values_mut() {
+            if node.id == myself {
+                continue;
+            }
+            if node.ping_sent != 0
+                && now - node.ping_sent > self.node_timeout
+                && !node.pfail
+                && !node.fail
+                && !node.handshake
+            {
+                node.pfail = true;
+            }
+            if node.last_ping == 0 || now - node.last_ping >= CLUSTER_PING_INTERVAL {
+                node.last_ping = now;
+                if node.ping_sent == 0 {
+                    node.ping_sent = now;
+                }
+                let addr = if node.ip.contains(':') {
+                    format!("[{}]:{}", node.ip, node.cport)
+                } else {
+                    format!("{}:{}", node.ip, node.cport)
+                };
+                targets.push((
+                    node.id.clone(),
+                    addr,
+                    if node.handshake { "MEET" } else { "PING" },
+                ));
+            }
+        }
+        self.mark_failing(now);
+        self.update_state();
+        self.messages_sent += targets.len() as u64;
+        targets
+            .into_iter()
+            .map(|(id, addr, kind)| (id, addr, self.message(kind)))
+            .collect()
+    }
+
+    /// Flags as failing the nodes this node cannot reach, if a majority of
+    /// the masters serving slots agree.
+    fn mark_failing(&mut self, now: i64) {
+        let needed = self.size() / 2 + 1;
+        let validity = self.node_timeout * 2;
+        let masters = self
+            .nodes
+            .keys()
+            .filter(|id| self.slots.iter().any(|o| o.as_ref() == Some(*id)))
+            .cloned()
+            .collect::<Vec<_>>();
+        for node in self.nodes.values_mut() {
+            node.fail_reports
+                .retain(|id, time| now - *time <= validity && masters.contains(id));
+            if node.pfail && !node.fail && node.fail_reports.len() + 1 >= needed {
+                node.fail = true;
+                node.pfail = false;
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Processes a bus message received from another node. `peer_ip` is the
+    /// address of the other side of the connection and `local_ip` the one of
+    /// this side, used to learn this node's own address. `target` is the node
+    /// a reply was requested from.
+    ///
+    /// Returns the reply to send back, if any.
+    pub fn process_message(
+        &mut self,
+        message: &str,
+        peer_ip: &str,
+        local_ip: &str,
+        target: Option<&str>,
+        now: i64,
+    ) -> Result<Option<String>, String> {
+        self.messages_received += 1;
+        let mut lines = message.lines().filter(|l| !l.is_empty());
+        let header = lines.next().unwrap_or("").split(' ').collect::<Vec<_>>();
+        let kind = header[0];
+        if header.len() != 2 || !["MEET", "PING", "PONG"].contains(&kind) {
+            return Err(format!(
+                "Invalid cluster bus message header '{}'",
+                header[0]
+            ));
+        }
+        let sender_epoch: u64 = parse_number(header[1])?;
+        let sender = parse_node_line(lines.next().unwrap_or(""))?;
+        let sender_ip = if peer_ip.is_empty() {
+            sender.ip.clone()
+        } else {
+            peer_ip.to_owned()
+        };
+
+        if self.myself().ip.is_empty() && !local_ip.is_empty() {
+            self.myself_mut().ip = local_ip.to_owned();
+            self.dirty = true;
+        }
+
+        // the reply to a MEET tells us the real id of the node
+        if let Some(target) = target {
+            if target != sender.id && self.nodes.get(target).is_some_and(|n| n.handshake) {
+                let mut node = self.nodes.remove(target).unwrap();
+                if !self.nodes.contains_key(&sender.id) {
+                    node.id = sender.id.clone();
+                    node.handshake = false;
+                    self.nodes.insert(node.id.clone(), node);
+                    self.dirty = true;
+                }
+            }
+        }
+        if kind == "MEET" && !self.nodes.contains_key(&sender.id) {
+            let node = Node::new(
+                sender.id.clone(),
+                &sender_ip,
+                sender.port,
+                sender.cport,
+                now,
+            );
+            self.nodes.insert(node.id.clone(), node);
+            self.dirty = true;
+        }
+
+        let reply = if kind == "PONG" {
+            None
+        } else {
+            self.messages_sent += 1;
+            Some(self.message("PONG"))
+        };
+        if sender.id == self.myself || !self.nodes.contains_key(&sender.id) {
+            return Ok(reply);
+        }
+
+        if sender_epoch > self.current_epoch {
+            self.current_epoch = sender_epoch;
+            self.dirty = true;
+        }
+        {
+            let node = self.nodes.get_mut(&sender.id).unwrap();
+            if node.ip != sender_ip
+                || node.port != sender.port
+                || node.cport != sender.cport
+                || node.config_epoch != sender.config_epoch
+            {
+                node.ip = sender_ip;
+                node.port = sender.port;
+                node.cport = sender.cport;
+                node.config_epoch = sender.config_epoch;
+                self.dirty = true;
+            }
+            if kind == "PONG" {
+                node.pong_received = now;
+                node.ping_sent = 0;
+                if node.pfail || node.fail {
+                    node.pfail = false;
+                    node.fail = false;
+                    self.dirty = true;
+                }
+            }
+        }
+
+        self.update_slots(&sender);
+        self.handle_epoch_collision(&sender);
+
+        let sender_is_master = !sender.slots.is_empty();
+        for line in lines {
+            let info = parse_node_line(line)?;
+            if info.id == self.myself {
+                continue;
+            }
+            match self.nodes.get_mut(&info.id) {
+                Some(node) => {
+                    if sender_is_master {
+                        if info.has_flag("fail?") || info.has_flag("fail") {
+                            node.fail_reports.insert(sender.id.clone(), now);
+                        } else {
+                            node.fail_reports.remove(&sender.id);
+                        }
+                    }
+                    if info.has_flag("fail") && !node.fail {
+                        node.fail = true;
+                        node.pfail = false;
+                        self.dirty = true;
+                    }
+                }
+                None => {
+                    if !info.has_flag("handshake") && !info.ip.is_empty() {
+                        self.meet(&info.ip, info.port, info.cport, now);
+                    }
+                }
+            }
+        }
+        self.mark_failing(now);
+        self.update_state();
+        Ok(reply)
+    }
+
+    /// Accepts the slots claimed by a node, if it has a greater config epoch
+    /// than their current owner.
+    fn update_slots(&mut self, sender: &NodeLine) {
+        for slot in sender.slots.iter() {
+            if self.importing.contains_key(slot) {
+                continue;
+            }
+            let take = match self.slot_owner(*slot) {
+                Some(owner) => owner.id != sender.id && owner.config_epoch < sender.config_epoch,
+                None => true,
+            };
+            if take {
+                if self.slots[*slot].as_ref() == Some(&self.myself) {
+                    self.migrating.remove(slot);
+                }
+                self.slots[*slot] = Some(sender.id.clone());
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Two masters with the same config epoch cannot tell which one owns a
+    /// slot both claim, so the one with the smaller id takes a new epoch.
+    fn handle_epoch_collision(&mut self, sender: &NodeLine) {
+        if sender.config_epoch != self.myself().config_epoch || sender.id <= self.myself {
+            return;
+        }
+        self.current_epoch += 1;
+        let current_epoch = self.current_epoch;
+        self.myself_mut().config_epoch = current_epoch;
+        self.dirty = true;
+    }
+}
+
+#[cfg(test)]
+mod test_cluster {
+    use super::{crc16, key_hash_slot, Cluster, CLUSTER_SLOTS};
+
+    #[test]
+    fn hash_slots() {
+        assert_eq!(crc16(b""), 0);
+        assert_eq!(key_hash_slot(b"123456789"), 0x31c3);
+        assert_eq!(key_hash_slot(b"user1000"), 3443);
+        assert_eq!(key_hash_slot(b"{user1000}.following"), 3443);
+        assert_eq!(
+            key_hash_slot(b"foo{}{bar}"),
+            crc16(b"foo{}{bar}") as usize % CLUSTER_SLOTS
+        );
+        assert_eq!(key_hash_slot(b"foo{{bar}}zap"), 4015);
+        assert_eq!(key_hash_slot(b"foo{bar}{zap}"), 5061);
+    }
+
+    #[test]
+    fn slots() {
+        let mut cluster = Cluster::new(6379, 15000);
+        assert_eq!(
+            cluster.add_slots(&[1, 1]).unwrap_err(),
+            "ERR Slot 1 specified multiple times"
+        );
+        cluster
+            .add_slots(&(0..CLUSTER_SLOTS).collect::<Vec<_>>())
+            .unwrap();
+        assert!(cluster.state_ok());
+        assert_eq!(cluster.slots_stats(), (CLUSTER_SLOTS, 0, 0));
+        assert_eq!(
+            cluster.add_slots(&[10]).unwrap_err(),
+            "ERR Slot 10 is already busy"
+        );
+        cluster.del_slots(&[10]).unwrap();
+        assert!(!cluster.state_ok());
+        assert_eq!(
+            cluster.del_slots(&[10]).unwrap_err(),
+            "ERR Slot 10 is already unassigned"
+        );
+        assert_eq!(
+            cluster.set_slot_migrating(10, "foo").unwrap_err(),
+            "ERR I'm not the owner of hash slot 10"
+        );
+        assert_eq!(
+            cluster.set_slot_importing(11, "foo").unwrap_err(),
+            "ERR I'm already the owner of hash slot 11"
+        );
+    }
+
+    /// Delivers the messages `from` sends in a cron run to `to`, and the
+    /// replies back.
+    fn exchange(from: &mut Cluster, to: &mut Cluster, now: i64) {
+        for (id, _, message) in from.cron(now) {
+            let reply = to
+                .process_message(&message, "127.0.0.1", "127.0.0.1", None, now)
+                .unwrap();
+            if let Some(reply) = reply {
+                from.process_message(&reply, "127.0.0.1", "127.0.0.1", Some(&id), now)
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn meet_and_gossip() {
+        let mut a = Cluster::new(7000, 15000);
+        let mut b = Cluster::new(7001, 15000);
+        a.add_slots(&(0..8192).collect::<Vec<_>>()).unwrap();
+        b.add_slots(&(8192..CLUSTER_SLOTS).collect::<Vec<_>>())
+            .unwrap();
+        a.meet("127.0.0.1", 7001, 17001, 0);
+
+        exchange(&mut a, &mut b, 0);
+        let b_id = b.myself().id.clone();
+        let a_id = a.myself().id.clone();
+        assert_eq!(a.node(&b_id).unwrap().port, 7001);
+        assert!(!a.node(&b_id).unwrap().handshake);
+        assert_eq!(b.node(&a_id).unwrap().port, 7000);
+        assert_eq!(a.myself().ip, "127.0.0.1");
+        assert_eq!(b.myself().ip, "127.0.0.1");
+
+        assert_eq!(a.slot_owner(8192).unwrap().id, b_id);
+        assert_eq!(b.slot_owner(0).unwrap().id, a_id);
+        assert!(a.state_ok());
+        assert!(b.state_ok());
+        // both started with config epoch 0, so one of them took a new one
+        assert_ne!(a.myself().config_epoch, b.myself().config_epoch);
+
+        // b stops answering
+        a.cron(1000);
+        a.cron(20000);
+        assert!(a.node(&b_id).unwrap().pfail);
+        // two masters need both to agree, but b cannot report itself
+        assert!(!a.node(&b_id).unwrap().fail);
+
+        exchange(&mut a, &mut b, 21000);
+        assert!(!a.node(&b_id).unwrap().pfail);
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let mut a = Cluster::new(7000, 15000);
+        let mut b = Cluster::new(7001, 15000);
+        a.add_slots(&[0, 1, 2, 5]).unwrap();
+        a.meet("127.0.0.1", 7001, 17001, 0);
+        exchange(&mut a, &mut b, 0);
+        let b_id = b.myself().id.clone();
+        a.set_slot_migrating(5, &b_id).unwrap();
+        a.current_epoch = 3;
+
+        let config = a.config_string();
+        assert!(config.contains(&format!(" 0-2 5 [5->-{}]\n", b_id)));
+        assert!(config.ends_with("vars currentEpoch 3 lastVoteEpoch 0\n"));
+
+        let loaded = Cluster::from_config(&config, 8000, 15000).unwrap();
+        assert_eq!(loaded.myself().id, a.myself().id);
+        assert_eq!(loaded.myself().port, 8000);
+        assert_eq!(loaded.node(&b_id).unwrap().port, 7001);
+        assert_eq!(loaded.current_epoch, 3);
+        assert_eq!(loaded.slot_ranges(&a.myself().id), vec![(0, 2), (5, 5)]);
+        assert_eq!(loaded.migrating(5).unwrap().id, b_id);
+        assert!(!loaded.state_ok());
+        assert!(!loaded.dirty);
+
+        assert!(Cluster::from_config("vars currentEpoch 0", 8000, 15000).is_err());
+    }
+
+    #[test]
+    fn slot_takeover() {
+        let mut a = Cluster::new(7000, 15000);
+        let mut b = Cluster::new(7001, 15000);
+        a.add_slots(&[0]).unwrap();
+        a.meet("127.0.0.1", 7001, 17001, 0);
+        exchange(&mut a, &mut b, 0);
+        exchange(&mut b, &mut a, 0);
+        let a_id = a.myself().id.clone();
+        let b_id = b.myself().id.clone();
+
+        a.set_slot_migrating(0, &b_id).unwrap();
+        b.set_slot_importing(0, &a_id).unwrap();
+        b.set_slot_node(0, &b_id).unwrap();
+        assert!(b.importing(0).is_none());
+        assert!(b.myself().config_epoch > a.myself().config_epoch);
+
+        exchange(&mut b, &mut a, 1000);
+        assert_eq!(a.slot_owner(0).unwrap().id, b_id);
+        assert!(a.migrating(0).is_none());
+    }
+}
@@ -1,3 +1,4 @@
+use std::mem::size_of;
 use std::str::from_utf8;
 
 use error::OperationError;
@@ -71,3 +72,44 @@ pub fn vec_to_usize(data: &[u8]) -> Result<usize, OperationError> {
     let res = from_utf8(&data)?;
     Ok(res.parse::<usize>()?)
 }
+
+/// Bytes used by a byte vector, including its allocation.
+///
+/// # Examples
+/// ```
+/// use database::dbutil::vec_memory_usage;
+///
+/// let v = Vec::with_capacity(10);
+/// assert_eq!(vec_memory_usage(&v), std::mem::size_of::<Vec<u8>>() + 10);
+/// ```
+pub fn vec_memory_usage(v: &Vec<u8>) -> usize {
+    size_of::<Vec<u8>>() + v.capacity()
+}
+
+/// Estimates the bytes used by the `len` elements of a collection by adding
+/// the sizes of the first `samples` elements and extrapolating. Zero
+/// samples measures every element.
+///
+/// # Examples
+/// ```
+/// use database::dbutil::sampled_memory_usage;
+///
+/// let sizes = vec![10, 20, 30, 40];
+/// assert_eq!(sampled_memory_usage(sizes.iter().cloned(), 4, 0), 100);
+/// assert_eq!(sampled_memory_usage(sizes.iter().cloned(), 4, 2), 60);
+/// assert_eq!(sampled_memory_usage(vec![].into_iter(), 0, 5), 0);
+/// ```
+pub fn sampled_memory_usage<I: Iterator<Item = usize>>(
+    sizes: I,
+    len: usize,
+    samples: usize,
+) -> usize {
+    let samples = if samples == 0 { len } else { samples };
+    let mut total = 0;
+    let mut measured = 0;
+    for size in sizes.take(samples) {
+        total += size;
+        measured += 1;
+    }
+    (total * len).checked_div(measured).unwrap_or(0)
+}
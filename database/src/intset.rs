@@ -0,0 +1,264 @@
+use std::str::from_utf8;
+
+/// Size in bytes of each entry encoding.
+pub const INTSET_ENC_INT16: usize = 2;
+pub const INTSET_ENC_INT32: usize = 4;
+pub const INTSET_ENC_INT64: usize = 8;
+
+/// A sorted set of integers stored in a contiguous little-endian buffer,
+/// modelled after Redis' intset.
+/// Every entry uses the same width, which is upgraded when a value that does
+/// not fit is inserted. Membership is resolved with a binary search.
+#[derive(PartialEq, Debug, Clone)]
+pub struct IntSet {
+    encoding: usize,
+    contents: Vec<u8>,
+}
+
+impl Default for IntSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the smallest encoding able to hold `value`.
+///
+/// # Examples
+/// ```
+/// use database::intset::*;
+///
+/// assert_eq!(value_encoding(-32768), INTSET_ENC_INT16);
+/// assert_eq!(value_encoding(32768), INTSET_ENC_INT32);
+/// assert_eq!(value_encoding(-2147483649), INTSET_ENC_INT64);
+/// ```
+pub fn value_encoding(value: i64) -> usize {
+    if value < i64::from(i32::MIN) || value > i64::from(i32::MAX) {
+        INTSET_ENC_INT64
+    } else if value < i64::from(i16::MIN) || value > i64::from(i16::MAX) {
+        INTSET_ENC_INT32
+    } else {
+        INTSET_ENC_INT16
+    }
+}
+
+/// Parses an element as an intset member. Only the canonical representation
+/// of a number is accepted, so that converting it back yields the same bytes.
+///
+/// # Examples
+/// ```
+/// use database::intset::parse_member;
+///
+/// assert_eq!(parse_member(b"-12"), Some(-12));
+/// assert_eq!(parse_member(b"012"), None);
+/// assert_eq!(parse_member(b"+12"), None);
+/// assert_eq!(parse_member(b"-0"), None);
+/// assert_eq!(parse_member(b"a"), None);
+/// ```
+pub fn parse_member(data: &[u8]) -> Option<i64> {
+    let s = from_utf8(data).ok()?;
+    let value = s.parse::<i64>().ok()?;
+    if format!("{}", value) == s {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Formats an intset member as a set element.
+pub fn member_to_vec(value: i64) -> Vec<u8> {
+    format!("{}", value).into_bytes()
+}
+
+fn encode_value(value: i64, encoding: usize) -> Vec<u8> {
+    match encoding {
+        INTSET_ENC_INT16 => (value as i16).to_le_bytes().to_vec(),
+        INTSET_ENC_INT32 => (value as i32).to_le_bytes().to_vec(),
+        _ => value.to_le_bytes().to_vec(),
+    }
+}
+
+impl IntSet {
+    pub fn new() -> IntSet {
+        IntSet {
+            encoding: INTSET_ENC_INT16,
+            contents: vec![],
+        }
+    }
+
+    /// Creates an intset from a serialized payload. Returns None if the
+    /// encoding is unknown or the entries are not sorted and unique.
+    pub fn from_contents(encoding: usize, contents: Vec<u8>) -> Option<IntSet> {
+        match encoding {
+            INTSET_ENC_INT16 | INTSET_ENC_INT32 | INTSET_ENC_INT64 => (),
+            _ => return None,
+        }
+        if !contents.len().is_multiple_of(encoding) {
+            return None;
+        }
+        let set = IntSet { encoding, contents };
+        for pos in 1..set.len() {
+            if set.get(pos - 1) >= set.get(pos) {
+                return None;
+            }
+        }
+        Some(set)
+    }
+
+    /// Bytes used by each entry.
+    pub fn encoding(&self) -> usize {
+        self.encoding
+    }
+
+    /// The serialized entries.
+    pub fn contents(&self) -> &[u8] {
+        &self.contents
+    }
+
+    pub fn len(&self) -> usize {
+        self.contents.len() / self.encoding
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty()
+    }
+
+    /// Returns the value at `pos`. Panics if out of range.
+    pub fn get(&self, pos: usize) -> i64 {
+        let start = pos * self.encoding;
+        let bytes = &self.contents[start..start + self.encoding];
+        match self.encoding {
+            INTSET_ENC_INT16 => i64::from(i16::from_le_bytes([bytes[0], bytes[1]])),
+            INTSET_ENC_INT32 => {
+                i64::from(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            }
+            _ => {
+                let mut b = [0; 8];
+                b.copy_from_slice(bytes);
+                i64::from_le_bytes(b)
+            }
+        }
+    }
+
+    /// Binary search for `value`. Returns its position or where it would be
+    /// inserted.
+    fn search(&self, value: i64) -> Result<usize, usize> {
+        if value_encoding(value) > self.encoding {
+            // cannot be in the set
+            return Err(if value < 0 { 0 } else { self.len() });
+        }
+        let mut low = 0;
+        let mut high = self.len();
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let current = self.get(mid);
+            if current == value {
+                return Ok(mid);
+            } else if current < value {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Err(low)
+    }
+
+    fn upgrade(&mut self, encoding: usize) {
+        let mut contents = Vec::with_capacity(self.len() * encoding);
+        for value in self.iter() {
+            contents.extend(encode_value(value, encoding));
+        }
+        self.encoding = encoding;
+        self.contents = contents;
+    }
+
+    pub fn contains(&self, value: i64) -> bool {
+        self.search(value).is_ok()
+    }
+
+    /// Inserts a value. Returns false if it was already in the set.
+    pub fn insert(&mut self, value: i64) -> bool {
+        let encoding = value_encoding(value);
+        if encoding > self.encoding {
+            self.upgrade(encoding);
+        }
+        match self.search(value) {
+            Ok(_) => false,
+            Err(pos) => {
+                let start = pos * self.encoding;
+                let bytes = encode_value(value, self.encoding);
+                self.contents.splice(start..start, bytes);
+                true
+            }
+        }
+    }
+
+    /// Removes a value. Returns false if it was not in the set.
+    pub fn remove(&mut self, value: i64) -> bool {
+        match self.search(value) {
+            Ok(pos) => {
+                let start = pos * self.encoding;
+                self.contents.drain(start..start + self.encoding);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Iterates the values in ascending order.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = i64> + 'a {
+        (0..self.len()).map(move |pos| self.get(pos))
+    }
+}
+
+#[cfg(test)]
+mod test_intset {
+    use super::*;
+
+    #[test]
+    fn insert_sorted() {
+        let mut set = IntSet::new();
+        assert!(set.insert(5));
+        assert!(set.insert(-3));
+        assert!(set.insert(1));
+        assert!(!set.insert(5));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![-3, 1, 5]);
+        assert_eq!(set.encoding(), INTSET_ENC_INT16);
+    }
+
+    #[test]
+    fn upgrade() {
+        let mut set = IntSet::new();
+        assert!(set.insert(1));
+        assert!(set.insert(70000));
+        assert_eq!(set.encoding(), INTSET_ENC_INT32);
+        assert!(set.insert(-5000000000));
+        assert_eq!(set.encoding(), INTSET_ENC_INT64);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![-5000000000, 1, 70000]);
+        assert!(set.contains(70000));
+        assert!(!set.contains(2));
+    }
+
+    #[test]
+    fn remove() {
+        let mut set = IntSet::new();
+        for i in -10..10 {
+            assert!(set.insert(i));
+        }
+        assert!(set.remove(-10));
+        assert!(set.remove(0));
+        assert!(!set.remove(0));
+        assert!(!set.remove(100000));
+        assert_eq!(set.len(), 18);
+        assert!(!set.contains(0));
+        assert!(set.contains(9));
+    }
+
+    #[test]
+    fn from_contents() {
+        let set = IntSet::from_contents(2, vec![0xff, 0xff, 1, 0]).unwrap();
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![-1, 1]);
+        assert!(IntSet::from_contents(2, vec![1, 0, 0xff, 0xff]).is_none());
+        assert!(IntSet::from_contents(3, vec![]).is_none());
+        assert!(IntSet::from_contents(4, vec![1, 0]).is_none());
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use rehashinghashmap::RehashingHashMap;
+
+use access::KeyAccess;
+use Value;
+
+/// Values with at most this many elements are freed right away, it is not
+/// worth sending them to another thread.
+pub const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Something detached from the keyspace, waiting to be dropped.
+pub enum Garbage {
+    Value(Value),
+    /// The keys, expirations and access times of a flushed database
+    Keyspace(
+        RehashingHashMap<Vec<u8>, Value>,
+        RehashingHashMap<Vec<u8>, i64>,
+        RehashingHashMap<Vec<u8>, KeyAccess>,
+    ),
+}
+
+impl Garbage {
+    /// Number of objects freed with it.
+    fn objects(&self) -> usize {
+        match *self {
+            Garbage::Value(_) => 1,
+            Garbage::Keyspace(ref data, _, _) => data.len(),
+        }
+    }
+}
+
+/// How much work it takes to free a value, roughly its number of elements.
+///
+/// # Examples
+///
+/// ```
+/// use database::Value;
+/// use database::lazyfree::free_effort;
+///
+/// let mut value = Value::Nil;
+/// value.set(b"foo".to_vec()).unwrap();
+/// assert_eq!(free_effort(&value), 1);
+///
+/// let mut value = Value::Nil;
+/// for i in 0..3 {
+///     value.push(vec![i], true).unwrap();
+/// }
+/// assert_eq!(free_effort(&value), 3);
+/// ```
+pub fn free_effort(value: &Value) -> usize {
+    match *value {
+        Value::Nil | Value::String(_) => 1,
+        Value::List(ref list) => list.llen(),
+        Value::Set(ref set) => set.scard(),
+        Value::SortedSet(ref zset) => zset.zcard(),
+    }
+}
+
+/// Drops values in a background thread. The thread is started the first time
+/// something is sent to it.
+pub struct Lazyfree {
+    sender: Mutex<Option<Sender<Garbage>>>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl Default for Lazyfree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lazyfree {
+    pub fn new() -> Self {
+        Lazyfree {
+            sender: Mutex::new(None),
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Sends `garbage` to the background thread.
+    pub fn free(&self, garbage: Garbage) {
+        let objects = garbage.objects();
+        self.pending.fetch_add(objects, Ordering::Relaxed);
+        let mut sender = self.sender.lock().unwrap_or_else(PoisonError::into_inner);
+        if sender.is_none() {
+            *sender = Some(self.start());
+        }
+        if sender.as_ref().unwrap().send(garbage).is_err() {
+            // the thread is gone, the garbage was dropped with the error
+            self.pending.fetch_sub(objects, Ordering::Relaxed);
+        }
+    }
+
+    fn start(&self) -> Sender<Garbage> {
+        let (tx, rx) = channel::<Garbage>();
+        let pending = self.pending.clone();
+        thread::spawn(move || {
+            for garbage in rx {
+                let objects = garbage.objects();
+                drop(garbage);
+                pending.fetch_sub(objects, Ordering::Relaxed);
+            }
+        });
+        tx
+    }
+
+    /// Number of objects sent to the background thread and not freed yet.
+    pub fn pending_objects(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+}
@@ -3,46 +3,73 @@ extern crate config;
 #[macro_use(log)]
 extern crate logger;
 extern crate crc64;
+extern crate parking_lot;
 extern crate parser;
 extern crate persistence;
 extern crate rand;
 extern crate rdbutil;
 extern crate rehashinghashmap;
 extern crate response;
+extern crate sha2;
 extern crate skiplist;
 extern crate util;
 
+pub mod access;
+pub mod acl;
+pub mod cluster;
 pub mod dbutil;
 pub mod error;
+pub mod intset;
+pub mod lazyfree;
 pub mod list;
+mod rdb;
+pub mod sentinel;
 pub mod set;
 pub mod string;
+pub mod tracking;
 pub mod zset;
 
 use std::collections::Bound;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::env;
-use std::io::Write;
+use std::fmt;
+use std::io;
+use std::io::{BufReader, Write};
 use std::iter::FromIterator;
-use std::ops::RangeFull;
+use std::mem;
+use std::net::TcpStream;
 use std::path::Path;
+use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
-use config::Config;
+use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, RawRwLock, RwLock};
+
+use access::KeyAccess;
+use acl::Acl;
+use cluster::Cluster;
+use config::{Config, NOTIFY_EXPIRED, NOTIFY_GENERIC, NOTIFY_KEYEVENT, NOTIFY_KEYSPACE};
 use crc64::crc64;
+use lazyfree::{free_effort, Garbage, Lazyfree, LAZYFREE_THRESHOLD};
 use logger::{Level, Logger};
 use parser::ParsedCommand;
 use persistence::aof::Aof;
 use rehashinghashmap::RehashingHashMap;
 use response::Response;
-use util::{get_random_hex_chars, glob_match, mstime};
+use sentinel::Sentinel;
+use util::{format_repr, get_random_hex_chars, glob_match, mstime, ustime};
 
 use error::OperationError;
+use intset::IntSet;
 use list::ValueList;
-use rdbutil::encode_u64_to_slice_u8;
+use rdbutil::constants::*;
+use rdbutil::{decode_len, decode_slice_u8, encode_u64_to_slice_u8};
+use rdbutil::{decode_u32_from_slice_u8, decode_u64_from_slice_u8};
 use set::ValueSet;
-use string::ValueString;
+use string::{Lcs, ValueString};
+use tracking::Tracking;
 use zset::ValueSortedSet;
 
 const ACTIVE_EXPIRE_CYCLE_LOOKUPS_PER_LOOP: usize = 20;
@@ -71,6 +98,12 @@ pub enum PubsubEvent {
     PatternUnsubscription(Vec<u8>, usize),
     /// A message was received, it may have matched a pattern and it was sent in a channel.
     Message(Vec<u8>, Option<Vec<u8>>, Vec<u8>),
+    /// Client subscribe to shard channel and its the shard subscription number.
+    ShardSubscription(Vec<u8>, usize),
+    /// Client unsubscribe from shard channel and the number of remaining shard subscriptions.
+    ShardUnsubscription(Vec<u8>, usize),
+    /// A message was received in a shard channel.
+    ShardMessage(Vec<u8>, Vec<u8>),
 }
 
 impl PubsubEvent {
@@ -110,10 +143,36 @@ impl PubsubEvent {
                 Response::Data(pattern.clone()),
                 Response::Integer(*subscriptions as i64),
             ]),
+            PubsubEvent::ShardSubscription(channel, subscriptions) => Response::Array(vec![
+                Response::Data(b"ssubscribe".to_vec()),
+                Response::Data(channel.clone()),
+                Response::Integer(*subscriptions as i64),
+            ]),
+            PubsubEvent::ShardUnsubscription(channel, subscriptions) => Response::Array(vec![
+                Response::Data(b"sunsubscribe".to_vec()),
+                Response::Data(channel.clone()),
+                Response::Integer(*subscriptions as i64),
+            ]),
+            PubsubEvent::ShardMessage(channel, message) => Response::Array(vec![
+                Response::Data(b"smessage".to_vec()),
+                Response::Data(channel.clone()),
+                Response::Data(message.clone()),
+            ]),
         }
     }
 }
 
+/// Updates `crc` with `data`. `crc64` reads eight bytes at a time as a
+/// `u64`, so they have to be aligned: the bytes before the first aligned
+/// address are fed alone.
+fn checksum(crc: u64, data: &[u8]) -> u64 {
+    let head = data.as_ptr().align_offset(8).min(data.len());
+    let crc = data[..head]
+        .iter()
+        .fold(crc, |crc, byte| crc64(crc, &[*byte]));
+    crc64(crc, &data[head..])
+}
+
 /// Gets all ValueSet references from a list of Value references.
 /// If a Value is nil, `default` is used.
 /// If any of the values is not a set, an error is returned instead.
@@ -376,6 +435,36 @@ impl Value {
         }
     }
 
+    /// Finds the longest common subsequence between two strings.
+    /// A nil value is an empty string.
+    /// Fails if any of the values is not a string.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut val1 = Value::Nil;
+    /// val1.set(b"ohmytext".to_vec()).unwrap();
+    /// let mut val2 = Value::Nil;
+    /// val2.set(b"mynewtext".to_vec()).unwrap();
+    /// let lcs = val1.lcs(&val2).unwrap();
+    /// assert_eq!(lcs.subsequence, b"mytext".to_vec());
+    /// assert_eq!(lcs.matches, vec![((4, 7), (5, 8)), ((2, 3), (0, 1))]);
+    /// assert_eq!(val1.lcs(&Value::Nil).unwrap().subsequence, b"".to_vec());
+    /// ```
+    pub fn lcs(&self, other: &Value) -> Result<Lcs, OperationError> {
+        let empty = ValueString::Data(vec![]);
+        let mut strings = Vec::with_capacity(2);
+        for value in &[self, other] {
+            strings.push(match value {
+                Value::Nil => &empty,
+                Value::String(s) => s,
+                _ => return Err(OperationError::WrongTypeError),
+            });
+        }
+        Ok(strings[0].lcs(strings[1]))
+    }
+
     /// Updates the string value overwriting with provided data from the index.
     /// If the string was shorter than the index, it is filled with null bytes.
     /// Fails if the value is not a string.
@@ -675,6 +764,40 @@ impl Value {
         }
     }
 
+    /// Gets the positions of the elements in a list equal to `el`.
+    /// A positive `rank` skips the first `rank - 1` matches, and a negative
+    /// one searches from the tail. At most `count` positions are returned,
+    /// all of them if it is 0. Only the first `maxlen` elements searched are
+    /// compared, all of them if it is 0.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut val = Value::Nil;
+    /// for el in &[1, 2, 1, 1] {
+    ///     val.push(vec![*el], true).unwrap();
+    /// }
+    /// assert_eq!(val.lpos(&[1], 1, 1, 0).unwrap(), vec![0]);
+    /// assert_eq!(val.lpos(&[1], 2, 0, 0).unwrap(), vec![2, 3]);
+    /// assert_eq!(val.lpos(&[1], -1, 2, 0).unwrap(), vec![3, 2]);
+    /// assert_eq!(val.lpos(&[1], 1, 0, 2).unwrap(), vec![0]);
+    /// assert_eq!(val.lpos(&[3], 1, 0, 0).unwrap(), vec![]);
+    /// ```
+    pub fn lpos(
+        &self,
+        el: &[u8],
+        rank: i64,
+        count: usize,
+        maxlen: usize,
+    ) -> Result<Vec<usize>, OperationError> {
+        match self {
+            Value::Nil => Ok(Vec::new()),
+            Value::List(value) => Ok(value.lpos(el, rank, count, maxlen)),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
     /// Remove up to `limit` elements, starting from either side, that match
     /// `newvalue`.
     /// Returns the number of removed elements.
@@ -1007,6 +1130,34 @@ impl Value {
         }
     }
 
+    /// Returns the number of elements in the intersection of the sets,
+    /// counting up to `limit`. A `limit` of zero means no limit.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut val1 = Value::Nil;
+    /// val1.sadd(vec![1], 3).unwrap();
+    /// val1.sadd(vec![2], 3).unwrap();
+    /// val1.sadd(vec![3], 3).unwrap();
+    /// let mut val2 = Value::Nil;
+    /// val2.sadd(vec![1], 3).unwrap();
+    /// val2.sadd(vec![2], 3).unwrap();
+    /// assert_eq!(val1.sintercard(&vec![&val2], 0).unwrap(), 2);
+    /// assert_eq!(val1.sintercard(&vec![&val2], 1).unwrap(), 1);
+    /// assert_eq!(val1.sintercard(&vec![&Value::Nil], 0).unwrap(), 0);
+    /// ```
+    pub fn sintercard(&self, set_values: &[&Value], limit: usize) -> Result<usize, OperationError> {
+        let emptyset = ValueSet::new();
+        let sets = get_set_list(set_values, &emptyset)?;
+        match self {
+            Value::Nil => Ok(0),
+            Value::Set(value) => Ok(value.sintercard(sets, limit)),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
     /// Returns the elements in any of the sets
     ///
     /// # Examples
@@ -1037,6 +1188,8 @@ impl Value {
     }
 
     /// Turns the value into a set using an existing HashSet.
+    /// `set_max_intset_entries` is the maximum number of elements the set can
+    /// have to use an intset encoding.
     ///
     /// # Examples
     /// ```
@@ -1044,11 +1197,11 @@ impl Value {
     /// use database::Value;
     ///
     /// let mut val1 = Value::Nil;
-    /// val1.create_set(vec![vec![1], vec![2], vec![3]].into_iter().collect::<HashSet<_>>());
+    /// val1.create_set(vec![vec![1], vec![2], vec![3]].into_iter().collect::<HashSet<_>>(), 512);
     /// assert_eq!(val1.scard().unwrap(), 3);
     /// ```
-    pub fn create_set(&mut self, set: HashSet<Vec<u8>>) {
-        *self = Value::Set(ValueSet::create_with_hashset(set));
+    pub fn create_set(&mut self, set: HashSet<Vec<u8>>, set_max_intset_entries: usize) {
+        *self = Value::Set(ValueSet::create_with_hashset(set, set_max_intset_entries));
     }
 
     /// Removes an element from a sorted set. Returns true if the element existed.
@@ -1074,6 +1227,30 @@ impl Value {
         }
     }
 
+    /// Removes and returns up to `count` members of a sorted set, with their
+    /// scores. The lowest scores are removed first, or the highest if `max`.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut val = Value::Nil;
+    /// assert_eq!(val.zpop(1, false).unwrap(), vec![]);
+    /// val.zadd(1.0, vec![1], false, false, false, false).unwrap();
+    /// val.zadd(2.0, vec![2], false, false, false, false).unwrap();
+    /// val.zadd(3.0, vec![3], false, false, false, false).unwrap();
+    /// assert_eq!(val.zpop(1, true).unwrap(), vec![(vec![3], 3.0)]);
+    /// assert_eq!(val.zpop(5, false).unwrap(), vec![(vec![1], 1.0), (vec![2], 2.0)]);
+    /// assert_eq!(val.zcard().unwrap(), 0);
+    /// ```
+    pub fn zpop(&mut self, count: usize, max: bool) -> Result<Vec<(Vec<u8>, f64)>, OperationError> {
+        match self {
+            Value::Nil => Ok(vec![]),
+            Value::SortedSet(value) => Ok(value.zpop(count, max)),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
     /// Adds an element to a sorted set.
     /// If `nx` is true, it only adds new element.
     /// If `xx` is true, it only updates an existing element.
@@ -1608,6 +1785,86 @@ impl Value {
         Ok(Value::SortedSet(value))
     }
 
+    /// Creates a new sorted set with the members of the first sorted set that
+    /// are not in any of the others.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut val1 = Value::Nil;
+    /// val1.zadd(1.0, vec![1], false, false, false, false).unwrap();
+    /// val1.zadd(2.0, vec![2], false, false, false, false).unwrap();
+    /// let mut val2 = Value::Nil;
+    /// val2.zadd(5.0, vec![2], false, false, false, false).unwrap();
+    /// let val3 = Value::Nil.zdiff(&vec![&val1, &val2, &Value::Nil]).unwrap();
+    /// assert_eq!(val3.zcard().unwrap(), 1);
+    /// assert_eq!(val3.zscore(vec![1]).unwrap(), Some(1.0));
+    /// ```
+    pub fn zdiff(&self, zset_values: &[&Value]) -> Result<Value, OperationError> {
+        let emptyzset = ValueSortedSet::new();
+        let zsets = get_zset_list(zset_values, &emptyzset)?;
+
+        let mut value = ValueSortedSet::new();
+        value.zdiff(zsets);
+        Ok(Value::SortedSet(value))
+    }
+
+    /// Counts the members in the intersection of sorted sets, stopping at
+    /// `limit` unless it is zero.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut val1 = Value::Nil;
+    /// let mut val2 = Value::Nil;
+    /// for i in 0..5 {
+    ///     val1.zadd(1.0, vec![i], false, false, false, false).unwrap();
+    ///     val2.zadd(2.0, vec![i + 2], false, false, false, false).unwrap();
+    /// }
+    /// assert_eq!(Value::Nil.zintercard(&vec![&val1, &val2], 0).unwrap(), 3);
+    /// assert_eq!(Value::Nil.zintercard(&vec![&val1, &val2], 2).unwrap(), 2);
+    /// assert_eq!(Value::Nil.zintercard(&vec![&val1, &Value::Nil], 0).unwrap(), 0);
+    /// ```
+    pub fn zintercard(
+        &self,
+        zset_values: &[&Value],
+        limit: usize,
+    ) -> Result<usize, OperationError> {
+        let emptyzset = ValueSortedSet::new();
+        let zsets = get_zset_list(zset_values, &emptyzset)?;
+        Ok(ValueSortedSet::intercard(&zsets, limit))
+    }
+
+    /// Returns random members of a sorted set with their scores. If
+    /// `allow_duplicates` is true, there are exactly `count` members,
+    /// otherwise they are distinct and up to the sorted set size.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut val = Value::Nil;
+    /// val.zadd(1.0, vec![1], false, false, false, false).unwrap();
+    /// val.zadd(2.0, vec![2], false, false, false, false).unwrap();
+    /// assert_eq!(val.zrandmember(5, false).unwrap().len(), 2);
+    /// assert_eq!(val.zrandmember(5, true).unwrap().len(), 5);
+    /// let (member, score) = val.zrandmember(1, false).unwrap().pop().unwrap();
+    /// assert_eq!(val.zscore(member).unwrap(), Some(score));
+    /// ```
+    pub fn zrandmember(
+        &self,
+        count: usize,
+        allow_duplicates: bool,
+    ) -> Result<Vec<(Vec<u8>, f64)>, OperationError> {
+        match self {
+            Value::Nil => Ok(vec![]),
+            Value::SortedSet(value) => Ok(value.zrandmember(count, allow_duplicates)),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
     /// Serializes and writes into `writer` the object current value.
     /// The serialized version also includes the type, the version and a crc.
     ///
@@ -1635,6 +1892,153 @@ impl Value {
         Ok(writer.write(&*data)?)
     }
 
+    /// Creates a value from a payload serialized by `dump`, verifying its
+    /// version and checksum.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut val = Value::Nil;
+    /// val.push(b"a".to_vec(), true).unwrap();
+    /// val.push(b"123".to_vec(), true).unwrap();
+    /// let mut serialized = vec![];
+    /// val.dump(&mut serialized).unwrap();
+    /// let restored = Value::restore(&serialized).unwrap();
+    /// assert_eq!(restored.lrange(0, -1).unwrap(), vec![&b"a"[..], &b"123"[..]]);
+    /// assert!(Value::restore(&serialized[1..]).is_err());
+    /// ```
+    pub fn restore(data: &[u8]) -> Result<Value, OperationError> {
+        let badpayload = || {
+            OperationError::ValueError("ERR DUMP payload version or checksum are wrong".to_owned())
+        };
+        if data.len() < 11 {
+            return Err(badpayload());
+        }
+        let (payload, footer) = data.split_at(data.len() - 8);
+        let mut crc = footer;
+        let version = payload[payload.len() - 2] as u16 | (payload[payload.len() - 1] as u16) << 8;
+        if version > VERSION || decode_u64_from_slice_u8(&mut crc)? != checksum(0, payload) {
+            return Err(badpayload());
+        }
+
+        let mut reader = &payload[1..payload.len() - 2];
+        let value = match Value::restore_body(payload[0], &mut reader) {
+            Ok(Some(value)) => value,
+            Ok(None) | Err(_) => return Err(Value::bad_data_format()),
+        };
+        if !reader.is_empty() || value.is_empty() {
+            return Err(Value::bad_data_format());
+        }
+        Ok(value)
+    }
+
+    fn bad_data_format() -> OperationError {
+        OperationError::ValueError("ERR Bad data format".to_owned())
+    }
+
+    /// Reads a value of type `valuetype` in the format used by RDB files and
+    /// DUMP payloads. Returns `None` if the contents are not valid.
+    fn restore_body(valuetype: u8, reader: &mut &[u8]) -> io::Result<Option<Value>> {
+        let mut value = Value::Nil;
+        match valuetype {
+            TYPE_STRING => value = Value::String(ValueString::new(decode_slice_u8(reader)?)),
+            TYPE_LIST => {
+                for _ in 0..decode_len(reader)? {
+                    value.push(decode_slice_u8(reader)?, true).unwrap();
+                }
+            }
+            TYPE_SET => {
+                let len = decode_len(reader)?;
+                let mut set = HashSet::new();
+                for _ in 0..len {
+                    set.insert(decode_slice_u8(reader)?);
+                }
+                if set.len() != len {
+                    return Ok(None);
+                }
+                // keep the encoding of the payload
+                value = Value::Set(ValueSet::Data(set));
+            }
+            TYPE_SET_INTSET => {
+                let blob = decode_slice_u8(reader)?;
+                let mut blob = &blob[..];
+                let encoding = decode_u32_from_slice_u8(&mut blob)? as usize;
+                let len = decode_u32_from_slice_u8(&mut blob)? as usize;
+                if blob.len() != len.saturating_mul(encoding) {
+                    return Ok(None);
+                }
+                match IntSet::from_contents(encoding, blob.to_vec()) {
+                    Some(set) => value = Value::Set(ValueSet::Integer(set)),
+                    None => return Ok(None),
+                }
+            }
+            TYPE_ZSET => {
+                let len = decode_len(reader)?;
+                for _ in 0..len {
+                    let member = decode_slice_u8(reader)?;
+                    // scores are strings, except for a few special lengths
+                    let score = match reader.first() {
+                        Some(&253) => return Ok(None), // NaN
+                        Some(&254) => f64::INFINITY,
+                        Some(&255) => f64::NEG_INFINITY,
+                        _ => match from_utf8(&decode_slice_u8(reader)?).map(|s| s.parse()) {
+                            Ok(Ok(score)) => score,
+                            _ => return Ok(None),
+                        },
+                    };
+                    if score.is_infinite() {
+                        *reader = &reader[1..];
+                    }
+                    value
+                        .zadd(score, member, false, false, false, false)
+                        .unwrap();
+                }
+                if value.zcard().unwrap() != len {
+                    return Ok(None);
+                }
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(value))
+    }
+
+    /// Internal representation of the value, as reported by `OBJECT ENCODING`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut value = Value::Nil;
+    /// value.set(b"1".to_vec()).unwrap();
+    /// assert_eq!(value.encoding(), "int");
+    /// value.append(b"a".to_vec()).unwrap();
+    /// assert_eq!(value.encoding(), "raw");
+    /// ```
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::String(s) => s.encoding(),
+            Value::List(l) => l.encoding(),
+            Value::Set(s) => s.encoding(),
+            Value::SortedSet(s) => s.encoding(),
+        }
+    }
+
+    /// Estimated bytes allocated by the value, besides the enum itself.
+    /// Only `samples` elements of a collection are measured and the rest
+    /// are extrapolated, zero measures all of them.
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            Value::Nil => 0,
+            Value::String(s) => s.memory_usage(),
+            Value::List(l) => l.memory_usage(samples),
+            Value::Set(s) => s.memory_usage(samples),
+            Value::SortedSet(s) => s.memory_usage(samples),
+        }
+    }
+
     pub fn debug_object(&self) -> String {
         match self {
             Value::Nil => "Value at:0x0000000000 refcount:0 encoding:nil serializedlength:0 lru:0 \
@@ -1660,48 +2064,179 @@ impl Value {
 
 type SenderMap<T> = HashMap<usize, Sender<T>>;
 
-pub struct Database {
-    pub config: Config,
-
-    data: Vec<RehashingHashMap<Vec<u8>, Value>>,
-
+/// The keys of one database index. Every keyspace has its own lock, so
+/// clients using different databases do not wait for each other.
+struct Keyspace {
+    data: RehashingHashMap<Vec<u8>, Value>,
     /// Maps a key to an expiration time. Expiration time is in milliseconds.
-    data_expiration_ms: Vec<RehashingHashMap<Vec<u8>, i64>>,
+    data_expiration_ms: RehashingHashMap<Vec<u8>, i64>,
+    /// Maps a key to its last access time and access frequency.
+    data_access: RehashingHashMap<Vec<u8>, KeyAccess>,
     /// Maps a key to a collection of client identifiers.
     /// Every time a key is modified, the watched key client is flushed.
     /// The clients who are subscribed to a key should check whether their id
     /// is still present
-    watched_keys: Vec<HashMap<Vec<u8>, HashSet<usize>>>,
+    watched_keys: HashMap<Vec<u8>, HashSet<usize>>,
+    /// Maps a pattern to a list of key listeners. When a key is modified a message
+    /// with `true` is published.
+    /// The `usize` key is used as a client identifier.
+    key_subscribers: RehashingHashMap<Vec<u8>, SenderMap<bool>>,
+}
+
+impl Keyspace {
+    fn new() -> Self {
+        Keyspace {
+            data: RehashingHashMap::new(),
+            data_expiration_ms: RehashingHashMap::new(),
+            data_access: RehashingHashMap::new(),
+            watched_keys: HashMap::new(),
+            key_subscribers: RehashingHashMap::new(),
+        }
+    }
+}
+
+/// Pubsub listeners. Channels are not bound to a database.
+struct Pubsub {
     /// Maps a channel to a list of pubsub events listeners.
     /// The `usize` key is used as a client identifier.
     subscribers: HashMap<Vec<u8>, SenderMap<Option<Response>>>,
     /// Maps a pattern to a list of pubsub events listeners.
     /// The `usize` key is used as a client identifier.
     pattern_subscribers: HashMap<Vec<u8>, SenderMap<Option<Response>>>,
-    /// Maps a pattern to a list of key listeners. When a key is modified a message
-    /// with `true` is published.
+    /// Maps a shard channel to a list of pubsub events listeners. Shard
+    /// channels are independent from regular channels and never match patterns.
     /// The `usize` key is used as a client identifier.
-    key_subscribers: Vec<RehashingHashMap<Vec<u8>, SenderMap<bool>>>,
+    shard_subscribers: HashMap<Vec<u8>, SenderMap<Option<Response>>>,
+}
+
+/// State shared by every `Database` handle. It is freed with the last one.
+struct Shared {
+    /// In their own `Arc`, so the guards of a handle own what they lock.
+    keyspaces: Vec<Arc<RwLock<Keyspace>>>,
+    pubsub: Mutex<Pubsub>,
     /// A unique identifier counter to assign to clients
-    subscriber_id: usize,
+    subscriber_id: AtomicUsize,
     /// Which database to try to run the active expire cycle next
-    active_expire_cycle_db: usize,
+    active_expire_cycle_db: AtomicUsize,
     /// Clients who are monitoring commands.
-    monitor_senders: Vec<Sender<String>>,
+    monitor_senders: Mutex<Vec<Sender<String>>>,
+    /// a random 40 digits hex string
+    run_id: String,
+    /// milliseconds when the database started
+    start_mstime: i64,
+    /// Aof reader/writer
+    aof: Mutex<Option<Aof>>,
+    /// Is it loading data from a file
+    loading: AtomicBool,
+    /// Notifies the server it has to stop, once the database is ready for it
+    shutdown_sender: Mutex<Option<Sender<()>>>,
+    /// Users and their permissions
+    acl: Mutex<Acl>,
+    /// Keys cached by clients, to send them invalidation messages
+    tracking: Mutex<Tracking>,
+    /// Cluster configuration, when running as a cluster node
+    cluster: Mutex<Option<Cluster>>,
+    /// Monitored masters, when running as a sentinel
+    sentinel: Mutex<Option<Sentinel>>,
+    /// Connections used by MIGRATE, by target "host:port"
+    migrate_sockets: Mutex<HashMap<String, MigrateSocket>>,
+    /// Drops big values in the background
+    lazyfree: Lazyfree,
+}
+
+/// Locks a mutex. A thread that panicked while holding it does not make the
+/// data unusable for everybody else.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A database locked by a handle, either to share it with other readers or
+/// to modify it.
+enum KeyspaceGuard {
+    Read(ArcRwLockReadGuard<RawRwLock, Keyspace>),
+    Write(ArcRwLockWriteGuard<RawRwLock, Keyspace>),
+}
+
+impl KeyspaceGuard {
+    fn read(lock: &Arc<RwLock<Keyspace>>) -> Self {
+        KeyspaceGuard::Read(lock.read_arc())
+    }
+
+    fn write(lock: &Arc<RwLock<Keyspace>>) -> Self {
+        KeyspaceGuard::Write(lock.write_arc())
+    }
+
+    fn is_write(&self) -> bool {
+        match *self {
+            KeyspaceGuard::Read(_) => false,
+            KeyspaceGuard::Write(_) => true,
+        }
+    }
+}
+
+/// A database that can be sent to other threads, to create `Database`
+/// handles from it.
+#[derive(Clone)]
+pub struct SharedDatabase {
+    shared: Arc<Shared>,
+    pub config: Config,
     /// Git version used
     pub git_sha1: &'static str,
     /// Did the code change from the git repository
     pub git_dirty: bool,
     pub version: &'static str,
     pub rustc_version: &'static str,
-    /// a random 40 digits hex string
-    pub run_id: String,
-    /// milliseconds when the database started
-    pub start_mstime: i64,
-    /// Aof reader/writer
-    pub aof: Option<Aof>,
-    /// Is it loading data from a file
-    pub loading: bool,
+}
+
+/// A handle to the shared database. Keys can only be used in the databases
+/// the handle locked, see `Database::lock`.
+pub struct Database {
+    shared: Arc<Shared>,
+    keyspaces: Vec<Option<KeyspaceGuard>>,
+    pub config: Config,
+    /// Git version used
+    pub git_sha1: &'static str,
+    /// Did the code change from the git repository
+    pub git_dirty: bool,
+    pub version: &'static str,
+    pub rustc_version: &'static str,
+    /// The client running a command with this handle. Tracking clients with
+    /// NOLOOP are not told about their own changes.
+    pub current_client: Option<usize>,
+}
+
+/// How long a MIGRATE connection is kept open without being used.
+const MIGRATE_SOCKET_IDLE_MS: i64 = 10000;
+
+/// A connection to another instance, kept open to be reused by MIGRATE.
+pub struct MigrateSocket {
+    pub stream: BufReader<TcpStream>,
+    /// The database selected in the target, if any
+    pub last_dbindex: Option<usize>,
+    /// Milliseconds when it was last used
+    pub last_use: i64,
+}
+
+/// Command arguments as shown by MONITOR, quoted and escaped.
+struct MonitorArguments<'a> {
+    command: &'a ParsedCommand<'a>,
+    redacted: &'a [usize],
+}
+
+impl<'a> fmt::Display for MonitorArguments<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for i in 0..self.command.argv.len() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            if self.redacted.contains(&i) {
+                f.write_str("\"(redacted)\"")?;
+            } else {
+                format_repr(f, self.command.get_slice(i).unwrap_or(b""))?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct Iter<'a> {
@@ -1731,66 +2266,320 @@ macro_rules! random_key {
     }};
 }
 
-impl Database {
-    /// Creates a new empty `Database` with a mock config.
-    pub fn mock() -> Self {
-        Database::new(Config::default(0, Logger::new(Level::Warning)))
-    }
-
-    /// Creates a new empty `Database`.
+impl SharedDatabase {
+    /// Creates a new empty database.
     pub fn new(config: Config) -> Self {
         env::set_current_dir(&Path::new(&*config.dir)).unwrap();
-        let size = config.databases as usize;
-        let mut data = Vec::with_capacity(size);
-        let mut data_expiration_ms = Vec::with_capacity(size);
-        let mut key_subscribers = Vec::with_capacity(size);
-        let mut watched_keys = Vec::with_capacity(size);
-        for _ in 0..size {
-            data.push(RehashingHashMap::new());
-            data_expiration_ms.push(RehashingHashMap::new());
-            key_subscribers.push(RehashingHashMap::new());
-            watched_keys.push(HashMap::new());
-        }
+        let keyspaces = (0..config.databases)
+            .map(|_| Arc::new(RwLock::new(Keyspace::new())))
+            .collect();
         let aof = if config.appendonly {
             Some(Aof::new(&*config.appendfilename).unwrap())
         } else {
             None
         };
 
-        Database {
+        let acl = Acl::new(config.requirepass.as_deref(), config.acllog_max_len);
+        let tracking = Tracking::new(config.tracking_table_max_keys);
+
+        let shared = Arc::new(Shared {
+            keyspaces,
+            pubsub: Mutex::new(Pubsub {
+                subscribers: HashMap::new(),
+                pattern_subscribers: HashMap::new(),
+                shard_subscribers: HashMap::new(),
+            }),
+            subscriber_id: AtomicUsize::new(0),
+            active_expire_cycle_db: AtomicUsize::new(0),
+            monitor_senders: Mutex::new(Vec::new()),
+            run_id: get_random_hex_chars(40),
+            start_mstime: mstime(),
+            aof: Mutex::new(aof),
+            loading: AtomicBool::new(false),
+            shutdown_sender: Mutex::new(None),
+            acl: Mutex::new(acl),
+            tracking: Mutex::new(tracking),
+            cluster: Mutex::new(None),
+            sentinel: Mutex::new(None),
+            migrate_sockets: Mutex::new(HashMap::new()),
+            lazyfree: Lazyfree::new(),
+        });
+        SharedDatabase {
+            shared,
             config,
-            data,
-            data_expiration_ms,
-            subscribers: HashMap::new(),
-            pattern_subscribers: HashMap::new(),
-            key_subscribers,
-            subscriber_id: 0,
-            watched_keys,
-            active_expire_cycle_db: 0,
-            monitor_senders: Vec::new(),
             version: "0.0.1",
             rustc_version: "",
             git_sha1: "00000000",
             git_dirty: true,
-            run_id: get_random_hex_chars(40),
-            start_mstime: mstime(),
-            aof,
-            loading: false,
         }
     }
 
+    /// Creates a handle that has no database locked.
+    pub fn handle(&self) -> Database {
+        Database {
+            shared: self.shared.clone(),
+            keyspaces: self.shared.keyspaces.iter().map(|_| None).collect(),
+            config: self.config.clone(),
+            git_sha1: self.git_sha1,
+            git_dirty: self.git_dirty,
+            version: self.version,
+            rustc_version: self.rustc_version,
+            current_client: None,
+        }
+    }
+}
+
+impl Database {
+    /// Creates a new empty `Database` with a mock config.
+    pub fn mock() -> Self {
+        Database::new(Config::default(0, Logger::new(Level::Warning)))
+    }
+
+    /// Creates a new empty `Database`, with all its databases locked.
+    pub fn new(config: Config) -> Self {
+        let mut db = SharedDatabase::new(config).handle();
+        db.lock_all();
+        db
+    }
+
+    /// Creates a `SharedDatabase` to make more handles to the same data.
+    pub fn shared(&self) -> SharedDatabase {
+        SharedDatabase {
+            shared: self.shared.clone(),
+            config: self.config.clone(),
+            git_sha1: self.git_sha1,
+            git_dirty: self.git_dirty,
+            version: self.version,
+            rustc_version: self.rustc_version,
+        }
+    }
+
+    /// Locks the databases in `indexes` to modify them, releasing the ones
+    /// that were locked before, unless they are the same. They are always
+    /// locked in ascending order, so two handles cannot wait for each other.
+    /// Indexes out of range are ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use database::Database;
+    ///
+    /// let mut db = Database::mock();
+    /// let mut other = db.shared().handle();
+    /// db.lock(&[0]);
+    /// other.lock(&[1]);
+    /// other.get_or_create(1, b"foo").set(b"bar".to_vec()).unwrap();
+    /// other.unlock();
+    /// db.lock(&[1, 0]);
+    /// assert_eq!(db.dbsize(1), 1);
+    /// ```
+    pub fn lock(&mut self, indexes: &[usize]) {
+        self.lock_keyspaces(indexes, true);
+    }
+
+    /// Locks the databases in `indexes` like `lock`, but only to read them.
+    /// Other handles can read the same databases at the same time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use database::Database;
+    ///
+    /// let mut db = Database::mock();
+    /// db.get_or_create(0, b"foo").set(b"bar".to_vec()).unwrap();
+    /// db.unlock();
+    /// let mut other = db.shared().handle();
+    /// db.lock_read(&[0]);
+    /// other.lock_read(&[0]);
+    /// assert_eq!(db.dbsize(0), 1);
+    /// assert_eq!(other.dbsize(0), 1);
+    /// ```
+    pub fn lock_read(&mut self, indexes: &[usize]) {
+        self.lock_keyspaces(indexes, false);
+    }
+
+    fn lock_keyspaces(&mut self, indexes: &[usize], write: bool) {
+        let mut indexes = indexes.to_vec();
+        indexes.sort_unstable();
+        indexes.dedup();
+        indexes.retain(|&index| index < self.keyspaces.len());
+        let locked = self
+            .keyspaces
+            .iter()
+            .enumerate()
+            .filter(|(_, keyspace)| keyspace.is_some())
+            .map(|(index, _)| index);
+        let same_mode = self
+            .keyspaces
+            .iter()
+            .flatten()
+            .all(|keyspace| keyspace.is_write() == write);
+        if same_mode && locked.eq(indexes.iter().cloned()) {
+            return;
+        }
+        self.unlock();
+        let shared = &self.shared;
+        for index in indexes {
+            self.keyspaces[index] = Some(if write {
+                KeyspaceGuard::write(&shared.keyspaces[index])
+            } else {
+                KeyspaceGuard::read(&shared.keyspaces[index])
+            });
+        }
+    }
+
+    /// Locks every database.
+    pub fn lock_all(&mut self) {
+        let indexes = (0..self.keyspaces.len()).collect::<Vec<_>>();
+        self.lock(&indexes);
+    }
+
+    /// Releases the databases locked by this handle.
+    pub fn unlock(&mut self) {
+        for keyspace in self.keyspaces.iter_mut() {
+            *keyspace = None;
+        }
+    }
+
+    fn keyspace(&self, index: usize) -> &Keyspace {
+        match &self.keyspaces[index] {
+            Some(KeyspaceGuard::Read(keyspace)) => keyspace,
+            Some(KeyspaceGuard::Write(keyspace)) => keyspace,
+            None => panic!("database {} is not locked", index),
+        }
+    }
+
+    fn keyspace_mut(&mut self, index: usize) -> &mut Keyspace {
+        match &mut self.keyspaces[index] {
+            Some(KeyspaceGuard::Write(keyspace)) => keyspace,
+            Some(KeyspaceGuard::Read(_)) => panic!("database {} is locked to read", index),
+            None => panic!("database {} is not locked", index),
+        }
+    }
+
+    pub fn acl(&self) -> MutexGuard<'_, Acl> {
+        lock(&self.shared.acl)
+    }
+
+    pub fn tracking(&self) -> MutexGuard<'_, Tracking> {
+        lock(&self.shared.tracking)
+    }
+
+    pub fn cluster(&self) -> MutexGuard<'_, Option<Cluster>> {
+        lock(&self.shared.cluster)
+    }
+
+    pub fn sentinel(&self) -> MutexGuard<'_, Option<Sentinel>> {
+        lock(&self.shared.sentinel)
+    }
+
+    pub fn aof(&self) -> MutexGuard<'_, Option<Aof>> {
+        lock(&self.shared.aof)
+    }
+
+    pub fn migrate_sockets(&self) -> MutexGuard<'_, HashMap<String, MigrateSocket>> {
+        lock(&self.shared.migrate_sockets)
+    }
+
+    pub fn is_cluster(&self) -> bool {
+        self.cluster().is_some()
+    }
+
+    pub fn is_sentinel(&self) -> bool {
+        self.sentinel().is_some()
+    }
+
+    pub fn loading(&self) -> bool {
+        self.shared.loading.load(Ordering::Relaxed)
+    }
+
+    pub fn set_loading(&self, loading: bool) {
+        self.shared.loading.store(loading, Ordering::Relaxed);
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.shared.run_id
+    }
+
+    pub fn set_shutdown_sender(&self, sender: Sender<()>) {
+        *lock(&self.shared.shutdown_sender) = Some(sender);
+    }
+
     pub fn uptime(&self) -> i64 {
-        mstime() - self.start_mstime
+        mstime() - self.shared.start_mstime
     }
 
     fn is_expired(&self, index: usize, key: &[u8]) -> bool {
-        !self.loading
-            && match self.data_expiration_ms[index].get(key) {
+        !self.loading()
+            && match self.keyspace(index).data_expiration_ms.get(key) {
                 Some(t) => t <= &mstime(),
                 None => false,
             }
     }
 
+    /// Estimated bytes used by a key, its value and its entries in the
+    /// keyspace tables. Only `samples` elements of a collection are
+    /// measured, zero measures all of them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use database::Database;
+    ///
+    /// let mut db = Database::mock();
+    ///
+    /// assert_eq!(db.memory_usage(0, b"foo", 5), None);
+    /// db.get_or_create(0, b"foo").set(b"bar".to_vec()).unwrap();
+    /// let small = db.memory_usage(0, b"foo", 5).unwrap();
+    /// db.get_or_create(0, b"foo").set(vec![0; 1000]).unwrap();
+    /// assert!(db.memory_usage(0, b"foo", 5).unwrap() >= small + 997);
+    /// ```
+    pub fn memory_usage(&self, index: usize, key: &[u8], samples: usize) -> Option<usize> {
+        self.get_notouch(index, key).map(|value| {
+            let mut usage = mem::size_of::<(Vec<u8>, Value)>()
+                + mem::size_of::<(Vec<u8>, KeyAccess)>()
+                + self.key_memory_usage(index, key, value, samples);
+            if self.keyspace(index).data_expiration_ms.get(key).is_some() {
+                usage += mem::size_of::<(Vec<u8>, i64)>();
+            }
+            usage
+        })
+    }
+
+    /// Bytes allocated for a key and its value, outside of the keyspace
+    /// tables. The key is copied in every table it is in.
+    fn key_memory_usage(&self, index: usize, key: &[u8], value: &Value, samples: usize) -> usize {
+        let keyspace = self.keyspace(index);
+        let copies = if keyspace.data_expiration_ms.get(key).is_some() {
+            3
+        } else {
+            2
+        };
+        copies * key.len() + value.memory_usage(samples)
+    }
+
+    /// Bytes reserved by the tables of a database, for the keys and for
+    /// their expirations.
+    pub fn memory_overhead(&self, index: usize) -> (usize, usize) {
+        let keyspace = self.keyspace(index);
+        // every bucket has a control byte
+        let main = keyspace.data.capacity() * (mem::size_of::<(Vec<u8>, Value)>() + 1)
+            + keyspace.data_access.capacity() * (mem::size_of::<(Vec<u8>, KeyAccess)>() + 1);
+        let expires =
+            keyspace.data_expiration_ms.capacity() * (mem::size_of::<(Vec<u8>, i64)>() + 1);
+        (main, expires)
+    }
+
+    /// Estimated bytes used by the keys and values of a database, outside of
+    /// its tables. It walks the whole database.
+    pub fn dataset_memory(&self, index: usize, samples: usize) -> usize {
+        self.keyspace(index)
+            .data
+            .iter()
+            .map(|(key, value)| self.key_memory_usage(index, key, value, samples))
+            .sum()
+    }
+
     /// Gets the number of items in a database.
     ///
     /// # Examples
@@ -1805,11 +2594,11 @@ impl Database {
     /// assert_eq!(db.dbsize(0), 1);
     /// ```
     pub fn dbsize(&self, index: usize) -> usize {
-        self.data[index].len()
+        self.keyspace(index).data.len()
     }
 
     pub fn db_expire_size(&self, index: usize) -> usize {
-        self.data_expiration_ms[index].len()
+        self.keyspace(index).data_expiration_ms.len()
     }
 
     /// Gets a value from the database if exists and it is not expired.
@@ -1830,13 +2619,49 @@ impl Database {
     /// assert_eq!(db.get(0, &vec![1]), Some(&value));
     /// ```
     pub fn get(&self, index: usize, key: &[u8]) -> Option<&Value> {
+        let value = self.get_notouch(index, key);
+        if value.is_some() {
+            self.touch(index, key);
+        }
+        value
+    }
+
+    /// Gets a value like `get`, without recording an access to the key.
+    pub fn get_notouch(&self, index: usize, key: &[u8]) -> Option<&Value> {
         if self.is_expired(index, key) {
             None
         } else {
-            self.data[index].get(key)
+            self.keyspace(index).data.get(key)
         }
     }
 
+    /// Records an access to a key, for `OBJECT IDLETIME` and `OBJECT FREQ`.
+    fn touch(&self, index: usize, key: &[u8]) {
+        if let Some(access) = self.keyspace(index).data_access.get(key) {
+            access.touch(
+                mstime(),
+                self.config.lfu_log_factor,
+                self.config.lfu_decay_time,
+            );
+        }
+    }
+
+    /// Milliseconds since a key was last accessed.
+    pub fn idle_ms(&self, index: usize, key: &[u8]) -> Option<i64> {
+        self.keyspace(index)
+            .data_access
+            .get(key)
+            .map(|access| access.idle_ms(mstime()))
+    }
+
+    /// Logarithmic access frequency counter of a key.
+    pub fn access_frequency(&self, index: usize, key: &[u8]) -> Option<u8> {
+        self.keyspace(index)
+            .data_access
+            .get(key)
+            .map(|access| access.freq(mstime(), self.config.lfu_decay_time))
+    }
+
     /// Gets a mutable reference to a value if exists.
     ///
     /// # Examples
@@ -1853,80 +2678,153 @@ impl Database {
     pub fn get_mut(&mut self, index: usize, key: &[u8]) -> Option<&mut Value> {
         if self.is_expired(index, key) {
             self.remove(index, key);
+            self.tracking().invalidate(key, self.current_client);
+            self.notify_keyspace_event(NOTIFY_EXPIRED, "expired", index, key);
             None
         } else {
-            self.data[index].get_mut(key)
+            self.touch(index, key);
+            self.keyspace_mut(index).data.get_mut(key)
+        }
+    }
+
+    /// Removes and returns a value by key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use database::{Database, Value};
+    ///
+    /// let mut db = Database::mock();
+    ///
+    /// assert_eq!(db.remove(0, &vec![1]), None);
+    /// db.get_or_create(0, &vec![1]).set(vec![1]).unwrap();
+    /// assert!(db.remove(0, &vec![1]).is_some());
+    /// ```
+    pub fn remove(&mut self, index: usize, key: &[u8]) -> Option<Value> {
+        let expired = self.is_expired(index, key);
+        let active_rehashing = self.config.active_rehashing;
+        let keyspace = self.keyspace_mut(index);
+        let mut r = keyspace.data.remove(key);
+        let expired_value = if expired { r.take() } else { None };
+
+        keyspace.data_expiration_ms.remove(key);
+        keyspace.data_access.remove(key);
+        if active_rehashing {
+            if keyspace.data.len() * 10 / 12 < keyspace.data.capacity() {
+                keyspace.data.shrink_to_fit();
+            }
+            if keyspace.data_expiration_ms.len() * 10 / 12 < keyspace.data_expiration_ms.capacity()
+            {
+                keyspace.data_expiration_ms.shrink_to_fit();
+            }
+            if keyspace.data_access.len() * 10 / 12 < keyspace.data_access.capacity() {
+                keyspace.data_access.shrink_to_fit();
+            }
+            if keyspace.key_subscribers.len() * 10 / 12 < keyspace.key_subscribers.capacity() {
+                keyspace.key_subscribers.shrink_to_fit();
+            }
         }
+
+        if let Some(value) = expired_value {
+            let lazy = self.config.lazyfree_lazy_expire;
+            self.free(value, lazy);
+        }
+        r
     }
 
-    /// Removes and returns a value by key.
+    /// Removes a key and drops its value. If `lazy` is true and the value
+    /// is big, it is dropped in a background thread.
+    /// Returns whether the key existed.
     ///
     /// # Examples
     ///
     /// ```
-    /// use database::{Database, Value};
+    /// use database::Database;
     ///
     /// let mut db = Database::mock();
     ///
-    /// assert_eq!(db.remove(0, &vec![1]), None);
-    /// db.get_or_create(0, &vec![1]).set(vec![1]).unwrap();
-    /// assert!(db.remove(0, &vec![1]).is_some());
+    /// db.get_or_create(0, b"foo").set(b"bar".to_vec()).unwrap();
+    /// assert!(db.delete(0, b"foo", true));
+    /// assert!(!db.delete(0, b"foo", true));
     /// ```
-    pub fn remove(&mut self, index: usize, key: &[u8]) -> Option<Value> {
-        let mut r = self.data[index].remove(key);
-        if self.is_expired(index, key) {
-            r = None;
+    pub fn delete(&mut self, index: usize, key: &[u8], lazy: bool) -> bool {
+        match self.remove(index, key) {
+            Some(value) => {
+                self.free(value, lazy);
+                true
+            }
+            None => false,
         }
+    }
 
-        self.data_expiration_ms[index].remove(key);
-        if self.config.active_rehashing {
-            if self.data[index].len() * 10 / 12 < self.data[index].capacity() {
-                self.data[index].shrink_to_fit();
-            }
-            if self.data_expiration_ms[index].len() * 10 / 12
-                < self.data_expiration_ms[index].capacity()
-            {
-                self.data_expiration_ms[index].shrink_to_fit();
-            }
-            if self.key_subscribers[index].len() * 10 / 12 < self.key_subscribers[index].capacity()
-            {
-                self.key_subscribers[index].shrink_to_fit();
-            }
+    /// Drops a value that was taken out of the database. If `lazy` is true
+    /// and the value is big, it is dropped in a background thread.
+    pub fn free(&self, value: Value, lazy: bool) {
+        if lazy && free_effort(&value) > LAZYFREE_THRESHOLD {
+            self.shared.lazyfree.free(Garbage::Value(value));
         }
+    }
 
-        r
+    /// Number of values waiting to be dropped in the background.
+    pub fn lazyfree_pending_objects(&self) -> usize {
+        self.shared.lazyfree.pending_objects()
     }
 
     /// Sets a key expiration time, in milliseconds.
     pub fn set_msexpiration(&mut self, index: usize, key: Vec<u8>, msexpiration: i64) {
         self.key_updated(index, &key);
-        self.data_expiration_ms[index].insert(key, msexpiration);
+        self.keyspace_mut(index)
+            .data_expiration_ms
+            .insert(key, msexpiration);
     }
 
     /// Gets a key expiration time, in milliseconds.
     pub fn get_msexpiration(&mut self, index: usize, key: &[u8]) -> Option<&i64> {
-        self.data_expiration_ms[index].get(key)
+        self.keyspace(index).data_expiration_ms.get(key)
     }
 
     /// Removes a key expiration time.
     pub fn remove_msexpiration(&mut self, index: usize, key: &[u8]) -> Option<i64> {
-        self.data_expiration_ms[index].remove(key)
+        self.keyspace_mut(index).data_expiration_ms.remove(key)
+    }
+
+    /// Removes all keys in a database. If `lazy` is true, they are dropped
+    /// in a background thread.
+    pub fn clear(&mut self, index: usize, lazy: bool) {
+        self.clear_data(index, lazy);
+        self.tracking().invalidate_all(self.current_client);
     }
 
-    /// Removes all keys in a database.
-    pub fn clear(&mut self, index: usize) {
+    fn clear_data(&mut self, index: usize, lazy: bool) {
         // FIXME: remove clone
-        let keys = self.watched_keys[index]
+        let keys = self
+            .keyspace(index)
+            .watched_keys
             .keys()
             .cloned()
             .collect::<HashSet<_>>();
         for key in keys {
-            if self.data[index].remove(&key).is_some() {
+            if let Some(value) = self.keyspace_mut(index).data.remove(&key) {
+                self.keyspace_mut(index).data_access.remove(&key);
+                self.free(value, lazy);
                 self.key_updated(index, &key);
             }
         }
-        self.data[index].clear();
-        self.data_expiration_ms[index].clear();
+        let shared = self.shared.clone();
+        let keyspace = self.keyspace_mut(index);
+        if lazy && !keyspace.data.is_empty() {
+            let data = mem::replace(&mut keyspace.data, RehashingHashMap::new());
+            let expirations =
+                mem::replace(&mut keyspace.data_expiration_ms, RehashingHashMap::new());
+            let access = mem::replace(&mut keyspace.data_access, RehashingHashMap::new());
+            shared
+                .lazyfree
+                .free(Garbage::Keyspace(data, expirations, access));
+        } else {
+            keyspace.data.clear();
+            keyspace.data_expiration_ms.clear();
+            keyspace.data_access.clear();
+        }
     }
 
     /// Returns a mutable reference to a value for a key. If the value was not
@@ -1951,53 +2849,64 @@ impl Database {
         let val = Value::Nil;
 
         if self.is_expired(index, key) {
-            self.remove_msexpiration(index, key);
-        }
-
-        match self.data[index].entry(key.to_vec()) {
-            Entry::Occupied(entry) => entry.into_mut(),
-            Entry::Vacant(entry) => entry.insert(val),
+            self.remove(index, key);
+            self.tracking().invalidate(key, self.current_client);
+            self.notify_keyspace_event(NOTIFY_EXPIRED, "expired", index, key);
         }
-    }
 
-    /// Sets up the hashmap to subscribe clients to a key.
-    fn ensure_key_subscribers(&mut self, index: usize, key: &[u8]) {
-        if !self.key_subscribers[index].contains_key(key) {
-            self.key_subscribers[index].insert(key.to_vec(), HashMap::new());
+        let now = mstime();
+        let log_factor = self.config.lfu_log_factor;
+        let decay_time = self.config.lfu_decay_time;
+        let keyspace = self.keyspace_mut(index);
+        match keyspace.data.entry(key.to_vec()) {
+            Entry::Occupied(entry) => {
+                if let Some(access) = keyspace.data_access.get(key) {
+                    access.touch(now, log_factor, decay_time);
+                }
+                entry.into_mut()
+            }
+            Entry::Vacant(entry) => {
+                keyspace
+                    .data_access
+                    .insert(key.to_vec(), KeyAccess::new(now));
+                entry.insert(val)
+            }
         }
     }
 
     /// Subscribes a callback to a key. When the key is modified the callback
     /// is called and automatically unsubscribe.
     pub fn key_subscribe(&mut self, index: usize, key: &[u8], sender: Sender<bool>) -> usize {
-        self.ensure_key_subscribers(index, key);
-        let key_subscribers = self.key_subscribers[index].get_mut(key).unwrap();
-        let subscriber_id = self.subscriber_id;
-        key_subscribers.insert(subscriber_id, sender);
-        self.subscriber_id += 1;
+        let subscriber_id = self.next_subscriber_id();
+        let key_subscribers = &mut self.keyspace_mut(index).key_subscribers;
+        if !key_subscribers.contains_key(key) {
+            key_subscribers.insert(key.to_vec(), HashMap::new());
+        }
+        key_subscribers
+            .get_mut(key)
+            .unwrap()
+            .insert(subscriber_id, sender);
         subscriber_id
     }
 
     pub fn key_watch(&mut self, index: usize, key: &[u8], identifier: usize) {
-        match self.watched_keys[index].contains_key(key) {
-            true => self.watched_keys[index]
-                .get_mut(key)
-                .unwrap()
-                .insert(identifier),
-            false => self.watched_keys[index]
+        let watched_keys = &mut self.keyspace_mut(index).watched_keys;
+        match watched_keys.contains_key(key) {
+            true => watched_keys.get_mut(key).unwrap().insert(identifier),
+            false => watched_keys
                 .insert(key.to_vec(), HashSet::from_iter(vec![identifier]))
                 .is_some(),
         };
     }
 
     pub fn key_unwatch(&mut self, index: usize, key: &[u8], identifier: usize) {
-        if let Some(s) = self.watched_keys[index].get_mut(key) {
+        if let Some(s) = self.keyspace_mut(index).watched_keys.get_mut(key) {
             s.remove(&identifier);
         }
     }
 
     pub fn key_watch_verify(&self, index: usize, key: &[u8], identifier: usize) -> bool {
-        match self.watched_keys[index].get(key) {
+        match self.keyspace(index).watched_keys.get(key) {
             Some(l) => l.contains(&identifier),
             None => false,
         }
@@ -2007,34 +2916,86 @@ impl Database {
     /// If the value is now empty, it is removed.
     pub fn key_updated(&mut self, index: usize, key: &[u8]) {
         if self.config.active_rehashing {
-            self.data[index].rehash();
-            self.data_expiration_ms[index].rehash();
-            self.key_subscribers[index].rehash();
+            let keyspace = self.keyspace_mut(index);
+            keyspace.data.rehash();
+            keyspace.data_expiration_ms.rehash();
+            keyspace.data_access.rehash();
+            keyspace.key_subscribers.rehash();
         }
 
-        let is_empty = match self.data[index].get(key) {
+        let is_empty = match self.keyspace(index).data.get(key) {
             Some(v) => v.is_empty(),
             None => false,
         };
         if is_empty {
-            self.remove(index, key);
+            if let Some(value) = self.remove(index, key) {
+                if !value.is_nil() {
+                    self.notify_keyspace_event(NOTIFY_GENERIC, "del", index, key);
+                }
+            }
         }
 
-        if let Some(callbacks) = self.key_subscribers[index].remove(key) {
+        let keyspace = self.keyspace_mut(index);
+        if let Some(callbacks) = keyspace.key_subscribers.remove(key) {
             for sender in callbacks.values() {
                 let _ = sender.send(true);
             }
         }
-        self.watched_keys[index].remove(key);
+        keyspace.watched_keys.remove(key);
+        self.tracking().invalidate(key, self.current_client);
     }
 
-    /// Sets up the hashmap to subscribe clients to a channel.
-    fn ensure_channel(&mut self, channel: &[u8]) {
-        if !self.subscribers.contains_key(channel) {
-            self.subscribers.insert(channel.to_vec(), HashMap::new());
+    /// Publishes a keyspace notification for an event on a key, if its class
+    /// is enabled in `notify-keyspace-events`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate config;
+    /// # extern crate database;
+    /// use database::Database;
+    /// # use config::{NOTIFY_KEYEVENT, NOTIFY_KEYSPACE, NOTIFY_STRING};
+    /// # use database::PubsubEvent;
+    /// # use std::sync::mpsc::channel;
+    ///
+    /// let mut db = Database::mock();
+    /// db.config.notify_keyspace_events = NOTIFY_KEYSPACE | NOTIFY_KEYEVENT | NOTIFY_STRING;
+    ///
+    /// let (tx, rx) = channel();
+    /// db.psubscribe(b"__key*__:*".to_vec(), tx);
+    /// db.notify_keyspace_event(NOTIFY_STRING, "set", 0, b"foo");
+    /// assert_eq!(rx.try_recv().unwrap().unwrap(), PubsubEvent::Message(
+    ///     b"__keyspace@0__:foo".to_vec(),
+    ///     Some(b"__key*__:*".to_vec()),
+    ///     b"set".to_vec(),
+    /// ).as_response());
+    /// assert_eq!(rx.try_recv().unwrap().unwrap(), PubsubEvent::Message(
+    ///     b"__keyevent@0__:set".to_vec(),
+    ///     Some(b"__key*__:*".to_vec()),
+    ///     b"foo".to_vec(),
+    /// ).as_response());
+    /// ```
+    pub fn notify_keyspace_event(&self, class: u32, event: &str, index: usize, key: &[u8]) {
+        let flags = self.config.notify_keyspace_events;
+        if flags & class == 0 {
+            return;
+        }
+
+        if flags & NOTIFY_KEYSPACE != 0 {
+            let mut channel = format!("__keyspace@{}__:", index).into_bytes();
+            channel.extend_from_slice(key);
+            self.publish(&channel, event.as_bytes());
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            let channel = format!("__keyevent@{}__:{}", index, event);
+            self.publish(channel.as_bytes(), key);
         }
     }
 
+    fn next_subscriber_id(&self) -> usize {
+        self.shared.subscriber_id.fetch_add(1, Ordering::Relaxed)
+    }
+
     /// Subscribes a Sender to a channel. Returns a subscriber_id that can be
     /// used to unsubscribe
     ///
@@ -2057,11 +3018,12 @@ impl Database {
     /// assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
     /// ```
     pub fn subscribe(&mut self, channel: Vec<u8>, sender: Sender<Option<Response>>) -> usize {
-        self.ensure_channel(&channel);
-        let channelsubscribers = self.subscribers.get_mut(&channel).unwrap();
-        let subscriber_id = self.subscriber_id;
-        channelsubscribers.insert(subscriber_id, sender);
-        self.subscriber_id += 1;
+        let subscriber_id = self.next_subscriber_id();
+        lock(&self.shared.pubsub)
+            .subscribers
+            .entry(channel)
+            .or_default()
+            .insert(subscriber_id, sender);
         subscriber_id
     }
 
@@ -2083,19 +3045,16 @@ impl Database {
     /// assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Disconnected);
     /// ```
     pub fn unsubscribe(&mut self, channel: Vec<u8>, subscriber_id: usize) -> bool {
-        if !self.subscribers.contains_key(&channel) {
+        let mut pubsub = lock(&self.shared.pubsub);
+        if !pubsub.subscribers.contains_key(&channel) {
             return false;
         }
-        let channelsubscribers = self.subscribers.get_mut(&channel).unwrap();
-        channelsubscribers.remove(&subscriber_id).is_some()
-    }
-
-    /// Sets up the hashmap to subscribe clients to a pattern.
-    fn pensure_channel(&mut self, pattern: &[u8]) {
-        if !self.pattern_subscribers.contains_key(pattern) {
-            self.pattern_subscribers
-                .insert(pattern.to_vec(), HashMap::new());
+        let channelsubscribers = pubsub.subscribers.get_mut(&channel).unwrap();
+        let removed = channelsubscribers.remove(&subscriber_id).is_some();
+        if channelsubscribers.is_empty() {
+            pubsub.subscribers.remove(&channel);
         }
+        removed
     }
 
     /// Subscribes a Sender to a pattern. Returns a subscriber_id that can be
@@ -2120,29 +3079,36 @@ impl Database {
     /// assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
     /// ```
     pub fn psubscribe(&mut self, pattern: Vec<u8>, sender: Sender<Option<Response>>) -> usize {
-        self.pensure_channel(&pattern);
-        let channelsubscribers = self.pattern_subscribers.get_mut(&pattern).unwrap();
-        let subscriber_id = self.subscriber_id;
-        channelsubscribers.insert(subscriber_id, sender);
-        self.subscriber_id += 1;
+        let subscriber_id = self.next_subscriber_id();
+        lock(&self.shared.pubsub)
+            .pattern_subscribers
+            .entry(pattern)
+            .or_default()
+            .insert(subscriber_id, sender);
         subscriber_id
     }
 
     /// Unsubscribes a Sender from a pattern.
     /// Returns true if it was subscribed
     pub fn punsubscribe(&mut self, pattern: Vec<u8>, subscriber_id: usize) -> bool {
-        if !self.pattern_subscribers.contains_key(&pattern) {
+        let mut pubsub = lock(&self.shared.pubsub);
+        if !pubsub.pattern_subscribers.contains_key(&pattern) {
             return false;
         }
-        let channelsubscribers = self.pattern_subscribers.get_mut(&pattern).unwrap();
-        channelsubscribers.remove(&subscriber_id).is_some()
+        let channelsubscribers = pubsub.pattern_subscribers.get_mut(&pattern).unwrap();
+        let removed = channelsubscribers.remove(&subscriber_id).is_some();
+        if channelsubscribers.is_empty() {
+            pubsub.pattern_subscribers.remove(&pattern);
+        }
+        removed
     }
 
     /// Publishes a message to a channel and all patterns that match the channel name.
     /// Returns the number of recipients who receive the message.
     pub fn publish(&self, channel_name: &[u8], message: &[u8]) -> usize {
+        let pubsub = lock(&self.shared.pubsub);
         let mut c = 0;
-        if let Some(channels) = self.subscribers.get(channel_name) {
+        if let Some(channels) = pubsub.subscribers.get(channel_name) {
             for channel in channels.values() {
                 if channel
                     .send(Some(
@@ -2156,7 +3122,7 @@ impl Database {
             }
         }
 
-        for (pattern, channels) in self.pattern_subscribers.iter() {
+        for (pattern, channels) in pubsub.pattern_subscribers.iter() {
             if glob_match(pattern, channel_name, false) {
                 for channel in channels.values() {
                     if channel
@@ -2178,7 +3144,139 @@ impl Database {
         c
     }
 
-    /// Removes all data from all databases.
+    /// Subscribes a Sender to a shard channel. Returns a subscriber_id that
+    /// can be used to unsubscribe
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Database;
+    /// # use database::PubsubEvent;
+    /// # use std::sync::mpsc::{channel, TryRecvError};
+    ///
+    /// let mut db = Database::mock();
+    ///
+    /// let (tx, rx) = channel();
+    /// db.ssubscribe(vec![1], tx.clone());
+    /// db.psubscribe(vec![b'*'], tx);
+    /// assert_eq!(db.spublish(&vec![1], &vec![0, 1, 2, 3]), 1);
+    /// assert_eq!(rx.try_recv().unwrap().unwrap(), PubsubEvent::ShardMessage(
+    ///     vec![1],
+    ///     vec![0, 1, 2, 3],
+    /// ).as_response());
+    /// assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    /// ```
+    pub fn ssubscribe(&mut self, channel: Vec<u8>, sender: Sender<Option<Response>>) -> usize {
+        let subscriber_id = self.next_subscriber_id();
+        lock(&self.shared.pubsub)
+            .shard_subscribers
+            .entry(channel)
+            .or_default()
+            .insert(subscriber_id, sender);
+        subscriber_id
+    }
+
+    /// Unsubscribes a Sender from a shard channel.
+    /// Returns true if it was subscribed
+    pub fn sunsubscribe(&mut self, channel: Vec<u8>, subscriber_id: usize) -> bool {
+        let mut pubsub = lock(&self.shared.pubsub);
+        let removed = match pubsub.shard_subscribers.get_mut(&channel) {
+            Some(channelsubscribers) => channelsubscribers.remove(&subscriber_id).is_some(),
+            None => return false,
+        };
+        if pubsub.shard_subscribers[&channel].is_empty() {
+            pubsub.shard_subscribers.remove(&channel);
+        }
+        removed
+    }
+
+    /// Publishes a message to a shard channel. Unlike `publish`, patterns are
+    /// not considered.
+    /// Returns the number of recipients who receive the message.
+    pub fn spublish(&self, channel_name: &[u8], message: &[u8]) -> usize {
+        let pubsub = lock(&self.shared.pubsub);
+        let channels = match pubsub.shard_subscribers.get(channel_name) {
+            Some(channels) => channels,
+            None => return 0,
+        };
+        channels
+            .values()
+            .filter(|channel| {
+                channel
+                    .send(Some(
+                        PubsubEvent::ShardMessage(channel_name.to_vec(), message.to_vec())
+                            .as_response(),
+                    ))
+                    .is_ok()
+            })
+            .count()
+    }
+
+    /// Lists the channels with at least one subscriber, optionally filtered
+    /// by a glob pattern. Pattern subscriptions are not included.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Database;
+    /// # use std::sync::mpsc::channel;
+    ///
+    /// let mut db = Database::mock();
+    ///
+    /// let (tx, _rx) = channel();
+    /// db.subscribe(b"foo".to_vec(), tx.clone());
+    /// db.subscribe(b"bar".to_vec(), tx.clone());
+    /// db.psubscribe(b"f*".to_vec(), tx);
+    /// assert_eq!(db.pubsub_channels(Some(b"f*")), vec![b"foo".to_vec()]);
+    /// assert_eq!(db.pubsub_channels(None).len(), 2);
+    /// ```
+    pub fn pubsub_channels(&self, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        lock(&self.shared.pubsub)
+            .subscribers
+            .keys()
+            .filter(|channel| match pattern {
+                Some(pattern) => glob_match(pattern, channel, false),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Number of clients subscribed to a channel, not counting patterns.
+    pub fn pubsub_numsub(&self, channel: &[u8]) -> usize {
+        lock(&self.shared.pubsub)
+            .subscribers
+            .get(channel)
+            .map_or(0, |s| s.len())
+    }
+
+    /// Lists the shard channels with at least one subscriber, optionally
+    /// filtered by a glob pattern.
+    pub fn pubsub_shardchannels(&self, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        lock(&self.shared.pubsub)
+            .shard_subscribers
+            .keys()
+            .filter(|channel| match pattern {
+                Some(pattern) => glob_match(pattern, channel, false),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Number of clients subscribed to a shard channel.
+    pub fn pubsub_shardnumsub(&self, channel: &[u8]) -> usize {
+        lock(&self.shared.pubsub)
+            .shard_subscribers
+            .get(channel)
+            .map_or(0, |s| s.len())
+    }
+
+    /// Number of unique patterns clients are subscribed to.
+    pub fn pubsub_numpat(&self) -> usize {
+        lock(&self.shared.pubsub).pattern_subscribers.len()
+    }
+
+    /// Removes all data from all databases. If `lazy` is true, it is dropped
+    /// in a background thread.
     ///
     /// # Examples
     ///
@@ -2190,13 +3288,14 @@ impl Database {
     /// assert_eq!(db.get(0, &vec![1]), None);
     /// db.get_or_create(0, &vec![1]).set(vec![1]).unwrap();
     /// db.get_or_create(1, &vec![1]).set(vec![1]).unwrap();
-    /// db.clearall();
+    /// db.clearall(false);
     /// assert!(!db.get(0, &vec![1]).is_some());
     /// ```
-    pub fn clearall(&mut self) {
-        for index in 0..(self.config.databases as usize) {
-            self.clear(index);
+    pub fn clearall(&mut self, lazy: bool) {
+        for index in 0..self.keyspaces.len() {
+            self.clear_data(index, lazy);
         }
+        self.tracking().invalidate_all(self.current_client);
     }
 
     /// Applies the config command mapping. This mapping allows arbitrary
@@ -2228,7 +3327,7 @@ impl Database {
     /// Iterate over the keys in one database
     pub fn iter_db(&self, dbindex: usize) -> Iter {
         Iter {
-            inner: self.data[dbindex].iter(),
+            inner: self.keyspace(dbindex).data.iter(),
         }
     }
 
@@ -2244,85 +3343,221 @@ impl Database {
         responses
     }
 
-    /// Tries to remove items that are already expired.
+    /// Tries to remove items that are already expired. The databases the
+    /// handle has not locked are locked one at a time.
     pub fn active_expire_cycle(&mut self, duration_ms: i64) {
-        let num_dbs = self.data.len();
+        let num_dbs = self.keyspaces.len();
         let dbs_per_call = num_dbs;
         let start = mstime();
         let mut iteration = 0;
 
         for _ in 0..dbs_per_call {
-            let dbindex = self.active_expire_cycle_db;
-
-            self.active_expire_cycle_db += 1;
-            if self.active_expire_cycle_db == num_dbs {
-                self.active_expire_cycle_db = 0;
+            let dbindex = self.shared.active_expire_cycle_db.load(Ordering::Relaxed) % num_dbs;
+            self.shared
+                .active_expire_cycle_db
+                .store((dbindex + 1) % num_dbs, Ordering::Relaxed);
+
+            let locked = match &self.keyspaces[dbindex] {
+                Some(keyspace) if keyspace.is_write() => true,
+                // it cannot be modified nor locked again while it is read
+                Some(_) => continue,
+                None => false,
+            };
+            if !locked {
+                self.keyspaces[dbindex] =
+                    Some(KeyspaceGuard::write(&self.shared.keyspaces[dbindex]));
+            }
+            let timeout = self.active_expire_cycle_db(dbindex, start, duration_ms, &mut iteration);
+            if !locked {
+                self.keyspaces[dbindex] = None;
             }
+            if timeout {
+                return;
+            }
+        }
+    }
 
-            loop {
-                let mut num = self.data_expiration_ms.len();
-                if num == 0 {
-                    break;
-                }
+    /// Removes expired items from a database, until there are few of them
+    /// or the time is over. Returns true in the latter case.
+    fn active_expire_cycle_db(
+        &mut self,
+        dbindex: usize,
+        start: i64,
+        duration_ms: i64,
+        iteration: &mut usize,
+    ) -> bool {
+        loop {
+            let mut num = self.db_expire_size(dbindex);
+            if num == 0 {
+                return false;
+            }
 
-                if num > ACTIVE_EXPIRE_CYCLE_LOOKUPS_PER_LOOP {
-                    num = ACTIVE_EXPIRE_CYCLE_LOOKUPS_PER_LOOP;
-                }
+            if num > ACTIVE_EXPIRE_CYCLE_LOOKUPS_PER_LOOP {
+                num = ACTIVE_EXPIRE_CYCLE_LOOKUPS_PER_LOOP;
+            }
 
-                let mut expired = 0;
-                while num > 0 && !self.data_expiration_ms[dbindex].is_empty() {
-                    num -= 1;
-                    let key = random_key!(self.data_expiration_ms[dbindex]);
-                    if self.get_mut(dbindex, &key).is_none() {
-                        expired += 1;
-                    }
+            let mut expired = 0;
+            while num > 0 && self.db_expire_size(dbindex) > 0 {
+                num -= 1;
+                let key = random_key!(self.keyspace(dbindex).data_expiration_ms);
+                if self.get_mut(dbindex, &key).is_none() {
+                    expired += 1;
                 }
+            }
 
-                iteration += 1;
-                if (iteration & 16) == 0 {
-                    let elapsed = mstime() - start;
-                    if elapsed > duration_ms {
-                        return;
-                    }
+            *iteration += 1;
+            if (*iteration & 16) == 0 {
+                let elapsed = mstime() - start;
+                if elapsed > duration_ms {
+                    return true;
                 }
+            }
 
-                if expired <= ACTIVE_EXPIRE_CYCLE_LOOKUPS_PER_LOOP / 4 {
-                    break;
-                }
+            if expired <= ACTIVE_EXPIRE_CYCLE_LOOKUPS_PER_LOOP / 4 {
+                return false;
             }
         }
     }
 
     pub fn monitor_add(&mut self, sender: Sender<String>) {
-        self.monitor_senders.push(sender);
-    }
-
-    pub fn log_command(&mut self, dbindex: usize, command: &ParsedCommand, write: bool) {
-        // FIXME: unnecessary free/alloc?
-        let bcommand = format!("{:?}", command);
-        let tmp = self
-            .monitor_senders
-            .drain(RangeFull)
-            .filter(|s| s.send(bcommand.clone()).is_ok())
-            .collect::<Vec<_>>();
-        self.monitor_senders = tmp;
-        if write {
-            let mut err = false;
-            if let Some(w) = &mut self.aof {
-                if let Err(e) = w.write(dbindex, command) {
+        lock(&self.shared.monitor_senders).push(sender);
+    }
+
+    /// Flushes the data to disk before exiting: the AOF is fsynced and, if
+    /// `save` is true, a final snapshot is saved.
+    /// Returns false if something failed and the server should not exit,
+    /// unless `force` is true.
+    pub fn prepare_shutdown(&mut self, save: bool, force: bool) -> bool {
+        if let Some(aof) = self.aof().as_mut() {
+            log!(
+                self.config.logger,
+                Notice,
+                "Calling fsync() on the AOF file."
+            );
+            if let Err(e) = aof.fsync() {
+                log!(
+                    self.config.logger,
+                    Warning,
+                    "Error trying to fsync the AOF file: {:?}",
+                    e
+                );
+                if !force {
+                    return false;
+                }
+            }
+        }
+        if save {
+            log!(
+                self.config.logger,
+                Notice,
+                "Saving the final RDB snapshot before exiting."
+            );
+            match self.save() {
+                Ok(_) => log!(self.config.logger, Notice, "DB saved on disk"),
+                Err(e) => {
                     log!(
                         self.config.logger,
                         Warning,
-                        "Error writing aof {:?}; stopped writing",
+                        "Error trying to save the DB, can't exit: {:?}",
                         e
                     );
-                    err = true;
+                    if !force {
+                        return false;
+                    }
                 }
             }
-            if err {
-                self.aof = None;
+        }
+        true
+    }
+
+    /// Prepares the database to shut down and asks the server to stop.
+    /// A snapshot is saved if `save` is `Some(true)`, or if it is `None` and
+    /// save points are configured.
+    pub fn shutdown(&mut self, save: Option<bool>, force: bool) -> bool {
+        let save = save.unwrap_or(!self.config.save.is_empty());
+        if !self.prepare_shutdown(save, force) {
+            return false;
+        }
+        if let Some(sender) = lock(&self.shared.shutdown_sender).as_ref() {
+            let _ = sender.send(());
+        }
+        true
+    }
+
+    /// Sends a command to the clients running MONITOR, and appends it to the
+    /// aof if it is a write. Arguments in `redacted` are not shown to monitors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate database;
+    /// # extern crate parser;
+    /// use database::Database;
+    /// # use parser::{Argument, ParsedCommand};
+    /// # use std::sync::mpsc::channel;
+    ///
+    /// let mut db = Database::mock();
+    /// let (tx, rx) = channel();
+    /// db.monitor_add(tx);
+    /// let command = ParsedCommand::new(b"auth secret", vec![
+    ///     Argument { pos: 0, len: 4 },
+    ///     Argument { pos: 5, len: 6 },
+    /// ]);
+    /// db.log_command(0, "127.0.0.1:6380", &command, &[1], false);
+    /// assert!(rx.try_recv().unwrap().ends_with(" [0 127.0.0.1:6380] \"auth\" \"(redacted)\""));
+    /// ```
+    pub fn log_command(
+        &mut self,
+        dbindex: usize,
+        client_addr: &str,
+        command: &ParsedCommand,
+        redacted: &[usize],
+        write: bool,
+    ) {
+        let mut monitor_senders = lock(&self.shared.monitor_senders);
+        if !monitor_senders.is_empty() {
+            let now = ustime();
+            let line = format!(
+                "{}.{:06} [{} {}] {}",
+                now / 1000000,
+                now % 1000000,
+                dbindex,
+                client_addr,
+                MonitorArguments { command, redacted }
+            );
+            monitor_senders.retain(|s| s.send(line.clone()).is_ok());
+        }
+        drop(monitor_senders);
+        if write {
+            self.propagate(dbindex, command);
+        }
+    }
+
+    /// Writes a command into the append only file, if enabled.
+    pub fn propagate(&mut self, dbindex: usize, command: &ParsedCommand) {
+        let mut aof = self.aof();
+        let mut err = false;
+        if let Some(w) = aof.as_mut() {
+            if let Err(e) = w.write(dbindex, command) {
+                log!(
+                    self.config.logger,
+                    Warning,
+                    "Error writing aof {:?}; stopped writing",
+                    e
+                );
+                err = true;
             }
         }
+        if err {
+            *aof = None;
+        }
+    }
+
+    /// Closes the MIGRATE connections that were not used recently.
+    pub fn close_idle_migrate_sockets(&mut self) {
+        let now = mstime();
+        self.migrate_sockets()
+            .retain(|_, socket| now - socket.last_use < MIGRATE_SOCKET_IDLE_MS);
     }
 }
 
@@ -2332,13 +3567,18 @@ mod test_command {
     use std::collections::HashSet;
     use std::i64;
     use std::sync::mpsc::channel;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
     use std::usize;
 
     use util::mstime;
 
-    use config::Config;
+    use config::{Config, NOTIFY_EXPIRED, NOTIFY_KEYEVENT};
+    use crc64::crc64;
     use list::ValueList;
     use logger::{Level, Logger};
+    use response::Response;
     use set::ValueSet;
     use string::ValueString;
     use zset;
@@ -2346,6 +3586,7 @@ mod test_command {
 
     use super::{Database, PubsubEvent, Value};
     use parser::{Argument, ParsedCommand};
+    use rdbutil::encode_u64_to_slice_u8;
 
     #[test]
     fn lpush() {
@@ -4235,14 +5476,14 @@ mod test_command {
             let key = vec![(i % 256) as u8, (i / 256) as u8];
             database.get_or_create(0, &key).set(key.clone()).unwrap();
         }
-        assert_eq!(database.data[0].len(), 1000);
-        assert!(database.data[0].capacity() >= 1000);
+        assert_eq!(database.keyspace(0).data.len(), 1000);
+        assert!(database.keyspace(0).data.capacity() >= 1000);
         for i in 0u32..1000 {
             let key = vec![(i % 256) as u8, (i / 256) as u8];
             database.remove(0, &key).unwrap();
         }
         // freeing memory
-        assert!(database.data[0].capacity() < 1000);
+        assert!(database.keyspace(0).data.capacity() < 1000);
     }
 
     #[test]
@@ -4255,14 +5496,14 @@ mod test_command {
             let key = vec![(i % 256) as u8, (i / 256) as u8];
             database.get_or_create(0, &key).set(key.clone()).unwrap();
         }
-        assert_eq!(database.data[0].len(), 1000);
-        assert!(database.data[0].capacity() >= 1000);
+        assert_eq!(database.keyspace(0).data.len(), 1000);
+        assert!(database.keyspace(0).data.capacity() >= 1000);
         for i in 0u32..1000 {
             let key = vec![(i % 256) as u8, (i / 256) as u8];
             database.remove(0, &key).unwrap();
         }
         // no freeing memory
-        assert!(database.data[0].capacity() > 1000);
+        assert!(database.keyspace(0).data.capacity() > 1000);
     }
 
     #[test]
@@ -4317,6 +5558,119 @@ mod test_command {
         assert_eq!(&*v, b"\x00\xc0\x01\x07\x00\xd9J2E\xd9\xcb\xc4\xe6");
     }
 
+    #[test]
+    fn restore_round_trip() {
+        let mut values = vec![];
+        let mut value = Value::Nil;
+        value.set(b"007".to_vec()).unwrap();
+        values.push(value);
+        let mut value = Value::Nil;
+        value.set(b"-12345".to_vec()).unwrap();
+        values.push(value);
+        let mut value = Value::Nil;
+        value.push(b"a".to_vec(), true).unwrap();
+        value.push(b"1".to_vec(), true).unwrap();
+        values.push(value);
+        let mut value = Value::Nil;
+        value.create_set(
+            vec![b"a".to_vec(), b"b".to_vec()].into_iter().collect(),
+            512,
+        );
+        values.push(value);
+        let mut value = Value::Nil;
+        value.create_set(
+            vec![b"1".to_vec(), b"300".to_vec()].into_iter().collect(),
+            512,
+        );
+        values.push(value);
+        let mut value = Value::Nil;
+        value.create_set(
+            vec![b"-1".to_vec(), b"70000".to_vec(), b"-5000000000".to_vec()]
+                .into_iter()
+                .collect(),
+            512,
+        );
+        values.push(value);
+        let mut value = Value::Nil;
+        value
+            .zadd(-1.5, b"a".to_vec(), false, false, false, false)
+            .unwrap();
+        value
+            .zadd(2.0, b"b".to_vec(), false, false, false, false)
+            .unwrap();
+        value
+            .zadd(f64::INFINITY, b"c".to_vec(), false, false, false, false)
+            .unwrap();
+        value
+            .zadd(f64::NEG_INFINITY, b"d".to_vec(), false, false, false, false)
+            .unwrap();
+        values.push(value);
+
+        for value in values {
+            let mut serialized = vec![];
+            value.dump(&mut serialized).unwrap();
+            let restored = Value::restore(&serialized).unwrap();
+            let mut reserialized = vec![];
+            restored.dump(&mut reserialized).unwrap();
+            assert_eq!(restored.debug_object(), value.debug_object());
+            assert_eq!(
+                Value::restore(&reserialized).unwrap().debug_object(),
+                value.debug_object()
+            );
+        }
+    }
+
+    #[test]
+    fn restore_zset_scores() {
+        let mut value = Value::Nil;
+        value
+            .zadd(-1.5, b"a".to_vec(), false, false, false, false)
+            .unwrap();
+        value
+            .zadd(f64::NEG_INFINITY, b"b".to_vec(), false, false, false, false)
+            .unwrap();
+        let mut serialized = vec![];
+        value.dump(&mut serialized).unwrap();
+        let restored = Value::restore(&serialized).unwrap();
+        assert_eq!(restored.zscore(b"a".to_vec()).unwrap(), Some(-1.5));
+        assert_eq!(
+            restored.zscore(b"b".to_vec()).unwrap(),
+            Some(f64::NEG_INFINITY)
+        );
+    }
+
+    #[test]
+    fn restore_invalid() {
+        let mut value = Value::Nil;
+        value.set(b"hello".to_vec()).unwrap();
+        let mut serialized = vec![];
+        value.dump(&mut serialized).unwrap();
+        assert_eq!(
+            format!(
+                "{}",
+                Value::restore(&serialized[..serialized.len() - 1]).unwrap_err()
+            ),
+            "ERR DUMP payload version or checksum are wrong"
+        );
+        serialized[2] = b'j';
+        assert!(Value::restore(&serialized).is_err());
+
+        // the payload does not need to be aligned
+        let mut data = vec![0];
+        value.set(b"a longer string".to_vec()).unwrap();
+        value.dump(&mut data).unwrap();
+        assert!(Value::restore(&data[1..]).is_ok());
+
+        // valid checksum but a truncated string
+        let mut data = b"\x00\x05abc\x07\x00".to_vec();
+        let crc = crc64(0, &data);
+        encode_u64_to_slice_u8(crc, &mut data).unwrap();
+        assert_eq!(
+            format!("{}", Value::restore(&data).unwrap_err()),
+            "ERR Bad data format"
+        );
+    }
+
     #[test]
     fn watch() {
         let config = Config::new(Logger::new(Level::Warning));
@@ -4372,6 +5726,29 @@ mod test_command {
         assert_eq!(database.dbsize(0), 2);
     }
 
+    #[test]
+    fn active_expire_notification() {
+        let mut config = Config::new(Logger::new(Level::Warning));
+        config.notify_keyspace_events = NOTIFY_KEYEVENT | NOTIFY_EXPIRED;
+        let mut database = Database::new(config);
+        let (tx, rx) = channel();
+        database.psubscribe(b"__key*__:*".to_vec(), tx);
+        let key = vec![1u8];
+        assert!(database.get_or_create(0, &key).set(vec![1u8]).is_ok());
+        database.set_msexpiration(0, key.clone(), mstime());
+        database.active_expire_cycle(0);
+        assert_eq!(
+            rx.try_recv().unwrap().unwrap(),
+            Response::Array(vec![
+                Response::Data(b"pmessage".to_vec()),
+                Response::Data(b"__key*__:*".to_vec()),
+                Response::Data(b"__keyevent@0__:expired".to_vec()),
+                Response::Data(key),
+            ])
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
     #[test]
     fn monitor_log() {
         let config = Config::new(Logger::new(Level::Warning));
@@ -4380,12 +5757,184 @@ mod test_command {
         database.monitor_add(tx.clone());
         database.monitor_add(tx.clone());
         database.log_command(
-            0,
-            &ParsedCommand::new(b"1", vec![Argument { pos: 0, len: 1 }]),
+            2,
+            "unix:/tmp/rsedis.sock",
+            &ParsedCommand::new(
+                b"1 a\nb",
+                vec![Argument { pos: 0, len: 1 }, Argument { pos: 2, len: 3 }],
+            ),
+            &[],
             true,
         );
-        assert_eq!(rx.try_recv().unwrap(), "\"1\" ".to_owned());
-        assert_eq!(rx.try_recv().unwrap(), "\"1\" ".to_owned());
+        for _ in 0..2 {
+            let line = rx.try_recv().unwrap();
+            let (timestamp, rest) = line.split_at(line.find(' ').unwrap());
+            let (seconds, micros) = timestamp.split_at(timestamp.find('.').unwrap());
+            assert!(seconds.parse::<i64>().is_ok());
+            assert_eq!(micros.len(), 7);
+            assert_eq!(rest, " [2 unix:/tmp/rsedis.sock] \"1\" \"a\\nb\"");
+        }
         assert!(rx.try_recv().is_err())
     }
+
+    #[test]
+    fn lazyfree() {
+        let mut config = Config::new(Logger::new(Level::Warning));
+        config.lazyfree_lazy_expire = true;
+        let mut database = Database::new(config);
+        for key in &[b"foo", b"bar"] {
+            for i in 0..100 {
+                database
+                    .get_or_create(0, *key)
+                    .sadd(format!("{}", i).into_bytes(), 512)
+                    .unwrap();
+            }
+        }
+        database.set_msexpiration(0, b"bar".to_vec(), mstime() - 1);
+        assert!(database.delete(0, b"foo", true));
+        assert!(database.get_mut(0, b"bar").is_none());
+        database
+            .get_or_create(1, b"foo")
+            .set(b"1".to_vec())
+            .unwrap();
+        database.clear(1, true);
+        assert_eq!(database.dbsize(1), 0);
+
+        for _ in 0..100 {
+            if database.lazyfree_pending_objects() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(database.lazyfree_pending_objects(), 0);
+    }
+
+    #[test]
+    fn key_access() {
+        let mut config = Config::new(Logger::new(Level::Warning));
+        // every access increments the frequency counter
+        config.lfu_log_factor = 0;
+        let mut database = Database::new(config);
+        assert_eq!(database.idle_ms(0, b"foo"), None);
+        database
+            .get_or_create(0, b"foo")
+            .set(b"1".to_vec())
+            .unwrap();
+        assert_eq!(database.access_frequency(0, b"foo"), Some(5));
+        database.get_notouch(0, b"foo").unwrap();
+        assert_eq!(database.access_frequency(0, b"foo"), Some(5));
+        database.get(0, b"foo").unwrap();
+        database.get_mut(0, b"foo").unwrap();
+        assert_eq!(database.access_frequency(0, b"foo"), Some(7));
+        assert!(database.idle_ms(0, b"foo").unwrap() < 1000);
+
+        database.remove(0, b"foo");
+        assert_eq!(database.access_frequency(0, b"foo"), None);
+        database
+            .get_or_create(0, b"foo")
+            .set(b"1".to_vec())
+            .unwrap();
+        database.clear(0, true);
+        assert_eq!(database.idle_ms(0, b"foo"), None);
+    }
+
+    #[test]
+    fn memory_overhead() {
+        let mut database = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(database.memory_overhead(0), (0, 0));
+        assert_eq!(database.dataset_memory(0, 0), 0);
+        for i in 0..10 {
+            let key = format!("key{}", i).into_bytes();
+            database.get_or_create(0, &key).set(vec![0; 100]).unwrap();
+        }
+        database.set_msexpiration(0, b"key0".to_vec(), mstime() + 10000);
+        let (main, expires) = database.memory_overhead(0);
+        assert!(main > 0);
+        assert!(expires > 0);
+        assert!(database.dataset_memory(0, 0) >= 10 * (100 + 2 * 4) + 4);
+    }
+
+    #[test]
+    fn shared_freed_with_last_handle() {
+        let database = Database::mock();
+        let shared = database.shared();
+        let weak = Arc::downgrade(&database.shared);
+        drop(database);
+        let mut handle = shared.handle();
+        drop(shared);
+        handle.lock(&[0]);
+        assert!(weak.upgrade().is_some());
+        drop(handle);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn lock_separate_databases() {
+        let mut database = Database::new(Config::new(Logger::new(Level::Warning)));
+        database.unlock();
+        let shared = database.shared();
+        let (locked_tx, locked_rx) = channel();
+        let (done_tx, done_rx) = channel();
+        let th = thread::spawn(move || {
+            let mut other = shared.handle();
+            other.lock(&[1]);
+            other.get_or_create(1, b"foo").set(b"1".to_vec()).unwrap();
+            locked_tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+        });
+        locked_rx.recv().unwrap();
+
+        // db 1 is still held by the other handle
+        database.lock(&[0]);
+        database
+            .get_or_create(0, b"foo")
+            .set(b"0".to_vec())
+            .unwrap();
+        assert_eq!(database.dbsize(0), 1);
+
+        done_tx.send(()).unwrap();
+        th.join().unwrap();
+        database.lock(&[0, 1]);
+        assert_eq!(
+            database.get(1, b"foo").unwrap().get().unwrap(),
+            b"1".to_vec()
+        );
+    }
+
+    #[test]
+    fn lock_in_opposite_order() {
+        let mut database = Database::new(Config::new(Logger::new(Level::Warning)));
+        database.unlock();
+        let (done_tx, done_rx) = channel();
+        let barrier = Arc::new(Barrier::new(2));
+        for &indexes in &[[0, 1], [1, 0]] {
+            let shared = database.shared();
+            let done_tx = done_tx.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let mut handle = shared.handle();
+                for _ in 0..100000 {
+                    barrier.wait();
+                    handle.lock(&indexes);
+                    for &index in &indexes {
+                        handle.get_or_create(index, b"counter").incr(1).unwrap();
+                    }
+                    handle.unlock();
+                }
+                done_tx.send(()).unwrap();
+            });
+        }
+
+        // neither thread waits forever for the database held by the other
+        for _ in 0..2 {
+            done_rx.recv_timeout(Duration::from_secs(30)).unwrap();
+        }
+        database.lock(&[0, 1]);
+        for index in 0..2 {
+            assert_eq!(
+                database.get(index, b"counter").unwrap().get().unwrap(),
+                b"200000".to_vec()
+            );
+        }
+    }
 }
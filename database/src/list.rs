@@ -1,8 +1,9 @@
 use std::collections::LinkedList;
 use std::io;
 use std::io::Write;
+use std::mem::size_of;
 
-use dbutil::normalize_position;
+use dbutil::{normalize_position, sampled_memory_usage, vec_memory_usage};
 use error::OperationError;
 use rdbutil::constants::*;
 use rdbutil::{encode_len, encode_slice_u8};
@@ -113,6 +114,40 @@ impl ValueList {
         }
     }
 
+    pub fn lpos(&self, el: &[u8], rank: i64, count: usize, maxlen: usize) -> Vec<usize> {
+        match *self {
+            ValueList::Data(ref list) => {
+                let len = list.len();
+                let maxlen = if maxlen == 0 { len } else { maxlen };
+                let skip = (rank.abs() - 1) as usize;
+                let positions: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+                    Box::new(
+                        list.iter()
+                            .take(maxlen)
+                            .enumerate()
+                            .filter(|&(_, x)| &x[..] == el)
+                            .map(|(i, _)| i),
+                    )
+                } else {
+                    Box::new(
+                        list.iter()
+                            .rev()
+                            .take(maxlen)
+                            .enumerate()
+                            .filter(|&(_, x)| &x[..] == el)
+                            .map(move |(i, _)| len - 1 - i),
+                    )
+                };
+                let positions = positions.skip(skip);
+                if count == 0 {
+                    positions.collect()
+                } else {
+                    positions.take(count).collect()
+                }
+            }
+        }
+    }
+
     pub fn lrem(&mut self, left: bool, limit: usize, newvalue: Vec<u8>) -> usize {
         let mut count = 0;
         let mut newlist = LinkedList::new();
@@ -228,17 +263,38 @@ impl ValueList {
         writer.write(&*data)
     }
 
+    /// Internal representation, as reported by `OBJECT ENCODING`.
+    pub fn encoding(&self) -> &'static str {
+        match *self {
+            ValueList::Data(_) => "linkedlist",
+        }
+    }
+
+    /// Estimated bytes allocated by the list, besides the enum itself.
+    /// Only `samples` elements are measured, or all of them if it is zero.
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match *self {
+            ValueList::Data(ref list) => {
+                // every node has a pointer to the next and previous ones
+                let node = 2 * size_of::<usize>();
+                sampled_memory_usage(
+                    list.iter().map(|el| node + vec_memory_usage(el)),
+                    list.len(),
+                    samples,
+                )
+            }
+        }
+    }
+
     pub fn debug_object(&self) -> String {
         let mut serialized_data = vec![];
         let serialized = self.dump(&mut serialized_data).unwrap();
-        let encoding = match *self {
-            ValueList::Data(_) => "linkedlist",
-        };
 
         format!(
             "Value at:0x0000000000 refcount:1 encoding:{} serializedlength:{} lru:0 \
              lru_seconds_idle:0",
-            encoding, serialized
+            self.encoding(),
+            serialized
         )
     }
 }
@@ -0,0 +1,293 @@
+use std::fs::{remove_file, rename, File};
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::process;
+use std::str::from_utf8;
+
+use rdbutil::constants::*;
+use rdbutil::{decode_len, decode_slice_u8, decode_u32_from_slice_u8, decode_u64_from_slice_u8};
+use rdbutil::{encode_len, encode_slice_u8, encode_u64_to_slice_u8};
+use util::mstime;
+
+use super::{checksum, Database, Value};
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A writer that keeps the checksum of everything written through it.
+struct Crc64Writer<W: Write> {
+    inner: W,
+    crc: u64,
+}
+
+impl<W: Write> Write for Crc64Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.crc = checksum(self.crc, &buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes a key and its value.
+/// The value serialization is the one used by DUMP, which starts with the
+/// value type and ends with the RDB version in two bytes.
+fn write_value<W: Write>(writer: &mut W, key: &[u8], value: &Value) -> io::Result<()> {
+    let mut data = vec![];
+    match value {
+        Value::Nil => return Ok(()),
+        Value::String(s) => s.dump(&mut data)?,
+        Value::List(l) => l.dump(&mut data)?,
+        Value::Set(s) => s.dump(&mut data)?,
+        Value::SortedSet(s) => s.dump(&mut data)?,
+    };
+    writer.write_all(&data[..1])?;
+    encode_slice_u8(key, writer, false)?;
+    writer.write_all(&data[1..data.len() - 2])
+}
+
+impl Database {
+    /// Saves every database into `config.dbfilename` using the RDB format.
+    /// The snapshot is written to a temporary file first, so the previous
+    /// one is kept if anything fails. All databases must be locked.
+    pub fn save(&self) -> io::Result<()> {
+        let path = Path::new(&*self.config.dbfilename);
+        let tmppath = path.with_file_name(format!("temp-{}.rdb", process::id()));
+        let r = self.save_to(&tmppath);
+        if r.is_err() {
+            let _ = remove_file(&tmppath);
+            return r;
+        }
+        rename(&tmppath, path)
+    }
+
+    fn save_to(&self, path: &Path) -> io::Result<()> {
+        let mut writer = Crc64Writer {
+            inner: BufWriter::new(File::create(path)?),
+            crc: 0,
+        };
+        write!(writer, "REDIS{:04}", VERSION)?;
+        for index in 0..self.keyspaces.len() {
+            let keyspace = self.keyspace(index);
+            if keyspace.data.is_empty() {
+                continue;
+            }
+            writer.write_all(&[OPCODE_SELECTDB])?;
+            encode_len(index, &mut writer)?;
+            for (key, value) in keyspace.data.iter() {
+                if let Some(ms) = keyspace.data_expiration_ms.get(key) {
+                    writer.write_all(&[OPCODE_EXPIRETIME_MS])?;
+                    encode_u64_to_slice_u8(*ms as u64, &mut writer)?;
+                }
+                write_value(&mut writer, key, value)?;
+            }
+        }
+        writer.write_all(&[OPCODE_EOF])?;
+        let crc = writer.crc;
+        encode_u64_to_slice_u8(crc, &mut writer.inner)?;
+        match writer.inner.into_inner() {
+            Ok(file) => file.sync_all(),
+            Err(e) => Err(e.into_error()),
+        }
+    }
+
+    /// Loads the snapshot in `config.dbfilename`, if there is one, into
+    /// the databases. Keys that already expired are skipped. Returns whether
+    /// the file existed. All databases must be locked.
+    pub fn load(&mut self) -> io::Result<bool> {
+        let mut data = vec![];
+        match File::open(&*self.config.dbfilename) {
+            Ok(mut file) => file.read_to_end(&mut data)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        self.load_from(&data)?;
+        Ok(true)
+    }
+
+    fn load_from(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() < 9 || &data[..5] != b"REDIS" {
+            return Err(invalid_data("Wrong signature trying to load DB from file"));
+        }
+        let version = match from_utf8(&data[5..9]).map(|v| v.parse::<u16>()) {
+            Ok(Ok(version)) if (1..=VERSION).contains(&version) => version,
+            _ => return Err(invalid_data("Can't handle RDB format version")),
+        };
+        let now = mstime();
+        let mut reader = &data[9..];
+        let mut dbindex = 0;
+        let mut expiration = None;
+        loop {
+            let mut opcode = [0; 1];
+            reader.read_exact(&mut opcode)?;
+            match opcode[0] {
+                OPCODE_EXPIRETIME_MS => {
+                    expiration = Some(decode_u64_from_slice_u8(&mut reader)? as i64);
+                }
+                OPCODE_EXPIRETIME => {
+                    expiration = Some(decode_u32_from_slice_u8(&mut reader)? as i64 * 1000);
+                }
+                OPCODE_SELECTDB => {
+                    dbindex = decode_len(&mut reader)?;
+                    if dbindex >= self.keyspaces.len() {
+                        return Err(invalid_data("DB index is out of range"));
+                    }
+                }
+                OPCODE_RESIZEDB => {
+                    decode_len(&mut reader)?;
+                    decode_len(&mut reader)?;
+                }
+                OPCODE_AUX => {
+                    decode_slice_u8(&mut reader)?;
+                    decode_slice_u8(&mut reader)?;
+                }
+                OPCODE_EOF => break,
+                valuetype => {
+                    let key = decode_slice_u8(&mut reader)?;
+                    let value = match Value::restore_body(valuetype, &mut reader)? {
+                        Some(value) => value,
+                        None => return Err(invalid_data("Bad data format")),
+                    };
+                    match expiration.take() {
+                        Some(ms) if ms < now => (),
+                        Some(ms) => {
+                            *self.get_or_create(dbindex, &key) = value;
+                            self.set_msexpiration(dbindex, key, ms);
+                        }
+                        None => *self.get_or_create(dbindex, &key) = value,
+                    }
+                }
+            }
+        }
+        // the checksum was added in version 5, and 0 means it is disabled
+        if version >= 5 {
+            let payload_len = data.len() - reader.len();
+            let crc = decode_u64_from_slice_u8(&mut reader)?;
+            if crc != 0 && crc != checksum(0, &data[..payload_len]) {
+                return Err(invalid_data("Wrong RDB checksum"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_rdb {
+    use std::env::temp_dir;
+    use std::fs::File;
+    use std::io::{Read, Write};
+
+    use crc64::crc64;
+    use util::mstime;
+
+    use super::super::{Database, Value};
+
+    #[test]
+    fn save() {
+        let mut path = temp_dir();
+        path.push("rdbtest.rdb");
+
+        let mut database = Database::mock();
+        database.config.dbfilename = path.to_str().unwrap().to_owned();
+        database
+            .get_or_create(1, b"key")
+            .set(b"value".to_vec())
+            .unwrap();
+        database.set_msexpiration(1, b"key".to_vec(), 1234);
+        database.save().unwrap();
+
+        let mut data = vec![];
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let len = data.len();
+        let mut expected =
+            b"REDIS0007\xfe\x01\xfc\xd2\x04\x00\x00\x00\x00\x00\x00\x00\x03key\x05value\xff"
+                .to_vec();
+        assert_eq!(&data[..len - 8], &*expected);
+        let crc = crc64(0, &expected);
+        expected.clear();
+        for i in 0..8 {
+            expected.push(((crc >> (i * 8)) & 0xff) as u8);
+        }
+        assert_eq!(&data[len - 8..], &*expected);
+    }
+
+    #[test]
+    fn load() {
+        let mut path = temp_dir();
+        path.push("rdbloadtest.rdb");
+
+        let mut database = Database::mock();
+        database.config.dbfilename = path.to_str().unwrap().to_owned();
+        database
+            .get_or_create(0, b"string")
+            .set(b"value".to_vec())
+            .unwrap();
+        database
+            .get_or_create(2, b"list")
+            .push(b"a".to_vec(), true)
+            .unwrap();
+        database
+            .get_or_create(2, b"set")
+            .sadd(b"1".to_vec(), 3)
+            .unwrap();
+        database
+            .get_or_create(3, b"zset")
+            .zadd(1.5, b"member".to_vec(), false, false, false, false)
+            .unwrap();
+        database
+            .get_or_create(3, b"volatile")
+            .set(b"1".to_vec())
+            .unwrap();
+        database.set_msexpiration(3, b"volatile".to_vec(), mstime() + 100000);
+        database
+            .get_or_create(3, b"gone")
+            .set(b"1".to_vec())
+            .unwrap();
+        database.set_msexpiration(3, b"gone".to_vec(), 1234);
+        database.save().unwrap();
+
+        let mut loaded = Database::mock();
+        loaded.config.dbfilename = database.config.dbfilename.clone();
+        assert!(loaded.load().unwrap());
+        assert_eq!(loaded.dbsize(0), 1);
+        assert_eq!(loaded.dbsize(2), 2);
+        assert_eq!(loaded.dbsize(3), 2);
+        match loaded.get(0, b"string") {
+            Some(Value::String(s)) => assert_eq!(s.to_vec(), b"value".to_vec()),
+            _ => panic!("Expected string"),
+        }
+        assert_eq!(
+            loaded.get(2, b"list").unwrap().lrange(0, -1).unwrap(),
+            vec![&b"a"[..]]
+        );
+        assert!(loaded.get(2, b"set").unwrap().sismember(b"1").unwrap());
+        assert_eq!(
+            loaded
+                .get(3, b"zset")
+                .unwrap()
+                .zscore(b"member".to_vec())
+                .unwrap(),
+            Some(1.5)
+        );
+        assert_eq!(
+            loaded.get_msexpiration(3, b"volatile"),
+            database.get_msexpiration(3, b"volatile")
+        );
+
+        // a corrupted file is not loaded
+        let mut data = vec![];
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let len = data.len();
+        data[len - 1] ^= 1;
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert!(loaded.load().is_err());
+
+        loaded.config.dbfilename = "/nonexistent/dump.rdb".to_owned();
+        assert!(!loaded.load().unwrap());
+    }
+}
@@ -0,0 +1,2029 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use rand::random;
+
+use response::Response;
+use util::get_random_hex_chars;
+
+/// Port a sentinel listens to by default.
+pub const SENTINEL_PORT: u16 = 26379;
+
+/// Channel sentinels use to announce themselves through the instances.
+pub const HELLO_CHANNEL: &str = "__sentinel__:hello";
+
+/// Length of a sentinel id, in hex characters.
+const SENTINEL_IDLEN: usize = 40;
+
+/// Milliseconds between two pings to the same instance.
+const PING_PERIOD: i64 = 1000;
+
+/// Milliseconds between two INFO to the same instance, unless a failover is
+/// going on.
+const INFO_PERIOD: i64 = 10000;
+
+/// Milliseconds between two INFO while the master is down or failing over.
+const FAST_INFO_PERIOD: i64 = 1000;
+
+/// Milliseconds between two hello messages published to the same instance.
+const HELLO_PERIOD: i64 = 2000;
+
+/// Milliseconds between asking other sentinels whether a master is down.
+const ASK_PERIOD: i64 = 1000;
+
+/// Maximum random delay, in milliseconds, before starting a failover, so
+/// sentinels don't all ask for votes at the same time.
+const MAX_DESYNC: i64 = 1000;
+
+/// Milliseconds to wait for a replica to be reconfigured during a failover.
+const REPLICA_RECONF_TIMEOUT: i64 = 10000;
+
+/// Maximum milliseconds to wait for the votes of the other sentinels.
+const ELECTION_TIMEOUT: i64 = 10000;
+
+const DEFAULT_DOWN_AFTER: i64 = 30000;
+const DEFAULT_FAILOVER_TIMEOUT: i64 = 180000;
+const DEFAULT_PARALLEL_SYNCS: usize = 1;
+
+/// What an instance said about itself in its last INFO.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Role {
+    Unknown,
+    Master,
+    Replica,
+}
+
+/// Progress of a replica being pointed to a new master during a failover.
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Reconf {
+    None,
+    /// REPLICAOF was sent, at the given time
+    Sent(i64),
+    /// The replica reports the new master but it is not synced yet
+    InProgress(i64),
+    Done,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum FailoverState {
+    None,
+    /// Waiting for the other sentinels to vote this one as leader
+    WaitStart,
+    SelectReplica,
+    SendReplicaofNoOne,
+    /// Waiting for the selected replica to report itself as master
+    WaitPromotion,
+    ReconfReplicas,
+    UpdateConfig,
+}
+
+impl FailoverState {
+    fn name(&self) -> &'static str {
+        match self {
+            FailoverState::None => "none",
+            FailoverState::WaitStart => "wait_start",
+            FailoverState::SelectReplica => "select_slave",
+            FailoverState::SendReplicaofNoOne => "send_slaveof_noone",
+            FailoverState::WaitPromotion => "wait_promotion",
+            FailoverState::ReconfReplicas => "reconf_slaves",
+            FailoverState::UpdateConfig => "update_config",
+        }
+    }
+}
+
+/// Which instance of a master a request is sent to.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Target {
+    Master,
+    Replica,
+    /// Another sentinel, by id
+    Sentinel(String),
+}
+
+#[derive(PartialEq, Clone, Debug)]
+enum RequestKind {
+    Ping,
+    Info,
+    Hello,
+    Subscribe,
+    AskMasterDown,
+    Replicaof,
+}
+
+/// A command a sentinel needs to send to an instance. Its reply is given
+/// back with `Sentinel::process_reply`.
+#[derive(Clone, Debug)]
+pub struct Request {
+    /// Name of the master the instance belongs to
+    pub master: String,
+    pub target: Target,
+    pub ip: String,
+    pub port: u16,
+    /// Arguments of an AUTH to send before the command, if any
+    pub auth: Option<Vec<Vec<u8>>>,
+    pub command: Vec<Vec<u8>>,
+    kind: RequestKind,
+}
+
+impl Request {
+    /// Whether the connection is kept open to receive hello messages.
+    pub fn is_subscription(&self) -> bool {
+        self.kind == RequestKind::Subscribe
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+/// The state of the connection with an instance.
+#[derive(Debug)]
+struct Link {
+    /// Milliseconds when the oldest unanswered ping was sent, 0 if none
+    ping_sent: i64,
+    /// Milliseconds when the last ping was sent
+    last_ping: i64,
+    ping_in_flight: bool,
+    /// Milliseconds of the last valid reply to a ping
+    last_ok_ping: i64,
+    /// Milliseconds of the last reply of any kind
+    last_avail: i64,
+    /// Milliseconds when the last INFO was sent
+    last_info: i64,
+    info_in_flight: bool,
+    /// Milliseconds when the last hello message was published
+    last_hello: i64,
+    /// Milliseconds when other sentinel was last asked about the master
+    last_ask: i64,
+    ask_in_flight: bool,
+    /// Whether there is a connection receiving hello messages
+    subscribed: bool,
+    /// Local address of the last connection to the instance, announced in
+    /// the hello messages
+    local_ip: Option<String>,
+}
+
+impl Link {
+    fn new(now: i64) -> Link {
+        Link {
+            ping_sent: 0,
+            last_ping: 0,
+            ping_in_flight: false,
+            last_ok_ping: now,
+            last_avail: now,
+            last_info: 0,
+            info_in_flight: false,
+            last_hello: 0,
+            last_ask: 0,
+            ask_in_flight: false,
+            subscribed: false,
+            local_ip: None,
+        }
+    }
+}
+
+/// A master, replica or sentinel, as seen by this sentinel.
+#[derive(Debug)]
+pub struct Instance {
+    pub ip: String,
+    pub port: u16,
+    /// Run id reported by INFO, or the id of a sentinel
+    pub runid: Option<String>,
+    /// Milliseconds since when the instance does not reply to pings
+    pub s_down_since: Option<i64>,
+    link: Link,
+    /// Milliseconds of the last INFO reply, 0 if none
+    info_refresh: i64,
+    pub role_reported: Role,
+    /// Milliseconds when the reported role changed
+    role_reported_time: i64,
+    /// The master a replica replicates from, as reported by INFO
+    pub master_host: Option<String>,
+    pub master_port: u16,
+    pub master_link_up: bool,
+    /// Seconds the replica link with its master has been down
+    master_link_down_time: i64,
+    pub priority: u64,
+    pub repl_offset: u64,
+    reconf: Reconf,
+    /// A REPLICAOF to send, to fix the instance configuration
+    replicaof: Option<Option<(String, u16)>>,
+    /// Milliseconds when the configuration was last fixed
+    last_fix: i64,
+    /// Milliseconds of the last hello message from a sentinel
+    last_hello: i64,
+    /// Whether a sentinel thinks the master is down
+    master_down: bool,
+    last_master_down_reply: i64,
+    /// The sentinel voted by a sentinel, and in which epoch
+    leader: Option<String>,
+    leader_epoch: u64,
+}
+
+impl Instance {
+    fn new(ip: &str, port: u16, now: i64) -> Instance {
+        Instance {
+            ip: ip.to_owned(),
+            port,
+            runid: None,
+            s_down_since: None,
+            link: Link::new(now),
+            info_refresh: 0,
+            role_reported: Role::Unknown,
+            role_reported_time: now,
+            master_host: None,
+            master_port: 0,
+            master_link_up: false,
+            master_link_down_time: 0,
+            priority: 100,
+            repl_offset: 0,
+            reconf: Reconf::None,
+            replicaof: None,
+            last_fix: 0,
+            last_hello: now,
+            master_down: false,
+            last_master_down_reply: 0,
+            leader: None,
+            leader_epoch: 0,
+        }
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+
+    /// Whether it replied to a ping recently.
+    fn link_ok(&self, now: i64) -> bool {
+        now - self.link.last_ok_ping <= PING_PERIOD * 5
+    }
+
+    fn describe(&self, kind: &str, flags: Vec<&str>, now: i64) -> Vec<(&'static str, String)> {
+        let since = |time: i64| if time == 0 { 0 } else { now - time };
+        let mut flags = flags;
+        flags.insert(0, kind);
+        if self.s_down_since.is_some() {
+            flags.push("s_down");
+        }
+        if !self.link_ok(now) {
+            flags.push("disconnected");
+        }
+        let mut fields = vec![
+            ("name", self.addr()),
+            ("ip", self.ip.clone()),
+            ("port", format!("{}", self.port)),
+            ("runid", self.runid.clone().unwrap_or_default()),
+            ("flags", flags.join(",")),
+            ("link-pending-commands", "0".to_owned()),
+            ("link-refcount", "1".to_owned()),
+            ("last-ping-sent", format!("{}", since(self.link.ping_sent))),
+            (
+                "last-ok-ping-reply",
+                format!("{}", now - self.link.last_ok_ping),
+            ),
+            ("last-ping-reply", format!("{}", now - self.link.last_avail)),
+        ];
+        if let Some(since) = self.s_down_since {
+            fields.push(("s-down-time", format!("{}", now - since)));
+        }
+        if kind == "sentinel" {
+            fields.push(("last-hello-message", format!("{}", now - self.last_hello)));
+            fields.push((
+                "voted-leader",
+                self.leader.clone().unwrap_or_else(|| "?".to_owned()),
+            ));
+            fields.push(("voted-leader-epoch", format!("{}", self.leader_epoch)));
+        } else {
+            let role = match self.role_reported {
+                Role::Master | Role::Unknown => "master",
+                Role::Replica => "slave",
+            };
+            fields.push(("info-refresh", format!("{}", since(self.info_refresh))));
+            fields.push(("role-reported", role.to_owned()));
+            fields.push((
+                "role-reported-time",
+                format!("{}", now - self.role_reported_time),
+            ));
+        }
+        if kind == "slave" {
+            fields.extend(vec![
+                (
+                    "master-link-down-time",
+                    format!("{}", self.master_link_down_time * 1000),
+                ),
+                (
+                    "master-link-status",
+                    if self.master_link_up { "ok" } else { "err" }.to_owned(),
+                ),
+                (
+                    "master-host",
+                    self.master_host.clone().unwrap_or_else(|| "?".to_owned()),
+                ),
+                ("master-port", format!("{}", self.master_port)),
+                ("slave-priority", format!("{}", self.priority)),
+                ("slave-repl-offset", format!("{}", self.repl_offset)),
+            ]);
+        }
+        fields
+    }
+}
+
+/// A monitored master, its replicas and the other sentinels monitoring it.
+#[derive(Debug)]
+pub struct Master {
+    pub name: String,
+    pub instance: Instance,
+    /// Sentinels that need to agree the master is down to fail it over
+    pub quorum: usize,
+    /// Milliseconds without replies before an instance is considered down
+    pub down_after: i64,
+    pub failover_timeout: i64,
+    /// Replicas reconfigured at the same time after a failover
+    pub parallel_syncs: usize,
+    pub auth_user: Option<String>,
+    pub auth_pass: Option<String>,
+    pub config_epoch: u64,
+    /// Milliseconds since when enough sentinels agree the master is down
+    pub o_down_since: Option<i64>,
+    /// Replicas, by address
+    pub replicas: BTreeMap<String, Instance>,
+    /// Other sentinels, by id
+    pub sentinels: BTreeMap<String, Instance>,
+    /// The sentinel this one voted as leader, and in which epoch
+    leader: Option<String>,
+    leader_epoch: u64,
+    pub failover_state: FailoverState,
+    failover_epoch: u64,
+    /// Milliseconds when the last failover started, or may start
+    failover_start_time: i64,
+    failover_state_change_time: i64,
+    /// Started with SENTINEL FAILOVER, no agreement is needed
+    force_failover: bool,
+    /// Address of the replica being promoted
+    promoted: Option<String>,
+}
+
+impl Master {
+    fn new(name: &str, ip: &str, port: u16, quorum: usize, now: i64) -> Master {
+        let mut instance = Instance::new(ip, port, now);
+        instance.role_reported = Role::Master;
+        Master {
+            name: name.to_owned(),
+            instance,
+            quorum,
+            down_after: DEFAULT_DOWN_AFTER,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            parallel_syncs: DEFAULT_PARALLEL_SYNCS,
+            auth_user: None,
+            auth_pass: None,
+            config_epoch: 0,
+            o_down_since: None,
+            replicas: BTreeMap::new(),
+            sentinels: BTreeMap::new(),
+            leader: None,
+            leader_epoch: 0,
+            failover_state: FailoverState::None,
+            failover_epoch: 0,
+            failover_start_time: 0,
+            failover_state_change_time: 0,
+            force_failover: false,
+            promoted: None,
+        }
+    }
+
+    /// Describes the master for SENTINEL MASTERS, as field/value pairs.
+    pub fn describe(&self, now: i64) -> Vec<(&'static str, String)> {
+        let mut flags = vec![];
+        if self.o_down_since.is_some() {
+            flags.push("o_down");
+        }
+        if self.failover_state != FailoverState::None {
+            flags.push("failover_in_progress");
+        }
+        let mut fields = self.instance.describe("master", flags, now);
+        fields[0].1 = self.name.clone();
+        if let Some(since) = self.o_down_since {
+            fields.push(("o-down-time", format!("{}", now - since)));
+        }
+        fields.extend(vec![
+            ("down-after-milliseconds", format!("{}", self.down_after)),
+            ("config-epoch", format!("{}", self.config_epoch)),
+            ("num-slaves", format!("{}", self.replicas.len())),
+            ("num-other-sentinels", format!("{}", self.sentinels.len())),
+            ("quorum", format!("{}", self.quorum)),
+            ("failover-timeout", format!("{}", self.failover_timeout)),
+            ("parallel-syncs", format!("{}", self.parallel_syncs)),
+        ]);
+        if self.failover_state != FailoverState::None {
+            fields.push(("failover-state", self.failover_state.name().to_owned()));
+        }
+        fields
+    }
+
+    /// Describes the replicas for SENTINEL REPLICAS.
+    pub fn describe_replicas(&self, now: i64) -> Vec<Vec<(&'static str, String)>> {
+        self.replicas
+            .iter()
+            .map(|(addr, replica)| {
+                let mut flags = vec![];
+                if self.promoted.as_ref() == Some(addr) {
+                    flags.push("promoted");
+                }
+                match replica.reconf {
+                    Reconf::None => (),
+                    Reconf::Sent(_) => flags.push("reconf_sent"),
+                    Reconf::InProgress(_) => flags.push("reconf_inprog"),
+                    Reconf::Done => flags.push("reconf_done"),
+                }
+                replica.describe("slave", flags, now)
+            })
+            .collect()
+    }
+
+    /// Describes the other sentinels for SENTINEL SENTINELS.
+    pub fn describe_sentinels(&self, now: i64) -> Vec<Vec<(&'static str, String)>> {
+        self.sentinels
+            .iter()
+            .map(|(id, sentinel)| {
+                let mut fields = sentinel.describe("sentinel", vec![], now);
+                fields[0].1 = id.clone();
+                fields
+            })
+            .collect()
+    }
+
+    /// Address of the master. Once a replica is promoted, its address is
+    /// reported even if the failover is not over yet.
+    pub fn current_addr(&self) -> (String, u16) {
+        match (&self.promoted, self.failover_state) {
+            (Some(promoted), FailoverState::ReconfReplicas)
+            | (Some(promoted), FailoverState::UpdateConfig) => parse_addr(promoted),
+            _ => (self.instance.ip.clone(), self.instance.port),
+        }
+    }
+
+    /// Describes the instance in an event, like `master mymaster 127.0.0.1 6379`.
+    fn event_id(&self) -> String {
+        format!(
+            "master {} {} {}",
+            self.name, self.instance.ip, self.instance.port
+        )
+    }
+
+    /// Describes an instance of the master in an event, like
+    /// `slave 127.0.0.1:6380 127.0.0.1 6380 @ mymaster 127.0.0.1 6379`.
+    fn instance_event_id(&self, kind: &str, instance: &Instance) -> String {
+        format!(
+            "{} {} {} {} @ {} {} {}",
+            kind,
+            instance.addr(),
+            instance.ip,
+            instance.port,
+            self.name,
+            self.instance.ip,
+            self.instance.port
+        )
+    }
+
+    fn auth(&self) -> Option<Vec<Vec<u8>>> {
+        let pass = self.auth_pass.as_ref()?;
+        let mut auth = vec![b"AUTH".to_vec()];
+        if let Some(user) = &self.auth_user {
+            auth.push(user.as_bytes().to_vec());
+        }
+        auth.push(pass.as_bytes().to_vec());
+        Some(auth)
+    }
+
+    fn sentinel_request(&self, id: &str, kind: RequestKind, command: Vec<Vec<u8>>) -> Request {
+        let sentinel = &self.sentinels[id];
+        Request {
+            master: self.name.clone(),
+            target: Target::Sentinel(id.to_owned()),
+            ip: sentinel.ip.clone(),
+            port: sentinel.port,
+            auth: None,
+            command,
+            kind,
+        }
+    }
+
+    fn replica_request(
+        &self,
+        replica: &Instance,
+        kind: RequestKind,
+        command: Vec<Vec<u8>>,
+    ) -> Request {
+        Request {
+            master: self.name.clone(),
+            target: Target::Replica,
+            ip: replica.ip.clone(),
+            port: replica.port,
+            auth: self.auth(),
+            command,
+            kind,
+        }
+    }
+
+    fn instance(&self, target: &Target, addr: &str) -> Option<&Instance> {
+        let instance = match target {
+            Target::Master => Some(&self.instance),
+            Target::Replica => self.replicas.get(addr),
+            Target::Sentinel(id) => self.sentinels.get(id),
+        }?;
+        if instance.addr() == addr {
+            Some(instance)
+        } else {
+            None
+        }
+    }
+
+    fn instance_mut(&mut self, target: &Target, addr: &str) -> Option<&mut Instance> {
+        let instance = match target {
+            Target::Master => Some(&mut self.instance),
+            Target::Replica => self.replicas.get_mut(addr),
+            Target::Sentinel(id) => self.sentinels.get_mut(id),
+        }?;
+        if instance.addr() == addr {
+            Some(instance)
+        } else {
+            None
+        }
+    }
+
+    /// Best replica to promote: the reachable one with the lowest priority,
+    /// the most data and the lowest run id.
+    fn select_replica(&self, now: i64) -> Option<String> {
+        let info_validity = if self.instance.s_down_since.is_some() {
+            FAST_INFO_PERIOD * 5
+        } else {
+            INFO_PERIOD * 3
+        };
+        self.replicas
+            .iter()
+            .filter(|(_, r)| {
+                r.s_down_since.is_none()
+                    && r.link_ok(now)
+                    && r.priority != 0
+                    && r.info_refresh != 0
+                    && now - r.info_refresh <= info_validity
+            })
+            .min_by(|(_, a), (_, b)| {
+                a.priority
+                    .cmp(&b.priority)
+                    .then(b.repl_offset.cmp(&a.repl_offset))
+                    .then(match (&a.runid, &b.runid) {
+                        (Some(a), Some(b)) => a.cmp(b),
+                        (a, b) => b.is_some().cmp(&a.is_some()),
+                    })
+            })
+            .map(|(addr, _)| addr.clone())
+    }
+}
+
+/// State shared by every master.
+struct State {
+    myid: String,
+    port: u16,
+    current_epoch: u64,
+    announce_ip: Option<String>,
+    /// Events to be logged and published, as (type, description)
+    events: Vec<(String, String)>,
+}
+
+impl State {
+    fn event(&mut self, kind: &str, description: String) {
+        self.events.push((kind.to_owned(), description));
+    }
+
+    fn set_current_epoch(&mut self, epoch: u64) {
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            self.event("+new-epoch", format!("{}", epoch));
+        }
+    }
+
+    /// Votes `runid` as the leader to fail over the master in `epoch`, unless
+    /// there is already a vote for that epoch. Returns the current vote.
+    fn vote_leader(
+        &mut self,
+        master: &mut Master,
+        epoch: u64,
+        runid: &str,
+        now: i64,
+    ) -> (Option<String>, u64) {
+        self.set_current_epoch(epoch);
+        if master.leader_epoch < epoch && self.current_epoch <= epoch {
+            master.leader = Some(runid.to_owned());
+            master.leader_epoch = self.current_epoch;
+            let description = format!("{} {}", runid, master.leader_epoch);
+            self.event("+vote-for-leader", description);
+            // voting someone else delays our own failover
+            if runid != self.myid {
+                master.failover_start_time = now + random::<i64>().abs() % MAX_DESYNC;
+            }
+        }
+        (master.leader.clone(), master.leader_epoch)
+    }
+
+    /// The hello message announcing this sentinel, `ip,port,runid,
+    /// current_epoch,master_name,master_ip,master_port,master_config_epoch`.
+    /// None until the local address is known.
+    fn hello(&self, master: &Master) -> Option<String> {
+        let ip = self
+            .announce_ip
+            .as_ref()
+            .or(master.instance.link.local_ip.as_ref())?;
+        let (master_ip, master_port) = master.current_addr();
+        Some(format!(
+            "{},{},{},{},{},{},{},{}",
+            ip,
+            self.port,
+            self.myid,
+            self.current_epoch,
+            master.name,
+            master_ip,
+            master_port,
+            master.config_epoch
+        ))
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
+/// Fields of an INFO reply.
+fn parse_info(info: &str) -> HashMap<&str, &str> {
+    info.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let pos = line.find(':')?;
+            Some((&line[..pos], line[pos + 1..].trim_end()))
+        })
+        .collect()
+}
+
+/// Address of a replica in the master INFO, either
+/// `ip=127.0.0.1,port=6380,state=online,...` or `127.0.0.1,6380,online`.
+fn parse_info_replica(value: &str) -> Option<(String, u16)> {
+    if value.contains('=') {
+        let fields = value
+            .split(',')
+            .filter_map(|field| {
+                let pos = field.find('=')?;
+                Some((&field[..pos], &field[pos + 1..]))
+            })
+            .collect::<HashMap<_, _>>();
+        Some(((*fields.get("ip")?).to_owned(), parse(fields.get("port")?)?))
+    } else {
+        let mut fields = value.split(',');
+        Some((fields.next()?.to_owned(), parse(fields.next()?)?))
+    }
+}
+
+/// Monitors masters and fails them over when they are not reachable.
+pub struct Sentinel {
+    state: State,
+    masters: BTreeMap<String, Master>,
+}
+
+impl Sentinel {
+    /// Creates a sentinel with a random id, listening on `port`.
+    pub fn new(port: u16) -> Sentinel {
+        Sentinel {
+            state: State {
+                myid: get_random_hex_chars(SENTINEL_IDLEN),
+                port,
+                current_epoch: 0,
+                announce_ip: None,
+                events: vec![],
+            },
+            masters: BTreeMap::new(),
+        }
+    }
+
+    pub fn myid(&self) -> &str {
+        &self.state.myid
+    }
+
+    pub fn set_myid(&mut self, myid: &str) -> Result<(), String> {
+        if myid.len() != SENTINEL_IDLEN {
+            return Err("Malformed Sentinel id in myid option.".to_owned());
+        }
+        self.state.myid = myid.to_owned();
+        Ok(())
+    }
+
+    pub fn current_epoch(&self) -> u64 {
+        self.state.current_epoch
+    }
+
+    pub fn set_announce_ip(&mut self, ip: &str) {
+        self.state.announce_ip = if ip.is_empty() {
+            None
+        } else {
+            Some(ip.to_owned())
+        };
+    }
+
+    /// Takes the events to be logged and published, as (type, description).
+    pub fn take_events(&mut self) -> Vec<(String, String)> {
+        self.state.events.drain(..).collect()
+    }
+
+    pub fn masters(&self) -> impl Iterator<Item = &Master> {
+        self.masters.values()
+    }
+
+    pub fn master(&self, name: &str) -> Option<&Master> {
+        self.masters.get(name)
+    }
+
+    /// Starts monitoring a master.
+    pub fn monitor(
+        &mut self,
+        name: &str,
+        ip: &str,
+        port: u16,
+        quorum: usize,
+        now: i64,
+    ) -> Result<(), String> {
+        if quorum == 0 {
+            return Err("ERR Quorum must be 1 or greater.".to_owned());
+        }
+        if self.masters.contains_key(name) {
+            return Err("ERR Duplicated master name.".to_owned());
+        }
+        let master = Master::new(name, ip, port, quorum, now);
+        self.state.event(
+            "+monitor",
+            format!("{} quorum {}", master.event_id(), quorum),
+        );
+        self.masters.insert(name.to_owned(), master);
+        Ok(())
+    }
+
+    /// Stops monitoring a master.
+    pub fn remove(&mut self, name: &str) -> Result<(), String> {
+        match self.masters.remove(name) {
+            Some(master) => {
+                self.state.event("-monitor", master.event_id());
+                Ok(())
+            }
+            None => Err("ERR No such master with that name".to_owned()),
+        }
+    }
+
+    /// Changes an option of a monitored master.
+    pub fn set(&mut self, name: &str, option: &str, value: &str) -> Result<(), String> {
+        let master = match self.masters.get_mut(name) {
+            Some(master) => master,
+            None => return Err("ERR No such master with that name".to_owned()),
+        };
+        let invalid = || {
+            Err(format!(
+                "ERR Invalid argument '{}' for SENTINEL SET '{}'",
+                value, option
+            ))
+        };
+        match &*option.to_ascii_lowercase() {
+            "down-after-milliseconds" => match parse(value) {
+                Some(ms) if ms > 0 => master.down_after = ms,
+                _ => return invalid(),
+            },
+            "failover-timeout" => match parse(value) {
+                Some(ms) if ms > 0 => master.failover_timeout = ms,
+                _ => return invalid(),
+            },
+            "parallel-syncs" => match parse(value) {
+                Some(n) if n > 0 => master.parallel_syncs = n,
+                _ => return invalid(),
+            },
+            "quorum" => match parse(value) {
+                Some(n) if n > 0 => master.quorum = n,
+                _ => return invalid(),
+            },
+            "auth-pass" => {
+                master.auth_pass = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.to_owned())
+                }
+            }
+            "auth-user" => {
+                master.auth_user = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.to_owned())
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "ERR Invalid argument '{}' for SENTINEL SET '{}'",
+                    option, name
+                ))
+            }
+        }
+        let description = format!("{} {} {}", master.event_id(), option, value);
+        self.state.event("+set", description);
+        Ok(())
+    }
+
+    /// Address of a master, as known by this sentinel.
+    pub fn get_master_addr(&self, name: &str) -> Option<(String, u16)> {
+        Some(self.masters.get(name)?.current_addr())
+    }
+
+    /// Starts a failover without asking the other sentinels.
+    pub fn failover(&mut self, name: &str, now: i64) -> Result<(), String> {
+        let master = match self.masters.get_mut(name) {
+            Some(master) => master,
+            None => return Err("ERR No such master with that name".to_owned()),
+        };
+        if master.failover_state != FailoverState::None {
+            return Err("INPROG Failover already in progress".to_owned());
+        }
+        if master.select_replica(now).is_none() {
+            return Err("NOGOODSLAVE No suitable replica to promote".to_owned());
+        }
+        let epoch = self.state.current_epoch + 1;
+        self.state.set_current_epoch(epoch);
+        master.force_failover = true;
+        start_failover(&mut self.state, master, now);
+        Ok(())
+    }
+
+    /// Checks whether the sentinels monitoring a master are enough to reach
+    /// the quorum and to authorize a failover.
+    pub fn ckquorum(&self, name: &str, now: i64) -> Result<String, String> {
+        let master = match self.masters.get(name) {
+            Some(master) => master,
+            None => return Err("ERR No such master with that name".to_owned()),
+        };
+        let voters = master.sentinels.len() + 1;
+        let usable = 1 + master
+            .sentinels
+            .values()
+            .filter(|s| s.s_down_since.is_none() && s.link_ok(now))
+            .count();
+        if usable < master.quorum {
+            Err(format!(
+                "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the \
+                 specified quorum for this master",
+                usable
+            ))
+        } else if usable <= voters / 2 {
+            Err(format!(
+                "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the \
+                 majority and authorize a failover",
+                usable
+            ))
+        } else {
+            Ok(format!(
+                "OK {} usable Sentinels. Quorum and failover authorization can be reached",
+                usable
+            ))
+        }
+    }
+
+    /// Replies to another sentinel whether the master at `ip:port` is down
+    /// and, if `runid` is not `*`, votes it as leader to fail it over.
+    /// Returns whether it is down, the voted leader and its epoch.
+    pub fn is_master_down_by_addr(
+        &mut self,
+        ip: &str,
+        port: u16,
+        epoch: u64,
+        runid: &str,
+        now: i64,
+    ) -> (bool, String, u64) {
+        let state = &mut self.state;
+        let master = match self
+            .masters
+            .values_mut()
+            .find(|m| m.instance.ip == ip && m.instance.port == port)
+        {
+            Some(master) => master,
+            None => return (false, "*".to_owned(), 0),
+        };
+        let down = master.instance.s_down_since.is_some();
+        if runid == "*" {
+            return (down, "*".to_owned(), 0);
+        }
+        let (leader, leader_epoch) = state.vote_leader(master, epoch, runid, now);
+        (down, leader.unwrap_or_else(|| "*".to_owned()), leader_epoch)
+    }
+
+    /// Lines of the INFO sentinel section.
+    pub fn info(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("sentinel_masters:{}", self.masters.len()),
+            "sentinel_tilt:0".to_owned(),
+            "sentinel_running_scripts:0".to_owned(),
+            "sentinel_scripts_queue_length:0".to_owned(),
+            "sentinel_simulate_failure_flags:0".to_owned(),
+        ];
+        for (i, master) in self.masters.values().enumerate() {
+            let status = if master.o_down_since.is_some() {
+                "odown"
+            } else if master.instance.s_down_since.is_some() {
+                "sdown"
+            } else {
+                "ok"
+            };
+            lines.push(format!(
+                "master{}:name={},status={},address={},slaves={},sentinels={}",
+                i,
+                master.name,
+                status,
+                master.instance.addr(),
+                master.replicas.len(),
+                master.sentinels.len() + 1
+            ));
+        }
+        lines
+    }
+
+    /// Periodic checks. Returns the commands to send to the instances.
+    pub fn cron(&mut self, now: i64) -> Vec<Request> {
+        let mut requests = vec![];
+        for master in self.masters.values_mut() {
+            master_cron(&mut self.state, master, now, &mut requests);
+        }
+        requests
+    }
+
+    /// Processes the reply to a request, `None` if the instance was not
+    /// reachable. `local_ip` is the local address of the connection.
+    pub fn process_reply(
+        &mut self,
+        request: &Request,
+        reply: Option<Response>,
+        local_ip: Option<String>,
+        now: i64,
+    ) {
+        let state = &mut self.state;
+        let master = match self.masters.get_mut(&request.master) {
+            Some(master) => master,
+            None => return,
+        };
+        let addr = request.addr();
+        let instance = match master.instance_mut(&request.target, &addr) {
+            Some(instance) => instance,
+            None => return,
+        };
+        if reply.is_some() {
+            instance.link.last_avail = now;
+            if local_ip.is_some() {
+                instance.link.local_ip = local_ip;
+            }
+        }
+        match request.kind {
+            RequestKind::Ping => {
+                instance.link.ping_in_flight = false;
+                let ok = match &reply {
+                    Some(Response::Status(_)) => true,
+                    Some(Response::Error(err)) => {
+                        err.starts_with("LOADING") || err.starts_with("MASTERDOWN")
+                    }
+                    _ => false,
+                };
+                if ok {
+                    instance.link.ping_sent = 0;
+                    instance.link.last_ok_ping = now;
+                }
+            }
+            RequestKind::Info => {
+                instance.link.info_in_flight = false;
+                if let Some(Response::Data(info)) = reply {
+                    let info = String::from_utf8_lossy(&info).into_owned();
+                    refresh_from_info(state, master, &request.target, &addr, &info, now);
+                }
+            }
+            RequestKind::AskMasterDown => {
+                instance.link.ask_in_flight = false;
+                if let Some(Response::Array(reply)) = reply {
+                    if let [Response::Integer(down), Response::Data(leader), Response::Integer(epoch)] =
+                        &reply[..]
+                    {
+                        instance.master_down = *down == 1;
+                        instance.last_master_down_reply = now;
+                        let leader = String::from_utf8_lossy(leader).into_owned();
+                        if leader != "*" {
+                            instance.leader = Some(leader);
+                            instance.leader_epoch = *epoch as u64;
+                        }
+                    }
+                }
+            }
+            RequestKind::Hello | RequestKind::Subscribe | RequestKind::Replicaof => (),
+        }
+    }
+
+    /// Whether a subscription to hello messages is still needed.
+    pub fn subscription_wanted(&self, request: &Request) -> bool {
+        match self.masters.get(&request.master) {
+            Some(master) => master.instance(&request.target, &request.addr()).is_some(),
+            None => false,
+        }
+    }
+
+    /// Notifies a subscription to hello messages was closed, so it is
+    /// created again.
+    pub fn subscription_closed(&mut self, request: &Request) {
+        if let Some(master) = self.masters.get_mut(&request.master) {
+            if let Some(instance) = master.instance_mut(&request.target, &request.addr()) {
+                instance.link.subscribed = false;
+            }
+        }
+    }
+
+    /// Processes a hello message, published by a sentinel to announce itself
+    /// and its view of the master.
+    pub fn process_hello(&mut self, message: &str, now: i64) {
+        let fields = message.split(',').collect::<Vec<_>>();
+        if fields.len() != 8 || fields[2] == self.state.myid {
+            return;
+        }
+        let (ip, runid, name, master_ip) = (fields[0], fields[2], fields[4], fields[5]);
+        let (port, current_epoch, master_port, master_config_epoch) = match (
+            parse::<u16>(fields[1]),
+            parse::<u64>(fields[3]),
+            parse::<u16>(fields[6]),
+            parse::<u64>(fields[7]),
+        ) {
+            (Some(a), Some(b), Some(c), Some(d)) => (a, b, c, d),
+            _ => return,
+        };
+        let state = &mut self.state;
+        let master = match self.masters.get_mut(name) {
+            Some(master) => master,
+            None => return,
+        };
+
+        if !master.sentinels.contains_key(runid) {
+            // a sentinel restarted with a new id in the same address
+            let duplicates = master
+                .sentinels
+                .iter()
+                .filter(|(_, s)| s.ip == ip && s.port == port)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for id in duplicates {
+                let sentinel = master.sentinels.remove(&id).unwrap();
+                let description = master.instance_event_id("sentinel", &sentinel);
+                state.event("-dup-sentinel", description);
+            }
+            let mut sentinel = Instance::new(ip, port, now);
+            sentinel.runid = Some(runid.to_owned());
+            let description = master.instance_event_id("sentinel", &sentinel);
+            state.event("+sentinel", description);
+            master.sentinels.insert(runid.to_owned(), sentinel);
+        }
+        let sentinel = master.sentinels.get_mut(runid).unwrap();
+        if sentinel.ip != ip || sentinel.port != port {
+            sentinel.ip = ip.to_owned();
+            sentinel.port = port;
+        }
+        sentinel.last_hello = now;
+
+        state.set_current_epoch(current_epoch);
+        if master.config_epoch < master_config_epoch {
+            master.config_epoch = master_config_epoch;
+            if master.instance.ip != master_ip || master.instance.port != master_port {
+                let description = format!(
+                    "{} {} {} {} @ {} {} {}",
+                    "sentinel",
+                    sentinel_addr(ip, port),
+                    ip,
+                    port,
+                    master.name,
+                    master.instance.ip,
+                    master.instance.port
+                );
+                state.event("+config-update-from", description);
+                switch_master(state, master, master_ip, master_port, now);
+            }
+        }
+    }
+}
+
+fn sentinel_addr(ip: &str, port: u16) -> String {
+    format!("{}:{}", ip, port)
+}
+
+/// Updates what is known of an instance with its INFO reply.
+fn refresh_from_info(
+    state: &mut State,
+    master: &mut Master,
+    target: &Target,
+    addr: &str,
+    info: &str,
+    now: i64,
+) {
+    let fields = parse_info(info);
+    let role = match fields.get("role") {
+        Some(&"master") => Role::Master,
+        Some(&"slave") => Role::Replica,
+        _ => Role::Unknown,
+    };
+    let mut new_replicas = vec![];
+    {
+        let instance = master.instance_mut(target, addr).unwrap();
+        instance.info_refresh = now;
+        if let Some(runid) = fields.get("run_id") {
+            if instance.runid.as_ref().is_some_and(|r| r != runid) {
+                let description = format!("{} {} {}", addr, instance.ip, instance.port);
+                state.event("+reboot", description);
+            }
+            instance.runid = Some((*runid).to_owned());
+        }
+        if role != instance.role_reported {
+            instance.role_reported = role;
+            instance.role_reported_time = now;
+        }
+        if role == Role::Replica {
+            instance.master_host = fields.get("master_host").map(|h| (*h).to_owned());
+            instance.master_port = fields
+                .get("master_port")
+                .and_then(|p| parse(p))
+                .unwrap_or(0);
+            instance.master_link_up = fields.get("master_link_status") == Some(&"up");
+            instance.master_link_down_time = fields
+                .get("master_link_down_since_seconds")
+                .and_then(|s| parse(s))
+                .unwrap_or(0);
+            instance.priority = fields
+                .get("replica_priority")
+                .or_else(|| fields.get("slave_priority"))
+                .and_then(|p| parse(p))
+                .unwrap_or(100);
+            instance.repl_offset = fields
+                .get("slave_repl_offset")
+                .and_then(|o| parse(o))
+                .unwrap_or(0);
+        }
+        if *target == Target::Master && role == Role::Master {
+            for (key, value) in fields.iter() {
+                if key.starts_with("slave") && key[5..].parse::<usize>().is_ok() {
+                    if let Some(replica) = parse_info_replica(value) {
+                        new_replicas.push(replica);
+                    }
+                }
+            }
+        }
+    }
+    for (ip, port) in new_replicas {
+        let replica_addr = sentinel_addr(&ip, port);
+        if !master.replicas.contains_key(&replica_addr) && replica_addr != master.instance.addr() {
+            let replica = Instance::new(&ip, port, now);
+            let description = master.instance_event_id("slave", &replica);
+            state.event("+slave", description);
+            master.replicas.insert(replica_addr, replica);
+        }
+    }
+
+    if *target != Target::Replica {
+        return;
+    }
+    let (master_ip, master_port) = (master.instance.ip.clone(), master.instance.port);
+    let promoted = master.promoted.clone();
+    let failover_state = master.failover_state;
+    let master_down = master.instance.s_down_since.is_some();
+    let replica = master.replicas.get_mut(addr).unwrap();
+    if role == Role::Master {
+        if failover_state == FailoverState::WaitPromotion && promoted.as_deref() == Some(addr) {
+            let description = format!(
+                "slave {} {} {} @ {} {} {}",
+                addr, replica.ip, replica.port, master.name, master_ip, master_port
+            );
+            master.config_epoch = master.failover_epoch;
+            state.event("+promoted-slave", description);
+            set_failover_state(state, master, FailoverState::ReconfReplicas, now);
+        } else if failover_state == FailoverState::None
+            && !master_down
+            && now - replica.role_reported_time > INFO_PERIOD * 2
+            && now - replica.last_fix > INFO_PERIOD
+        {
+            // an old master that came back, or a replica promoted by
+            // someone else: turn it into a replica again
+            replica.last_fix = now;
+            replica.replicaof = Some(Some((master_ip.clone(), master_port)));
+            let description = format!(
+                "slave {} {} {} @ {} {} {}",
+                addr, replica.ip, replica.port, master.name, master_ip, master_port
+            );
+            state.event("+convert-to-slave", description);
+        }
+    } else if role == Role::Replica {
+        let replicates = |host: &str, port: u16| {
+            replica.master_host.as_deref() == Some(host) && replica.master_port == port
+        };
+        if failover_state == FailoverState::ReconfReplicas {
+            let promoted = match &promoted {
+                Some(promoted) => parse_addr(promoted),
+                None => return,
+            };
+            if replicates(&promoted.0, promoted.1) {
+                let description = format!(
+                    "slave {} {} {} @ {} {} {}",
+                    addr, replica.ip, replica.port, master.name, master_ip, master_port
+                );
+                match replica.reconf {
+                    Reconf::Sent(_) if !replica.master_link_up => {
+                        replica.reconf = Reconf::InProgress(now);
+                        state.event("+slave-reconf-inprog", description);
+                    }
+                    Reconf::Sent(_) | Reconf::InProgress(_) if replica.master_link_up => {
+                        replica.reconf = Reconf::Done;
+                        state.event("+slave-reconf-done", description);
+                    }
+                    _ => (),
+                }
+            }
+        } else if failover_state == FailoverState::None
+            && !master_down
+            && !replicates(&master_ip, master_port)
+            && now - replica.last_fix > INFO_PERIOD
+        {
+            replica.last_fix = now;
+            replica.replicaof = Some(Some((master_ip.clone(), master_port)));
+            let description = format!(
+                "slave {} {} {} @ {} {} {}",
+                addr, replica.ip, replica.port, master.name, master_ip, master_port
+            );
+            state.event("+fix-slave-config", description);
+        }
+    }
+}
+
+/// Splits an `ip:port` address built by the sentinel.
+fn parse_addr(addr: &str) -> (String, u16) {
+    let pos = addr.rfind(':').unwrap();
+    (addr[..pos].to_owned(), addr[pos + 1..].parse().unwrap())
+}
+
+fn master_cron(state: &mut State, master: &mut Master, now: i64, requests: &mut Vec<Request>) {
+    let fast_info = master.o_down_since.is_some() || master.failover_state != FailoverState::None;
+    let info_period = if fast_info {
+        FAST_INFO_PERIOD
+    } else {
+        INFO_PERIOD
+    };
+
+    // the master and its replicas
+    let mut targets = vec![(Target::Master, master.instance.addr())];
+    targets.extend(
+        master
+            .replicas
+            .keys()
+            .map(|addr| (Target::Replica, addr.clone())),
+    );
+    for (target, addr) in targets {
+        let hello = state.hello(master);
+        let down_after = master.down_after;
+        let name = master.name.clone();
+        let auth = master.auth();
+        let instance = master.instance_mut(&target, &addr).unwrap();
+        let (ip, port) = (instance.ip.clone(), instance.port);
+        let request = |kind, command: Vec<&[u8]>| Request {
+            master: name.clone(),
+            target: target.clone(),
+            ip: ip.clone(),
+            port,
+            auth: auth.clone(),
+            command: command.into_iter().map(|arg| arg.to_vec()).collect(),
+            kind,
+        };
+        let mut new_requests = vec![];
+        if !instance.link.ping_in_flight
+            && now - instance.link.last_ping >= PING_PERIOD.min(down_after)
+        {
+            new_requests.push(request(RequestKind::Ping, vec![b"PING"]));
+        }
+        if !instance.link.info_in_flight && now - instance.link.last_info >= info_period {
+            new_requests.push(request(RequestKind::Info, vec![b"INFO"]));
+        }
+        if !instance.link.subscribed {
+            new_requests.push(request(
+                RequestKind::Subscribe,
+                vec![b"SUBSCRIBE", HELLO_CHANNEL.as_bytes()],
+            ));
+        }
+        if let Some(hello) = hello {
+            if now - instance.link.last_hello >= HELLO_PERIOD {
+                new_requests.push(request(
+                    RequestKind::Hello,
+                    vec![b"PUBLISH", HELLO_CHANNEL.as_bytes(), hello.as_bytes()],
+                ));
+            }
+        }
+        if let Some(replicaof) = instance.replicaof.take() {
+            let command = match &replicaof {
+                Some((ip, port)) => {
+                    let port = format!("{}", port);
+                    request(
+                        RequestKind::Replicaof,
+                        vec![b"REPLICAOF", ip.as_bytes(), port.as_bytes()],
+                    )
+                }
+                None => request(RequestKind::Replicaof, vec![b"REPLICAOF", b"NO", b"ONE"]),
+            };
+            new_requests.push(command);
+        }
+        for request in new_requests.iter() {
+            match request.kind {
+                RequestKind::Ping => {
+                    instance.link.ping_in_flight = true;
+                    instance.link.last_ping = now;
+                    if instance.link.ping_sent == 0 {
+                        instance.link.ping_sent = now;
+                    }
+                }
+                RequestKind::Info => {
+                    instance.link.info_in_flight = true;
+                    instance.link.last_info = now;
+                }
+                RequestKind::Subscribe => instance.link.subscribed = true,
+                RequestKind::Hello => instance.link.last_hello = now,
+                _ => (),
+            }
+        }
+        requests.extend(new_requests);
+    }
+
+    // the other sentinels
+    let ids = master.sentinels.keys().cloned().collect::<Vec<_>>();
+    for id in ids {
+        let ask = master.instance.s_down_since.is_some();
+        let runid = if master.failover_state == FailoverState::None {
+            "*".to_owned()
+        } else {
+            state.myid.clone()
+        };
+        let ask_command = vec![
+            b"SENTINEL".to_vec(),
+            b"is-master-down-by-addr".to_vec(),
+            master.instance.ip.as_bytes().to_vec(),
+            format!("{}", master.instance.port).into_bytes(),
+            format!("{}", state.current_epoch).into_bytes(),
+            runid.into_bytes(),
+        ];
+        let ping = master.sentinel_request(&id, RequestKind::Ping, vec![b"PING".to_vec()]);
+        let ask_request = master.sentinel_request(&id, RequestKind::AskMasterDown, ask_command);
+        let down_after = master.down_after;
+        let sentinel = master.sentinels.get_mut(&id).unwrap();
+        if !sentinel.link.ping_in_flight
+            && now - sentinel.link.last_ping >= PING_PERIOD.min(down_after)
+        {
+            sentinel.link.ping_in_flight = true;
+            sentinel.link.last_ping = now;
+            if sentinel.link.ping_sent == 0 {
+                sentinel.link.ping_sent = now;
+            }
+            requests.push(ping);
+        }
+        // old replies are not valid any more
+        if now - sentinel.last_master_down_reply > ASK_PERIOD * 5 {
+            sentinel.master_down = false;
+            sentinel.leader = None;
+        }
+        if ask && !sentinel.link.ask_in_flight && now - sentinel.link.last_ask >= ASK_PERIOD {
+            sentinel.link.ask_in_flight = true;
+            sentinel.link.last_ask = now;
+            requests.push(ask_request);
+        }
+    }
+
+    check_subjectively_down(state, master, now);
+    check_objectively_down(state, master, now);
+    if master.failover_state == FailoverState::None
+        && master.o_down_since.is_some()
+        && now - master.failover_start_time >= master.failover_timeout * 2
+    {
+        start_failover(state, master, now);
+    }
+    failover_cron(state, master, now, requests);
+}
+
+fn check_subjectively_down(state: &mut State, master: &mut Master, now: i64) {
+    let down_after = master.down_after;
+    let is_down = |instance: &Instance| {
+        instance.link.ping_sent != 0 && now - instance.link.ping_sent > down_after
+    };
+    let mut events = vec![];
+    if is_down(&master.instance) {
+        if master.instance.s_down_since.is_none() {
+            master.instance.s_down_since = Some(now);
+            events.push(("+sdown", master.event_id()));
+        }
+    } else if master.instance.s_down_since.take().is_some() {
+        events.push(("-sdown", master.event_id()));
+    }
+    for kind in &["slave", "sentinel"] {
+        let ids = if *kind == "slave" {
+            master.replicas.keys().cloned().collect::<Vec<_>>()
+        } else {
+            master.sentinels.keys().cloned().collect::<Vec<_>>()
+        };
+        for id in ids {
+            let instance = if *kind == "slave" {
+                &master.replicas[&id]
+            } else {
+                &master.sentinels[&id]
+            };
+            let description = master.instance_event_id(kind, instance);
+            let down = is_down(instance);
+            let instance = if *kind == "slave" {
+                master.replicas.get_mut(&id).unwrap()
+            } else {
+                master.sentinels.get_mut(&id).unwrap()
+            };
+            if down {
+                if instance.s_down_since.is_none() {
+                    instance.s_down_since = Some(now);
+                    events.push(("+sdown", description));
+                }
+            } else if instance.s_down_since.take().is_some() {
+                events.push(("-sdown", description));
+            }
+        }
+    }
+    for (kind, description) in events {
+        state.event(kind, description);
+    }
+}
+
+fn check_objectively_down(state: &mut State, master: &mut Master, now: i64) {
+    let mut votes = 0;
+    if master.instance.s_down_since.is_some() {
+        votes = 1 + master.sentinels.values().filter(|s| s.master_down).count();
+    }
+    if votes > 0 && votes >= master.quorum {
+        if master.o_down_since.is_none() {
+            master.o_down_since = Some(now);
+            let description = format!("{} #quorum {}/{}", master.event_id(), votes, master.quorum);
+            state.event("+odown", description);
+        }
+    } else if master.o_down_since.take().is_some() {
+        state.event("-odown", master.event_id());
+    }
+}
+
+fn start_failover(state: &mut State, master: &mut Master, now: i64) {
+    if !master.force_failover {
+        let epoch = state.current_epoch + 1;
+        state.set_current_epoch(epoch);
+    }
+    master.failover_state = FailoverState::WaitStart;
+    master.failover_epoch = state.current_epoch;
+    master.failover_start_time = now + random::<i64>().abs() % MAX_DESYNC;
+    master.failover_state_change_time = now;
+    let description = master.event_id();
+    state.event("+try-failover", description);
+}
+
+fn abort_failover(state: &mut State, master: &mut Master, reason: &str, now: i64) {
+    state.event(reason, master.event_id());
+    master.failover_state = FailoverState::None;
+    master.failover_state_change_time = now;
+    master.force_failover = false;
+    master.promoted = None;
+    for replica in master.replicas.values_mut() {
+        replica.reconf = Reconf::None;
+    }
+}
+
+fn set_failover_state(
+    state: &mut State,
+    master: &mut Master,
+    failover_state: FailoverState,
+    now: i64,
+) {
+    master.failover_state = failover_state;
+    master.failover_state_change_time = now;
+    let kind = format!(
+        "+failover-state-{}",
+        failover_state.name().replace('_', "-")
+    );
+    state.event(&kind, master.event_id());
+}
+
+/// The sentinel that most other sentinels voted to lead the failover in
+/// `epoch`, if it has enough votes. This sentinel votes too.
+fn get_leader(state: &mut State, master: &mut Master, epoch: u64, now: i64) -> Option<String> {
+    let voters = master.sentinels.len() + 1;
+    let mut votes: HashMap<String, usize> = HashMap::new();
+    for sentinel in master.sentinels.values() {
+        if let Some(leader) = &sentinel.leader {
+            if sentinel.leader_epoch == state.current_epoch {
+                *votes.entry(leader.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+    let winner = |votes: &HashMap<String, usize>| {
+        votes
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(id, count)| (id.clone(), *count))
+    };
+    let candidate = winner(&votes).map_or_else(|| state.myid.clone(), |(id, _)| id);
+    let (myvote, myvote_epoch) = state.vote_leader(master, epoch, &candidate, now);
+    if let Some(myvote) = myvote {
+        if myvote_epoch == epoch {
+            *votes.entry(myvote).or_insert(0) += 1;
+        }
+    }
+    match winner(&votes) {
+        Some((id, count)) if count > voters / 2 && count >= master.quorum => Some(id),
+        _ => None,
+    }
+}
+
+fn failover_cron(state: &mut State, master: &mut Master, now: i64, requests: &mut Vec<Request>) {
+    let elapsed = now - master.failover_state_change_time;
+    match master.failover_state {
+        FailoverState::None => (),
+        FailoverState::WaitStart => {
+            if now < master.failover_start_time {
+                return;
+            }
+            let epoch = master.failover_epoch;
+            let leader = if master.force_failover {
+                Some(state.myid.clone())
+            } else {
+                get_leader(state, master, epoch, now)
+            };
+            if leader.as_ref() == Some(&state.myid) {
+                let description = format!("{} {}", master.event_id(), epoch);
+                state.event("+elected-leader", description);
+                set_failover_state(state, master, FailoverState::SelectReplica, now);
+            } else if elapsed > master.failover_timeout.min(ELECTION_TIMEOUT) {
+                abort_failover(state, master, "-failover-abort-not-elected", now);
+            }
+        }
+        FailoverState::SelectReplica => match master.select_replica(now) {
+            Some(addr) => {
+                let description = master.instance_event_id("slave", &master.replicas[&addr]);
+                state.event("+selected-slave", description);
+                master.promoted = Some(addr);
+                // the event is sent with the replica once it is reachable
+                master.failover_state = FailoverState::SendReplicaofNoOne;
+                master.failover_state_change_time = now;
+            }
+            None => abort_failover(state, master, "-failover-abort-no-good-slave", now),
+        },
+        FailoverState::SendReplicaofNoOne => {
+            let addr = master.promoted.clone().unwrap();
+            let replica = &master.replicas[&addr];
+            if !replica.link_ok(now) {
+                if elapsed > master.failover_timeout {
+                    abort_failover(state, master, "-failover-abort-slave-timeout", now);
+                }
+                return;
+            }
+            let command = vec![b"REPLICAOF".to_vec(), b"NO".to_vec(), b"ONE".to_vec()];
+            requests.push(master.replica_request(replica, RequestKind::Replicaof, command));
+            let description = master.instance_event_id("slave", replica);
+            state.event("+failover-state-send-slaveof-noone", description);
+            set_failover_state(state, master, FailoverState::WaitPromotion, now);
+        }
+        FailoverState::WaitPromotion => {
+            if elapsed > master.failover_timeout {
+                abort_failover(state, master, "-failover-abort-slave-timeout", now);
+            }
+        }
+        FailoverState::ReconfReplicas => reconf_replicas(state, master, now, requests),
+        FailoverState::UpdateConfig => {
+            let (ip, port) = parse_addr(master.promoted.as_ref().unwrap());
+            switch_master(state, master, &ip, port, now);
+        }
+    }
+}
+
+/// Points the replicas to the promoted one, a few at a time.
+fn reconf_replicas(state: &mut State, master: &mut Master, now: i64, requests: &mut Vec<Request>) {
+    let promoted = master.promoted.clone().unwrap();
+    let (ip, port) = parse_addr(&promoted);
+    let mut events = vec![];
+    let mut in_progress = 0;
+    for replica in master.replicas.values_mut() {
+        match replica.reconf {
+            Reconf::Sent(sent) | Reconf::InProgress(sent) => {
+                if now - sent > REPLICA_RECONF_TIMEOUT {
+                    replica.reconf = Reconf::Done;
+                    events.push(("-slave-reconf-sent-timeout", replica.addr()));
+                } else {
+                    in_progress += 1;
+                }
+            }
+            Reconf::None | Reconf::Done => (),
+        }
+    }
+    let addrs = master.replicas.keys().cloned().collect::<Vec<_>>();
+    for addr in addrs {
+        if in_progress >= master.parallel_syncs {
+            break;
+        }
+        let replica = &master.replicas[&addr];
+        if addr == promoted || replica.reconf != Reconf::None || replica.s_down_since.is_some() {
+            continue;
+        }
+        let command = vec![
+            b"REPLICAOF".to_vec(),
+            ip.as_bytes().to_vec(),
+            format!("{}", port).into_bytes(),
+        ];
+        requests.push(master.replica_request(replica, RequestKind::Replicaof, command));
+        events.push(("+slave-reconf-sent", addr.clone()));
+        master.replicas.get_mut(&addr).unwrap().reconf = Reconf::Sent(now);
+        in_progress += 1;
+    }
+    for (kind, addr) in events {
+        let description = master.instance_event_id("slave", &master.replicas[&addr]);
+        state.event(kind, description);
+    }
+
+    let done = master.replicas.iter().all(|(addr, replica)| {
+        *addr == promoted || replica.reconf == Reconf::Done || replica.s_down_since.is_some()
+    });
+    let timeout = now - master.failover_state_change_time > master.failover_timeout;
+    if done || timeout {
+        if timeout {
+            state.event("+failover-end-for-timeout", master.event_id());
+        }
+        state.event("+failover-end", master.event_id());
+        set_failover_state(state, master, FailoverState::UpdateConfig, now);
+    }
+}
+
+/// Changes the address of the master. The previous master, and its other
+/// replicas, become replicas of the new one.
+fn switch_master(state: &mut State, master: &mut Master, ip: &str, port: u16, now: i64) {
+    let description = format!(
+        "{} {} {} {} {}",
+        master.name, master.instance.ip, master.instance.port, ip, port
+    );
+    state.event("+switch-master", description);
+
+    let new_addr = sentinel_addr(ip, port);
+    let mut addrs = master
+        .replicas
+        .keys()
+        .filter(|addr| **addr != new_addr)
+        .cloned()
+        .collect::<Vec<_>>();
+    if master.instance.addr() != new_addr {
+        addrs.push(master.instance.addr());
+    }
+    let mut instance = Instance::new(ip, port, now);
+    instance.role_reported = Role::Master;
+    master.instance = instance;
+    let replicas = addrs
+        .into_iter()
+        .map(|addr| {
+            let (ip, port) = parse_addr(&addr);
+            let mut replica = Instance::new(&ip, port, now);
+            // replicas that are still replicas keep their subscription
+            replica.link.subscribed = master
+                .replicas
+                .get(&addr)
+                .is_some_and(|r| r.link.subscribed);
+            (addr, replica)
+        })
+        .collect();
+    master.replicas = replicas;
+    for sentinel in master.sentinels.values_mut() {
+        sentinel.master_down = false;
+        sentinel.leader = None;
+    }
+    master.o_down_since = None;
+    master.failover_state = FailoverState::None;
+    master.failover_state_change_time = now;
+    master.force_failover = false;
+    master.promoted = None;
+    master.leader = None;
+    for replica in master.replicas.values() {
+        let description = master.instance_event_id("slave", replica);
+        state.event("+slave", description);
+    }
+}
+
+#[cfg(test)]
+mod test_sentinel {
+    use response::Response;
+
+    use super::{FailoverState, Request, RequestKind, Sentinel, Target};
+
+    /// Milliseconds when the tests start, so the first periodic checks are due
+    const START: i64 = 1000000;
+
+    fn requests(requests: &[Request], kind: RequestKind) -> Vec<&Request> {
+        requests.iter().filter(|r| r.kind == kind).collect()
+    }
+
+    fn reply(
+        sentinel: &mut Sentinel,
+        requests: &[Request],
+        kind: RequestKind,
+        addr: &str,
+        reply: Option<Response>,
+        now: i64,
+    ) {
+        // there is at most one request of each kind to an instance
+        if let Some(request) = requests.iter().find(|r| r.kind == kind && r.addr() == addr) {
+            sentinel.process_reply(request, reply, Some("127.0.0.1".to_owned()), now);
+        }
+    }
+
+    fn master_info() -> Response {
+        Response::Data(
+            b"# Replication\r\nrole:master\r\nconnected_slaves:2\r\n\
+              slave0:ip=127.0.0.1,port=6380,state=online,offset=10,lag=0\r\n\
+              slave1:ip=127.0.0.1,port=6381,state=online,offset=10,lag=0\r\n"
+                .to_vec(),
+        )
+    }
+
+    fn replica_info(runid: &str, offset: u64) -> Response {
+        Response::Data(
+            format!(
+                "run_id:{}\r\nrole:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:6379\r\n\
+                 master_link_status:up\r\nslave_priority:100\r\nslave_repl_offset:{}\r\n",
+                runid, offset
+            )
+            .into_bytes(),
+        )
+    }
+
+    #[test]
+    fn monitor_and_set() {
+        let mut sentinel = Sentinel::new(26379);
+        sentinel
+            .monitor("mymaster", "127.0.0.1", 6379, 2, 0)
+            .unwrap();
+        assert_eq!(
+            sentinel
+                .monitor("mymaster", "127.0.0.1", 6379, 2, 0)
+                .unwrap_err(),
+            "ERR Duplicated master name."
+        );
+        assert!(sentinel.monitor("other", "127.0.0.1", 6379, 0, 0).is_err());
+        sentinel
+            .set("mymaster", "down-after-milliseconds", "2000")
+            .unwrap();
+        assert!(sentinel.set("mymaster", "quorum", "0").is_err());
+        assert!(sentinel.set("mymaster", "unknown", "1").is_err());
+        assert_eq!(sentinel.master("mymaster").unwrap().down_after, 2000);
+        assert_eq!(
+            sentinel.get_master_addr("mymaster"),
+            Some(("127.0.0.1".to_owned(), 6379))
+        );
+        assert_eq!(
+            sentinel.take_events(),
+            vec![
+                (
+                    "+monitor".to_owned(),
+                    "master mymaster 127.0.0.1 6379 quorum 2".to_owned()
+                ),
+                (
+                    "+set".to_owned(),
+                    "master mymaster 127.0.0.1 6379 down-after-milliseconds 2000".to_owned()
+                ),
+            ]
+        );
+        sentinel.remove("mymaster").unwrap();
+        assert!(sentinel.remove("mymaster").is_err());
+    }
+
+    #[test]
+    fn discover_replicas_and_sentinels() {
+        let mut sentinel = Sentinel::new(26379);
+        sentinel
+            .monitor("mymaster", "127.0.0.1", 6379, 2, START)
+            .unwrap();
+        let sent = sentinel.cron(START);
+        assert_eq!(requests(&sent, RequestKind::Ping).len(), 1);
+        assert_eq!(requests(&sent, RequestKind::Subscribe).len(), 1);
+        // the local address is not known yet
+        assert_eq!(requests(&sent, RequestKind::Hello).len(), 0);
+        reply(
+            &mut sentinel,
+            &sent,
+            RequestKind::Info,
+            "127.0.0.1:6379",
+            Some(master_info()),
+            START + 10,
+        );
+        assert_eq!(sentinel.master("mymaster").unwrap().replicas.len(), 2);
+
+        let sent = sentinel.cron(START + 2000);
+        // hello messages go to the master and its replicas
+        let hello = requests(&sent, RequestKind::Hello);
+        assert_eq!(hello.len(), 3);
+        let message = String::from_utf8(hello[0].command[2].clone()).unwrap();
+        assert_eq!(
+            message,
+            format!(
+                "127.0.0.1,26379,{},0,mymaster,127.0.0.1,6379,0",
+                sentinel.myid()
+            )
+        );
+        // replicas are pinged and asked for INFO too
+        assert_eq!(requests(&sent, RequestKind::Info).len(), 2);
+
+        // our own hello is ignored
+        sentinel.process_hello(&message, START + 2000);
+        assert_eq!(sentinel.master("mymaster").unwrap().sentinels.len(), 0);
+        let other = "a".repeat(40);
+        sentinel.process_hello(
+            &format!("127.0.0.1,26380,{},3,mymaster,127.0.0.1,6379,0", other),
+            START + 2000,
+        );
+        assert_eq!(sentinel.master("mymaster").unwrap().sentinels.len(), 1);
+        assert_eq!(sentinel.current_epoch(), 3);
+        assert!(sentinel.ckquorum("mymaster", START + 2000).is_ok());
+        sentinel.set("mymaster", "quorum", "3").unwrap();
+        assert!(sentinel
+            .ckquorum("mymaster", START + 2000)
+            .unwrap_err()
+            .starts_with("NOQUORUM 2"));
+    }
+
+    #[test]
+    fn failover() {
+        let mut sentinel = Sentinel::new(26379);
+        sentinel
+            .monitor("mymaster", "127.0.0.1", 6379, 2, START)
+            .unwrap();
+        sentinel
+            .set("mymaster", "down-after-milliseconds", "1000")
+            .unwrap();
+        let other = "a".repeat(40);
+        sentinel.process_hello(
+            &format!("127.0.0.1,26380,{},0,mymaster,127.0.0.1,6379,0", other),
+            START,
+        );
+        let sent = sentinel.cron(START);
+        reply(
+            &mut sentinel,
+            &sent,
+            RequestKind::Info,
+            "127.0.0.1:6379",
+            Some(master_info()),
+            START,
+        );
+
+        let mut now = START;
+        let mut voted = false;
+        while now < START + 60000 {
+            now += 100;
+            let sent = sentinel.cron(now);
+            reply(
+                &mut sentinel,
+                &sent,
+                RequestKind::Ping,
+                "127.0.0.1:6379",
+                None,
+                now,
+            );
+            for addr in &["127.0.0.1:6380", "127.0.0.1:6381", "127.0.0.1:26380"] {
+                let pong = Some(Response::Status("PONG".to_owned()));
+                reply(&mut sentinel, &sent, RequestKind::Ping, addr, pong, now);
+            }
+            reply(
+                &mut sentinel,
+                &sent,
+                RequestKind::Info,
+                "127.0.0.1:6380",
+                Some(replica_info("b", 5)),
+                now,
+            );
+            reply(
+                &mut sentinel,
+                &sent,
+                RequestKind::Info,
+                "127.0.0.1:6381",
+                Some(replica_info("c", 10)),
+                now,
+            );
+            // the other sentinel agrees the master is down, and votes for us
+            for request in requests(&sent, RequestKind::AskMasterDown) {
+                assert_eq!(request.target, Target::Sentinel(other.clone()));
+                let runid = String::from_utf8(request.command[5].clone()).unwrap();
+                let epoch = String::from_utf8(request.command[4].clone()).unwrap();
+                let leader = if runid == "*" {
+                    b"*".to_vec()
+                } else {
+                    runid.into_bytes()
+                };
+                voted |= leader != b"*";
+                let response = Response::Array(vec![
+                    Response::Integer(1),
+                    Response::Data(leader),
+                    Response::Integer(epoch.parse().unwrap()),
+                ]);
+                sentinel.process_reply(request, Some(response), None, now);
+            }
+            for request in requests(&sent, RequestKind::Replicaof) {
+                if request.command[1] == b"NO" {
+                    // the replica with the most data is promoted
+                    assert_eq!(request.addr(), "127.0.0.1:6381");
+                    let info = Response::Data(b"run_id:c\r\nrole:master\r\n".to_vec());
+                    let info_request = Request {
+                        kind: RequestKind::Info,
+                        ..request.clone()
+                    };
+                    sentinel.process_reply(&info_request, Some(info), None, now + 1);
+                } else {
+                    assert_eq!(
+                        request.command,
+                        vec![
+                            b"REPLICAOF".to_vec(),
+                            b"127.0.0.1".to_vec(),
+                            b"6381".to_vec()
+                        ]
+                    );
+                }
+            }
+            if sentinel.master("mymaster").unwrap().instance.port == 6381 {
+                break;
+            }
+        }
+        assert!(voted);
+        let master = sentinel.master("mymaster").unwrap();
+        assert_eq!(master.instance.port, 6381);
+        assert_eq!(master.failover_state, FailoverState::None);
+        assert_eq!(master.config_epoch, 1);
+        assert!(master.replicas.contains_key("127.0.0.1:6379"));
+        assert!(master.replicas.contains_key("127.0.0.1:6380"));
+        let events = sentinel.take_events();
+        let kinds = events.iter().map(|e| &*e.0).collect::<Vec<_>>();
+        for kind in &[
+            "+sdown",
+            "+odown",
+            "+try-failover",
+            "+elected-leader",
+            "+selected-slave",
+            "+promoted-slave",
+            "+slave-reconf-sent",
+            "+failover-end",
+        ] {
+            assert!(kinds.contains(kind), "missing {}", kind);
+        }
+        assert!(events.contains(&(
+            "+switch-master".to_owned(),
+            "mymaster 127.0.0.1 6379 127.0.0.1 6381".to_owned()
+        )));
+    }
+
+    #[test]
+    fn vote() {
+        let mut sentinel = Sentinel::new(26379);
+        sentinel
+            .monitor("mymaster", "127.0.0.1", 6379, 2, 0)
+            .unwrap();
+        let (a, b) = ("a".repeat(40), "b".repeat(40));
+        assert_eq!(
+            sentinel.is_master_down_by_addr("127.0.0.1", 6379, 1, &a, 0),
+            (false, a.clone(), 1)
+        );
+        // only one vote per epoch
+        assert_eq!(
+            sentinel.is_master_down_by_addr("127.0.0.1", 6379, 1, &b, 0),
+            (false, a.clone(), 1)
+        );
+        assert_eq!(
+            sentinel.is_master_down_by_addr("127.0.0.1", 6379, 2, &b, 0),
+            (false, b.clone(), 2)
+        );
+        assert_eq!(sentinel.current_epoch(), 2);
+        assert_eq!(
+            sentinel.is_master_down_by_addr("127.0.0.1", 6380, 3, &b, 0),
+            (false, "*".to_owned(), 0)
+        );
+    }
+
+    #[test]
+    fn config_update_from_hello() {
+        let mut sentinel = Sentinel::new(26379);
+        sentinel
+            .monitor("mymaster", "127.0.0.1", 6379, 2, 0)
+            .unwrap();
+        let other = "a".repeat(40);
+        sentinel.process_hello(
+            &format!("127.0.0.1,26380,{},4,mymaster,127.0.0.1,6380,4", other),
+            0,
+        );
+        let master = sentinel.master("mymaster").unwrap();
+        assert_eq!(master.instance.port, 6380);
+        assert_eq!(master.config_epoch, 4);
+        assert!(master.replicas.contains_key("127.0.0.1:6379"));
+        // an older configuration is ignored
+        sentinel.process_hello(
+            &format!("127.0.0.1,26380,{},4,mymaster,127.0.0.1,6379,3", other),
+            0,
+        );
+        assert_eq!(sentinel.get_master_addr("mymaster").unwrap().1, 6380);
+    }
+}
@@ -1,19 +1,20 @@
 use std::collections::HashSet;
 use std::io;
 use std::io::Write;
+use std::mem::size_of;
 
-use dbutil::usize_to_vec;
-use dbutil::vec_to_usize;
+use dbutil::sampled_memory_usage;
+use intset::{member_to_vec, parse_member, IntSet};
 use rdbutil::constants::*;
-use rdbutil::{encode_len, encode_slice_u8, EncodeError};
-use rdbutil::{encode_u16_to_slice_u8, encode_u32_to_slice_u8, encode_u64_to_slice_u8};
+use rdbutil::{encode_len, encode_slice_u8};
+use rdbutil::{encode_u32_to_slice_u8, EncodeError};
 
 use rand::distributions::{IndependentSample, Range, Sample};
 use rand::thread_rng;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum ValueSet {
-    Integer(HashSet<usize>),
+    Integer(IntSet),
     Data(HashSet<Vec<u8>>),
 }
 
@@ -25,24 +26,25 @@ impl Default for ValueSet {
 
 impl ValueSet {
     pub fn new() -> ValueSet {
-        ValueSet::Integer(HashSet::new())
+        ValueSet::Integer(IntSet::new())
     }
 
-    pub fn create_with_hashset(h: HashSet<Vec<u8>>) -> ValueSet {
-        let mut s = HashSet::new();
+    /// Creates a set with the elements in `h`, using an intset if they are
+    /// all integers and there are no more than `max_intset_entries`.
+    pub fn create_with_hashset(h: HashSet<Vec<u8>>, max_intset_entries: usize) -> ValueSet {
+        if h.len() > max_intset_entries {
+            return ValueSet::Data(h);
+        }
+        let mut s = IntSet::new();
         for v in h.iter() {
-            match vec_to_usize(&v) {
-                Ok(n) => {
+            match parse_member(v) {
+                Some(n) => {
                     s.insert(n);
                 }
-                Err(_) => break,
+                None => return ValueSet::Data(h),
             }
         }
-        if h.len() == s.len() {
-            ValueSet::Integer(s)
-        } else {
-            ValueSet::Data(h)
-        }
+        ValueSet::Integer(s)
     }
 
     pub fn is_intset(&self) -> bool {
@@ -53,23 +55,21 @@ impl ValueSet {
     }
 
     fn make_data(&mut self) {
-        let mut h = HashSet::new();
-        match self {
-            ValueSet::Integer(set) => {
-                for i in set.iter() {
-                    h.insert(usize_to_vec(*i));
-                }
-            }
+        let h = match self {
+            ValueSet::Integer(set) => set.iter().map(member_to_vec).collect(),
             ValueSet::Data(_) => return,
-        }
+        };
         *self = ValueSet::Data(h);
     }
 
     pub fn sadd(&mut self, el: Vec<u8>, max_int_size: usize) -> bool {
         match self {
             ValueSet::Integer(set) => {
-                if set.len() < max_int_size {
-                    if let Ok(v) = vec_to_usize(&el) {
+                if let Some(v) = parse_member(&el) {
+                    if set.contains(v) {
+                        return false;
+                    }
+                    if set.len() < max_int_size {
                         return set.insert(v);
                     }
                 }
@@ -86,9 +86,9 @@ impl ValueSet {
         match self {
             ValueSet::Data(set) => set.remove(el),
             ValueSet::Integer(set) => {
-                match vec_to_usize(&el) {
-                    Ok(v) => set.remove(&v),
-                    Err(_) => false, // only have usize, removing not a usize
+                match parse_member(el) {
+                    Some(v) => set.remove(v),
+                    None => false, // only have integers, removing not an integer
                 }
             }
         }
@@ -98,14 +98,22 @@ impl ValueSet {
         match self {
             ValueSet::Data(set) => set.contains(el),
             ValueSet::Integer(set) => {
-                match vec_to_usize(&el) {
-                    Ok(v) => set.contains(&v),
-                    Err(_) => false, // only have usize, removing not a usize
+                match parse_member(el) {
+                    Some(v) => set.contains(v),
+                    None => false, // only have integers, looking for not an integer
                 }
             }
         }
     }
 
+    /// Like `sismember` but avoids formatting the value for intsets.
+    fn contains_integer(&self, v: i64) -> bool {
+        match self {
+            ValueSet::Data(set) => set.contains(&member_to_vec(v)),
+            ValueSet::Integer(set) => set.contains(v),
+        }
+    }
+
     pub fn scard(&self) -> usize {
         match self {
             ValueSet::Data(set) => set.len(),
@@ -116,7 +124,7 @@ impl ValueSet {
     pub fn smembers(&self) -> Vec<Vec<u8>> {
         match self {
             ValueSet::Data(set) => set.iter().cloned().collect::<Vec<_>>(),
-            ValueSet::Integer(set) => set.iter().copied().map(usize_to_vec).collect::<Vec<_>>(),
+            ValueSet::Integer(set) => set.iter().map(member_to_vec).collect::<Vec<_>>(),
         }
     }
 
@@ -159,16 +167,14 @@ impl ValueSet {
 
     fn srandmember_integer(
         &self,
-        set: &HashSet<usize>,
+        set: &IntSet,
         count: usize,
         allow_duplicates: bool,
     ) -> Vec<Vec<u8>> {
-        // TODO: implemented in O(n), should be O(1)
-        let mut r = Vec::new();
-        for pos in self.get_random_positions(set.len(), count, allow_duplicates) {
-            r.push(usize_to_vec(*set.iter().skip(pos).take(1).next().unwrap()));
-        }
-        r
+        self.get_random_positions(set.len(), count, allow_duplicates)
+            .into_iter()
+            .map(|pos| member_to_vec(set.get(pos)))
+            .collect()
     }
 
     pub fn srandmember(&self, count: usize, allow_duplicates: bool) -> Vec<Vec<u8>> {
@@ -179,19 +185,24 @@ impl ValueSet {
     }
 
     pub fn spop(&mut self, count: usize) -> Vec<Vec<u8>> {
-        // TODO: implemented in O(n), should be O(1)
-
         let len = self.scard();
         if count >= len {
             return match self {
                 ValueSet::Data(set) => set.drain().collect::<Vec<_>>(),
-                ValueSet::Integer(set) => set.drain().map(usize_to_vec).collect::<Vec<_>>(),
+                ValueSet::Integer(set) => {
+                    let r = set.iter().map(member_to_vec).collect::<Vec<_>>();
+                    *set = IntSet::new();
+                    r
+                }
             };
         }
 
+        // positions are sorted in reverse order, so removing an element does
+        // not shift the ones still to be popped
         let positions = self.get_random_positions(self.scard(), count, false);
         match self {
             ValueSet::Data(set) => {
+                // TODO: implemented in O(n), should be O(1)
                 let mut r = Vec::new();
                 for pos in positions {
                     let el = set.iter().skip(pos).take(1).next().unwrap().clone();
@@ -203,9 +214,9 @@ impl ValueSet {
             ValueSet::Integer(set) => {
                 let mut r = Vec::new();
                 for pos in positions {
-                    let el = *set.iter().skip(pos).take(1).next().unwrap();
-                    set.remove(&el);
-                    r.push(usize_to_vec(el));
+                    let el = set.get(pos);
+                    set.remove(el);
+                    r.push(member_to_vec(el));
                 }
                 r
             }
@@ -214,130 +225,72 @@ impl ValueSet {
 
     pub fn sdiff(&self, sets: Vec<&ValueSet>) -> HashSet<Vec<u8>> {
         match self {
-            ValueSet::Data(original_set) => {
-                let mut elements: HashSet<Vec<u8>> = original_set.clone();
-                for newvalue in sets {
-                    match newvalue {
-                        ValueSet::Integer(set) => {
-                            for el in set {
-                                elements.remove(&usize_to_vec(*el));
-                            }
-                        }
-                        ValueSet::Data(set) => {
-                            for el in set {
-                                elements.remove(el);
-                            }
-                        }
-                    }
-                }
-                elements
-            }
-            ValueSet::Integer(original_set) => {
-                let mut elements: HashSet<usize> = original_set.clone();
-                for newvalue in sets {
-                    match newvalue {
-                        ValueSet::Integer(set) => {
-                            for el in set.iter() {
-                                elements.remove(&el);
-                            }
-                        }
-                        ValueSet::Data(set) => {
-                            for el in set {
-                                match vec_to_usize(el) {
-                                    Ok(i) => elements.remove(&i),
-                                    Err(_) => false,
-                                };
-                            }
-                        }
-                    }
-                }
-                elements.into_iter().map(usize_to_vec).collect()
-            }
+            ValueSet::Data(original_set) => original_set
+                .iter()
+                .filter(|el| !sets.iter().any(|set| set.sismember(el)))
+                .cloned()
+                .collect(),
+            ValueSet::Integer(original_set) => original_set
+                .iter()
+                .filter(|el| !sets.iter().any(|set| set.contains_integer(*el)))
+                .map(member_to_vec)
+                .collect(),
         }
     }
 
-    pub fn sinter(&self, sets: Vec<&ValueSet>) -> HashSet<Vec<u8>> {
-        match self {
-            ValueSet::Data(original_set) => {
-                let mut result: HashSet<Vec<u8>> = original_set.clone();
-                for newvalue in sets {
-                    match newvalue {
-                        ValueSet::Integer(set) => {
-                            result = result
-                                .intersection(
-                                    &set.iter()
-                                        .copied()
-                                        .map(usize_to_vec)
-                                        .collect::<HashSet<_>>(),
-                                )
-                                .cloned()
-                                .collect();
-                        }
-                        ValueSet::Data(set) => {
-                            result = result.intersection(set).cloned().collect();
+    /// Intersects `self` with `sets`, stopping once `limit` elements were
+    /// found. A `limit` of zero means no limit.
+    /// The smallest set is iterated and its elements are looked up in the
+    /// others, so an empty set finishes the operation right away.
+    fn intersect<'a>(&'a self, mut sets: Vec<&'a ValueSet>, limit: usize) -> HashSet<Vec<u8>> {
+        sets.push(self);
+        sets.sort_by_key(|set| set.scard());
+        let (smallest, others) = sets.split_first().unwrap();
+        let mut result = HashSet::new();
+        match smallest {
+            ValueSet::Data(set) => {
+                for el in set.iter() {
+                    if others.iter().all(|other| other.sismember(el)) {
+                        result.insert(el.clone());
+                        if result.len() == limit {
+                            break;
                         }
                     }
-                    if result.is_empty() {
-                        break;
-                    }
                 }
-                result
             }
-            ValueSet::Integer(original_set) => {
-                let mut result: HashSet<usize> =
-                    original_set.iter().cloned().collect::<HashSet<_>>();
-                for newvalue in sets {
-                    match newvalue {
-                        ValueSet::Integer(set) => {
-                            result = result
-                                .intersection(&set.iter().cloned().collect::<HashSet<_>>())
-                                .cloned()
-                                .collect();
-                        }
-                        ValueSet::Data(set) => {
-                            result = result
-                                .intersection(
-                                    &set.iter()
-                                        .filter_map(|x| vec_to_usize(x).ok())
-                                        .collect::<HashSet<_>>(),
-                                )
-                                .cloned()
-                                .collect();
+            ValueSet::Integer(set) => {
+                for el in set.iter() {
+                    if others.iter().all(|other| other.contains_integer(el)) {
+                        result.insert(member_to_vec(el));
+                        if result.len() == limit {
+                            break;
                         }
                     }
-                    if result.is_empty() {
-                        break;
-                    }
                 }
-                result.into_iter().map(usize_to_vec).collect()
             }
         }
+        result
+    }
+
+    pub fn sinter(&self, sets: Vec<&ValueSet>) -> HashSet<Vec<u8>> {
+        self.intersect(sets, 0)
+    }
+
+    /// Returns the number of elements in the intersection of `self` and
+    /// `sets`, counting up to `limit` (zero means no limit).
+    pub fn sintercard(&self, sets: Vec<&ValueSet>, limit: usize) -> usize {
+        self.intersect(sets, limit).len()
     }
 
     pub fn sunion(&self, sets: Vec<&ValueSet>) -> HashSet<Vec<u8>> {
-        let mut result: HashSet<Vec<u8>> = match self {
-            ValueSet::Data(original_set) => original_set.clone(),
-            ValueSet::Integer(set) => set
-                .iter()
-                .copied()
-                .map(usize_to_vec)
-                .collect::<HashSet<_>>(),
-        };
+        let mut result: HashSet<Vec<u8>> = self.smembers().into_iter().collect();
         for newvalue in sets {
             match newvalue {
                 ValueSet::Integer(set) => {
-                    result = result
-                        .union(
-                            &set.iter()
-                                .copied()
-                                .map(usize_to_vec)
-                                .collect::<HashSet<_>>(),
-                        )
-                        .cloned()
-                        .collect();
+                    result.extend(set.iter().map(member_to_vec));
                 }
                 ValueSet::Data(set) => {
-                    result = result.union(set).cloned().collect();
+                    result.extend(set.iter().cloned());
                 }
             }
         }
@@ -350,28 +303,9 @@ impl ValueSet {
         match self {
             ValueSet::Integer(set) => {
                 settype = TYPE_SET_INTSET;
-                let max = *set.iter().max().unwrap();
-                let encoding = if max <= 0xff {
-                    2
-                } else if max <= 0xffff {
-                    4
-                } else if max <= 0xffffffff {
-                    8
-                } else {
-                    panic!("Set element is too large")
-                };
-
                 let mut tmp = vec![];
-                encode_u32_to_slice_u8(encoding, &mut tmp).unwrap();
-                encode_u32_to_slice_u8(set.len() as u32, &mut tmp).unwrap();
-                for item in set.iter() {
-                    let r = match encoding {
-                        2 => encode_u16_to_slice_u8(*item as u16, &mut tmp),
-                        4 => encode_u32_to_slice_u8(*item as u32, &mut tmp),
-                        8 => encode_u64_to_slice_u8(*item as u64, &mut tmp),
-                        _ => panic!("Unexpected encoding {}", encoding),
-                    };
-                    match r {
+                for n in &[set.encoding() as u32, set.len() as u32] {
+                    match encode_u32_to_slice_u8(*n, &mut tmp) {
                         Ok(_) => (),
                         Err(err) => match err {
                             EncodeError::IOError(err) => return Err(err),
@@ -379,6 +313,7 @@ impl ValueSet {
                         },
                     }
                 }
+                tmp.extend_from_slice(set.contents());
                 encode_len(tmp.len(), &mut v).unwrap();
                 v.extend(tmp);
             }
@@ -400,17 +335,36 @@ impl ValueSet {
         writer.write(&*data)
     }
 
+    /// Internal representation, as reported by `OBJECT ENCODING`.
+    pub fn encoding(&self) -> &'static str {
+        match *self {
+            ValueSet::Integer(_) => "intset",
+            ValueSet::Data(_) => "hashtable",
+        }
+    }
+
+    /// Estimated bytes allocated by the set, besides the enum itself.
+    /// Only `samples` elements are measured, or all of them if it is zero.
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match *self {
+            ValueSet::Integer(ref set) => set.contents().len(),
+            ValueSet::Data(ref set) => {
+                // the table reserves its whole capacity, plus a control byte
+                // for each bucket
+                let table = set.capacity() * (size_of::<Vec<u8>>() + 1);
+                table + sampled_memory_usage(set.iter().map(|el| el.capacity()), set.len(), samples)
+            }
+        }
+    }
+
     pub fn debug_object(&self) -> String {
         let mut serialized_data = vec![];
         let serialized = self.dump(&mut serialized_data).unwrap();
-        let encoding = match *self {
-            ValueSet::Integer(_) => "intset",
-            ValueSet::Data(_) => "hashtable",
-        };
         format!(
             "Value at:0x0000000000 refcount:1 encoding:{} serializedlength:{} lru:0 \
              lru_seconds_idle:0",
-            encoding, serialized
+            self.encoding(),
+            serialized
         )
     }
 }
@@ -635,7 +589,76 @@ mod test_set {
 
     #[test]
     fn create_numeric() {
-        let s = ValueSet::create_with_hashset(HashSet::from_iter(vec![b"319".to_vec()]));
+        let s = ValueSet::create_with_hashset(HashSet::from_iter(vec![b"319".to_vec()]), 512);
+        assert!(s.is_intset());
+        let s = ValueSet::create_with_hashset(HashSet::from_iter(vec![b"-319".to_vec()]), 512);
         assert!(s.is_intset());
+        let s = ValueSet::create_with_hashset(
+            HashSet::from_iter(vec![b"1".to_vec(), b"2".to_vec()]),
+            1,
+        );
+        assert!(!s.is_intset());
+        let s = ValueSet::create_with_hashset(HashSet::from_iter(vec![b"007".to_vec()]), 512);
+        assert!(!s.is_intset());
+    }
+
+    #[test]
+    fn intset_negative() {
+        let mut set = ValueSet::new();
+        assert!(set.sadd(b"-5".to_vec(), 100));
+        assert!(set.sadd(b"-70000".to_vec(), 100));
+        assert!(set.sadd(b"3".to_vec(), 100));
+        assert!(set.is_intset());
+        assert!(set.sismember(b"-70000"));
+        assert!(!set.sismember(b"-7"));
+        assert_eq!(
+            set.smembers(),
+            vec![b"-70000".to_vec(), b"-5".to_vec(), b"3".to_vec()]
+        );
+        assert!(set.sadd(b"-0".to_vec(), 100));
+        assert!(!set.is_intset());
+        assert!(set.sismember(b"-5"));
+    }
+
+    #[test]
+    fn intset_max_entries() {
+        let mut set = ValueSet::new();
+        assert!(set.sadd(b"1".to_vec(), 2));
+        assert!(set.sadd(b"2".to_vec(), 2));
+        assert!(!set.sadd(b"2".to_vec(), 2));
+        assert!(set.is_intset());
+        assert!(set.sadd(b"3".to_vec(), 2));
+        assert!(!set.is_intset());
+        assert_eq!(set.scard(), 3);
+    }
+
+    #[test]
+    fn sintercard() {
+        let mut set = ValueSet::new();
+        let mut set2 = ValueSet::new();
+        for i in 0..10 {
+            set.sadd(format!("{}", i).into_bytes(), 100);
+            set2.sadd(format!("{}", i * 2).into_bytes(), 100);
+        }
+        set2.sadd(b"foo".to_vec(), 100);
+        assert_eq!(set.sintercard(vec![&set2], 0), 5);
+        assert_eq!(set.sintercard(vec![&set2], 3), 3);
+        assert_eq!(set.sintercard(vec![&set2, &ValueSet::new()], 0), 0);
+    }
+
+    #[test]
+    fn memory_usage() {
+        let mut set = ValueSet::new();
+        for i in 0..10 {
+            set.sadd(format!("{}", i).into_bytes(), 100);
+        }
+        assert_eq!(set.encoding(), "intset");
+        assert_eq!(set.memory_usage(0), 20);
+        set.sadd(b"foo".to_vec(), 100);
+        assert_eq!(set.encoding(), "hashtable");
+        let usage = set.memory_usage(0);
+        assert!(usage > 20);
+        // with few samples it is still a close estimate
+        assert!(set.memory_usage(1) > usage / 2);
     }
 }
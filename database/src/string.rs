@@ -1,3 +1,4 @@
+use std::cmp::max;
 use std::io;
 use std::io::Write;
 use std::str;
@@ -10,6 +11,16 @@ use rdbutil::{encode_i64, encode_slice_u8, EncodeError};
 
 const HLL_ERROR: f64 = 0.0019;
 
+/// Longest common subsequence between two strings.
+#[derive(PartialEq, Debug)]
+pub struct Lcs {
+    pub subsequence: Vec<u8>,
+    /// Ranges of contiguous bytes in the subsequence, from the last one to
+    /// the first one. Each item has the inclusive start and end positions in
+    /// the first string and in the second string.
+    pub matches: Vec<((usize, usize), (usize, usize))>,
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum ValueString {
     Integer(i64),
@@ -240,6 +251,63 @@ impl ValueString {
         Ok(())
     }
 
+    pub fn lcs(&self, other: &ValueString) -> Lcs {
+        let a = self.to_vec();
+        let b = other.to_vec();
+        let width = b.len() + 1;
+        // table[i * width + j] is the lcs length of a[..i] and b[..j]
+        let mut table = vec![0u32; (a.len() + 1) * width];
+        for i in 1..=a.len() {
+            for j in 1..=b.len() {
+                table[i * width + j] = if a[i - 1] == b[j - 1] {
+                    table[(i - 1) * width + j - 1] + 1
+                } else {
+                    max(table[(i - 1) * width + j], table[i * width + j - 1])
+                };
+            }
+        }
+
+        let mut subsequence = vec![0; table[a.len() * width + b.len()] as usize];
+        let mut matches = vec![];
+        // the range being built, as (a start, a end, b start, b end)
+        let mut current: Option<(usize, usize, usize, usize)> = None;
+        let (mut i, mut j, mut idx) = (a.len(), b.len(), subsequence.len());
+        while i > 0 && j > 0 {
+            if a[i - 1] == b[j - 1] {
+                idx -= 1;
+                subsequence[idx] = a[i - 1];
+                current = match current {
+                    Some((astart, aend, bstart, bend)) if astart == i && bstart == j => {
+                        Some((i - 1, aend, j - 1, bend))
+                    }
+                    Some((astart, aend, bstart, bend)) => {
+                        matches.push(((astart, aend), (bstart, bend)));
+                        Some((i - 1, i - 1, j - 1, j - 1))
+                    }
+                    None => Some((i - 1, i - 1, j - 1, j - 1)),
+                };
+                i -= 1;
+                j -= 1;
+            } else {
+                if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                    i -= 1;
+                } else {
+                    j -= 1;
+                }
+                if let Some((astart, aend, bstart, bend)) = current.take() {
+                    matches.push(((astart, aend), (bstart, bend)));
+                }
+            }
+        }
+        if let Some((astart, aend, bstart, bend)) = current {
+            matches.push(((astart, aend), (bstart, bend)));
+        }
+        Lcs {
+            subsequence,
+            matches,
+        }
+    }
+
     pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
         let mut v = vec![];
         match self {
@@ -262,18 +330,31 @@ impl ValueString {
         writer.write(&*data)
     }
 
+    /// Internal representation, as reported by `OBJECT ENCODING`.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            ValueString::Integer(_) => "int",
+            ValueString::Data(_) => "raw",
+        }
+    }
+
+    /// Bytes allocated by the string, besides the enum itself.
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            ValueString::Integer(_) => 0,
+            ValueString::Data(data) => data.capacity(),
+        }
+    }
+
     pub fn debug_object(&self) -> String {
         let mut serialized_data = vec![];
         let serialized = self.dump(&mut serialized_data).unwrap();
-        let encoding = match self {
-            ValueString::Integer(_) => "int",
-            ValueString::Data(_) => "raw",
-        };
 
         format!(
             "Value at:0x0000000000 refcount:1 encoding:{} serializedlength:{} lru:0 \
              lru_seconds_idle:0",
-            encoding, serialized
+            self.encoding(),
+            serialized
         )
     }
 }
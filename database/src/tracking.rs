@@ -0,0 +1,277 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::Sender;
+
+use response::Response;
+
+/// Channel invalidation messages are delivered on to RESP2 redirect clients.
+pub const INVALIDATE_CHANNEL: &[u8] = b"__redis__:invalidate";
+
+/// How a client wants to be told about changes in the keys it caches.
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct TrackingOptions {
+    /// Id of the client invalidation messages are sent to.
+    pub redirect: Option<usize>,
+    /// Broadcast every change to keys matching `prefixes` instead of only
+    /// the keys the client read.
+    pub bcast: bool,
+    /// Key prefixes a broadcasting client is interested in, all keys if empty.
+    pub prefixes: Vec<Vec<u8>>,
+    /// Only keys read right after `CLIENT CACHING yes` are tracked.
+    pub optin: bool,
+    /// Keys read right after `CLIENT CACHING no` are not tracked.
+    pub optout: bool,
+    /// Do not notify about keys the client modifies itself.
+    pub noloop: bool,
+}
+
+/// Server side state for client side caching: which clients read which keys,
+/// and where to send their invalidation messages.
+pub struct Tracking {
+    /// Connected clients, by id, that can receive invalidation messages.
+    senders: HashMap<usize, Sender<Option<Response>>>,
+    /// Clients with tracking enabled.
+    clients: HashMap<usize, TrackingOptions>,
+    /// Keys read by tracking clients, and who read them.
+    table: HashMap<Vec<u8>, HashSet<usize>>,
+    /// Maximum number of keys in `table`, 0 for no limit.
+    max_keys: usize,
+}
+
+impl Tracking {
+    pub fn new(max_keys: usize) -> Tracking {
+        Tracking {
+            senders: HashMap::new(),
+            clients: HashMap::new(),
+            table: HashMap::new(),
+            max_keys,
+        }
+    }
+
+    /// Registers a connected client so it can be used as a redirect target.
+    pub fn connect(&mut self, client_id: usize, sender: Sender<Option<Response>>) {
+        self.senders.insert(client_id, sender);
+    }
+
+    /// Forgets a client that disconnected, disabling its tracking.
+    pub fn disconnect(&mut self, client_id: usize) {
+        self.senders.remove(&client_id);
+        self.clients.remove(&client_id);
+    }
+
+    /// Turns tracking on for a client, replacing previous options.
+    pub fn enable(&mut self, client_id: usize, options: TrackingOptions) -> Result<(), String> {
+        if let Some(redirect) = options.redirect {
+            if !self.senders.contains_key(&redirect) {
+                return Err("ERR The client ID you want redirect to does not exist".to_owned());
+            }
+        }
+        self.clients.insert(client_id, options);
+        Ok(())
+    }
+
+    /// Turns tracking off for a client. Keys it read stay in the table until
+    /// they are invalidated, but it is no longer notified.
+    pub fn disable(&mut self, client_id: usize) {
+        self.clients.remove(&client_id);
+    }
+
+    /// Tracking options of a client, if it has tracking enabled.
+    pub fn options(&self, client_id: usize) -> Option<&TrackingOptions> {
+        self.clients.get(&client_id)
+    }
+
+    /// Number of keys in the invalidation table.
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// Remembers that a client read a key. If the table grows over its limit,
+    /// other keys are invalidated to make room.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use database::tracking::{Tracking, TrackingOptions};
+    /// # use std::sync::mpsc::channel;
+    /// #
+    /// let mut tracking = Tracking::new(1);
+    /// let (tx, rx) = channel();
+    /// tracking.connect(1, tx);
+    /// tracking.enable(2, TrackingOptions {
+    ///     redirect: Some(1),
+    ///     ..TrackingOptions::default()
+    /// }).unwrap();
+    /// tracking.remember(2, b"foo");
+    /// tracking.remember(2, b"bar");
+    /// assert_eq!(tracking.len(), 1);
+    /// assert!(rx.try_recv().is_ok());
+    /// ```
+    pub fn remember(&mut self, client_id: usize, key: &[u8]) {
+        self.table
+            .entry(key.to_vec())
+            .or_default()
+            .insert(client_id);
+        while self.max_keys > 0 && self.table.len() > self.max_keys {
+            let evicted = match self.table.keys().find(|k| &k[..] != key) {
+                Some(k) => k.clone(),
+                None => break,
+            };
+            self.invalidate(&evicted, Some(client_id));
+        }
+    }
+
+    /// Notifies the clients that read a key, or broadcast a matching prefix,
+    /// that it changed. `current_client` is the client that changed it, used
+    /// for `noloop`.
+    pub fn invalidate(&mut self, key: &[u8], current_client: Option<usize>) {
+        let mut recipients = self.table.remove(key).unwrap_or_default();
+        for (id, options) in self.clients.iter() {
+            if options.bcast
+                && (options.prefixes.is_empty()
+                    || options.prefixes.iter().any(|p| key.starts_with(p)))
+            {
+                recipients.insert(*id);
+            }
+        }
+        for id in recipients {
+            self.send(
+                id,
+                Response::Array(vec![Response::Data(key.to_vec())]),
+                current_client,
+            );
+        }
+    }
+
+    /// Notifies every tracking client that all keys changed, after a flush.
+    pub fn invalidate_all(&mut self, current_client: Option<usize>) {
+        self.table.clear();
+        let ids = self.clients.keys().cloned().collect::<Vec<_>>();
+        for id in ids {
+            self.send(id, Response::Nil, current_client);
+        }
+    }
+
+    /// Sends an invalidation message for `client_id` to its redirect client.
+    /// Without a redirect there is nowhere to deliver it on a RESP2
+    /// connection, so it is dropped.
+    fn send(&self, client_id: usize, keys: Response, current_client: Option<usize>) {
+        let options = match self.clients.get(&client_id) {
+            Some(options) => options,
+            None => return,
+        };
+        if options.noloop && current_client == Some(client_id) {
+            return;
+        }
+        let sender = match options.redirect.and_then(|id| self.senders.get(&id)) {
+            Some(sender) => sender,
+            None => return,
+        };
+        let _ = sender.send(Some(Response::Array(vec![
+            Response::Data(b"message".to_vec()),
+            Response::Data(INVALIDATE_CHANNEL.to_vec()),
+            keys,
+        ])));
+    }
+}
+
+#[cfg(test)]
+mod test_tracking {
+    use std::sync::mpsc::channel;
+
+    use response::Response;
+
+    use super::{Tracking, TrackingOptions, INVALIDATE_CHANNEL};
+
+    fn invalidation(keys: Response) -> Response {
+        Response::Array(vec![
+            Response::Data(b"message".to_vec()),
+            Response::Data(INVALIDATE_CHANNEL.to_vec()),
+            keys,
+        ])
+    }
+
+    #[test]
+    fn invalidate_read_keys() {
+        let mut tracking = Tracking::new(0);
+        let (tx, rx) = channel();
+        tracking.connect(1, tx);
+        assert!(tracking
+            .enable(
+                2,
+                TrackingOptions {
+                    redirect: Some(3),
+                    ..TrackingOptions::default()
+                }
+            )
+            .is_err());
+        tracking
+            .enable(
+                2,
+                TrackingOptions {
+                    redirect: Some(1),
+                    ..TrackingOptions::default()
+                },
+            )
+            .unwrap();
+        tracking.remember(2, b"foo");
+        tracking.invalidate(b"bar", None);
+        tracking.invalidate(b"foo", None);
+        tracking.invalidate(b"foo", None);
+        assert_eq!(
+            rx.try_recv().unwrap().unwrap(),
+            invalidation(Response::Array(vec![Response::Data(b"foo".to_vec())]))
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn broadcast_prefixes() {
+        let mut tracking = Tracking::new(0);
+        let (tx, rx) = channel();
+        tracking.connect(1, tx);
+        tracking
+            .enable(
+                1,
+                TrackingOptions {
+                    redirect: Some(1),
+                    bcast: true,
+                    prefixes: vec![b"user:".to_vec()],
+                    ..TrackingOptions::default()
+                },
+            )
+            .unwrap();
+        tracking.invalidate(b"item:1", None);
+        tracking.invalidate(b"user:1", None);
+        assert_eq!(
+            rx.try_recv().unwrap().unwrap(),
+            invalidation(Response::Array(vec![Response::Data(b"user:1".to_vec())]))
+        );
+        tracking.invalidate_all(None);
+        assert_eq!(rx.try_recv().unwrap().unwrap(), invalidation(Response::Nil));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn noloop() {
+        let mut tracking = Tracking::new(0);
+        let (tx, rx) = channel();
+        tracking.connect(1, tx);
+        tracking
+            .enable(
+                2,
+                TrackingOptions {
+                    redirect: Some(1),
+                    noloop: true,
+                    ..TrackingOptions::default()
+                },
+            )
+            .unwrap();
+        tracking.remember(2, b"foo");
+        tracking.invalidate(b"foo", Some(2));
+        assert!(rx.try_recv().is_err());
+    }
+}
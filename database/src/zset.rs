@@ -5,10 +5,13 @@ use std::collections::HashSet;
 use std::f64::{INFINITY, NEG_INFINITY};
 use std::io;
 use std::io::Write;
+use std::mem::size_of;
 
+use rand::distributions::{IndependentSample, Range};
+use rand::thread_rng;
 use skiplist::OrderedSkipList;
 
-use dbutil::normalize_position;
+use dbutil::{normalize_position, sampled_memory_usage};
 use error::OperationError;
 use rdbutil::constants::*;
 use rdbutil::{encode_len, encode_slice_u8};
@@ -309,6 +312,28 @@ impl ValueSortedSet {
         true
     }
 
+    /// Removes up to `count` members with the lowest scores, or with the
+    /// highest if `max` is true, and returns them with their scores.
+    pub fn zpop(&mut self, count: usize, max: bool) -> Vec<(Vec<u8>, f64)> {
+        let (skiplist, hmap) = match *self {
+            ValueSortedSet::Data(ref mut skiplist, ref mut hmap) => (skiplist, hmap),
+        };
+        let mut popped = Vec::with_capacity(count.min(skiplist.len()));
+        while popped.len() < count {
+            let member = match if max {
+                skiplist.pop_back()
+            } else {
+                skiplist.pop_front()
+            } {
+                Some(member) => member,
+                None => break,
+            };
+            hmap.remove(&member.s);
+            popped.push((member.s, member.f));
+        }
+        popped
+    }
+
     pub fn zremrangebyscore(&mut self, min: Bound<f64>, max: Bound<f64>) -> usize {
         let pos = match min {
             Bound::Included(s) => self.zcount(Bound::Unbounded, Bound::Excluded(s)),
@@ -471,7 +496,7 @@ impl ValueSortedSet {
         if rev {
             let len = skiplist.len();
             let mut c = count;
-            if c + offset > len {
+            if c.saturating_add(offset) > len {
                 c = if len > offset { len - offset } else { 0 };
             }
 
@@ -718,6 +743,78 @@ impl ValueSortedSet {
         }
     }
 
+    /// Adds the members of the first sorted set that are not in any of the
+    /// others, with their scores.
+    pub fn zdiff(&mut self, zsets: Vec<&ValueSortedSet>) {
+        let (first, others) = match zsets.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        let hm = match **first {
+            ValueSortedSet::Data(_, ref hm) => hm,
+        };
+        for (k, v) in hm {
+            if others.iter().all(|zset| zset.zscore(k).is_none()) {
+                let _ = self.zadd(*v, k.clone(), false, false, false, false, true);
+            }
+        }
+    }
+
+    /// Counts the members in all the sorted sets, stopping at `limit` if it
+    /// is not zero.
+    pub fn intercard(zsets: &[&ValueSortedSet], limit: usize) -> usize {
+        let smallest = match zsets.iter().min_by_key(|zset| zset.zcard()) {
+            Some(zset) => zset,
+            None => return 0,
+        };
+        let hm = match **smallest {
+            ValueSortedSet::Data(_, ref hm) => hm,
+        };
+        let mut count = 0;
+        for k in hm.keys() {
+            if zsets.iter().all(|zset| zset.zscore(k).is_some()) {
+                count += 1;
+                if count == limit {
+                    break;
+                }
+            }
+        }
+        count
+    }
+
+    /// Returns `count` random members with their scores. Unless
+    /// `allow_duplicates` is true, the members are distinct and there are
+    /// at most as many as in the sorted set.
+    pub fn zrandmember(&self, count: usize, allow_duplicates: bool) -> Vec<(Vec<u8>, f64)> {
+        let skiplist = match *self {
+            ValueSortedSet::Data(ref skiplist, _) => skiplist,
+        };
+        let len = skiplist.len();
+        if len == 0 {
+            return vec![];
+        }
+        let range = Range::new(0, len);
+        let mut rng = thread_rng();
+        let positions = if allow_duplicates {
+            (0..count).map(|_| range.ind_sample(&mut rng)).collect()
+        } else if count >= len {
+            (0..len).collect()
+        } else {
+            let mut positions = HashSet::with_capacity(count);
+            while positions.len() < count {
+                positions.insert(range.ind_sample(&mut rng));
+            }
+            positions.into_iter().collect::<Vec<_>>()
+        };
+        positions
+            .into_iter()
+            .map(|pos| {
+                let member = &skiplist[pos];
+                (member.s.clone(), member.f)
+            })
+            .collect()
+    }
+
     pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
         let mut v = vec![];
         let settype;
@@ -736,7 +833,7 @@ impl ValueSortedSet {
                             v.write_all(&[255])?;
                         }
                     } else {
-                        let scorestr = format!("{}", score.abs()).to_owned();
+                        let scorestr = format!("{}", score);
                         encode_slice_u8(scorestr.as_bytes(), &mut v, false)?;
                     }
                 }
@@ -752,16 +849,46 @@ impl ValueSortedSet {
         writer.write(&*data)
     }
 
+    /// Internal representation, as reported by `OBJECT ENCODING`.
+    pub fn encoding(&self) -> &'static str {
+        match *self {
+            ValueSortedSet::Data(_, _) => "skiplist",
+        }
+    }
+
+    /// Estimated bytes allocated by the sorted set, besides the enum itself.
+    /// Only `samples` elements are measured, or all of them if it is zero.
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match *self {
+            ValueSortedSet::Data(_, ref hmap) => {
+                // a skiplist node has the member, the level, a pointer to
+                // the previous node and two vectors with its links and their
+                // lengths, about two levels on average
+                let node = size_of::<Option<SortedSetMember>>()
+                    + 2 * size_of::<usize>()
+                    + 2 * (size_of::<Vec<usize>>() + 2 * size_of::<usize>());
+                // the table reserves its whole capacity, plus a control byte
+                // for each bucket
+                let table = hmap.capacity() * (size_of::<(Vec<u8>, f64)>() + 1);
+                // every member is stored both in the skiplist and in the table
+                table
+                    + sampled_memory_usage(
+                        hmap.keys().map(|member| node + 2 * member.capacity()),
+                        hmap.len(),
+                        samples,
+                    )
+            }
+        }
+    }
+
     pub fn debug_object(&self) -> String {
         let mut serialized_data = vec![];
         let serialized = self.dump(&mut serialized_data).unwrap();
-        let encoding = match *self {
-            ValueSortedSet::Data(_, _) => "skiplist",
-        };
         format!(
             "Value at:0x0000000000 refcount:1 encoding:{} serializedlength:{} lru:0 \
              lru_seconds_idle:0",
-            encoding, serialized
+            self.encoding(),
+            serialized
         )
     }
 }
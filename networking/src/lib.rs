@@ -1,13 +1,13 @@
-use logger::{log, sendlog};
+use logger::{log, log_and_exit, sendlog};
 
 use std::{
-    io::{self, Read, Write},
+    io::{self, BufRead, BufReader, IoSlice, Read, Write},
     net::{SocketAddr, TcpStream, ToSocketAddrs},
     process,
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc, Mutex, MutexGuard,
+        Arc,
     },
     thread,
     time::Duration,
@@ -15,14 +15,24 @@ use std::{
 
 use net2::{TcpBuilder, TcpStreamExt};
 #[cfg(unix)]
-use std::{fs::File, path::Path};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
 #[cfg(unix)]
-use unix_socket::{UnixListener, UnixStream};
+use std::{
+    fs::{remove_file, File},
+    path::Path,
+};
+#[cfg(unix)]
+use unix_socket::{AddressKind, UnixListener, UnixStream};
 
 use config::Config;
-use database::Database;
+use database::cluster::CLUSTER_PORT_INCR;
+use database::sentinel::Request;
+use database::{Database, SharedDatabase};
 use logger::Level;
-use parser::{OwnedParsedCommand, ParseError, Parser};
+use parser::{OwnedParsedCommand, ParseError, ParsedCommand, Parser};
 use response::{Response, ResponseError};
 
 /// A stream connection.
@@ -47,11 +57,27 @@ impl Stream {
         }
     }
 
-    /// Write a buffer into this object, returning how many bytes were written.
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    /// Write a slice of buffers into this object, returning how many bytes
+    /// were written.
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write_vectored(bufs),
+            Stream::Unix(s) => s.write_vectored(bufs),
+        }
+    }
+
+    /// Address of the other end of the connection. UNIX sockets are
+    /// described by the path they listen on.
+    fn peer_addr(&self) -> String {
         match self {
-            Stream::Tcp(s) => s.write(buf),
-            Stream::Unix(s) => s.write(buf),
+            Stream::Tcp(s) => tcp_peer_addr(s),
+            Stream::Unix(s) => match s.local_addr() {
+                Ok(addr) => match addr.address() {
+                    AddressKind::Pathname(path) => format!("unix:{}", path.display()),
+                    _ => "unix:".to_owned(),
+                },
+                Err(_) => "unix:".to_owned(),
+            },
         }
     }
 
@@ -106,10 +132,18 @@ impl Stream {
         }
     }
 
-    /// Write a buffer into this object, returning how many bytes were written.
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    /// Write a slice of buffers into this object, returning how many bytes
+    /// were written.
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write_vectored(bufs),
+        }
+    }
+
+    /// Address of the other end of the connection.
+    fn peer_addr(&self) -> String {
         match self {
-            Stream::Tcp(s) => s.write(buf),
+            Stream::Tcp(s) => tcp_peer_addr(s),
         }
     }
 
@@ -149,12 +183,400 @@ impl Read for Stream {
     }
 }
 
+/// Formats a TCP peer as `ip:port`.
+fn tcp_peer_addr(stream: &TcpStream) -> String {
+    match stream.peer_addr() {
+        Ok(addr) => format!("{}:{}", addr.ip(), addr.port()),
+        Err(_) => "?:0".to_owned(),
+    }
+}
+
+/// Connect, read and write timeout for cluster bus connections.
+const CLUSTER_BUS_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Cluster bus messages longer than this are rejected.
+const CLUSTER_BUS_MAX_MESSAGE: usize = 1024 * 1024;
+
+/// Milliseconds between two runs of the cluster cron.
+const CLUSTER_CRON_INTERVAL: u64 = 100;
+
+/// Reads a cluster bus message, which ends with an empty line.
+fn read_cluster_message(stream: &mut TcpStream) -> io::Result<String> {
+    let mut message = vec![];
+    let mut buf = [0; 4096];
+    while !message.ends_with(b"\n\n") {
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "incomplete cluster bus message",
+            ));
+        }
+        message.extend_from_slice(&buf[..len]);
+        if message.len() > CLUSTER_BUS_MAX_MESSAGE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "cluster bus message too long",
+            ));
+        }
+    }
+    String::from_utf8(message)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid cluster bus message"))
+}
+
+/// Processes a message received from another node, and sends back the reply.
+fn handle_cluster_connection(db: &SharedDatabase, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLUSTER_BUS_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLUSTER_BUS_TIMEOUT))?;
+    let message = read_cluster_message(&mut stream)?;
+    let peer_ip = stream.peer_addr()?.ip().to_string();
+    let local_ip = stream.local_addr()?.ip().to_string();
+    let reply = {
+        let mut db = db.handle();
+        let reply = match db.cluster().as_mut() {
+            Some(cluster) => {
+                cluster.process_message(&message, &peer_ip, &local_ip, None, util::mstime())
+            }
+            None => return Ok(()),
+        };
+        command::cluster::save_config(&mut db);
+        reply.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+    };
+    if let Some(reply) = reply {
+        stream.write_all(reply.as_bytes())?;
+        stream.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Sends a message to another node and processes its reply.
+fn send_cluster_message(
+    db: &SharedDatabase,
+    id: &str,
+    addr: &str,
+    message: &str,
+) -> io::Result<()> {
+    let addr = match addr.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid node address",
+            ))
+        }
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, CLUSTER_BUS_TIMEOUT)?;
+    stream.set_read_timeout(Some(CLUSTER_BUS_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLUSTER_BUS_TIMEOUT))?;
+    stream.write_all(message.as_bytes())?;
+    stream.write_all(b"\n")?;
+    let reply = read_cluster_message(&mut stream)?;
+    let local_ip = stream.local_addr()?.ip().to_string();
+    let mut db = db.handle();
+    if let Some(cluster) = db.cluster().as_mut() {
+        cluster
+            .process_message(
+                &reply,
+                &addr.ip().to_string(),
+                &local_ip,
+                Some(id),
+                util::mstime(),
+            )
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    }
+    command::cluster::save_config(&mut db);
+    Ok(())
+}
+
+/// Connect, read and write timeout for the connections of a sentinel to the
+/// instances it monitors.
+const SENTINEL_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Milliseconds between two runs of the sentinel cron.
+const SENTINEL_CRON_INTERVAL: u64 = 100;
+
+fn invalid_reply() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid reply")
+}
+
+/// Reads a reply sent by a server.
+fn read_reply<R: BufRead>(stream: &mut R) -> io::Result<Response> {
+    let mut line = String::new();
+    if stream.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !line.ends_with("\r\n") {
+        return Err(invalid_reply());
+    }
+    let (kind, value) = (&line[..1], &line[1..line.len() - 2]);
+    let len = || value.parse::<i64>().map_err(|_| invalid_reply());
+    Ok(match kind {
+        "+" => Response::Status(value.to_owned()),
+        "-" => Response::Error(value.to_owned()),
+        ":" => Response::Integer(len()?),
+        "$" if len()? < 0 => Response::Nil,
+        "$" => {
+            let mut data = vec![0; len()? as usize + 2];
+            stream.read_exact(&mut data)?;
+            data.truncate(data.len() - 2);
+            Response::Data(data)
+        }
+        "*" if len()? < 0 => Response::Nil,
+        "*" => {
+            let mut array = vec![];
+            for _ in 0..len()? {
+                array.push(read_reply(stream)?);
+            }
+            Response::Array(array)
+        }
+        _ => return Err(invalid_reply()),
+    })
+}
+
+fn write_command(stream: &mut BufReader<TcpStream>, args: &[Vec<u8>]) -> io::Result<()> {
+    let command = Response::Array(args.iter().cloned().map(Response::Data).collect());
+    stream.get_mut().write_all(&command.as_bytes())
+}
+
+/// Connects to the instance a sentinel request is for, and authenticates.
+/// Returns the connection and its local address.
+fn sentinel_connect(request: &Request) -> io::Result<(BufReader<TcpStream>, String)> {
+    let addr = match (&*request.ip, request.port).to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid instance address",
+            ))
+        }
+    };
+    let stream = TcpStream::connect_timeout(&addr, SENTINEL_TIMEOUT)?;
+    stream.set_read_timeout(Some(SENTINEL_TIMEOUT))?;
+    stream.set_write_timeout(Some(SENTINEL_TIMEOUT))?;
+    let local_ip = stream.local_addr()?.ip().to_string();
+    let mut stream = BufReader::new(stream);
+    if let Some(auth) = &request.auth {
+        write_command(&mut stream, auth)?;
+        if let Response::Error(err) = read_reply(&mut stream)? {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, err));
+        }
+    }
+    Ok((stream, local_ip))
+}
+
+/// Sends a command to an instance monitored by the sentinel, and processes
+/// its reply.
+fn send_sentinel_request(db: &SharedDatabase, request: &Request) -> io::Result<()> {
+    let reply = sentinel_connect(request).and_then(|(mut stream, local_ip)| {
+        write_command(&mut stream, &request.command)?;
+        Ok((read_reply(&mut stream)?, local_ip))
+    });
+    let mut db = db.handle();
+    let (response, local_ip, r) = match reply {
+        Ok((response, local_ip)) => (Some(response), Some(local_ip), Ok(())),
+        Err(err) => (None, None, Err(err)),
+    };
+    if let Some(sentinel) = db.sentinel().as_mut() {
+        sentinel.process_reply(request, response, local_ip, util::mstime());
+    }
+    command::sentinel::flush_events(&mut db);
+    r
+}
+
+/// Subscribes to the hello messages published in an instance, and processes
+/// them until the instance is not monitored any more or the connection is
+/// lost.
+fn sentinel_subscribe(db: &SharedDatabase, request: &Request) -> io::Result<()> {
+    let mut db = db.handle();
+    let (mut stream, _) = sentinel_connect(request)?;
+    write_command(&mut stream, &request.command)?;
+    loop {
+        let message = match read_reply(&mut stream) {
+            Ok(Response::Array(message)) => message,
+            Ok(_) => continue,
+            Err(ref err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                match db.sentinel().as_ref() {
+                    Some(sentinel) if sentinel.subscription_wanted(request) => continue,
+                    _ => return Ok(()),
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        if let [Response::Data(kind), _, Response::Data(payload)] = &message[..] {
+            if kind == b"message" {
+                if let Some(sentinel) = db.sentinel().as_mut() {
+                    sentinel.process_hello(&String::from_utf8_lossy(payload), util::mstime());
+                }
+                command::sentinel::flush_events(&mut db);
+            }
+        }
+    }
+}
+
+/// Maximum number of buffered commands executed in a single database lock
+/// acquisition, so a long pipeline does not starve other clients.
+const MAX_COMMANDS_PER_BATCH: usize = 1000;
+
+/// Once this many bytes of responses are queued, they are written even if
+/// more responses are waiting.
+const MAX_OUTPUT_BATCH_BYTES: usize = 64 * 1024;
+
+/// Bulk responses at least this big are written from their own buffer
+/// instead of being copied into the output buffer.
+const OUTPUT_COPY_LIMIT: usize = 16 * 1024;
+
+/// Responses queued to be written to a client.
+struct Output {
+    /// Serialized responses
+    buf: Vec<u8>,
+    /// Large payloads, with the position in `buf` they must be written at
+    payloads: Vec<(usize, Vec<u8>)>,
+    /// Total length of the payloads
+    payloads_len: usize,
+}
+
+impl Output {
+    fn new() -> Output {
+        Output {
+            buf: Vec::new(),
+            payloads: Vec::new(),
+            payloads_len: 0,
+        }
+    }
+
+    /// Number of bytes waiting to be written
+    fn len(&self) -> usize {
+        self.buf.len() + self.payloads_len
+    }
+
+    /// Queues a response.
+    fn push(&mut self, response: Response) {
+        match response {
+            Response::Data(d) if d.len() >= OUTPUT_COPY_LIMIT => {
+                self.buf
+                    .extend_from_slice(format!("${}\r\n", d.len()).as_bytes());
+                self.payloads_len += d.len();
+                self.payloads.push((self.buf.len(), d));
+                self.buf.extend_from_slice(b"\r\n");
+            }
+            response => response.write_to(&mut self.buf),
+        }
+    }
+
+    /// Writes every queued response with vectored writes, and empties the
+    /// queue.
+    fn write_to(&mut self, stream: &mut Stream) -> io::Result<()> {
+        let r = {
+            let mut slices = Vec::with_capacity(self.payloads.len() * 2 + 1);
+            let mut pos = 0;
+            for (at, payload) in self.payloads.iter() {
+                slices.push(IoSlice::new(&self.buf[pos..*at]));
+                slices.push(IoSlice::new(payload));
+                pos = *at;
+            }
+            slices.push(IoSlice::new(&self.buf[pos..]));
+            write_all_vectored(stream, &mut slices)
+        };
+        self.buf.clear();
+        self.payloads.clear();
+        self.payloads_len = 0;
+        r
+    }
+}
+
+/// Writes all the slices, retrying until they are completely written.
+fn write_all_vectored(stream: &mut Stream, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match stream.write_vectored(slices) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Reports a protocol error to the client before closing the connection.
+fn protocol_error(
+    err: ParseError,
+    stream_tx: &Sender<Option<Response>>,
+    sender: &Sender<(Level, String)>,
+) {
+    match err {
+        ParseError::BadProtocol(_) => {
+            let _ = stream_tx.send(Some(Response::Error(format!("ERR {}", err))));
+        }
+        ParseError::QueryBufferLimit => {
+            let _ = sendlog!(
+                sender,
+                Warning,
+                "Closing client that reached max query buffer length"
+            );
+        }
+        _ => {
+            let _ = sendlog!(sender, Verbose, "Protocol error from client: {:?}", err);
+        }
+    }
+}
+
+/// Locks the databases a command uses, and runs it. Read-only commands
+/// share them with other readers. The databases locked for the previous
+/// command are kept if they are the same.
+fn execute(
+    parsed_command: ParsedCommand,
+    db: &mut Database,
+    client: &mut command::Client,
+) -> Result<Response, ResponseError> {
+    let databases = command::databases(&parsed_command, db, client);
+    if command::read_only(&parsed_command, db) {
+        db.lock_read(&databases);
+    } else {
+        db.lock(&databases);
+    }
+    command::command(parsed_command, db, client)
+}
+
+/// The state a client leaves in the database. It is cleaned up when the
+/// guard is dropped, also if the client thread panics: pubsub and tracking
+/// keep senders to the client writer thread, which would otherwise never
+/// see its channel close and the socket would stay open.
+struct ClientGuard {
+    db: SharedDatabase,
+    client: command::Client,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let mut db = self.db.handle();
+        for (channel_name, subscriber_id) in self.client.subscriptions.drain() {
+            db.unsubscribe(channel_name, subscriber_id);
+        }
+        for (pattern, subscriber_id) in self.client.pattern_subscriptions.drain() {
+            db.punsubscribe(pattern, subscriber_id);
+        }
+        for (channel_name, subscriber_id) in self.client.shard_subscriptions.drain() {
+            db.sunsubscribe(channel_name, subscriber_id);
+        }
+        db.tracking().disconnect(self.client.id);
+    }
+}
+
 /// A client connection
 struct Client {
     /// The socket connection
     stream: Stream,
     /// A reference to the database
-    db: Arc<Mutex<Database>>,
+    db: SharedDatabase,
     /// The client unique identifier
     id: usize,
 }
@@ -162,7 +584,7 @@ struct Client {
 /// The database server
 pub struct Server {
     /// A reference to the database
-    db: Arc<Mutex<Database>>,
+    db: SharedDatabase,
     /// A list of channels listening for incoming connections
     listener_channels: Vec<Sender<u8>>,
     /// A list of threads listening for incoming connections
@@ -171,11 +593,17 @@ pub struct Server {
     pub next_id: Arc<AtomicUsize>,
     /// Sender to signal hz thread to stop
     hz_stop: Option<Sender<()>>,
+    /// Sender to signal the cluster cron thread to stop
+    cluster_stop: Option<Sender<()>>,
+    /// Sender to signal the sentinel cron thread to stop
+    sentinel_stop: Option<Sender<()>>,
+    /// Receives a message when the database is ready to shut down
+    shutdown_receiver: Receiver<()>,
 }
 
 impl Client {
     /// Creates a new TCP socket client
-    pub fn tcp(stream: TcpStream, db: Arc<Mutex<Database>>, id: usize) -> Client {
+    pub fn tcp(stream: TcpStream, db: SharedDatabase, id: usize) -> Client {
         Client {
             stream: Stream::Tcp(stream),
             db,
@@ -185,7 +613,7 @@ impl Client {
 
     /// Creates a new UNIX socket client
     #[cfg(unix)]
-    pub fn unix(stream: UnixStream, db: Arc<Mutex<Database>>, id: usize) -> Client {
+    pub fn unix(stream: UnixStream, db: SharedDatabase, id: usize) -> Client {
         Client {
             stream: Stream::Unix(stream),
             db,
@@ -193,7 +621,8 @@ impl Client {
         }
     }
 
-    /// Creates a thread that writes into the client stream each response received
+    /// Creates a thread that writes into the client stream each response
+    /// received. Responses that are already queued are written together.
     fn create_writer_thread(
         &self,
         sender: Sender<(Level, String)>,
@@ -201,13 +630,26 @@ impl Client {
     ) {
         let mut stream = self.stream.try_clone().unwrap();
         thread::spawn(move || {
+            let mut output = Output::new();
             while let Ok(Some(msg)) = rx.recv() {
-                match stream.write(&*msg.as_bytes()) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        sendlog!(sender, Warning, "Error writing to client: {:?}", e).unwrap()
+                output.push(msg);
+                let mut closed = false;
+                while output.len() < MAX_OUTPUT_BATCH_BYTES {
+                    match rx.try_recv() {
+                        Ok(Some(msg)) => output.push(msg),
+                        Ok(None) | Err(TryRecvError::Disconnected) => {
+                            closed = true;
+                            break;
+                        }
+                        Err(TryRecvError::Empty) => break,
                     }
                 }
+                if let Err(e) = output.write_to(&mut stream) {
+                    sendlog!(sender, Warning, "Error writing to client: {:?}", e).unwrap()
+                }
+                if closed {
+                    break;
+                }
             }
         });
     }
@@ -218,14 +660,23 @@ impl Client {
         let (stream_tx, rx) = channel::<Option<Response>>();
         self.create_writer_thread(sender.clone(), rx);
 
-        let mut client = command::Client::new(stream_tx.clone(), self.id);
+        let mut guard = ClientGuard {
+            db: self.db.clone(),
+            client: command::Client::new(stream_tx.clone(), self.id),
+        };
+        let client = &mut guard.client;
+        client.addr = self.stream.peer_addr();
         let mut parser = Parser::new();
+        let mut db = self.db.handle();
+        db.tracking().connect(self.id, stream_tx.clone());
+        parser.set_max_bulk_len(db.config.proto_max_bulk_len);
+        parser.set_max_query_buffer(db.config.client_query_buffer_limit);
 
         let mut this_command: Option<OwnedParsedCommand>;
         let mut next_command: Option<OwnedParsedCommand> = None;
+        let mut read_more = false;
         loop {
-            // FIXME: is_incomplete parses the command a second time
-            if next_command.is_none() && parser.is_incomplete() {
+            if read_more {
                 parser.allocate();
                 let len = {
                     let pos = parser.written;
@@ -241,6 +692,7 @@ impl Client {
                     }
                 };
                 parser.written += len;
+                read_more = false;
 
                 // client closed connection
                 if len == 0 {
@@ -258,69 +710,81 @@ impl Client {
             // try to parse received command
             let parsed_command = match &this_command {
                 Some(c) => c.get_command(),
-                None => {
-                    match parser.next() {
-                        Ok(p) => p,
-                        Err(err) => {
-                            match err {
-                                // if it's incomplete, keep adding to the buffer
-                                ParseError::Incomplete => {
-                                    continue;
-                                }
-                                ParseError::BadProtocol(s) => {
-                                    let _ = stream_tx.send(Some(Response::Error(s)));
-                                    break;
-                                }
-                                _ => {
-                                    let _ = sendlog!(
-                                        sender,
-                                        Verbose,
-                                        "Protocol error from client: {:?}",
-                                        err
-                                    );
-                                    break;
-                                }
-                            }
-                        }
+                None => match parser.next() {
+                    Ok(p) => p,
+                    // if it's incomplete, keep adding to the buffer
+                    Err(ParseError::Incomplete) => {
+                        read_more = true;
+                        continue;
                     }
-                }
+                    Err(err) => {
+                        protocol_error(err, &stream_tx, &sender);
+                        break;
+                    }
+                },
             };
 
-            let r = {
-                let mut db = match self.db.lock() {
-                    Ok(db) => db,
-                    Err(_) => break,
-                };
+            let mut parse_error = None;
+            let mut wait = None;
+            {
+                // execute the command, and then every other command that is
+                // already buffered while we are holding the lock
+                let mut r = execute(parsed_command, &mut db, client);
+                let mut executed = 1;
+                loop {
+                    match r {
+                        // received a response, send it to the client
+                        Ok(response) => {
+                            if stream_tx.send(Some(response)).is_err() {
+                                error = true;
+                                break;
+                            }
+                        }
+                        // There is no reply to send, that's ok
+                        Err(ResponseError::NoReply) => (),
+                        // We have to wait until a sender signals us back, but
+                        // not while holding the lock
+                        Err(ResponseError::Wait(receiver)) => {
+                            wait = Some(receiver);
+                            break;
+                        }
+                    }
 
-                // execute the command
-                command::command(parsed_command, &mut *db, &mut client)
-            };
+                    // let other clients use the database
+                    if executed == MAX_COMMANDS_PER_BATCH {
+                        break;
+                    }
 
-            // check out the response
-            match r {
-                // received a response, send it to the client
-                Ok(response) => {
-                    match stream_tx.send(Some(response)) {
-                        Ok(_) => (),
-                        Err(_) => error = true,
+                    r = match parser.next() {
+                        Ok(p) => execute(p, &mut db, client),
+                        Err(ParseError::Incomplete) => {
+                            read_more = true;
+                            break;
+                        }
+                        Err(err) => {
+                            parse_error = Some(err);
+                            break;
+                        }
                     };
+                    executed += 1;
                 }
-                // no response
-                Err(err) => {
-                    match err {
-                        // There is no reply to send, that's ok
-                        ResponseError::NoReply => (),
-                        // We have to wait until a sender signals us back and then retry
-                        // (Repeating the same command is actually wrong because of the timeout)
-                        ResponseError::Wait(receiver) => {
-                            // if we receive a None, send a nil, otherwise execute the command
-                            match receiver.recv().unwrap() {
-                                Some(cmd) => next_command = Some(cmd),
-                                None => match stream_tx.send(Some(Response::Nil)) {
-                                    Ok(_) => (),
-                                    Err(_) => error = true,
-                                },
-                            }
+                db.unlock();
+            }
+
+            if let Some(err) = parse_error {
+                protocol_error(err, &stream_tx, &sender);
+                break;
+            }
+
+            // Retry the command once a sender signals us back
+            // (Repeating the same command is actually wrong because of the timeout)
+            if let Some(receiver) = wait {
+                // if we receive a None, send a nil, otherwise execute the command
+                match receiver.recv().unwrap() {
+                    Some(cmd) => next_command = Some(cmd),
+                    None => {
+                        if stream_tx.send(Some(Response::Nil)).is_err() {
+                            error = true;
                         }
                     }
                 }
@@ -337,17 +801,6 @@ impl Client {
                 break;
             }
         }
-
-        {
-            let mut db = match self.db.lock() {
-                Ok(db) => db,
-                Err(_) => return,
-            };
-
-            for (channel_name, subscriber_id) in client.subscriptions.into_iter() {
-                db.unsubscribe(channel_name.clone(), subscriber_id);
-            }
-        }
     }
 }
 
@@ -410,25 +863,31 @@ macro_rules! handle_listener {
 impl Server {
     /// Creates a new server
     pub fn new(config: Config) -> Server {
-        let db = Database::new(config);
+        let db = SharedDatabase::new(config);
+        let (shutdown_sender, shutdown_receiver) = channel();
+        db.handle().set_shutdown_sender(shutdown_sender);
         Server {
-            db: Arc::new(Mutex::new(db)),
+            db,
             listener_channels: Vec::new(),
             listener_threads: Vec::new(),
             next_id: Arc::new(AtomicUsize::default()),
             hz_stop: None,
+            cluster_stop: None,
+            sentinel_stop: None,
+            shutdown_receiver,
         }
     }
 
-    pub fn get_mut_db(&self) -> MutexGuard<database::Database> {
-        self.db.lock().unwrap()
+    pub fn get_mut_db(&mut self) -> &mut SharedDatabase {
+        &mut self.db
     }
 
-    /// Runs the server. If `config.daemonize` is true, it forks and exits.
+    /// Runs the server until it is shut down. If `config.daemonize` is true,
+    /// it forks and exits.
     #[cfg(unix)]
     pub fn run(&mut self) {
         let (daemonize, pidfile) = {
-            let db = self.db.lock().unwrap();
+            let db = &self.db;
             (db.config.daemonize, db.config.pidfile.clone())
         };
         if daemonize {
@@ -437,34 +896,115 @@ impl Server {
                     match write!(fp, "{}", process::id()) {
                         Ok(_) => (),
                         Err(e) => {
-                            let db = self.db.lock().unwrap();
+                            let db = &self.db;
                             log!(db.config.logger, Warning, "Error writing pid: {}", e);
                         }
                     }
                 }
+                self.handle_signals();
                 self.start();
-                self.join();
+                self.wait_shutdown();
+                let db = &self.db;
+                log!(db.config.logger, Notice, "Removing the pid file.");
+                if let Err(e) = remove_file(Path::new(&*pidfile)) {
+                    log!(
+                        db.config.logger,
+                        Warning,
+                        "Error removing the pid file: {}",
+                        e
+                    );
+                }
+                log!(
+                    db.config.logger,
+                    Warning,
+                    "rsedis is now ready to exit, bye bye..."
+                );
             };
         } else {
+            self.handle_signals();
             self.start();
-            self.join();
+            self.wait_shutdown();
+            let db = &self.db;
+            log!(
+                db.config.logger,
+                Warning,
+                "rsedis is now ready to exit, bye bye..."
+            );
         }
     }
 
     #[cfg(not(unix))]
     pub fn run(&mut self) {
         let daemonize = {
-            let db = self.db.lock().unwrap();
+            let db = &self.db;
             db.config.daemonize
         };
         if daemonize {
             panic!("Cannot daemonize in non-unix");
         } else {
             self.start();
-            self.join();
+            self.wait_shutdown();
+            let db = &self.db;
+            log!(
+                db.config.logger,
+                Warning,
+                "rsedis is now ready to exit, bye bye..."
+            );
         }
     }
 
+    /// Blocks until the database is ready to shut down, and then stops the
+    /// server.
+    fn wait_shutdown(&mut self) {
+        let _ = self.shutdown_receiver.recv();
+        self.stop();
+    }
+
+    /// Creates a thread that shuts down the server on SIGTERM and SIGINT.
+    #[cfg(unix)]
+    fn handle_signals(&self) {
+        let db = self.db.clone();
+        let mut signals = match Signals::new([SIGTERM, SIGINT]) {
+            Ok(signals) => signals,
+            Err(e) => {
+                log!(
+                    db.config.logger,
+                    Warning,
+                    "Error setting signal handlers: {}",
+                    e
+                );
+                return;
+            }
+        };
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                let name = if signal == SIGINT {
+                    "SIGINT"
+                } else {
+                    "SIGTERM"
+                };
+                log!(
+                    db.config.logger,
+                    Warning,
+                    "Received {} scheduling shutdown...",
+                    name
+                );
+                // the final snapshot saves every database
+                let mut db = db.handle();
+                db.lock_all();
+                if !db.shutdown(None, false) {
+                    log!(
+                        db.config.logger,
+                        Warning,
+                        "{} received but errors trying to shut down the server, check the \
+                         logs for more information",
+                        name
+                    );
+                }
+            }
+        });
+    }
+
     #[cfg(windows)]
     fn reuse_address(&self, _: &TcpBuilder) -> io::Result<()> {
         Ok(())
@@ -502,7 +1042,7 @@ impl Server {
             let listener = builder.bind(addr)?.listen(tcp_backlog)?;
             self.listener_channels.push(tx);
             {
-                let db = self.db.lock().unwrap();
+                let db = &self.db;
                 let th = handle_listener!(
                     db.config.logger,
                     listener,
@@ -521,7 +1061,22 @@ impl Server {
     /// Starts threads listening to new connections.
     pub fn start(&mut self) {
         let (tcp_keepalive, timeout, addresses, tcp_backlog) = {
-            let db = self.db.lock().unwrap();
+            let mut db = self.db.handle();
+            if db.config.aclfile.is_some() {
+                if let Err(err) = command::acl::load(&mut db) {
+                    log_and_exit!(db.config.logger, Warning, 1, "{}", err);
+                }
+            }
+            if db.config.cluster_enabled {
+                if let Err(err) = command::cluster::load(&mut db) {
+                    log_and_exit!(db.config.logger, Warning, 1, "{}", err);
+                }
+            }
+            if db.config.sentinel_mode {
+                if let Err(err) = command::sentinel::load(&mut db) {
+                    log_and_exit!(db.config.logger, Warning, 1, "{}", err);
+                }
+            }
             (
                 db.config.tcp_keepalive,
                 db.config.timeout,
@@ -532,7 +1087,7 @@ impl Server {
         for (host, port) in addresses {
             match self.listen((&host[..], port), tcp_keepalive, timeout, tcp_backlog) {
                 Ok(_) => {
-                    let db = self.db.lock().unwrap();
+                    let db = &self.db;
                     log!(
                         db.config.logger,
                         Notice,
@@ -541,7 +1096,7 @@ impl Server {
                     );
                 }
                 Err(err) => {
-                    let db = self.db.lock().unwrap();
+                    let db = &self.db;
                     log!(
                         db.config.logger,
                         Warning,
@@ -556,31 +1111,202 @@ impl Server {
         }
 
         self.handle_unixsocket();
+        self.start_cluster(tcp_backlog);
+        self.start_sentinel();
 
         {
             let (hz_stop_tx, hz_stop_rx) = channel();
             self.hz_stop = Some(hz_stop_tx);
-            let dblock = self.db.clone();
+            let db = self.db.clone();
             thread::spawn(move || {
+                let mut db = db.handle();
                 while hz_stop_rx.try_recv().is_err() {
-                    let mut db = dblock.lock().unwrap();
-                    let hz = db.config.hz;
                     db.active_expire_cycle(10);
-                    drop(db);
-                    thread::sleep(Duration::from_millis(10000 / hz as u64));
+                    db.close_idle_migrate_sockets();
+                    thread::sleep(Duration::from_millis(10000 / db.config.hz as u64));
                 }
             });
         }
 
-        let mut db = self.db.lock().unwrap();
-        if db.aof.is_some() {
-            command::aof::load(&mut *db);
+        let mut db = self.db.handle();
+        // a sentinel has no data
+        if db.is_sentinel() {
+            return;
+        }
+        db.lock_all();
+        if db.aof().is_some() {
+            command::aof::load(&mut db);
+        } else {
+            let start = util::mstime();
+            db.set_loading(true);
+            match db.load() {
+                Ok(true) => log!(
+                    db.config.logger,
+                    Notice,
+                    "DB loaded from disk: {:.3} seconds",
+                    (util::mstime() - start) as f64 / 1000.0
+                ),
+                Ok(false) => (),
+                Err(err) => log_and_exit!(
+                    db.config.logger,
+                    Warning,
+                    1,
+                    "Fatal error loading the DB: {}. Exiting.",
+                    err
+                ),
+            }
+            db.set_loading(false);
         }
     }
 
+    /// Listens to the cluster bus and starts the thread that pings the other
+    /// nodes, if running in cluster mode.
+    fn start_cluster(&mut self, tcp_backlog: i32) {
+        let addresses = {
+            let db = self.db.handle();
+            if !db.is_cluster() {
+                return;
+            }
+            db.config.addresses()
+        };
+        for (host, port) in addresses {
+            let cport = port.wrapping_add(CLUSTER_PORT_INCR);
+            if let Err(err) = self.listen_cluster_bus((&host[..], cport), tcp_backlog) {
+                let db = &self.db;
+                log!(
+                    db.config.logger,
+                    Warning,
+                    "Creating the cluster bus listening socket {}:{}: {:?}",
+                    host,
+                    cport,
+                    err
+                );
+            }
+        }
+
+        let (cluster_stop_tx, cluster_stop_rx) = channel();
+        self.cluster_stop = Some(cluster_stop_tx);
+        let dblock = self.db.clone();
+        thread::spawn(move || {
+            while cluster_stop_rx.try_recv().is_err() {
+                let messages = {
+                    let mut db = dblock.handle();
+                    let messages = match db.cluster().as_mut() {
+                        Some(cluster) => cluster.cron(util::mstime()),
+                        None => vec![],
+                    };
+                    command::cluster::save_config(&mut db);
+                    messages
+                };
+                for (id, addr, message) in messages {
+                    let db = dblock.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = send_cluster_message(&db, &id, &addr, &message) {
+                            log!(
+                                db.config.logger,
+                                Verbose,
+                                "Sending cluster bus message to {}: {}",
+                                addr,
+                                err
+                            );
+                        }
+                    });
+                }
+                thread::sleep(Duration::from_millis(CLUSTER_CRON_INTERVAL));
+            }
+        });
+    }
+
+    /// Starts the thread that monitors the masters, if running as a sentinel.
+    fn start_sentinel(&mut self) {
+        if !self.db.handle().is_sentinel() {
+            return;
+        }
+        let (sentinel_stop_tx, sentinel_stop_rx) = channel();
+        self.sentinel_stop = Some(sentinel_stop_tx);
+        let dblock = self.db.clone();
+        thread::spawn(move || {
+            while sentinel_stop_rx.try_recv().is_err() {
+                let requests = {
+                    let mut db = dblock.handle();
+                    let requests = match db.sentinel().as_mut() {
+                        Some(sentinel) => sentinel.cron(util::mstime()),
+                        None => vec![],
+                    };
+                    command::sentinel::flush_events(&mut db);
+                    requests
+                };
+                for request in requests {
+                    let db = dblock.clone();
+                    thread::spawn(move || {
+                        let r = if request.is_subscription() {
+                            let r = sentinel_subscribe(&db, &request);
+                            if let Some(sentinel) = db.handle().sentinel().as_mut() {
+                                sentinel.subscription_closed(&request);
+                            }
+                            r
+                        } else {
+                            send_sentinel_request(&db, &request)
+                        };
+                        if let Err(err) = r {
+                            log!(
+                                db.config.logger,
+                                Verbose,
+                                "Sending {} to {}: {}",
+                                String::from_utf8_lossy(&request.command[0]),
+                                request.addr(),
+                                err
+                            );
+                        }
+                    });
+                }
+                thread::sleep(Duration::from_millis(SENTINEL_CRON_INTERVAL));
+            }
+        });
+    }
+
+    /// Listens to cluster bus messages from other nodes.
+    fn listen_cluster_bus<T: ToSocketAddrs>(&mut self, t: T, tcp_backlog: i32) -> io::Result<()> {
+        for addr in t.to_socket_addrs()? {
+            let builder = match addr {
+                SocketAddr::V4(_) => TcpBuilder::new_v4(),
+                SocketAddr::V6(_) => TcpBuilder::new_v6(),
+            }?;
+            self.reuse_address(&builder)?;
+            let listener = builder.bind(addr)?.listen(tcp_backlog)?;
+            let (tx, rx) = channel::<u8>();
+            self.listener_channels.push(tx);
+            let db = self.db.clone();
+            let th = thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if rx.try_recv().is_ok() {
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    let db = db.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = handle_cluster_connection(&db, stream) {
+                            log!(
+                                db.config.logger,
+                                Verbose,
+                                "Reading cluster bus message: {}",
+                                err
+                            );
+                        }
+                    });
+                }
+            });
+            self.listener_threads.push(th);
+        }
+        Ok(())
+    }
+
     #[cfg(unix)]
     fn handle_unixsocket(&mut self) {
-        let db = self.db.lock().unwrap();
+        let db = &self.db;
         if let Some(unixsocket) = &db.config.unixsocket {
             let tcp_keepalive = db.config.tcp_keepalive;
             let timeout = db.config.timeout;
@@ -615,7 +1341,7 @@ impl Server {
 
     #[cfg(not(unix))]
     fn handle_unixsocket(&mut self) {
-        let db = self.db.lock().unwrap();
+        let db = &self.db;
         if db.config.unixsocket.is_some() {
             let _ = writeln!(
                 &mut std::io::stderr(),
@@ -627,35 +1353,82 @@ impl Server {
     /// Sends a kill signal to the listeners and connects to the incoming
     /// connections to break the listening loop.
     pub fn stop(&mut self) {
-        for sender in self.listener_channels.iter() {
+        for sender in self.listener_channels.drain(..) {
             let _ = sender.send(0);
-            let db = self.db.lock().unwrap();
+        }
+        {
+            let db = self.db.handle();
             for (host, port) in db.config.addresses() {
                 for addrs in (&host[..], port).to_socket_addrs().unwrap() {
                     let _ = TcpStream::connect(addrs);
                 }
+                if db.is_cluster() {
+                    let cport = port.wrapping_add(CLUSTER_PORT_INCR);
+                    for addrs in (&host[..], cport).to_socket_addrs().unwrap() {
+                        let _ = TcpStream::connect(addrs);
+                    }
+                }
+            }
+            #[cfg(unix)]
+            {
+                if let Some(unixsocket) = &db.config.unixsocket {
+                    let _ = UnixStream::connect(unixsocket);
+                }
             }
         }
         if let Some(t) = &self.hz_stop {
             let _ = t.send(());
         }
+        if let Some(t) = &self.cluster_stop {
+            let _ = t.send(());
+        }
+        if let Some(t) = &self.sentinel_stop {
+            let _ = t.send(());
+        }
         self.join();
+        self.remove_unixsocket();
+    }
+
+    #[cfg(unix)]
+    fn remove_unixsocket(&self) {
+        let db = &self.db;
+        if let Some(unixsocket) = &db.config.unixsocket {
+            log!(db.config.logger, Notice, "Removing the unix socket file.");
+            if let Err(e) = remove_file(unixsocket) {
+                log!(
+                    db.config.logger,
+                    Warning,
+                    "Error removing the unix socket file: {}",
+                    e
+                );
+            }
+        }
     }
+
+    #[cfg(not(unix))]
+    fn remove_unixsocket(&self) {}
 }
 
 #[cfg(test)]
 mod test_networking {
 
+    use std::env::temp_dir;
     use std::io::{Read, Write};
     use std::net::TcpStream;
     use std::str::from_utf8;
+    use std::sync::atomic::Ordering;
     use std::thread;
 
     use config::Config;
     use logger::{Level, Logger};
 
-    use super::Server;
-    use std::time::Duration;
+    use super::{execute, ClientGuard, Server};
+    use parser::parse;
+    use response::Response;
+    use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+    use std::time::{Duration, Instant};
+
+    use database::SharedDatabase;
 
     #[test]
     fn parse_ping() {
@@ -737,10 +1510,214 @@ mod test_networking {
         let addr = format!("127.0.0.1:{}", port);
         let _ = TcpStream::connect(&*addr);
         thread::sleep(Duration::from_millis(100));
-        assert_eq!(*server.next_id.lock().unwrap(), 1);
+        assert_eq!(server.next_id.load(Ordering::Relaxed), 1);
         let _ = TcpStream::connect(&*addr);
         thread::sleep(Duration::from_millis(100));
-        assert_eq!(*server.next_id.lock().unwrap(), 2);
+        assert_eq!(server.next_id.load(Ordering::Relaxed), 2);
+        server.stop();
+    }
+
+    #[test]
+    fn pipeline() {
+        let port = 16383;
+        let mut server = Server::new(Config::default(port, Logger::new(Level::Warning)));
+        server.start();
+
+        let addr = format!("127.0.0.1:{}", port);
+        let mut stream = TcpStream::connect(&*addr).unwrap();
+        let mut message = vec![];
+        for _ in 0..2500 {
+            message.extend_from_slice(b"*1\r\n$4\r\nping\r\n");
+        }
+        message.extend_from_slice(b"echo \"hello world\"\r\n");
+        assert!(stream.write_all(&message).is_ok());
+
+        let mut expected = vec![];
+        for _ in 0..2500 {
+            expected.extend_from_slice(b"+PONG\r\n");
+        }
+        expected.extend_from_slice(b"$11\r\nhello world\r\n");
+        let mut received = vec![0u8; expected.len()];
+        assert!(stream.read_exact(&mut received).is_ok());
+        assert_eq!(received, expected);
+        server.stop();
+    }
+
+    #[test]
+    fn shutdown() {
+        let port = 16384;
+        let mut server = Server::new(Config::default(port, Logger::new(Level::Warning)));
+        server.start();
+
+        let addr = format!("127.0.0.1:{}", port);
+        let mut stream = TcpStream::connect(&*addr).unwrap();
+        assert!(stream
+            .write_all(b"shutdown abort\r\nshutdown nosave\r\n")
+            .is_ok());
+        let mut h = [0u8; 31];
+        assert!(stream.read_exact(&mut h).is_ok());
+        assert_eq!(from_utf8(&h).unwrap(), "-ERR No shutdown in progress.\r\n");
+        server.wait_shutdown();
+        assert!(TcpStream::connect(&*addr).is_err());
+    }
+
+    #[test]
+    fn unsubscribe_on_disconnect() {
+        let port = 16386;
+        let mut server = Server::new(Config::default(port, Logger::new(Level::Warning)));
+        server.start();
+
+        let addr = format!("127.0.0.1:{}", port);
+        {
+            let mut stream = TcpStream::connect(&*addr).unwrap();
+            assert!(stream
+                .write_all(b"subscribe channel\r\npsubscribe pattern*\r\nssubscribe shard\r\n")
+                .is_ok());
+            let mut h = [0u8; 111];
+            assert!(stream.read_exact(&mut h).is_ok());
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let mut stream = TcpStream::connect(&*addr).unwrap();
+        assert!(stream
+            .write_all(b"pubsub numpat\r\npubsub numsub channel\r\npubsub shardnumsub shard\r\n")
+            .is_ok());
+        let mut h = [0u8; 44];
+        assert!(stream.read_exact(&mut h).is_ok());
+        assert_eq!(
+            from_utf8(&h).unwrap(),
+            ":0\r\n*2\r\n$7\r\nchannel\r\n:0\r\n*2\r\n$5\r\nshard\r\n:0\r\n"
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn client_guard_on_panic() {
+        let db = SharedDatabase::new(Config::new(Logger::new(Level::Warning)));
+        let (tx, rx) = channel();
+        db.handle().tracking().connect(1, tx.clone());
+        let guard = ClientGuard {
+            db: db.clone(),
+            client: command::Client::new(tx, 1),
+        };
+        assert!(thread::spawn(move || {
+            let _guard = guard;
+            panic!("client thread died");
+        })
+        .join()
+        .is_err());
+        // no sender is left, the writer thread would stop
+        assert!(rx.recv().is_err());
+    }
+
+    /// Runs `message` in its own thread and handle, like a client would.
+    fn execute_in_thread(db: &SharedDatabase, message: &'static [u8]) -> Receiver<Response> {
+        let db = db.clone();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut handle = db.handle();
+            let parsed = parse(message).unwrap().0;
+            let response = execute(parsed, &mut handle, &mut command::Client::mock());
+            tx.send(response.unwrap()).unwrap();
+        });
+        rx
+    }
+
+    #[test]
+    fn read_only_commands_run_concurrently() {
+        let db = SharedDatabase::new(Config::new(Logger::new(Level::Warning)));
+        let mut reader = db.handle();
+        reader.lock(&[0]);
+        reader.get_or_create(0, b"key").set(b"1".to_vec()).unwrap();
+        reader.lock_read(&[0]);
+
+        // another reader does not wait for the database to be released
+        let get = execute_in_thread(&db, b"get key\r\n");
+        assert_eq!(
+            get.recv_timeout(Duration::from_secs(10)).unwrap(),
+            Response::Data(b"1".to_vec())
+        );
+
+        // a writer does
+        let set = execute_in_thread(&db, b"set key 2\r\n");
+        assert_eq!(
+            set.recv_timeout(Duration::from_millis(100)).unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+        reader.unlock();
+        assert_eq!(
+            set.recv_timeout(Duration::from_secs(10)).unwrap(),
+            Response::Status("OK".to_owned())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn remove_unixsocket() {
+        let port = 16385;
+        let mut path = temp_dir();
+        path.push(format!("rsedis-{}.sock", port));
+        let mut config = Config::default(port, Logger::new(Level::Warning));
+        config.unixsocket = Some(path.to_str().unwrap().to_owned());
+        let mut server = Server::new(config);
+        server.start();
+        assert!(path.exists());
+        server.stop();
+        assert!(!path.exists());
+    }
+
+    /// Measures how many LRANGE commands per second several clients get
+    /// through. It is ignored by default, run it with
+    /// `cargo test --release -p networking read_throughput -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn read_throughput() {
+        let port = 16387;
+        let mut server = Server::new(Config::default(port, Logger::new(Level::Warning)));
+        server.start();
+        let addr = format!("127.0.0.1:{}", port);
+
+        let mut push = b"*1002\r\n$5\r\nrpush\r\n$1\r\nl\r\n".to_vec();
+        for _ in 0..1000 {
+            push.extend_from_slice(b"$1\r\nx\r\n");
+        }
+        let mut stream = TcpStream::connect(&*addr).unwrap();
+        stream.write_all(&push).unwrap();
+        let mut reply = [0u8; 7];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b":1000\r\n");
+
+        let duration = Duration::from_secs(2);
+        for &clients in &[1, 2, 4, 8, 16] {
+            let threads = (0..clients)
+                .map(|_| {
+                    let addr = addr.clone();
+                    thread::spawn(move || {
+                        let mut stream = TcpStream::connect(&*addr).unwrap();
+                        // "*1000\r\n" followed by 1000 "$1\r\nx\r\n"
+                        let mut reply = vec![0u8; 7007];
+                        let start = Instant::now();
+                        let mut requests = 0;
+                        while start.elapsed() < duration {
+                            stream
+                                .write_all(
+                                    b"*4\r\n$6\r\nlrange\r\n$1\r\nl\r\n$1\r\n0\r\n$2\r\n-1\r\n",
+                                )
+                                .unwrap();
+                            stream.read_exact(&mut reply).unwrap();
+                            requests += 1;
+                        }
+                        requests
+                    })
+                })
+                .collect::<Vec<_>>();
+            let requests: u64 = threads.into_iter().map(|t| t.join().unwrap()).sum();
+            println!(
+                "{} clients: {} requests per second",
+                clients,
+                requests / duration.as_secs()
+            );
+        }
         server.stop();
     }
 }
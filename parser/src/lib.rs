@@ -1,14 +1,36 @@
 extern crate util;
 
+use std::borrow::Cow;
+use std::cmp::{max, min};
 use std::collections::Bound;
 use std::error::Error;
 use std::f64::{INFINITY, NEG_INFINITY};
 use std::fmt;
-use std::iter;
+use std::mem;
 use std::num::{ParseFloatError, ParseIntError};
 use std::str::{from_utf8, Utf8Error};
 
-use util::format_repr;
+use util::{format_repr, splitargs};
+
+/// Maximum length of an inline command line, excluding the line terminator
+pub const INLINE_MAX_SIZE: usize = 64 * 1024;
+
+/// Default maximum length of a bulk argument
+pub const PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Default maximum number of bytes buffered for an incomplete command
+pub const CLIENT_QUERY_BUFFER_LIMIT: usize = 1024 * 1024 * 1024;
+
+/// Maximum number of arguments in a multibulk command
+const PROTO_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+/// Minimum free space in the stream parser buffer before reading
+const PROTO_READ_LEN: usize = 16 * 1024;
+
+/// Maximum space reserved ahead of the data for a bulk that is still
+/// missing. Bigger bulks grow the buffer as they arrive, so announcing a
+/// length does not allocate it.
+const PROTO_MBULK_BIG_ARG: usize = 32 * 1024;
 
 /// A command argument
 #[derive(Debug, Clone)]
@@ -21,8 +43,9 @@ pub struct Argument {
 
 /// A protocol parser
 pub struct ParsedCommand<'a> {
-    /// The data itself
-    data: &'a [u8],
+    /// The data itself. Inline commands own an unescaped copy of their
+    /// arguments, multibulk commands borrow the input buffer.
+    data: Cow<'a, [u8]>,
     /// The arguments location and length
     pub argv: Vec<Argument>,
 }
@@ -42,6 +65,8 @@ pub enum ParseError {
     BadProtocol(String),
     /// Expected one type of argument and received another
     InvalidArgument,
+    /// The client buffered more data than allowed without completing a command
+    QueryBufferLimit,
 }
 
 impl ParseError {
@@ -57,6 +82,7 @@ impl ParseError {
             ParseError::Incomplete => "Incomplete data".to_owned(),
             ParseError::BadProtocol(ref s) => format!("Protocol error: {}", s),
             ParseError::InvalidArgument => "Invalid argument".to_owned(),
+            ParseError::QueryBufferLimit => "Query buffer limit reached".to_owned(),
         }
     }
 }
@@ -73,6 +99,7 @@ impl Error for ParseError {
             ParseError::Incomplete => "Incomplete data",
             ParseError::BadProtocol(_) => "Protocol error",
             ParseError::InvalidArgument => "Invalid argument",
+            ParseError::QueryBufferLimit => "Query buffer limit reached",
         }
     }
 
@@ -107,15 +134,44 @@ impl OwnedParsedCommand {
         }
     }
 
-    pub fn get_command(&self) -> ParsedCommand {
+    pub fn get_command(&self) -> ParsedCommand<'_> {
         ParsedCommand::new(&*self.data, self.argv.clone())
     }
 }
 
 impl<'a> ParsedCommand<'a> {
     /// Creates a new parser with the data and arguments provided
-    pub fn new(data: &[u8], argv: Vec<Argument>) -> ParsedCommand {
-        ParsedCommand { data, argv }
+    pub fn new(data: &[u8], argv: Vec<Argument>) -> ParsedCommand<'_> {
+        ParsedCommand {
+            data: Cow::Borrowed(data),
+            argv,
+        }
+    }
+
+    /// Creates a new parser owning a list of arguments
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use parser::ParsedCommand;
+    /// let parser = ParsedCommand::from_args(vec![b"GET".to_vec(), b"key".to_vec()]);
+    /// assert_eq!(parser.get_str(0).unwrap(), "GET");
+    /// assert_eq!(parser.get_str(1).unwrap(), "key");
+    /// ```
+    pub fn from_args(args: Vec<Vec<u8>>) -> ParsedCommand<'static> {
+        let mut data = Vec::with_capacity(args.iter().map(|a| a.len()).sum());
+        let mut argv = Vec::with_capacity(args.len());
+        for arg in args {
+            argv.push(Argument {
+                pos: data.len(),
+                len: arg.len(),
+            });
+            data.extend(arg);
+        }
+        ParsedCommand {
+            data: Cow::Owned(data),
+            argv,
+        }
     }
 
     /// Gets a `Bound` from a parameter.
@@ -251,16 +307,31 @@ impl<'a> ParsedCommand<'a> {
         Ok(&self.data[arg.pos..arg.pos + arg.len])
     }
 
-    pub fn get_data(&self) -> &'a [u8] {
-        self.data
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
     }
 
     pub fn into_owned(self) -> OwnedParsedCommand {
-        OwnedParsedCommand::new(Vec::from(self.data), self.argv)
+        match self.data {
+            Cow::Owned(data) => OwnedParsedCommand::new(data, self.argv),
+            Cow::Borrowed(_) => self.to_owned(),
+        }
     }
 
+    /// Copies the arguments, and only the arguments, into an
+    /// `OwnedParsedCommand`.
     pub fn to_owned(&self) -> OwnedParsedCommand {
-        OwnedParsedCommand::new(Vec::from(self.data), self.argv.clone())
+        let mut data = Vec::with_capacity(self.argv.iter().map(|a| a.len).sum());
+        let argv = self
+            .argv
+            .iter()
+            .map(|a| {
+                let pos = data.len();
+                data.extend_from_slice(&self.data[a.pos..a.pos + a.len]);
+                Argument { pos, len: a.len }
+            })
+            .collect();
+        OwnedParsedCommand::new(data, argv)
     }
 }
 
@@ -274,51 +345,251 @@ impl<'a> fmt::Debug for ParsedCommand<'a> {
     }
 }
 
-/// Parses the length of the paramenter in the slice
-/// Upon success, it returns a tuple with the length of the argument and the
-/// length of the parsed length.
-fn parse_int(input: &[u8], len: usize, name: &str) -> Result<(Option<usize>, usize), ParseError> {
-    if input.is_empty() {
-        return Err(ParseError::Incomplete);
-    }
-    let mut i = 0;
-    let mut argc = 0;
-    let mut argco = None;
-    while input[i] as char != '\r' {
-        let c = input[i] as char;
-        if argc == 0 && c == '-' {
-            while input[i] as char != '\r' {
-                i += 1;
+/// The step the parser is at in the command being received
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Waiting for the first byte of a command
+    Start,
+    /// Looking for the end of an inline command line
+    Inline,
+    /// Reading the number of arguments of a multibulk command
+    MultibulkLength,
+    /// Reading the length of the next bulk argument
+    BulkLength,
+    /// Waiting for a bulk argument of the given length
+    Bulk(usize),
+}
+
+/// A command found by `Progress::advance`
+enum Parsed {
+    /// A multibulk command, its start and end in the buffer and its arguments
+    /// relative to the start
+    Multibulk(usize, usize, Vec<Argument>),
+    /// An inline command, with its unescaped arguments
+    Inline(Vec<Vec<u8>>),
+}
+
+/// Partial progress parsing a buffer. Every byte before `cursor` has already
+/// been consumed and is not scanned again when more data arrives.
+struct Progress {
+    /// Start of the command being parsed
+    position: usize,
+    /// First byte not consumed yet
+    cursor: usize,
+    state: State,
+    /// Arguments still expected by the current multibulk command
+    pending: usize,
+    /// Arguments parsed so far, relative to `position`
+    argv: Vec<Argument>,
+    /// How far `read_length` already looked for the end of the line
+    scanned: usize,
+    allow_inline: bool,
+    max_bulk_len: usize,
+}
+
+impl Progress {
+    fn new(allow_inline: bool) -> Progress {
+        Progress {
+            position: 0,
+            cursor: 0,
+            state: State::Start,
+            pending: 0,
+            argv: vec![],
+            scanned: 0,
+            allow_inline,
+            max_bulk_len: PROTO_MAX_BULK_LEN,
+        }
+    }
+
+    /// Moves the positions back after the buffer was shifted `n` bytes.
+    fn shift(&mut self, n: usize) {
+        self.position -= n;
+        self.cursor -= n;
+        self.scanned = self.scanned.saturating_sub(n);
+    }
+
+    /// Number of bytes that are known to be missing to complete the
+    /// current argument.
+    fn missing(&self, written: usize) -> usize {
+        match self.state {
+            State::Bulk(len) => (self.cursor + len + 2).saturating_sub(written),
+            _ => 0,
+        }
+    }
+
+    /// Reads a "\r\n" terminated length, starting `skip` bytes after the
+    /// cursor. Negative lengths are returned as `None`.
+    fn read_length(
+        &mut self,
+        data: &[u8],
+        skip: usize,
+        name: &str,
+    ) -> Result<Option<usize>, ParseError> {
+        let start = self.cursor + skip;
+        let from = max(start, self.scanned);
+        let cr = match data[from..].iter().position(|&c| c == b'\r') {
+            Some(p) => from + p,
+            None => {
+                self.scanned = data.len();
+                if data.len() - start > INLINE_MAX_SIZE {
+                    return Err(ParseError::BadProtocol(format!(
+                        "too big {} count string",
+                        name
+                    )));
+                }
+                return Err(ParseError::Incomplete);
             }
-            argco = None;
-            break;
-        } else if c < '0' || c > '9' {
-            return Err(ParseError::BadProtocol(format!("invalid {} length", name)));
-        }
-        argc *= 10;
-        argc += input[i] as usize - '0' as usize;
-        i += 1;
-        if i == len {
+        };
+        if cr + 1 == data.len() {
+            self.scanned = cr;
             return Err(ParseError::Incomplete);
         }
-        argco = Some(argc);
-    }
-    i += 1;
-    if i == len {
-        return Err(ParseError::Incomplete);
+        if data[cr + 1] != b'\n' {
+            return Err(ParseError::BadProtocol(format!(
+                "expected \\r\\n separator, got \\r{}",
+                data[cr + 1] as char
+            )));
+        }
+        let len = match from_utf8(&data[start..cr])
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+        {
+            Some(len) => len,
+            None => return Err(ParseError::BadProtocol(format!("invalid {} length", name))),
+        };
+        self.cursor = cr + 2;
+        Ok(if len < 0 { None } else { Some(len as usize) })
     }
-    if input[i] as char != '\n' {
-        return Err(ParseError::BadProtocol(format!(
-            "expected \\r\\n separator, got \\r{}",
-            input[i] as char
-        )));
+
+    /// Consumes as much of `data` as possible, returning the next command
+    /// once it is complete.
+    fn advance(&mut self, data: &[u8]) -> Result<Parsed, ParseError> {
+        loop {
+            match self.state {
+                State::Start => {
+                    while self.cursor < data.len()
+                        && (data[self.cursor] == b'\r' || data[self.cursor] == b'\n')
+                    {
+                        self.cursor += 1;
+                    }
+                    self.position = self.cursor;
+                    if self.cursor == data.len() {
+                        return Err(ParseError::Incomplete);
+                    }
+                    if data[self.cursor] == b'*' {
+                        self.state = State::MultibulkLength;
+                    } else if self.allow_inline && !is_reply_type(data[self.cursor]) {
+                        self.state = State::Inline;
+                    } else {
+                        return Err(ParseError::BadProtocol(format!(
+                            "expected '*', got '{}'",
+                            data[self.cursor] as char
+                        )));
+                    }
+                }
+                State::Inline => {
+                    let newline = match data[self.cursor..].iter().position(|&c| c == b'\n') {
+                        Some(p) => self.cursor + p,
+                        None => {
+                            self.cursor = data.len();
+                            if self.cursor - self.position > INLINE_MAX_SIZE {
+                                return Err(ParseError::BadProtocol(
+                                    "too big inline request".to_owned(),
+                                ));
+                            }
+                            return Err(ParseError::Incomplete);
+                        }
+                    };
+                    if newline - self.position > INLINE_MAX_SIZE {
+                        return Err(ParseError::BadProtocol("too big inline request".to_owned()));
+                    }
+                    let mut line = &data[self.position..newline];
+                    if line.last() == Some(&b'\r') {
+                        line = &line[..line.len() - 1];
+                    }
+                    let args = match splitargs(line) {
+                        Ok(args) => args,
+                        Err(_) => {
+                            return Err(ParseError::BadProtocol(
+                                "unbalanced quotes in request".to_owned(),
+                            ))
+                        }
+                    };
+                    self.cursor = newline + 1;
+                    self.position = self.cursor;
+                    self.state = State::Start;
+                    // blank lines are ignored
+                    if !args.is_empty() {
+                        return Ok(Parsed::Inline(args));
+                    }
+                }
+                State::MultibulkLength => {
+                    let argc = self.read_length(data, 1, "multibulk")?.unwrap_or(0);
+                    if argc > PROTO_MAX_MULTIBULK_LEN {
+                        return Err(ParseError::BadProtocol(
+                            "invalid multibulk length".to_owned(),
+                        ));
+                    }
+                    self.pending = argc;
+                    self.argv = Vec::with_capacity(argc);
+                    self.state = State::BulkLength;
+                }
+                State::BulkLength => {
+                    if self.pending == 0 {
+                        let start = self.position;
+                        self.position = self.cursor;
+                        self.state = State::Start;
+                        let argv = mem::take(&mut self.argv);
+                        return Ok(Parsed::Multibulk(start, self.cursor, argv));
+                    }
+                    if self.cursor == data.len() {
+                        return Err(ParseError::Incomplete);
+                    }
+                    if data[self.cursor] != b'$' {
+                        return Err(ParseError::BadProtocol(format!(
+                            "expected '$', got '{}'",
+                            data[self.cursor] as char
+                        )));
+                    }
+                    let len = match self.read_length(data, 1, "bulk")? {
+                        Some(len) if len <= self.max_bulk_len => len,
+                        _ => return Err(ParseError::BadProtocol("invalid bulk length".to_owned())),
+                    };
+                    self.state = State::Bulk(len);
+                }
+                State::Bulk(len) => {
+                    if data.len() - self.cursor < len + 2 {
+                        return Err(ParseError::Incomplete);
+                    }
+                    if &data[self.cursor + len..self.cursor + len + 2] != b"\r\n" {
+                        return Err(ParseError::BadProtocol(
+                            "expected \\r\\n after bulk argument".to_owned(),
+                        ));
+                    }
+                    self.argv.push(Argument {
+                        pos: self.cursor - self.position,
+                        len,
+                    });
+                    self.cursor += len + 2;
+                    self.pending -= 1;
+                    self.state = State::BulkLength;
+                }
+            }
+        }
     }
+}
 
-    Ok((argco, i + 1))
+/// Whether `c` starts a reply. A line starting with it is not taken as an
+/// inline command.
+fn is_reply_type(c: u8) -> bool {
+    c == b'+' || c == b'-' || c == b':' || c == b'$'
 }
 
 /// Creates a parser from a buffer.
 ///
+/// Input starting with '*' is parsed as a multibulk command, anything else
+/// is treated as an inline command.
+///
 /// # Examples
 ///
 /// ```
@@ -330,80 +601,55 @@ fn parse_int(input: &[u8], len: usize, name: &str) -> Result<(Option<usize>, usi
 /// assert_eq!(parser.get_str(1).unwrap(), "mykey");
 /// assert_eq!(parser.get_i64(2).unwrap(), 10);
 /// ```
-pub fn parse(input: &[u8]) -> Result<(ParsedCommand, usize), ParseError> {
-    let mut pos = 0;
-    while input.len() > pos && input[pos] as char == '\r' {
-        if pos + 1 < input.len() {
-            if input[pos + 1] as char != '\n' {
-                return Err(ParseError::BadProtocol(format!(
-                    "expected \\r\\n separator, got \
-                     \\r{}",
-                    input[pos + 1] as char
-                )));
-            }
-            pos += 2;
-        } else {
-            return Err(ParseError::Incomplete);
-        }
-    }
-    if pos >= input.len() {
-        return Err(ParseError::Incomplete);
-    }
-    if input[pos] as char != '*' {
-        return Err(ParseError::BadProtocol(format!(
-            "expected '*', got '{}'",
-            input[pos] as char
-        )));
-    }
-    pos += 1;
-    let len = input.len();
-    let (argco, intlen) = parse_int(&input[pos..len], len - pos, "multibulk")?;
-    let argc = match argco {
-        Some(i) => i,
-        None => 0,
-    };
-    pos += intlen;
-    if argc > 1024 * 1024 {
-        return Err(ParseError::BadProtocol(
-            "invalid multibulk length".to_owned(),
-        ));
-    }
-    let mut argv = Vec::new();
-    for i in 0..argc {
-        if input.len() == pos {
-            return Err(ParseError::Incomplete);
-        }
-        if input[pos] as char != '$' {
-            return Err(ParseError::BadProtocol(format!(
-                "expected '$', got '{}'",
-                input[pos] as char
-            )));
-        }
-        pos += 1;
-        let (argleno, arglenlen) = parse_int(&input[pos..len], len - pos, "bulk")?;
-        let arglen = match argleno {
-            Some(i) => i,
-            None => return Err(ParseError::BadProtocol("invalid bulk length".to_owned())),
-        };
-        if arglen > 512 * 1024 * 1024 {
-            return Err(ParseError::BadProtocol("invalid bulk length".to_owned()));
-        }
-        pos += arglenlen;
-        let arg = Argument { pos, len: arglen };
-        argv.push(arg);
-        pos += arglen + 2;
-        if pos > len || (pos == len && i != argc - 1) {
-            return Err(ParseError::Incomplete);
-        }
-    }
-    Ok((ParsedCommand::new(input, argv), pos))
+///
+/// ```
+/// # use parser::parse;
+/// let s = b"SET mykey \"hello world\"\r\n";
+/// let (parser, len) = parse(s).unwrap();
+/// assert_eq!(len, 25);
+/// assert_eq!(parser.get_str(0).unwrap(), "SET");
+/// assert_eq!(parser.get_str(1).unwrap(), "mykey");
+/// assert_eq!(parser.get_str(2).unwrap(), "hello world");
+/// ```
+pub fn parse(input: &[u8]) -> Result<(ParsedCommand<'_>, usize), ParseError> {
+    parse_command(input, true)
 }
 
-/// A stream parser
+/// Creates a parser from a buffer, rejecting inline commands.
+///
+/// # Examples
+///
+/// ```
+/// # use parser::{parse_multibulk, ParseError};
+/// assert!(parse_multibulk(b"*1\r\n$4\r\nPING\r\n").is_ok());
+/// match parse_multibulk(b"PING\r\n") {
+///     Err(ParseError::BadProtocol(_)) => {}
+///     _ => panic!("inline command should be rejected"),
+/// }
+/// ```
+pub fn parse_multibulk(input: &[u8]) -> Result<(ParsedCommand<'_>, usize), ParseError> {
+    parse_command(input, false)
+}
+
+fn parse_command(
+    input: &[u8],
+    allow_inline: bool,
+) -> Result<(ParsedCommand<'_>, usize), ParseError> {
+    let mut progress = Progress::new(allow_inline);
+    Ok(match progress.advance(input)? {
+        Parsed::Multibulk(start, end, argv) => (ParsedCommand::new(&input[start..end], argv), end),
+        Parsed::Inline(args) => (ParsedCommand::from_args(args), progress.position),
+    })
+}
+
+/// A stream parser. It keeps the progress on partially received commands
+/// across reads, and hands out commands borrowing its buffer.
 pub struct Parser {
     data: Vec<u8>,
-    pub position: usize,
     pub written: usize,
+    progress: Progress,
+    /// Maximum number of bytes buffered for a single incomplete command
+    max_query_buffer: usize,
 }
 
 impl Default for Parser {
@@ -416,62 +662,110 @@ impl Parser {
     pub fn new() -> Parser {
         Parser {
             data: vec![],
-            position: 0,
             written: 0,
+            progress: Progress::new(true),
+            max_query_buffer: CLIENT_QUERY_BUFFER_LIMIT,
         }
     }
 
+    /// Creates a stream parser that only accepts multibulk commands.
+    pub fn multibulk() -> Parser {
+        Parser {
+            progress: Progress::new(false),
+            ..Parser::new()
+        }
+    }
+
+    /// Sets the maximum length of a bulk argument.
+    pub fn set_max_bulk_len(&mut self, max_bulk_len: usize) {
+        self.progress.max_bulk_len = max_bulk_len;
+    }
+
+    /// Sets the maximum number of bytes buffered for an incomplete command.
+    pub fn set_max_query_buffer(&mut self, max_query_buffer: usize) {
+        self.max_query_buffer = max_query_buffer;
+    }
+
+    /// Start of the first command that has not been returned yet.
+    pub fn position(&self) -> usize {
+        self.progress.position
+    }
+
+    /// Makes room in the buffer for the next read, moving pending data to the
+    /// start of the buffer before growing it.
     pub fn allocate(&mut self) {
-        if self.position > 0 && self.written == self.position {
+        if self.progress.position == self.written && self.progress.cursor == self.written {
+            self.progress.shift(self.written);
             self.written = 0;
-            self.position = 0;
         }
 
-        let len = self.data.len();
-        let add = if len == 0 {
-            16
-        } else if self.written * 2 > len {
-            len
-        } else {
-            0
-        };
+        let missing = min(self.progress.missing(self.written), PROTO_MBULK_BIG_ARG);
+        let wanted = max(missing, PROTO_READ_LEN);
+        if self.data.len() - self.written >= wanted {
+            return;
+        }
 
-        if add > 0 {
-            self.data.extend(iter::repeat(0).take(add));
+        let position = self.progress.position;
+        if position > 0 {
+            self.data.copy_within(position..self.written, 0);
+            self.written -= position;
+            self.progress.shift(position);
+            if self.data.len() - self.written >= wanted {
+                return;
+            }
         }
+
+        let len = max(self.data.len() * 2, self.written + wanted);
+        self.data.resize(len, 0);
     }
 
     pub fn get_mut(&mut self) -> &mut Vec<u8> {
         &mut self.data
     }
 
-    pub fn is_incomplete(&self) -> bool {
-        let data = &(&*self.data)[self.position..self.written];
-        match parse(data) {
-            Ok(_) => false,
-            Err(e) => e.is_incomplete(),
-        }
-    }
-
     #[allow(clippy::should_implement_trait)]
-    pub fn next(&mut self) -> Result<ParsedCommand, ParseError> {
-        let data = &(&*self.data)[self.position..self.written];
-        let (r, len) = parse(data)?;
-        self.position += len;
-        Ok(r)
+    pub fn next(&mut self) -> Result<ParsedCommand<'_>, ParseError> {
+        let data = &(&*self.data)[..self.written];
+        match self.progress.advance(data) {
+            Ok(Parsed::Multibulk(start, end, argv)) => {
+                Ok(ParsedCommand::new(&data[start..end], argv))
+            }
+            Ok(Parsed::Inline(args)) => Ok(ParsedCommand::from_args(args)),
+            Err(ParseError::Incomplete) => {
+                if self.written - self.progress.position > self.max_query_buffer {
+                    Err(ParseError::QueryBufferLimit)
+                } else {
+                    Err(ParseError::Incomplete)
+                }
+            }
+            Err(e) => Err(e),
+        }
     }
 }
 
 impl fmt::Debug for Parser {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         f.write_str("Parser: ")?;
-        format_repr(f, &(&*self.data)[self.position..self.written])
+        format_repr(f, &(&*self.data)[self.progress.position..self.written])
     }
 }
 
 #[cfg(test)]
 mod test_parser {
-    use super::{parse, ParseError, Parser};
+    use std::cmp::min;
+
+    use super::{parse, parse_multibulk, ParseError, Parser, INLINE_MAX_SIZE};
+
+    fn feed(parser: &mut Parser, mut message: &[u8]) {
+        while !message.is_empty() {
+            parser.allocate();
+            let written = parser.written;
+            let len = min(parser.get_mut().len() - written, message.len());
+            parser.get_mut()[written..written + len].copy_from_slice(&message[..len]);
+            parser.written += len;
+            message = &message[len..];
+        }
+    }
 
     #[test]
     fn parse_valid() {
@@ -507,13 +801,82 @@ mod test_parser {
         }
     }
 
+    #[test]
+    fn parse_multibulk_invalid() {
+        let message = b"-2\r\n$3\r\nfoo";
+        match parse_multibulk(message).unwrap_err() {
+            ParseError::BadProtocol(_) => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn parse_bulk_without_terminator() {
+        match parse(b"*1\r\n$3\r\nfooXY").unwrap_err() {
+            ParseError::BadProtocol(_) => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn parse_inline() {
+        let message = b"set 'my key' \"\\x41b\"\r\n";
+        let (command, len) = parse(message).unwrap();
+        assert_eq!(len, message.len());
+        assert_eq!(command.argv.len(), 3);
+        assert_eq!(command.get_str(0).unwrap(), "set");
+        assert_eq!(command.get_str(1).unwrap(), "my key");
+        assert_eq!(command.get_str(2).unwrap(), "Ab");
+    }
+
+    #[test]
+    fn parse_inline_newline_only() {
+        let (command, len) = parse(b"ping\nping\n").unwrap();
+        assert_eq!(len, 5);
+        assert_eq!(command.get_str(0).unwrap(), "ping");
+    }
+
+    #[test]
+    fn parse_inline_incomplete() {
+        assert_eq!(parse(b"get fo").unwrap_err(), ParseError::Incomplete);
+    }
+
+    #[test]
+    fn parse_inline_unbalanced_quotes() {
+        match parse(b"get \"foo\r\n").unwrap_err() {
+            ParseError::BadProtocol(_) => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn parse_inline_too_big() {
+        let message = vec![b'a'; INLINE_MAX_SIZE + 1];
+        match parse(&message).unwrap_err() {
+            ParseError::BadProtocol(_) => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn parser_multibulk_rejects_inline() {
+        let mut parser = Parser::multibulk();
+        let message = b"ping\r\n";
+        parser.written += message.len();
+        parser.get_mut().extend(&*message.to_vec());
+        match parser.next().unwrap_err() {
+            ParseError::BadProtocol(_) => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
     #[test]
     fn parser_basic() {
         let mut parser = Parser::new();
         {
             let message = b"*2\r\n$3\r\nfoo\r\n$4\r\nbarz\r\n";
             parser.written += message.len();
-            let mut v = parser.get_mut();
+            let v = parser.get_mut();
             v.extend(&*message.to_vec());
         }
         {
@@ -544,7 +907,7 @@ mod test_parser {
         {
             let message = b"\r\n\r\n*1\r\n$3\r\nfoo\r\n";
             parser.written += message.len();
-            let mut v = parser.get_mut();
+            let v = parser.get_mut();
             v.extend(&*message.to_vec());
         }
 
@@ -562,7 +925,7 @@ mod test_parser {
             let message = b"*2\r\n$3\r\nfoo\r\n$4\r\nbarz\r\n";
             parser.written += message.len();
             parser.written += message.len();
-            let mut v = parser.get_mut();
+            let v = parser.get_mut();
             v.extend(&*message.to_vec());
             v.extend(&*message.to_vec());
         }
@@ -577,26 +940,123 @@ mod test_parser {
         let message = b"*2\r\n$3\r\nfoo\r\n$4\r\nbarz\r\n";
         {
             parser.written += message.len();
-            let mut v = parser.get_mut();
+            let v = parser.get_mut();
             v.extend(&*message.to_vec());
         }
         parser.next().unwrap();
         {
             parser.written += message.len();
-            let mut v = parser.get_mut();
+            let v = parser.get_mut();
             v.extend(&*message.to_vec());
         }
         parser.next().unwrap();
         assert_eq!(parser.next().unwrap_err(), ParseError::Incomplete);
     }
 
+    #[test]
+    fn parser_byte_by_byte() {
+        let mut parser = Parser::new();
+        let message = b"*2\r\n$3\r\nfoo\r\n$4\r\nbarz\r\nping\r\n";
+        let mut commands = vec![];
+        for b in message.iter() {
+            feed(&mut parser, &[*b]);
+            match parser.next() {
+                Ok(command) => commands.push(command.to_owned()),
+                Err(e) => assert_eq!(e, ParseError::Incomplete),
+            }
+        }
+        assert_eq!(commands.len(), 2);
+        let command = commands[0].get_command();
+        assert_eq!(command.argv.len(), 2);
+        assert_eq!(command.get_str(0).unwrap(), "foo");
+        assert_eq!(command.get_str(1).unwrap(), "barz");
+        assert_eq!(commands[1].get_command().get_str(0).unwrap(), "ping");
+    }
+
+    #[test]
+    fn parser_borrows_command() {
+        let mut parser = Parser::new();
+        feed(&mut parser, b"*1\r\n$3\r\nfoo\r\n*1\r\n$3\r\nbar\r\n");
+        {
+            let command = parser.next().unwrap();
+            assert_eq!(command.get_data(), b"*1\r\n$3\r\nfoo\r\n");
+            assert_eq!(command.get_str(0).unwrap(), "foo");
+        }
+        assert_eq!(parser.position(), 13);
+        assert_eq!(parser.next().unwrap().get_str(0).unwrap(), "bar");
+    }
+
+    #[test]
+    fn parser_allocate_keeps_pending() {
+        let mut parser = Parser::new();
+        feed(&mut parser, b"*1\r\n$3\r\nfoo\r\n*1\r\n$3\r\nb");
+        parser.next().unwrap();
+        let big = vec![b'a'; 64 * 1024];
+        // a large read moves the pending command to the start of the buffer
+        feed(&mut parser, b"ar\r\n");
+        feed(&mut parser, &big);
+        assert_eq!(parser.next().unwrap().get_str(0).unwrap(), "bar");
+    }
+
+    #[test]
+    fn parser_allocate_big_bulk() {
+        let mut parser = Parser::new();
+        feed(&mut parser, b"*1\r\n$536870912\r\n");
+        assert_eq!(parser.next().unwrap_err(), ParseError::Incomplete);
+        parser.allocate();
+        assert!(parser.get_mut().len() < 1024 * 1024);
+
+        // the buffer grows with the data
+        let mut parser = Parser::new();
+        let big = vec![b'a'; 1024 * 1024];
+        feed(&mut parser, b"*1\r\n$1048576\r\n");
+        feed(&mut parser, &big);
+        feed(&mut parser, b"\r\n");
+        assert_eq!(
+            parser.next().unwrap().get_slice(0).unwrap().len(),
+            big.len()
+        );
+        assert!(parser.get_mut().len() < 4 * 1024 * 1024);
+    }
+
+    #[test]
+    fn parser_max_bulk_len() {
+        let mut parser = Parser::new();
+        parser.set_max_bulk_len(3);
+        feed(&mut parser, b"*1\r\n$3\r\nfoo\r\n*1\r\n$4\r\n");
+        parser.next().unwrap();
+        assert_eq!(
+            parser.next().unwrap_err(),
+            ParseError::BadProtocol("invalid bulk length".to_owned())
+        );
+    }
+
+    #[test]
+    fn parser_query_buffer_limit() {
+        let mut parser = Parser::new();
+        parser.set_max_query_buffer(10);
+        feed(&mut parser, b"*1\r\n$20\r\n");
+        assert_eq!(parser.next().unwrap_err(), ParseError::Incomplete);
+        feed(&mut parser, b"0123");
+        assert_eq!(parser.next().unwrap_err(), ParseError::QueryBufferLimit);
+    }
+
+    #[test]
+    fn parse_to_owned_copies_arguments() {
+        let message = b"*1\r\n$3\r\nfoo\r\n*1\r\n$3\r\nbar\r\n";
+        let (command, len) = parse(message).unwrap();
+        assert_eq!(len, 13);
+        let owned = command.to_owned();
+        assert_eq!(owned.get_command().get_data(), b"foo");
+    }
+
     #[test]
     fn parser_debug_formatter() {
         let mut parser = Parser::new();
         let message = b"*2\r\n$3\r\n\x01\x00\x08\r\n$4\r\n\xffarz\r\n";
         {
             parser.written += message.len();
-            let mut v = parser.get_mut();
+            let v = parser.get_mut();
             v.extend(&*message.to_vec());
         }
         assert_eq!(
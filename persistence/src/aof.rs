@@ -43,10 +43,25 @@ impl Aof {
         self.fp.seek(SeekFrom::Start(pos as u64)).is_ok()
     }
 
+    /// Makes sure every command written is on disk.
+    pub fn fsync(&mut self) -> io::Result<()> {
+        self.fp.sync_all()
+    }
+
     pub fn write(&mut self, dbindex: usize, command: &ParsedCommand) -> io::Result<()> {
         self.select(dbindex)?;
-        self.fp.write_all(command.get_data())?;
-        Ok(())
+        // Re-encode the arguments instead of copying the received bytes, the
+        // command may have been sent inline or followed by pipelined data.
+        // The record is built in memory to write it with a single call.
+        let mut buf = Vec::with_capacity(command.get_data().len() + 16);
+        write!(buf, "*{}\r\n", command.argv.len())?;
+        for i in 0..command.argv.len() {
+            let arg = command.get_slice(i).unwrap();
+            write!(buf, "${}\r\n", arg.len())?;
+            buf.extend_from_slice(arg);
+            buf.extend_from_slice(b"\r\n");
+        }
+        self.fp.write_all(&buf)
     }
 }
 
@@ -90,6 +105,30 @@ mod test_aof {
         }
     }
 
+    #[test]
+    fn test_write_inline() {
+        let mut path = temp_dir();
+        path.push("aoftest3");
+
+        {
+            let command = parse(b"set key \"hello world\"\r\nget key\r\n").unwrap().0;
+
+            let mut w = Aof::new(path.as_path()).unwrap();
+            w.write(0, &command).unwrap()
+        }
+        {
+            let mut data = String::with_capacity(100);
+            File::open(path.as_path())
+                .unwrap()
+                .read_to_string(&mut data)
+                .unwrap();
+            assert_eq!(
+                data,
+                "*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$11\r\nhello world\r\n"
+            );
+        }
+    }
+
     #[test]
     fn test_read() {
         let mut path = temp_dir();
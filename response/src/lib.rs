@@ -41,34 +41,49 @@ impl Debug for ResponseError {
     }
 }
 
+/// Appends a protocol line with its type prefix and terminator.
+fn write_line(buf: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    buf.push(prefix);
+    buf.extend_from_slice(line);
+    buf.extend_from_slice(b"\r\n");
+}
+
 impl Response {
     /// Serializes the response into an array of bytes using Redis protocol.
     pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_to(&mut buf);
+        buf
+    }
+
+    /// Serializes the response using Redis protocol, appending it to an
+    /// existing buffer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use response::Response;
+    /// let mut buf = b"+OK\r\n".to_vec();
+    /// Response::Array(vec![Response::Integer(1), Response::Nil]).write_to(&mut buf);
+    /// assert_eq!(buf, b"+OK\r\n*2\r\n:1\r\n$-1\r\n");
+    /// ```
+    pub fn write_to(&self, buf: &mut Vec<u8>) {
         match self {
-            Response::Nil => b"$-1\r\n".to_vec(),
-            Response::Data(d) => [
-                &b"$"[..],
-                &d.len().to_string().into_bytes()[..],
-                b"\r\n",
-                &d[..],
-                b"\r\n",
-            ]
-            .concat(),
-            Response::Integer(i) => [&b":"[..], &i.to_string().into_bytes()[..], b"\r\n"].concat(),
-            Response::Error(d) => [&b"-"[..], (*d).as_bytes(), b"\r\n"].concat(),
-            Response::Status(d) => [
-                &b"+"[..],
-                (*d).as_bytes(),
-                &"\r\n".to_owned().into_bytes()[..],
-            ]
-            .concat(),
-            Response::Array(a) => [
-                &b"*"[..],
-                &a.len().to_string().into_bytes()[..],
-                b"\r\n",
-                &(a.iter().map(|el| el.as_bytes()).collect::<Vec<_>>()[..].concat())[..],
-            ]
-            .concat(),
+            Response::Nil => buf.extend_from_slice(b"$-1\r\n"),
+            Response::Data(d) => {
+                write_line(buf, b'$', d.len().to_string().as_bytes());
+                buf.extend_from_slice(d);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Response::Integer(i) => write_line(buf, b':', i.to_string().as_bytes()),
+            Response::Error(d) => write_line(buf, b'-', d.as_bytes()),
+            Response::Status(d) => write_line(buf, b'+', d.as_bytes()),
+            Response::Array(a) => {
+                write_line(buf, b'*', a.len().to_string().as_bytes());
+                for el in a.iter() {
+                    el.write_to(buf);
+                }
+            }
         }
     }
 
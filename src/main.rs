@@ -6,12 +6,21 @@ use std::process::exit;
 use crate::release::*;
 use compat::getpid;
 use config::Config;
+use database::sentinel::SENTINEL_PORT;
 use logger::{Level, Logger};
 use networking::Server;
 
 fn main() {
     let mut config = Config::new(Logger::new(Level::Notice));
-    if let Some(f) = args().nth(1) {
+    // like redis-server, it runs as a sentinel with `--sentinel` or when the
+    // binary is called `redis-sentinel`
+    let sentinel_mode = args().skip(1).any(|arg| arg == "--sentinel")
+        || args().next().is_some_and(|arg| arg.ends_with("sentinel"));
+    if sentinel_mode {
+        config.sentinel_mode = true;
+        config.port = SENTINEL_PORT;
+    }
+    if let Some(f) = args().skip(1).find(|arg| !arg.starts_with("--")) {
         if config.parsefile(f).is_err() {
             exit(1);
         }
@@ -20,7 +29,7 @@ fn main() {
     let (port, daemonize) = (config.port, config.daemonize);
     let mut server = Server::new(config);
     {
-        let mut db = server.get_mut_db();
+        let db = server.get_mut_db();
         db.git_sha1 = GIT_SHA1;
         db.git_dirty = GIT_DIRTY;
         db.version = env!("CARGO_PKG_VERSION");
@@ -41,6 +41,8 @@ pub fn match_char(e1: &u8, e2: &u8, ignore_case: bool) -> bool {
 /// assert!(glob_match(&b"fooba?".to_vec(), &b"foobar".to_vec(), false));
 /// assert!(glob_match(&b"fooba?".to_vec(), &b"foobaz".to_vec(), false));
 /// assert!(!glob_match(&b"fooba?".to_vec(), &b"foofoo".to_vec(), false));
+/// assert!(glob_match(&b"foo*".to_vec(), &b"foo".to_vec(), false));
+/// assert!(glob_match(&b"f*o**".to_vec(), &b"foo".to_vec(), false));
 /// ```
 #[must_use]
 pub fn glob_match(pattern: &[u8], element: &[u8], ignore_case: bool) -> bool {
@@ -149,10 +151,9 @@ pub fn glob_match(pattern: &[u8], element: &[u8], ignore_case: bool) -> bool {
         }
         patternpos += 1;
         if elementpos == element.len() {
-            for i in &pattern[patternpos..pattern.len()] {
-                if *i != star {
-                    break;
-                }
+            // trailing stars match the empty string
+            while patternpos < pattern.len() && pattern[patternpos] == star {
+                patternpos += 1;
             }
             break;
         }